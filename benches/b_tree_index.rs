@@ -0,0 +1,166 @@
+//! Compares the index B+tree with a `BTreeMap` behind a `RwLock`, the structure it replaces.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use quokka_rs::b_tree_index::BPlusTree;
+
+const SIZES: [u64; 2] = [10_000, 1_000_000];
+
+/// Keys in a scrambled order so inserts don't always hit the rightmost leaf
+fn keys(n: u64) -> Vec<u64> {
+    (0..n)
+        .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % n)
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for n in SIZES {
+        let keys = keys(n);
+        group.bench_with_input(BenchmarkId::new("b_plus_tree", n), &keys, |b, keys| {
+            b.iter(|| {
+                let tree = BPlusTree::new();
+                for key in keys {
+                    tree.insert(*key, *key);
+                }
+                tree
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("rwlock_btree_map", n), &keys, |b, keys| {
+            b.iter(|| {
+                let map = RwLock::new(BTreeMap::new());
+                for key in keys {
+                    map.write().unwrap().insert(*key, *key);
+                }
+                map
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for n in SIZES {
+        let keys = keys(n);
+        let tree = BPlusTree::new();
+        let map = RwLock::new(BTreeMap::new());
+        for key in keys.iter() {
+            tree.insert(*key, *key);
+            map.write().unwrap().insert(*key, *key);
+        }
+        group.bench_function(BenchmarkId::new("b_plus_tree", n), |b| {
+            b.iter(|| {
+                for key in keys.iter().take(1_000) {
+                    black_box(tree.get(key));
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("rwlock_btree_map", n), |b| {
+            b.iter(|| {
+                for key in keys.iter().take(1_000) {
+                    black_box(map.read().unwrap().get(key).copied());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_1000");
+    for n in SIZES {
+        let tree = BPlusTree::new();
+        let map = RwLock::new(BTreeMap::new());
+        for key in keys(n) {
+            tree.insert(key, key);
+            map.write().unwrap().insert(key, key);
+        }
+        let start = n / 2;
+        group.bench_function(BenchmarkId::new("b_plus_tree", n), |b| {
+            b.iter(|| {
+                tree.range(start..start + 1_000)
+                    .map(|(_, v)| v)
+                    .sum::<u64>()
+            })
+        });
+        group.bench_function(BenchmarkId::new("rwlock_btree_map", n), |b| {
+            b.iter(|| {
+                map.read()
+                    .unwrap()
+                    .range(start..start + 1_000)
+                    .map(|(_, v)| *v)
+                    .sum::<u64>()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Point reads from several threads while one thread inserts
+fn mixed(c: &mut Criterion) {
+    const READERS: u64 = 4;
+    const OPS: u64 = 20_000;
+    let mut group = c.benchmark_group("mixed_4_readers_1_writer");
+    group.sample_size(10);
+    group.bench_function("b_plus_tree", |b| {
+        b.iter(|| {
+            let tree = Arc::new(BPlusTree::new());
+            for key in 0..OPS {
+                tree.insert(key * 2, key);
+            }
+            std::thread::scope(|s| {
+                let writer = tree.clone();
+                s.spawn(move || {
+                    for key in 0..OPS {
+                        writer.insert(key * 2 + 1, key);
+                    }
+                });
+                for reader in 0..READERS {
+                    let tree = tree.clone();
+                    s.spawn(move || {
+                        for key in 0..OPS {
+                            black_box(tree.get(&((key * 7 + reader) % (OPS * 2))));
+                        }
+                    });
+                }
+            });
+        })
+    });
+    group.bench_function("rwlock_btree_map", |b| {
+        b.iter(|| {
+            let map = Arc::new(RwLock::new(BTreeMap::new()));
+            for key in 0..OPS {
+                map.write().unwrap().insert(key * 2, key);
+            }
+            std::thread::scope(|s| {
+                let writer = map.clone();
+                s.spawn(move || {
+                    for key in 0..OPS {
+                        writer.write().unwrap().insert(key * 2 + 1, key);
+                    }
+                });
+                for reader in 0..READERS {
+                    let map = map.clone();
+                    s.spawn(move || {
+                        for key in 0..OPS {
+                            black_box(
+                                map.read()
+                                    .unwrap()
+                                    .get(&((key * 7 + reader) % (OPS * 2)))
+                                    .copied(),
+                            );
+                        }
+                    });
+                }
+            });
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert, get, range, mixed);
+criterion_main!(benches);
@@ -0,0 +1,114 @@
+//! Compares the I/O backends of the write-ahead log: how long a synced commit takes, and how long
+//! startup takes to replay a log they wrote.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::array::{Float64Array, Int32Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use datafusion_common::{Constraints, TableReference};
+use quokka_rs::catalog::next_table_id;
+use quokka_rs::file_io::IoBackend;
+use quokka_rs::session::new_context;
+use quokka_rs::sql::TableStorage;
+use quokka_rs::system_catalog::TableEntry;
+use quokka_rs::wal::{LogRecord, Wal, WalOptions};
+use tokio::runtime::Runtime;
+
+/// Rows inserted by each logged write
+const ROWS_PER_WRITE: i32 = 100;
+
+/// Writes in the replayed log
+const WRITES: usize = 1_000;
+
+/// The backends this machine supports
+fn backends() -> Vec<IoBackend> {
+    let mut backends = vec![IoBackend::Std];
+    for backend in [
+        IoBackend::IoUring { direct: false },
+        IoBackend::IoUring { direct: true },
+    ] {
+        if backend.or_fallback() == backend {
+            backends.push(backend);
+        }
+    }
+    backends
+}
+
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("price", DataType::Float64, true),
+    ]))
+}
+
+fn create_table(id: u64) -> LogRecord {
+    let name = TableReference::full("datafusion", "public", "bench").resolve("", "");
+    let entry = TableEntry::new(
+        id,
+        name,
+        TableStorage::Block,
+        schema(),
+        Constraints::empty(),
+        HashMap::new(),
+    );
+    LogRecord::create_table(&entry, false).expect("table can be logged")
+}
+
+fn insert(id: u64, write: i32) -> LogRecord {
+    let ids = Int32Array::from_iter_values(write * ROWS_PER_WRITE..(write + 1) * ROWS_PER_WRITE);
+    let prices = Float64Array::from_iter_values(ids.values().iter().map(|id| *id as f64 / 2.0));
+    let batch = RecordBatch::try_new(schema(), vec![Arc::new(ids), Arc::new(prices)])
+        .expect("batch matches schema");
+    LogRecord::change(id, &schema(), &[], &[batch]).expect("rows can be logged")
+}
+
+fn commit(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime can be built");
+    let mut group = c.benchmark_group("wal_commit");
+    for backend in backends() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let options = WalOptions::new(dir.path()).with_io(backend);
+        let (mut wal, _) = runtime
+            .block_on(Wal::open(options, &new_context()))
+            .expect("log can be opened");
+        let record = insert(next_table_id(), 0);
+        group.bench_function(BenchmarkId::from_parameter(backend), |b| {
+            b.iter(|| wal.append(vec![record.clone()]).expect("commit is logged"))
+        });
+    }
+    group.finish();
+}
+
+fn recovery(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime can be built");
+    let mut group = c.benchmark_group("wal_recovery");
+    group.sample_size(10);
+    for backend in backends() {
+        let dir = tempfile::tempdir().expect("temp dir can be created");
+        let options = WalOptions::new(dir.path()).with_io(backend);
+        let (mut wal, _) = runtime
+            .block_on(Wal::open(options.clone(), &new_context()))
+            .expect("log can be opened");
+        let id = next_table_id();
+        wal.append(vec![create_table(id)]).expect("table is logged");
+        for write in 0..WRITES {
+            wal.append(vec![insert(id, write as i32)])
+                .expect("write is logged");
+        }
+        drop(wal);
+        group.bench_function(BenchmarkId::from_parameter(backend), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(Wal::open(options.clone(), &new_context()))
+                    .expect("log can be replayed")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, commit, recovery);
+criterion_main!(benches);
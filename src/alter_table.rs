@@ -0,0 +1,644 @@
+//! `ALTER TABLE`: adding, dropping and renaming the columns of Quokka tables, and renaming the
+//! tables themselves.
+//!
+//! A table's schema never changes in place. A new version of the table is built from the rows
+//! of the current one, which for memory tables reuses their column arrays, so only the added
+//! column is allocated and the indexes are rebuilt. It's built outside the writer while holding
+//! the table's [`TableLock`](crate::catalog::TableLock), so only writes to that table wait for
+//! it, and the writer then swaps the new version in for the old one in the catalog. Queries
+//! carry on with the version they started with, and writes still queued for the old version fail
+//! (see [`Retired`](crate::catalog::Retired)). Renaming a table only registers the same version
+//! under its new name.
+//!
+//! Each [`TableChange`] is logged, with the value an added column was back-filled with, so
+//! replaying it rebuilds the same rows.
+
+use std::fmt;
+use std::sync::Arc;
+
+use arrow::datatypes::{Field, FieldRef, Schema};
+use arrow::record_batch::{RecordBatch, RecordBatchOptions};
+use datafusion::datasource::TableProvider;
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::Expr;
+use datafusion::physical_expr::{create_physical_expr, execution_props::ExecutionProps};
+use datafusion::scalar::ScalarValue;
+use datafusion_common::{
+    exec_err, plan_err, Constraint, Constraints, DFSchema, DataFusionError, OwnedTableReference,
+    ResolvedTableReference, TableReference,
+};
+use tokio::sync::OwnedRwLockWriteGuard;
+
+use crate::catalog::TableId;
+use crate::sql::{as_block_table, as_mem_table};
+use crate::system_catalog::{system_catalog, table_lock, SystemCatalog, TableEntry};
+use crate::transaction::ActiveSnapshot;
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied};
+
+/// One operation of an `ALTER TABLE` statement
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::large_enum_variant)]
+pub enum AlterTableOperation {
+    /// `ADD [COLUMN] [IF NOT EXISTS] name type [DEFAULT expr]`
+    AddColumn {
+        field: Field,
+        default: Option<Expr>,
+        if_not_exists: bool,
+    },
+    /// `DROP [COLUMN] [IF EXISTS] name`
+    DropColumn { name: String, if_exists: bool },
+    /// `RENAME COLUMN old TO new`
+    RenameColumn { old: String, new: String },
+    /// `RENAME TO name`
+    RenameTable { name: OwnedTableReference },
+}
+
+impl fmt::Display for AlterTableOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlterTableOperation::AddColumn { field, default, .. } => {
+                write!(f, "ADD COLUMN {} {}", field.name(), field.data_type())?;
+                match default {
+                    Some(default) => write!(f, " DEFAULT {default}"),
+                    None => Ok(()),
+                }
+            }
+            AlterTableOperation::DropColumn { name, .. } => write!(f, "DROP COLUMN {name}"),
+            AlterTableOperation::RenameColumn { old, new } => {
+                write!(f, "RENAME COLUMN {old} TO {new}")
+            }
+            AlterTableOperation::RenameTable { name } => write!(f, "RENAME TO {name}"),
+        }
+    }
+}
+
+/// An [`AlterTableOperation`] checked against the table it changes, as it's logged
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum TableChange {
+    /// Add `field`, giving the rows already in the table the value `fill`
+    AddColumn {
+        field: FieldRef,
+        fill: ScalarValue,
+        default: Option<Expr>,
+    },
+    DropColumn(String),
+    RenameColumn {
+        old: String,
+        new: String,
+    },
+    RenameTable(ResolvedTableReference<'static>),
+}
+
+/// Apply the operations of `ALTER TABLE table`. The new version of the table is built while
+/// writes to it are held off, and the session's writer swaps it in and logs the changes.
+pub async fn alter_table(
+    ctx: &SessionContext,
+    table: &OwnedTableReference,
+    if_exists: bool,
+    operations: &[AlterTableOperation],
+) -> Result<()> {
+    let state = ctx.state();
+    let options = &state.config_options().catalog;
+    let name = table
+        .clone()
+        .resolve(&options.default_catalog, &options.default_schema);
+    let system_catalog = system_catalog(ctx)?;
+    let Some(entry) = system_catalog.find(&name) else {
+        return match ctx.table_exist(table.clone())? {
+            true => plan_err!("{table} is not a Quokka table"),
+            false if if_exists => Ok(()),
+            false => plan_err!("Table {table} does not exist"),
+        };
+    };
+    let id = entry.id;
+    let (entry, table, _lock) = lock_table(ctx, &system_catalog, id).await?;
+    let props = state.execution_props();
+    let mut changes = vec![];
+    let mut altered = entry.clone();
+    for operation in operations {
+        if let Some(change) = check(&altered, operation, props)? {
+            (altered, _) = apply(altered, vec![], &change)?;
+            changes.push(change);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let (altered, new_version) =
+        build_version(ctx, &system_catalog, &entry, &table, &changes).await?;
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    let session = ctx.clone();
+    writer
+        .submit(move |_| async move {
+            // Dropping the table doesn't wait for the lock
+            let current = session.table_provider(entry.name.clone()).await;
+            if !current.is_ok_and(|current| Arc::ptr_eq(&current, &table)) {
+                return exec_err!("Table {id} was dropped");
+            }
+            system_catalog.replace_table(&session, &entry, &table, altered, new_version)?;
+            let log = if log {
+                changes
+                    .iter()
+                    .map(|change| LogRecord::alter_table(id, change))
+                    .collect::<Result<_>>()?
+            } else {
+                vec![]
+            };
+            Ok(Applied::new(0, log))
+        })
+        .await?;
+    Ok(())
+}
+
+/// The entry and current version of table `id`, locked so no write to it runs until the lock
+/// is released
+async fn lock_table(
+    ctx: &SessionContext,
+    system_catalog: &SystemCatalog,
+    id: TableId,
+) -> Result<(
+    TableEntry,
+    Arc<dyn TableProvider>,
+    OwnedRwLockWriteGuard<()>,
+)> {
+    loop {
+        let Some(entry) = system_catalog.entry(id) else {
+            return exec_err!("Table {id} was dropped");
+        };
+        let table = ctx.table_provider(entry.name.clone()).await?;
+        let Some(lock) = table_lock(&table) else {
+            return exec_err!("Only Quokka tables can be altered");
+        };
+        let guard = lock.lock_for_alter().await;
+        // Another statement may have altered the table while this one waited for it
+        let Some(entry) = system_catalog.entry(id) else {
+            return exec_err!("Table {id} was dropped");
+        };
+        let current = ctx.table_provider(entry.name.clone()).await?;
+        if Arc::ptr_eq(&current, &table) {
+            return Ok((entry, table, guard));
+        }
+    }
+}
+
+/// Check `operation` against the table `entry` describes, returning `None` if it does nothing
+/// because of `IF [NOT] EXISTS`
+fn check(
+    entry: &TableEntry,
+    operation: &AlterTableOperation,
+    props: &ExecutionProps,
+) -> Result<Option<TableChange>> {
+    let has_column = |name: &str| entry.schema.field_with_name(name).is_ok();
+    Ok(Some(match operation {
+        AlterTableOperation::AddColumn {
+            field,
+            default,
+            if_not_exists,
+        } => {
+            if has_column(field.name()) {
+                if *if_not_exists {
+                    return Ok(None);
+                }
+                return plan_err!(
+                    "Column {} of table {} already exists",
+                    field.name(),
+                    entry.name
+                );
+            }
+            let fill = match default {
+                Some(default) => evaluate_default(default, props)?.cast_to(field.data_type())?,
+                None => ScalarValue::try_from(field.data_type())?,
+            };
+            TableChange::AddColumn {
+                field: Arc::new(field.clone()),
+                fill,
+                default: default.clone(),
+            }
+        }
+        AlterTableOperation::DropColumn { name, if_exists } => {
+            if !has_column(name) {
+                if *if_exists {
+                    return Ok(None);
+                }
+                return plan_err!("Column {name} of table {} does not exist", entry.name);
+            }
+            TableChange::DropColumn(name.clone())
+        }
+        AlterTableOperation::RenameColumn { old, new } => TableChange::RenameColumn {
+            old: old.clone(),
+            new: new.clone(),
+        },
+        AlterTableOperation::RenameTable { name } => {
+            // A table stays in its schema unless the new name says otherwise
+            let name = name
+                .clone()
+                .resolve(&entry.name.catalog, &entry.name.schema);
+            TableChange::RenameTable(
+                TableReference::full(
+                    name.catalog.to_string(),
+                    name.schema.to_string(),
+                    name.table.to_string(),
+                )
+                .resolve("", ""),
+            )
+        }
+    }))
+}
+
+/// The value a default gives the rows already in the table. Defaults are evaluated once, so
+/// every row gets the same value even from a volatile expression.
+fn evaluate_default(default: &Expr, props: &ExecutionProps) -> Result<ScalarValue> {
+    if let Expr::Literal(value) = default {
+        return Ok(value.clone());
+    }
+    let expr = create_physical_expr(default, &DFSchema::empty(), props)?;
+    let batch = RecordBatch::try_new_with_options(
+        Arc::new(Schema::empty()),
+        vec![],
+        &RecordBatchOptions::new().with_row_count(Some(1)),
+    )?;
+    let value = expr.evaluate(&batch)?.into_array(1)?;
+    ScalarValue::try_from_array(&value, 0)
+}
+
+/// Build the new version of table `id` with `changes` applied, and swap it into the catalog.
+/// Replays logged changes, which nothing else writes to the table during.
+pub(crate) async fn apply_changes(
+    ctx: &SessionContext,
+    system_catalog: &SystemCatalog,
+    id: TableId,
+    changes: &[TableChange],
+) -> Result<Arc<dyn TableProvider>> {
+    let Some(old) = system_catalog.entry(id) else {
+        return exec_err!("Table {id} is not in the system catalog");
+    };
+    let table = ctx.table_provider(old.name.clone()).await?;
+    let (entry, new_version) = build_version(ctx, system_catalog, &old, &table, changes).await?;
+    system_catalog.replace_table(ctx, &old, &table, entry, new_version.clone())?;
+    Ok(new_version)
+}
+
+/// The entry and new version of `table`, whose entry is `old`, with `changes` applied. Renaming
+/// a table keeps the same version, and otherwise nothing may write to `table` meanwhile.
+async fn build_version(
+    ctx: &SessionContext,
+    system_catalog: &SystemCatalog,
+    old: &TableEntry,
+    table: &Arc<dyn TableProvider>,
+    changes: &[TableChange],
+) -> Result<(TableEntry, Arc<dyn TableProvider>)> {
+    let renames_only = changes
+        .iter()
+        .all(|change| matches!(change, TableChange::RenameTable(_)));
+    let mut partitions = if renames_only {
+        vec![]
+    } else {
+        table_partitions(table).await?
+    };
+    let mut entry = old.clone();
+    for change in changes {
+        (entry, partitions) = apply(entry, partitions, change)?;
+    }
+    let new_version = if renames_only {
+        table.clone()
+    } else {
+        system_catalog.build_table(ctx, &entry, partitions).await?
+    };
+    Ok((entry, new_version))
+}
+
+/// The rows of a Quokka table, by partition. Nothing writes to it meanwhile, so they're the
+/// latest rows.
+async fn table_partitions(table: &Arc<dyn TableProvider>) -> Result<Vec<Vec<RecordBatch>>> {
+    if let Some(mem_table) = as_mem_table(table) {
+        Ok(mem_table.partitions().await)
+    } else if let Some(block_table) = as_block_table(table) {
+        let column_ids: Vec<usize> = (0..table.schema().fields().len()).collect();
+        let snapshot = ActiveSnapshot::begin();
+        Ok(vec![block_table.batches(&column_ids, snapshot.snapshot())?])
+    } else {
+        exec_err!("Only Quokka tables can be altered")
+    }
+}
+
+/// Apply `change` to a table's entry and to its rows
+pub(crate) fn apply(
+    mut entry: TableEntry,
+    partitions: Vec<Vec<RecordBatch>>,
+    change: &TableChange,
+) -> Result<(TableEntry, Vec<Vec<RecordBatch>>)> {
+    let rewrite = |partitions: Vec<Vec<RecordBatch>>,
+                   rewrite_batch: &dyn Fn(&RecordBatch) -> Result<RecordBatch>| {
+        partitions
+            .into_iter()
+            .map(|batches| {
+                batches
+                    .iter()
+                    .map(rewrite_batch)
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+    let partitions = match change {
+        TableChange::AddColumn {
+            field,
+            fill,
+            default,
+        } => {
+            if entry.schema.field_with_name(field.name()).is_ok() {
+                return plan_err!(
+                    "Column {} of table {} already exists",
+                    field.name(),
+                    entry.name
+                );
+            }
+            let has_rows = partitions
+                .iter()
+                .flatten()
+                .any(|batch| batch.num_rows() > 0);
+            if fill.is_null() && !field.is_nullable() && has_rows {
+                return exec_err!(
+                    "Column {} can't be NOT NULL without a default, since {} has rows",
+                    field.name(),
+                    entry.name
+                );
+            }
+            let mut fields = entry.schema.fields().to_vec();
+            fields.push(field.clone());
+            let schema = Arc::new(Schema::new_with_metadata(
+                fields,
+                entry.schema.metadata().clone(),
+            ));
+            let partitions = rewrite(partitions, &|batch| {
+                let mut columns = batch.columns().to_vec();
+                columns.push(fill.to_array_of_size(batch.num_rows())?);
+                Ok(RecordBatch::try_new(schema.clone(), columns)?)
+            })?;
+            if let Some(default) = default {
+                entry
+                    .column_defaults
+                    .insert(field.name().clone(), default.clone());
+            }
+            entry.schema = schema;
+            partitions
+        }
+        TableChange::DropColumn(name) => {
+            let index = entry.schema.index_of(name)?;
+            if entry.schema.fields().len() == 1 {
+                return plan_err!("Can't drop {name}, the only column of {}", entry.name);
+            }
+            if entry.partition_by.as_ref() == Some(name) {
+                return plan_err!("Can't drop {name}, the partition key of {}", entry.name);
+            }
+            if let Some(policy) = policy_on(&entry, name) {
+                return plan_err!("Can't drop {name}, which policy {policy} uses");
+            }
+            let shift = |columns: &Vec<usize>| {
+                columns
+                    .iter()
+                    .map(|column| if *column > index { column - 1 } else { *column })
+                    .collect()
+            };
+            let mut constraints = vec![];
+            for constraint in entry.constraints.iter() {
+                match constraint {
+                    Constraint::PrimaryKey(columns) if columns.contains(&index) => {
+                        return plan_err!("Can't drop {name}, the primary key of {}", entry.name);
+                    }
+                    // Like Postgres, dropping a column drops the constraints and indexes on it
+                    Constraint::Unique(columns) if columns.contains(&index) => {}
+                    Constraint::PrimaryKey(columns) => {
+                        constraints.push(Constraint::PrimaryKey(shift(columns)))
+                    }
+                    Constraint::Unique(columns) => {
+                        constraints.push(Constraint::Unique(shift(columns)))
+                    }
+                }
+            }
+            entry.constraints = Constraints::new_unverified(constraints);
+            entry.indexes.retain(|index| !index.columns.contains(name));
+            entry.column_defaults.remove(name);
+            let kept: Vec<usize> = (0..entry.schema.fields().len())
+                .filter(|column| *column != index)
+                .collect();
+            entry.schema = Arc::new(entry.schema.project(&kept)?);
+            rewrite(partitions, &|batch| Ok(batch.project(&kept)?))?
+        }
+        TableChange::RenameColumn { old, new } => {
+            let index = entry.schema.index_of(old)?;
+            if entry.schema.field_with_name(new).is_ok() {
+                return plan_err!("Column {new} of table {} already exists", entry.name);
+            }
+            if let Some(policy) = policy_on(&entry, old) {
+                return plan_err!("Can't rename {old}, which policy {policy} uses");
+            }
+            let mut fields = entry.schema.fields().to_vec();
+            fields[index] = Arc::new(fields[index].as_ref().clone().with_name(new));
+            let mut metadata = entry.schema.metadata().clone();
+            if metadata.get("primary_key") == Some(old) {
+                metadata.insert("primary_key".to_string(), new.clone());
+            }
+            let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+            if let Some(default) = entry.column_defaults.remove(old) {
+                entry.column_defaults.insert(new.clone(), default);
+            }
+            if entry.partition_by.as_ref() == Some(old) {
+                entry.partition_by = Some(new.clone());
+            }
+            for index in entry.indexes.iter_mut() {
+                for column in index.columns.iter_mut() {
+                    if column == old {
+                        *column = new.clone();
+                    }
+                }
+            }
+            let partitions = rewrite(partitions, &|batch| {
+                Ok(RecordBatch::try_new(
+                    schema.clone(),
+                    batch.columns().to_vec(),
+                )?)
+            })?;
+            entry.schema = schema;
+            partitions
+        }
+        TableChange::RenameTable(name) => {
+            entry.name = name.clone();
+            partitions
+        }
+    };
+    Ok((entry, partitions))
+}
+
+/// Name of a policy of `entry` that uses column `column`
+fn policy_on<'a>(entry: &'a TableEntry, column: &str) -> Option<&'a str> {
+    entry
+        .policies
+        .iter()
+        .find(|policy| policy.columns().iter().any(|c| c == column))
+        .map(|policy| policy.name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::session::new_context;
+    use crate::test_util::{query, run};
+
+    #[tokio::test]
+    async fn add_drop_and_rename_columns() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, price DOUBLE)",
+            "INSERT INTO products VALUES (1, 'apple', 1.0), (2, 'pear', 2.0)",
+            "CREATE UNIQUE INDEX products_name ON products (name)",
+            "CREATE INDEX products_price ON products (price)",
+            "ALTER TABLE products ADD COLUMN stock INT DEFAULT 10",
+            "ALTER TABLE products DROP COLUMN price",
+            "ALTER TABLE products RENAME COLUMN name TO title",
+            "INSERT INTO products (id, title) VALUES (3, 'plum')",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        assert_eq!(
+            "+----+-------+-------+\n\
+             | id | title | stock |\n\
+             +----+-------+-------+\n\
+             | 1  | apple | 10    |\n\
+             | 2  | pear  | 10    |\n\
+             | 3  | plum  | 10    |\n\
+             +----+-------+-------+",
+            query(&ctx, "SELECT * FROM products ORDER BY id").await?
+        );
+
+        // The index on the dropped column is gone and the other one follows the rename
+        let table = ctx.table_provider("products").await?;
+        let mem_table = as_mem_table(&table).expect("memory table");
+        assert_eq!(
+            vec![("products_name".to_string(), vec!["title".to_string()], true)],
+            mem_table.index_definitions().await
+        );
+        let e = run(&ctx, "INSERT INTO products VALUES (4, 'pear', 1)")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Execution error: Duplicate key value violates unique index products_name",
+            e.strip_backtrace()
+        );
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 2  |\n+----+",
+            query(&ctx, "SELECT id FROM products WHERE title = 'pear'").await?
+        );
+
+        let e = run(&ctx, "ALTER TABLE products DROP COLUMN id")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Error during planning: Can't drop id, the primary key of datafusion.public.products",
+            e.strip_backtrace()
+        );
+        let e = run(&ctx, "ALTER TABLE products ADD COLUMN code INT NOT NULL")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Execution error: Column code can't be NOT NULL without a default, since \
+             datafusion.public.products has rows",
+            e.strip_backtrace()
+        );
+        run(
+            &ctx,
+            "ALTER TABLE products ADD COLUMN IF NOT EXISTS stock INT",
+        )
+        .await?;
+        run(&ctx, "ALTER TABLE products DROP COLUMN IF EXISTS price").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn alter_block_table_and_rename_it() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE events (id INT, kind VARCHAR) WITH (storage = 'block')",
+            "INSERT INTO events VALUES (1, 'click'), (2, 'view')",
+            "ALTER TABLE events ADD COLUMN at BIGINT",
+            "UPDATE events SET at = 5 WHERE id = 2",
+            "ALTER TABLE events RENAME COLUMN kind TO event_kind",
+            "ALTER TABLE events RENAME TO clicks",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        assert_eq!(
+            "+----+------------+----+\n\
+             | id | event_kind | at |\n\
+             +----+------------+----+\n\
+             | 1  | click      |    |\n\
+             | 2  | view       | 5  |\n\
+             +----+------------+----+",
+            query(&ctx, "SELECT * FROM clicks ORDER BY id").await?
+        );
+        assert!(run(&ctx, "SELECT * FROM events").await.is_err());
+        assert_eq!(
+            "+------------+\n| table_name |\n+------------+\n| clicks     |\n+------------+",
+            query(&ctx, "SELECT table_name FROM qs_catalog.qs_tables").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_to_the_old_version_fail() -> Result<()> {
+        let ctx = new_context();
+        run(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+        )
+        .await?;
+        let old = ctx.table_provider("products").await?;
+        run(&ctx, "ALTER TABLE products ADD COLUMN price DOUBLE").await?;
+        let e = as_mem_table(&old)
+            .expect("memory table")
+            .delete(&ctx.state(), None)
+            .await
+            .unwrap_err();
+        assert!(e.strip_backtrace().contains("was altered or dropped"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebuilding_a_table_only_holds_up_its_writes() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+            "CREATE TABLE brands (id INT PRIMARY KEY)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let products = ctx.table_provider("products").await?;
+        let rebuild = table_lock(&products)
+            .expect("Quokka table")
+            .lock_for_alter()
+            .await;
+        run(&ctx, "INSERT INTO brands VALUES (1)").await?;
+        let insert = tokio::spawn({
+            let ctx = ctx.clone();
+            async move { run(&ctx, "INSERT INTO products VALUES (1, 'apple')").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!insert.is_finished());
+        drop(rebuild);
+        insert.await.expect("the insert doesn't panic")?;
+        assert_eq!(
+            "+----+-------+\n\
+             | id | name  |\n\
+             +----+-------+\n\
+             | 1  | apple |\n\
+             +----+-------+",
+            query(&ctx, "SELECT * FROM products").await?
+        );
+        Ok(())
+    }
+}
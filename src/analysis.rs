@@ -0,0 +1,1079 @@
+//! Text analysis: how full-text indexes and queries turn text into the terms they look up.
+//!
+//! An [`Analyzer`] splits text into [`Token`]s with a [`Tokenizer`], then passes them through a
+//! chain of [`TokenFilter`]s that lowercase, fold accents, drop stop words or stem them with the
+//! Snowball stemmer of one of the [`Language`]s. The built-in analyzers are
+//!
+//! * `standard`, Unicode words, lowercased, which fulltext indexes use by default
+//! * `whitespace`, whatever is between whitespace, as written
+//! * `english` and `german`, which also drop the language's stop words and stem the rest
+//!
+//! and `CREATE TEXT ANALYZER name TOKENIZER ngram(2, 3) FILTERS lowercase, asciifolding` registers
+//! a custom one in the system catalog's [`Analyzers`], logged and checkpointed like roles.
+//! `CREATE INDEX ... USING fulltext (columns) WITH (analyzer = 'english')` chooses the analyzer of
+//! an index, and `match()` analyzes its query with the analyzer of the index on the column it
+//! searches, so the terms of queries and rows agree. `quokka_tokenize(text, 'analyzer')` shows the
+//! terms an analyzer makes of some text.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use arrow::array::{ArrayRef, ListBuilder, StringBuilder};
+use arrow::datatypes::{DataType, Field};
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::{ColumnarValue, Expr, ScalarUDFImpl, Signature, Volatility};
+use datafusion::scalar::ScalarValue;
+use datafusion_common::tree_node::{Transformed, TreeNode, VisitRecursion};
+use datafusion_common::{exec_err, plan_err, DataFusionError};
+use datafusion_expr::expr::{Exists, InSubquery, ScalarFunction, ScalarFunctionDefinition};
+use datafusion_expr::{LogicalPlan, ScalarUDF, Subquery, TypeSignature};
+use datafusion_sql::sqlparser::dialect::GenericDialect;
+use datafusion_sql::sqlparser::parser::{Parser, ParserError};
+use datafusion_sql::sqlparser::tokenizer::Token as SqlToken;
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::system_catalog::{system_catalog, SystemCatalog};
+use crate::text_index::text_values;
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied};
+
+/// Name of the analyzer fulltext indexes use unless they choose another
+pub const STANDARD: &str = "standard";
+/// Name of the function showing the terms an analyzer makes of some text
+pub const TOKENIZE: &str = "quokka_tokenize";
+
+/// Names of the built-in analyzers, which custom analyzers can't replace
+const BUILTINS: [&str; 4] = [STANDARD, "whitespace", "english", "german"];
+
+/// Lucene's English stop words
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// The most common German stop words, from the Snowball list
+const GERMAN_STOP_WORDS: &[&str] = &[
+    "aber", "alle", "als", "also", "am", "an", "auch", "auf", "aus", "bei", "bin", "bis", "bist",
+    "da", "damit", "dann", "das", "dass", "dem", "den", "der", "des", "die", "dies", "dieser",
+    "doch", "dort", "du", "durch", "ein", "eine", "einem", "einen", "einer", "eines", "er", "es",
+    "für", "hat", "hatte", "ich", "ihr", "im", "in", "ist", "ja", "kein", "man", "mit", "nach",
+    "nicht", "noch", "nun", "nur", "ob", "oder", "ohne", "sich", "sie", "sind", "so", "um", "und",
+    "uns", "unter", "vom", "von", "vor", "war", "was", "wenn", "wie", "wir", "wird", "zu", "zum",
+    "zur", "über",
+];
+
+/// A term and its position in the text it came from. Terms made from the same word, like its
+/// n-grams, share the word's position, and dropping stop words leaves gaps in the positions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub term: String,
+    pub position: u32,
+}
+
+/// How an analyzer splits text into tokens
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Tokenizer {
+    /// Words as defined by Unicode's word boundaries (UAX #29), without punctuation
+    Standard,
+    /// Runs of characters other than whitespace
+    Whitespace,
+    /// Every substring of `min` to `max` characters of each word
+    NGram { min: usize, max: usize },
+    /// The prefixes of `min` to `max` characters of each word
+    EdgeNGram { min: usize, max: usize },
+}
+
+impl Tokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let words: Vec<&str> = match self {
+            Tokenizer::Whitespace => text.split_whitespace().collect(),
+            _ => text.unicode_words().collect(),
+        };
+        let mut tokens = vec![];
+        for (position, word) in words.into_iter().enumerate() {
+            let position = position as u32;
+            let token = |term: &[char]| Token {
+                term: term.iter().collect(),
+                position,
+            };
+            match self {
+                Tokenizer::Standard | Tokenizer::Whitespace => tokens.push(Token {
+                    term: word.to_string(),
+                    position,
+                }),
+                Tokenizer::NGram { min, max } => {
+                    let chars: Vec<char> = word.chars().collect();
+                    for start in 0..chars.len() {
+                        for len in *min..=(*max).min(chars.len() - start) {
+                            tokens.push(token(&chars[start..start + len]));
+                        }
+                    }
+                }
+                Tokenizer::EdgeNGram { min, max } => {
+                    let chars: Vec<char> = word.chars().collect();
+                    for len in *min..=(*max).min(chars.len()) {
+                        tokens.push(token(&chars[..len]));
+                    }
+                }
+            }
+        }
+        tokens
+    }
+}
+
+impl fmt::Display for Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tokenizer::Standard => write!(f, "standard"),
+            Tokenizer::Whitespace => write!(f, "whitespace"),
+            Tokenizer::NGram { min, max } => write!(f, "ngram({min}, {max})"),
+            Tokenizer::EdgeNGram { min, max } => write!(f, "edge_ngram({min}, {max})"),
+        }
+    }
+}
+
+/// A language with a Snowball stemmer, and maybe stop words
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    Arabic,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Greek,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Tamil,
+    Turkish,
+}
+
+impl Language {
+    pub const ALL: [Language; 18] = [
+        Language::Arabic,
+        Language::Danish,
+        Language::Dutch,
+        Language::English,
+        Language::Finnish,
+        Language::French,
+        Language::German,
+        Language::Greek,
+        Language::Hungarian,
+        Language::Italian,
+        Language::Norwegian,
+        Language::Portuguese,
+        Language::Romanian,
+        Language::Russian,
+        Language::Spanish,
+        Language::Swedish,
+        Language::Tamil,
+        Language::Turkish,
+    ];
+
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::Arabic => Algorithm::Arabic,
+            Language::Danish => Algorithm::Danish,
+            Language::Dutch => Algorithm::Dutch,
+            Language::English => Algorithm::English,
+            Language::Finnish => Algorithm::Finnish,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Greek => Algorithm::Greek,
+            Language::Hungarian => Algorithm::Hungarian,
+            Language::Italian => Algorithm::Italian,
+            Language::Norwegian => Algorithm::Norwegian,
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::Romanian => Algorithm::Romanian,
+            Language::Russian => Algorithm::Russian,
+            Language::Spanish => Algorithm::Spanish,
+            Language::Swedish => Algorithm::Swedish,
+            Language::Tamil => Algorithm::Tamil,
+            Language::Turkish => Algorithm::Turkish,
+        }
+    }
+
+    /// The common words of the language, if we have a list of them
+    fn stop_words(self) -> Option<&'static [&'static str]> {
+        match self {
+            Language::English => Some(ENGLISH_STOP_WORDS),
+            Language::German => Some(GERMAN_STOP_WORDS),
+            _ => None,
+        }
+    }
+
+    /// The Snowball stem of a lowercase word
+    fn stem(self, word: &str) -> String {
+        Stemmer::create(self.algorithm()).stem(word).into_owned()
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+impl FromStr for Language {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.to_ascii_lowercase();
+        match Language::ALL
+            .into_iter()
+            .find(|language| language.to_string() == name)
+        {
+            Some(language) => Ok(language),
+            None => {
+                let languages: Vec<String> =
+                    Language::ALL.iter().map(ToString::to_string).collect();
+                plan_err!(
+                    "Unsupported language {s}, expected one of {}",
+                    languages.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// The words a stop filter drops
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StopWords {
+    /// The common words of a language
+    Language(Language),
+    /// A list of words
+    Words(Vec<String>),
+}
+
+impl StopWords {
+    fn contains(&self, term: &str) -> bool {
+        match self {
+            StopWords::Language(language) => language
+                .stop_words()
+                .is_some_and(|words| words.contains(&term)),
+            StopWords::Words(words) => words.iter().any(|word| word == term),
+        }
+    }
+}
+
+/// A step of an analyzer's chain, which changes or drops tokens
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TokenFilter {
+    Lowercase,
+    /// Remove accents and replace letters like `ß` and `æ` with their closest ASCII spelling
+    AsciiFolding,
+    /// Drop stop words, which are compared to the terms as they are at this step
+    Stop(StopWords),
+    /// Reduce lowercase words to their Snowball stem
+    Stemmer(Language),
+}
+
+impl TokenFilter {
+    fn apply(&self, tokens: Vec<Token>) -> Vec<Token> {
+        match self {
+            TokenFilter::Stop(words) => tokens
+                .into_iter()
+                .filter(|token| !words.contains(&token.term))
+                .collect(),
+            filter => tokens
+                .into_iter()
+                .map(|token| Token {
+                    term: match filter {
+                        TokenFilter::Lowercase => token.term.to_lowercase(),
+                        TokenFilter::AsciiFolding => fold_to_ascii(&token.term),
+                        TokenFilter::Stemmer(language) => language.stem(&token.term),
+                        TokenFilter::Stop(_) => unreachable!("stop words are dropped above"),
+                    },
+                    position: token.position,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for TokenFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenFilter::Lowercase => write!(f, "lowercase"),
+            TokenFilter::AsciiFolding => write!(f, "asciifolding"),
+            TokenFilter::Stop(StopWords::Language(language)) => write!(f, "stop({language})"),
+            TokenFilter::Stop(StopWords::Words(words)) => {
+                let words: Vec<String> = words
+                    .iter()
+                    .map(|word| format!("'{}'", word.replace('\'', "''")))
+                    .collect();
+                write!(f, "stop({})", words.join(", "))
+            }
+            TokenFilter::Stemmer(language) => write!(f, "stemmer({language})"),
+        }
+    }
+}
+
+/// Decompose `term` and drop its combining marks, then spell the letters that don't decompose
+/// into ASCII
+fn fold_to_ascii(term: &str) -> String {
+    let mut folded = String::with_capacity(term.len());
+    for c in term.nfd().filter(|c| !is_combining_mark(*c)) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'Æ' => folded.push_str("AE"),
+            'œ' => folded.push_str("oe"),
+            'Œ' => folded.push_str("OE"),
+            'þ' => folded.push_str("th"),
+            'Þ' => folded.push_str("TH"),
+            'ø' => folded.push('o'),
+            'Ø' => folded.push('O'),
+            'ł' => folded.push('l'),
+            'Ł' => folded.push('L'),
+            'đ' => folded.push('d'),
+            'Đ' => folded.push('D'),
+            'ı' => folded.push('i'),
+            c => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// A tokenizer and a chain of filters
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Analyzer {
+    pub tokenizer: Tokenizer,
+    pub filters: Vec<TokenFilter>,
+}
+
+impl Analyzer {
+    /// An analyzer that tokenizes with `tokenizer`, then applies `filters` in order
+    pub fn try_new(tokenizer: Tokenizer, filters: Vec<TokenFilter>) -> Result<Self> {
+        if let Tokenizer::NGram { min, max } | Tokenizer::EdgeNGram { min, max } = tokenizer {
+            if min == 0 || min > max {
+                return plan_err!(
+                    "Invalid n-gram sizes ({min}, {max}), the minimum must be at least 1 and at \
+                     most the maximum"
+                );
+            }
+        }
+        Ok(Self { tokenizer, filters })
+    }
+
+    /// The `standard` analyzer
+    pub fn standard() -> Arc<Self> {
+        static STANDARD_ANALYZER: OnceLock<Arc<Analyzer>> = OnceLock::new();
+        STANDARD_ANALYZER
+            .get_or_init(|| {
+                Arc::new(Analyzer {
+                    tokenizer: Tokenizer::Standard,
+                    filters: vec![TokenFilter::Lowercase],
+                })
+            })
+            .clone()
+    }
+
+    /// The built-in analyzer called `name`, if there is one
+    pub fn builtin(name: &str) -> Option<Arc<Self>> {
+        let language = match name {
+            STANDARD => return Some(Self::standard()),
+            "whitespace" => {
+                return Some(Arc::new(Analyzer {
+                    tokenizer: Tokenizer::Whitespace,
+                    filters: vec![],
+                }))
+            }
+            "english" => Language::English,
+            "german" => Language::German,
+            _ => return None,
+        };
+        Some(Arc::new(Analyzer {
+            tokenizer: Tokenizer::Standard,
+            filters: vec![
+                TokenFilter::Lowercase,
+                TokenFilter::Stop(StopWords::Language(language)),
+                TokenFilter::Stemmer(language),
+            ],
+        }))
+    }
+
+    /// The tokens of `text`, in order
+    pub fn tokens(&self, text: &str) -> Vec<Token> {
+        self.filters
+            .iter()
+            .fold(self.tokenizer.tokenize(text), |tokens, filter| {
+                filter.apply(tokens)
+            })
+    }
+
+    /// The terms of `text`, in order
+    pub fn terms(&self, text: &str) -> Vec<String> {
+        self.tokens(text)
+            .into_iter()
+            .map(|token| token.term)
+            .collect()
+    }
+
+    /// The distinct terms of a query, in the order they first appear
+    pub fn query_terms(&self, query: &str) -> Vec<String> {
+        let mut terms = self.terms(query);
+        let mut seen = std::collections::HashSet::new();
+        terms.retain(|term| seen.insert(term.clone()));
+        terms
+    }
+
+    /// Parse the definition of an analyzer, `TOKENIZER tokenizer [FILTERS filter, ...]`, as
+    /// written after the name in `CREATE TEXT ANALYZER`
+    pub fn parse(parser: &mut Parser) -> Result<Self> {
+        let sql_error = |e| DataFusionError::SQL(e, None);
+        expect_word(parser, "tokenizer").map_err(sql_error)?;
+        let name = parser.parse_identifier(false).map_err(sql_error)?.value;
+        let tokenizer = match name.to_ascii_lowercase().as_str() {
+            "standard" => Tokenizer::Standard,
+            "whitespace" => Tokenizer::Whitespace,
+            tokenizer @ ("ngram" | "edge_ngram") => {
+                let (min, max) = if parser.consume_token(&SqlToken::LParen) {
+                    let min = parser.parse_literal_uint().map_err(sql_error)?;
+                    parser.expect_token(&SqlToken::Comma).map_err(sql_error)?;
+                    let max = parser.parse_literal_uint().map_err(sql_error)?;
+                    parser.expect_token(&SqlToken::RParen).map_err(sql_error)?;
+                    (min as usize, max as usize)
+                } else {
+                    (2, 3)
+                };
+                if tokenizer == "ngram" {
+                    Tokenizer::NGram { min, max }
+                } else {
+                    Tokenizer::EdgeNGram { min, max }
+                }
+            }
+            _ => return plan_err!("Unknown tokenizer {name}"),
+        };
+        let filters = if parse_word(parser, "filters") {
+            let mut filters = vec![];
+            loop {
+                filters.push(parse_filter(parser)?);
+                if !parser.consume_token(&SqlToken::Comma) {
+                    break;
+                }
+            }
+            filters
+        } else {
+            vec![]
+        };
+        Self::try_new(tokenizer, filters)
+    }
+}
+
+/// Consume the next token if it is the unquoted word `word`
+fn parse_word(parser: &mut Parser, word: &str) -> bool {
+    match parser.peek_token().token {
+        SqlToken::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word) => {
+            parser.next_token();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn expect_word(parser: &mut Parser, word: &str) -> Result<(), ParserError> {
+    if parse_word(parser, word) {
+        return Ok(());
+    }
+    parser.expected(&word.to_ascii_uppercase(), parser.peek_token())
+}
+
+fn parse_filter(parser: &mut Parser) -> Result<TokenFilter> {
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let name = parser.parse_identifier(false).map_err(sql_error)?.value;
+    let filter = match name.to_ascii_lowercase().as_str() {
+        "lowercase" => TokenFilter::Lowercase,
+        "asciifolding" => TokenFilter::AsciiFolding,
+        "stop" => {
+            parser.expect_token(&SqlToken::LParen).map_err(sql_error)?;
+            let words = if matches!(parser.peek_token().token, SqlToken::SingleQuotedString(_)) {
+                StopWords::Words(
+                    parser
+                        .parse_comma_separated(Parser::parse_literal_string)
+                        .map_err(sql_error)?,
+                )
+            } else {
+                let language: Language = parser
+                    .parse_identifier(false)
+                    .map_err(sql_error)?
+                    .value
+                    .parse()?;
+                if language.stop_words().is_none() {
+                    return plan_err!(
+                        "No stop words for {language}, list them with stop('word', ...)"
+                    );
+                }
+                StopWords::Language(language)
+            };
+            parser.expect_token(&SqlToken::RParen).map_err(sql_error)?;
+            TokenFilter::Stop(words)
+        }
+        "stemmer" => {
+            parser.expect_token(&SqlToken::LParen).map_err(sql_error)?;
+            let language = parser.parse_identifier(false).map_err(sql_error)?.value;
+            parser.expect_token(&SqlToken::RParen).map_err(sql_error)?;
+            TokenFilter::Stemmer(language.parse()?)
+        }
+        _ => return plan_err!("Unknown token filter {name}"),
+    };
+    Ok(filter)
+}
+
+/// The definition of the analyzer, as [`Analyzer::parse`] reads it
+impl fmt::Display for Analyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TOKENIZER {}", self.tokenizer)?;
+        if !self.filters.is_empty() {
+            let filters: Vec<String> = self.filters.iter().map(|f| f.to_string()).collect();
+            write!(f, " FILTERS {}", filters.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Analyzer {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser::new(&GenericDialect {})
+            .try_with_sql(s)
+            .map_err(|e| DataFusionError::SQL(e, None))?;
+        let analyzer = Self::parse(&mut parser)?;
+        parser
+            .expect_token(&SqlToken::EOF)
+            .map_err(|e| DataFusionError::SQL(e, None))?;
+        Ok(analyzer)
+    }
+}
+
+/// The analyzers made with `CREATE TEXT ANALYZER`, alongside the built-in ones
+#[derive(Debug, Default)]
+pub struct Analyzers {
+    custom: BTreeMap<String, Arc<Analyzer>>,
+}
+
+impl Analyzers {
+    /// The analyzer called `name`, built-in or custom
+    pub fn get(&self, name: &str) -> Option<Arc<Analyzer>> {
+        Analyzer::builtin(name).or_else(|| self.custom.get(name).cloned())
+    }
+
+    pub fn create(&mut self, name: &str, analyzer: Analyzer) -> Result<()> {
+        if self.get(name).is_some() {
+            return exec_err!("Text analyzer {name} already exists");
+        }
+        self.custom.insert(name.to_string(), Arc::new(analyzer));
+        Ok(())
+    }
+
+    /// Forget the custom analyzer called `name`, returning whether there was one
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.custom.remove(name).is_some()
+    }
+
+    /// Every analyzer by name, with whether it is built in
+    pub fn all(&self) -> Vec<(String, Arc<Analyzer>, bool)> {
+        let builtins = BUILTINS.iter().filter_map(|name| {
+            Analyzer::builtin(name).map(|analyzer| (name.to_string(), analyzer, true))
+        });
+        let custom = self
+            .custom
+            .iter()
+            .map(|(name, analyzer)| (name.clone(), analyzer.clone(), false));
+        builtins.chain(custom).collect()
+    }
+}
+
+/// Register `analyzer` as `name` on the session's writer
+pub async fn create_analyzer(
+    ctx: &SessionContext,
+    name: &str,
+    analyzer: Analyzer,
+    if_not_exists: bool,
+) -> Result<()> {
+    let name = name.to_string();
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut analyzers = system_catalog.analyzers_mut();
+            if if_not_exists && analyzers.get(&name).is_some() {
+                return Ok(Applied::new(0, vec![]));
+            }
+            let records = if log {
+                vec![LogRecord::create_analyzer(&name, &analyzer)]
+            } else {
+                vec![]
+            };
+            analyzers.create(&name, analyzer)?;
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Drop the custom analyzers called `names` on the session's writer. Analyzers that indexes use
+/// can't be dropped.
+pub async fn drop_analyzers(
+    ctx: &SessionContext,
+    names: Vec<String>,
+    if_exists: bool,
+) -> Result<()> {
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            for name in &names {
+                if BUILTINS.contains(&name.as_str()) {
+                    return exec_err!("Can't drop the built-in text analyzer {name}");
+                }
+                if system_catalog.analyzers().get(name).is_none() && !if_exists {
+                    return exec_err!("Text analyzer {name} does not exist");
+                }
+                for entry in system_catalog.entries() {
+                    if let Some(index) = entry
+                        .indexes
+                        .iter()
+                        .find(|index| index.analyzer.as_ref() == Some(name))
+                    {
+                        return exec_err!(
+                            "Text analyzer {name} is used by index {} of {}",
+                            index.name,
+                            entry.name
+                        );
+                    }
+                }
+            }
+            let mut analyzers = system_catalog.analyzers_mut();
+            let mut records = vec![];
+            for name in names {
+                if analyzers.remove(&name) && log {
+                    records.push(LogRecord::drop_analyzer(&name));
+                }
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// The records that recreate the custom analyzers of `system_catalog`, for a checkpoint. Indexes
+/// refer to them by name, so they're replayed before the tables.
+pub(crate) fn capture(system_catalog: &SystemCatalog) -> Vec<LogRecord> {
+    system_catalog
+        .analyzers()
+        .custom
+        .iter()
+        .map(|(name, analyzer)| LogRecord::create_analyzer(name, analyzer))
+        .collect()
+}
+
+/// `quokka_tokenize(text, 'analyzer')`, the terms the analyzer makes of `text`. The analyzer is
+/// looked up when the statement is planned, by [`bind_analyzers`].
+#[derive(Debug)]
+pub struct TokenizeFunction {
+    signature: Signature,
+    analyzer: Option<Arc<Analyzer>>,
+}
+
+impl TokenizeFunction {
+    pub fn new() -> Self {
+        Self::with_analyzer(None)
+    }
+
+    /// Bound calls are stable rather than immutable, as DataFusion compares functions by name
+    /// and signature and would otherwise take them for unbound ones
+    fn with_analyzer(analyzer: Option<Arc<Analyzer>>) -> Self {
+        let volatility = if analyzer.is_some() {
+            Volatility::Stable
+        } else {
+            Volatility::Immutable
+        };
+        let signature = Signature::one_of(
+            [DataType::Utf8, DataType::LargeUtf8]
+                .into_iter()
+                .map(|data_type| TypeSignature::Exact(vec![data_type, DataType::Utf8]))
+                .collect(),
+            volatility,
+        );
+        Self {
+            signature,
+            analyzer,
+        }
+    }
+}
+
+impl Default for TokenizeFunction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScalarUDFImpl for TokenizeFunction {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        TOKENIZE
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType> {
+        Ok(DataType::List(Arc::new(Field::new(
+            "item",
+            DataType::Utf8,
+            true,
+        ))))
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        let [text, ColumnarValue::Scalar(ScalarValue::Utf8(name))] = args else {
+            return exec_err!("{TOKENIZE}() takes some text and the name of an analyzer");
+        };
+        let analyzer = match (&self.analyzer, name) {
+            (Some(analyzer), _) => analyzer.clone(),
+            (None, Some(name)) => match Analyzer::builtin(name) {
+                Some(analyzer) => analyzer,
+                None => return exec_err!("Text analyzer {name} does not exist"),
+            },
+            (None, None) => return exec_err!("{TOKENIZE}() needs an analyzer"),
+        };
+        let texts: ArrayRef = text.clone().into_array(1)?;
+        let mut terms = ListBuilder::new(StringBuilder::new());
+        for text in text_values(&texts)? {
+            match text {
+                Some(text) => {
+                    for term in analyzer.terms(text) {
+                        terms.values().append_value(term);
+                    }
+                    terms.append(true);
+                }
+                None => terms.append(false),
+            }
+        }
+        let terms: ArrayRef = Arc::new(terms.finish());
+        Ok(match text {
+            ColumnarValue::Scalar(_) => {
+                ColumnarValue::Scalar(ScalarValue::try_from_array(&terms, 0)?)
+            }
+            ColumnarValue::Array(_) => ColumnarValue::Array(terms),
+        })
+    }
+}
+
+/// Bind every `quokka_tokenize(text, 'name')` in `plan` to the analyzer called `name` in
+/// `system_catalog`
+pub fn bind_analyzers(plan: LogicalPlan, system_catalog: &SystemCatalog) -> Result<LogicalPlan> {
+    plan.transform_up(&|plan| {
+        let exprs = plan.expressions();
+        if !exprs.iter().any(has_tokenize) {
+            return Ok(Transformed::No(plan));
+        }
+        let new_exprs = exprs
+            .into_iter()
+            .map(|expr| bind_expr(expr, system_catalog))
+            .collect::<Result<Vec<_>>>()?;
+        let inputs = plan.inputs().into_iter().cloned().collect();
+        Ok(Transformed::Yes(plan.with_new_exprs(new_exprs, inputs)?))
+    })
+}
+
+fn bind_expr(expr: Expr, system_catalog: &SystemCatalog) -> Result<Expr> {
+    let bind_subquery = |subquery: Subquery| -> Result<Subquery> {
+        Ok(Subquery {
+            subquery: Arc::new(bind_analyzers(
+                subquery.subquery.as_ref().clone(),
+                system_catalog,
+            )?),
+            outer_ref_columns: subquery.outer_ref_columns,
+        })
+    };
+    expr.transform_up(&|expr| {
+        Ok(match expr {
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                args,
+            }) if udf.name() == TOKENIZE => {
+                let Some(Expr::Literal(ScalarValue::Utf8(Some(name)))) = args.get(1) else {
+                    return plan_err!("{TOKENIZE}() needs the name of an analyzer as a literal");
+                };
+                let analyzer = system_catalog.analyzer(name)?;
+                let udf = ScalarUDF::new_from_impl(TokenizeFunction::with_analyzer(Some(analyzer)));
+                Transformed::Yes(Expr::ScalarFunction(ScalarFunction {
+                    func_def: ScalarFunctionDefinition::UDF(Arc::new(udf)),
+                    args,
+                }))
+            }
+            Expr::ScalarSubquery(subquery) => {
+                Transformed::Yes(Expr::ScalarSubquery(bind_subquery(subquery)?))
+            }
+            Expr::Exists(Exists { subquery, negated }) => Transformed::Yes(Expr::Exists(Exists {
+                subquery: bind_subquery(subquery)?,
+                negated,
+            })),
+            Expr::InSubquery(InSubquery {
+                expr,
+                subquery,
+                negated,
+            }) => Transformed::Yes(Expr::InSubquery(InSubquery {
+                expr,
+                subquery: bind_subquery(subquery)?,
+                negated,
+            })),
+            expr => Transformed::No(expr),
+        })
+    })
+}
+
+fn has_tokenize(expr: &Expr) -> bool {
+    let mut found = false;
+    let _ = expr.apply(&mut |expr| {
+        found = match expr {
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                ..
+            }) => udf.name() == TOKENIZE,
+            Expr::ScalarSubquery(_) | Expr::Exists(_) | Expr::InSubquery(_) => true,
+            _ => false,
+        };
+        Ok(if found {
+            VisitRecursion::Stop
+        } else {
+            VisitRecursion::Continue
+        })
+    });
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::new_context;
+    use crate::test_util::query;
+
+    use super::*;
+
+    fn terms(analyzer: &Analyzer, text: &str) -> Vec<String> {
+        analyzer.terms(text)
+    }
+
+    #[test]
+    fn tokenizers_and_filters() -> Result<()> {
+        let standard = Analyzer::standard();
+        assert_eq!(
+            vec!["the", "quick", "brown", "fox's", "42"],
+            terms(&standard, "The quick-brown FOX's, 42!")
+        );
+        assert_eq!(vec!["fox", "the"], standard.query_terms("fox the FOX"));
+
+        let whitespace = Analyzer::builtin("whitespace").unwrap();
+        assert_eq!(
+            vec!["Quick-brown", "FOX!"],
+            terms(&whitespace, "Quick-brown FOX!")
+        );
+
+        let english = Analyzer::builtin("english").unwrap();
+        let tokens = english.tokens("The runners are running to the connections");
+        assert_eq!(
+            vec![("runner", 1), ("run", 3), ("connect", 6)],
+            tokens
+                .iter()
+                .map(|token| (token.term.as_str(), token.position))
+                .collect::<Vec<_>>()
+        );
+
+        let german = Analyzer::builtin("german").unwrap();
+        assert_eq!(
+            vec!["haus", "strass"],
+            terms(&german, "Die Häuser der Straße")
+        );
+
+        let ngrams: Analyzer = "TOKENIZER ngram(2, 3) FILTERS lowercase".parse()?;
+        assert_eq!(
+            vec!["ru", "rus", "us", "ust", "st", "go"],
+            terms(&ngrams, "Rust go")
+        );
+        let edge: Analyzer =
+            "tokenizer edge_ngram(1, 3) filters asciifolding, lowercase, stop('c')".parse()?;
+        assert_eq!(
+            vec!["ca", "caf", "oe", "oeu", "oeuf"],
+            terms(&edge, "Café Œuf")
+        );
+        assert_eq!(
+            "TOKENIZER edge_ngram(1, 3) FILTERS asciifolding, lowercase, stop('c')",
+            edge.to_string()
+        );
+        assert_eq!(edge, edge.to_string().parse()?);
+        assert_eq!("naive skoldpadda", fold_to_ascii("naïve sköldpadda"));
+        Ok(())
+    }
+
+    #[test]
+    fn stemmers() {
+        for (language, word, stem) in [
+            ("english", "caresses", "caress"),
+            ("english", "ponies", "poni"),
+            ("english", "agreed", "agre"),
+            ("english", "hopping", "hop"),
+            ("english", "generously", "generous"),
+            ("english", "connecting", "connect"),
+            ("english", "electricity", "electr"),
+            ("english", "skies", "sky"),
+            ("english", "succeeding", "succeed"),
+            ("german", "aufeinanderfolgenden", "aufeinanderfolg"),
+            ("german", "häusern", "haus"),
+            ("german", "straße", "strass"),
+            ("german", "freundlichkeit", "freundlich"),
+            ("french", "continuation", "continu"),
+            ("french", "chevaux", "cheval"),
+            ("french", "nationalité", "national"),
+            ("spanish", "canciones", "cancion"),
+            ("spanish", "bibliotecas", "bibliotec"),
+            ("italian", "abbandonata", "abbandon"),
+            ("portuguese", "bibliotecas", "bibliotec"),
+            ("dutch", "lichamelijke", "licham"),
+            ("swedish", "klokheten", "klok"),
+            ("russian", "вечеров", "вечер"),
+        ] {
+            let language: Language = language.parse().unwrap();
+            assert_eq!(stem, language.stem(word), "{language} {word}");
+        }
+        for language in Language::ALL {
+            assert_eq!(language, language.to_string().parse().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_analyzers() -> Result<()> {
+        let ctx = new_context();
+        query(
+            &ctx,
+            "CREATE TEXT ANALYZER prefixes TOKENIZER edge_ngram(2, 4) \
+             FILTERS lowercase, asciifolding",
+        )
+        .await?;
+        assert_eq!(
+            "+--------------------------------+\n\
+             | terms                          |\n\
+             +--------------------------------+\n\
+             | [pa, pat, pate, cr, cre, crem] |\n\
+             +--------------------------------+",
+            query(
+                &ctx,
+                "SELECT quokka_tokenize('Pâté Crème', 'prefixes') AS terms"
+            )
+            .await?
+        );
+        assert_eq!(
+            "+--------------------+\n\
+             | terms              |\n\
+             +--------------------+\n\
+             | [connect, connect] |\n\
+             +--------------------+",
+            query(
+                &ctx,
+                "SELECT quokka_tokenize('connected connections', 'english') AS terms"
+            )
+            .await?
+        );
+
+        query(&ctx, "CREATE TABLE t (id INT PRIMARY KEY, name VARCHAR)").await?;
+        query(
+            &ctx,
+            "INSERT INTO t VALUES (1, 'Crème brûlée'), (2, 'Creamy soup'), (3, 'Pâté')",
+        )
+        .await?;
+        query(
+            &ctx,
+            "CREATE INDEX t_name ON t USING fulltext (name) WITH (analyzer = 'prefixes')",
+        )
+        .await?;
+        // Queries are analyzed like the index, so prefixes match whole words
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 1  |\n| 2  |\n+----+",
+            query(
+                &ctx,
+                "SELECT id FROM t WHERE match(name, 'cre') ORDER BY id"
+            )
+            .await?
+        );
+
+        let error = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { query(&ctx, sql).await.unwrap_err().strip_backtrace() }
+        };
+        assert_eq!(
+            "Execution error: Text analyzer prefixes is used by index t_name of datafusion.public.t",
+            error("DROP TEXT ANALYZER prefixes").await
+        );
+        assert_eq!(
+            "Execution error: Text analyzer english already exists",
+            error("CREATE TEXT ANALYZER english TOKENIZER standard").await
+        );
+        assert_eq!(
+            "Execution error: Can't drop the built-in text analyzer standard",
+            error("DROP TEXT ANALYZER standard").await
+        );
+        assert_eq!(
+            "Error during planning: Unknown token filter uppercase",
+            error("CREATE TEXT ANALYZER a TOKENIZER standard FILTERS uppercase").await
+        );
+        assert_eq!(
+            "Error during planning: Unsupported language klingon, expected one of arabic, danish, \
+             dutch, english, finnish, french, german, greek, hungarian, italian, norwegian, \
+             portuguese, romanian, russian, spanish, swedish, tamil, turkish",
+            error("CREATE TEXT ANALYZER a TOKENIZER standard FILTERS stemmer(klingon)").await
+        );
+        assert_eq!(
+            "Error during planning: No stop words for french, list them with stop('word', ...)",
+            error("CREATE TEXT ANALYZER a TOKENIZER standard FILTERS stop(french)").await
+        );
+        assert_eq!(
+            "Error during planning: Invalid n-gram sizes (3, 2), the minimum must be at least 1 \
+             and at most the maximum",
+            error("CREATE TEXT ANALYZER a TOKENIZER ngram(3, 2)").await
+        );
+        assert_eq!(
+            "Error during planning: Text analyzer missing does not exist",
+            error("SELECT quokka_tokenize('a', 'missing')").await
+        );
+        assert_eq!(
+            "Error during planning: Text analyzer missing does not exist",
+            error("CREATE INDEX i ON t USING fulltext (name) WITH (analyzer = 'missing')").await
+        );
+        assert_eq!(
+            "Error during planning: Only fulltext indexes have an analyzer",
+            error("CREATE INDEX i ON t (name) WITH (analyzer = 'english')").await
+        );
+
+        query(&ctx, "DROP INDEX t_name").await?;
+        query(&ctx, "DROP TEXT ANALYZER prefixes").await?;
+        query(&ctx, "DROP TEXT ANALYZER IF EXISTS prefixes").await?;
+        assert_eq!(
+            "Error during planning: Text analyzer prefixes does not exist",
+            error("SELECT quokka_tokenize('a', 'prefixes')").await
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,911 @@
+//! Users, roles and the privileges granted to them.
+//!
+//! `CREATE ROLE` and `CREATE USER`, which is a role that can log in, add a [`Role`] to the system
+//! catalog's [`Roles`]. `GRANT SELECT, INSERT ON t TO analysts` grants privileges on a table, or
+//! on every table of a schema with `ON SCHEMA s`, and `GRANT analysts TO alice` makes a role a
+//! member of another, whose privileges it then has too. `ALTER ROLE` changes a role's attributes,
+//! including `TENANT 'acme'`, the tenant its sessions act for. Everything is logged and
+//! checkpointed with the catalog. Passwords are only kept as Argon2id hashes.
+//!
+//! The Flight SQL server authenticates the handshake's Basic credentials against the roles, sets
+//! `quokka.tenant_id` to the user's tenant and runs [`check_privileges`] on every plan before
+//! executing it. Until the first role that can log in is created the server is open, as a new
+//! installation has to be set up by someone.
+//! Superusers may do anything; everyone else needs
+//!
+//! * `SELECT` on the tables a query reads,
+//! * `INSERT`, `UPDATE` or `DELETE` on the table a statement changes, including `TRUNCATE`,
+//! * `CREATE` on the schema for DDL on its tables, indexes and policies,
+//!
+//! and only superusers can manage roles, schemas, text analyzers and checkpoints, or `SET` the
+//! session's identity, which [row-level security](crate::policy) relies on.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use datafusion::common::tree_node::{TreeNode, VisitRecursion};
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::{DdlStatement, Expr, LogicalPlan, Statement, WriteOp};
+use datafusion_common::{exec_err, plan_err, DataFusionError, OwnedTableReference};
+use datafusion_expr::expr::{Exists, InSubquery};
+
+use crate::catalog::TableId;
+use crate::session::QuokkaOptions;
+use crate::sql::{QuokkaStatement, QuokkaStatementNode};
+use crate::system_catalog::{system_catalog, SystemCatalog, SYSTEM_SCHEMA};
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied};
+
+/// A privilege that can be granted on a table or schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Privilege {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    /// Create, alter and drop tables, indexes and policies in a schema
+    Create,
+}
+
+impl Privilege {
+    pub const ALL: [Privilege; 5] = [
+        Privilege::Select,
+        Privilege::Insert,
+        Privilege::Update,
+        Privilege::Delete,
+        Privilege::Create,
+    ];
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Privilege::Select => "SELECT",
+            Privilege::Insert => "INSERT",
+            Privilege::Update => "UPDATE",
+            Privilege::Delete => "DELETE",
+            Privilege::Create => "CREATE",
+        })
+    }
+}
+
+impl FromStr for Privilege {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Privilege::ALL
+            .into_iter()
+            .find(|privilege| privilege.to_string().eq_ignore_ascii_case(s))
+        {
+            Some(privilege) => Ok(privilege),
+            None => plan_err!("Unknown privilege {s}"),
+        }
+    }
+}
+
+/// What privileges are granted on
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Securable {
+    /// A Quokka table, which keeps its grants when it's renamed
+    Table(TableId),
+    /// Every table of a schema, including those created later
+    Schema { catalog: String, schema: String },
+}
+
+/// The object of `GRANT ... ON`, before it's resolved to a [`Securable`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GrantObject {
+    Table(OwnedTableReference),
+    /// `SCHEMA s` or `ALL TABLES IN SCHEMA s`
+    Schema(String),
+}
+
+impl fmt::Display for GrantObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrantObject::Table(table) => write!(f, "{table}"),
+            GrantObject::Schema(schema) => write!(f, "SCHEMA {schema}"),
+        }
+    }
+}
+
+/// A user or role
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Role {
+    pub name: String,
+    /// Whether the role is a user, which can log in
+    pub login: bool,
+    pub superuser: bool,
+    /// Argon2id hash of the password as a PHC string, which holds the salt and costs:
+    /// `$argon2id$v=19$m=...,t=...,p=...$<salt>$<hash>`
+    password_hash: Option<String>,
+    /// Roles whose privileges this role has
+    pub member_of: BTreeSet<String>,
+    /// Tenant the role's sessions act for, which [row-level security](crate::policy) policies
+    /// see as `quokka.tenant_id`
+    pub tenant_id: Option<String>,
+}
+
+impl Role {
+    pub fn new(name: &str, login: bool, superuser: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            login,
+            superuser,
+            password_hash: None,
+            member_of: BTreeSet::new(),
+            tenant_id: None,
+        }
+    }
+
+    /// Set the password, which is hashed with a new random salt
+    pub fn with_password(self, password: &str) -> Result<Self> {
+        Ok(self.with_password_hash(Some(hash_password(password)?)))
+    }
+
+    /// Set the hash of the password, as logged
+    pub fn with_password_hash(mut self, password_hash: Option<String>) -> Self {
+        self.password_hash = password_hash;
+        self
+    }
+
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
+    /// Set the tenant the role's sessions act for
+    pub fn with_tenant_id(mut self, tenant_id: Option<String>) -> Self {
+        self.tenant_id = tenant_id;
+        self
+    }
+
+    /// Whether `password` is the role's. The hash holds the parameters it was made with, and
+    /// the comparison takes the same time wherever the hashes differ.
+    fn verify_password(&self, password: &str) -> bool {
+        let Some(password_hash) = &self.password_hash else {
+            return false;
+        };
+        PasswordHash::new(password_hash).is_ok_and(|password_hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &password_hash)
+                .is_ok()
+        })
+    }
+}
+
+/// Argon2id hash of `password` with a new random salt, as a PHC string
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| DataFusionError::Execution(format!("Can't hash the password: {e}")))?;
+    Ok(password_hash.to_string())
+}
+
+/// The attributes `ALTER ROLE` sets, `None` for those it leaves as they are
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RoleAttributes {
+    pub login: Option<bool>,
+    pub superuser: Option<bool>,
+    /// The new password, or `Some(None)` to remove it
+    pub password: Option<Option<String>>,
+    /// The new tenant, or `Some(None)` to remove it
+    pub tenant_id: Option<Option<String>>,
+}
+
+/// The roles of the system catalog and the privileges granted to them
+#[derive(Debug, Default)]
+pub struct Roles {
+    roles: BTreeMap<String, Role>,
+    grants: BTreeMap<(String, Securable), BTreeSet<Privilege>>,
+}
+
+impl Roles {
+    pub fn create(&mut self, role: Role) -> Result<()> {
+        if self.roles.contains_key(&role.name) {
+            return exec_err!("Role {} already exists", role.name);
+        }
+        self.roles.insert(role.name.clone(), role);
+        Ok(())
+    }
+
+    /// Replace the attributes of the existing role named like `role`, keeping its memberships
+    pub fn alter(&mut self, role: Role) -> Result<()> {
+        let Some(existing) = self.roles.get_mut(&role.name) else {
+            return exec_err!("Role {} does not exist", role.name);
+        };
+        *existing = Role {
+            member_of: std::mem::take(&mut existing.member_of),
+            ..role
+        };
+        Ok(())
+    }
+
+    /// Drop role `name`, along with its grants and memberships. Returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        if self.roles.remove(name).is_none() {
+            return false;
+        }
+        for role in self.roles.values_mut() {
+            role.member_of.remove(name);
+        }
+        self.grants.retain(|(grantee, _), _| grantee != name);
+        true
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Role> {
+        self.roles.get(name)
+    }
+
+    /// Every role, ordered by name
+    pub fn roles(&self) -> impl Iterator<Item = &Role> {
+        self.roles.values()
+    }
+
+    /// Every grant, as grantee, securable and privilege
+    pub fn grants(&self) -> impl Iterator<Item = (&str, &Securable, Privilege)> {
+        self.grants
+            .iter()
+            .flat_map(|((grantee, securable), privileges)| {
+                privileges
+                    .iter()
+                    .map(move |privilege| (grantee.as_str(), securable, *privilege))
+            })
+    }
+
+    pub fn grant(
+        &mut self,
+        grantee: &str,
+        securable: Securable,
+        privileges: &[Privilege],
+    ) -> Result<()> {
+        if !self.roles.contains_key(grantee) {
+            return exec_err!("Role {grantee} does not exist");
+        }
+        self.grants
+            .entry((grantee.to_string(), securable))
+            .or_default()
+            .extend(privileges);
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, grantee: &str, securable: Securable, privileges: &[Privilege]) {
+        let key = (grantee.to_string(), securable);
+        if let Some(granted) = self.grants.get_mut(&key) {
+            for privilege in privileges {
+                granted.remove(privilege);
+            }
+            if granted.is_empty() {
+                self.grants.remove(&key);
+            }
+        }
+    }
+
+    /// Make `member` a member of `role`
+    pub fn grant_role(&mut self, role: &str, member: &str) -> Result<()> {
+        if !self.roles.contains_key(role) {
+            return exec_err!("Role {role} does not exist");
+        }
+        if self.member_roles(role).contains(member) {
+            return exec_err!("Role {member} is already a member of {role}, or {role} of {member}");
+        }
+        match self.roles.get_mut(member) {
+            Some(member) => {
+                member.member_of.insert(role.to_string());
+                Ok(())
+            }
+            None => exec_err!("Role {member} does not exist"),
+        }
+    }
+
+    pub fn revoke_role(&mut self, role: &str, member: &str) {
+        if let Some(member) = self.roles.get_mut(member) {
+            member.member_of.remove(role);
+        }
+    }
+
+    /// Forget the grants on `securable`, which was dropped
+    pub fn forget(&mut self, securable: &Securable) {
+        self.grants.retain(|(_, granted), _| granted != securable);
+    }
+
+    /// Whether any role can log in, after which the server only accepts those
+    pub fn has_logins(&self) -> bool {
+        self.roles.values().any(|role| role.login)
+    }
+
+    /// Check that `user` can log in with `password`
+    pub fn authenticate(&self, user: &str, password: &str) -> Result<()> {
+        match self.roles.get(user) {
+            Some(role) if role.login && role.verify_password(password) => Ok(()),
+            _ => exec_err!("Password authentication failed for user {user}"),
+        }
+    }
+
+    pub fn is_superuser(&self, user: &str) -> bool {
+        self.roles.get(user).is_some_and(|role| role.superuser)
+    }
+
+    /// Whether `user` or a role it's a member of has `privilege` on any of `securables`
+    pub fn has_privilege(
+        &self,
+        user: &str,
+        privilege: Privilege,
+        securables: &[Securable],
+    ) -> bool {
+        self.member_roles(user).iter().any(|role| {
+            securables.iter().any(|securable| {
+                self.grants
+                    .get(&(role.clone(), securable.clone()))
+                    .is_some_and(|privileges| privileges.contains(&privilege))
+            })
+        })
+    }
+
+    /// `role` and the roles it's a member of, directly or not
+    fn member_roles(&self, role: &str) -> BTreeSet<String> {
+        let mut roles = BTreeSet::new();
+        let mut pending = vec![role.to_string()];
+        while let Some(role) = pending.pop() {
+            if let Some(member_of) = self.roles.get(&role).map(|role| &role.member_of) {
+                pending.extend(member_of.iter().filter(|r| !roles.contains(*r)).cloned());
+            }
+            roles.insert(role);
+        }
+        roles
+    }
+}
+
+/// Create role `role` on the session's writer, unless it exists and `if_not_exists` is set
+pub async fn create_role(ctx: &SessionContext, role: Role, if_not_exists: bool) -> Result<()> {
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut roles = system_catalog.roles_mut();
+            if if_not_exists && roles.get(&role.name).is_some() {
+                return Ok(Applied::new(0, vec![]));
+            }
+            let records = if log {
+                vec![LogRecord::create_role(&role)]
+            } else {
+                vec![]
+            };
+            roles.create(role)?;
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Set `attributes` of role `name` on the session's writer
+pub async fn alter_role(
+    ctx: &SessionContext,
+    name: String,
+    attributes: RoleAttributes,
+) -> Result<()> {
+    let system_catalog = system_catalog(ctx)?;
+    // Hashing is slow, so it doesn't hold up the writer
+    let password_hash = attributes
+        .password
+        .map(|password| password.as_deref().map(hash_password).transpose())
+        .transpose()?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut roles = system_catalog.roles_mut();
+            let Some(role) = roles.get(&name) else {
+                return exec_err!("Role {name} does not exist");
+            };
+            let mut role = role.clone();
+            role.login = attributes.login.unwrap_or(role.login);
+            role.superuser = attributes.superuser.unwrap_or(role.superuser);
+            if let Some(password_hash) = password_hash {
+                role.password_hash = password_hash;
+            }
+            if let Some(tenant_id) = attributes.tenant_id {
+                role.tenant_id = tenant_id;
+            }
+            let records = if log {
+                vec![LogRecord::alter_role(&role)]
+            } else {
+                vec![]
+            };
+            roles.alter(role)?;
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Drop the roles called `names` on the session's writer
+pub async fn drop_roles(ctx: &SessionContext, names: Vec<String>, if_exists: bool) -> Result<()> {
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut roles = system_catalog.roles_mut();
+            if let Some(name) = names.iter().find(|name| roles.get(name).is_none()) {
+                if !if_exists {
+                    return exec_err!("Role {name} does not exist");
+                }
+            }
+            let mut records = vec![];
+            for name in names {
+                if roles.remove(&name) && log {
+                    records.push(LogRecord::drop_role(&name));
+                }
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Grant, or with `revoke` revoke, `privileges` on `objects` to `grantees` on the session's
+/// writer
+pub async fn grant(
+    ctx: &SessionContext,
+    privileges: Vec<Privilege>,
+    objects: &[GrantObject],
+    grantees: Vec<String>,
+    revoke: bool,
+) -> Result<()> {
+    let state = ctx.state();
+    let options = &state.config_options().catalog;
+    let system_catalog = system_catalog(ctx)?;
+    let mut securables = vec![];
+    for object in objects {
+        securables.push(match object {
+            GrantObject::Table(table) => {
+                let name = table
+                    .clone()
+                    .resolve(&options.default_catalog, &options.default_schema);
+                match system_catalog.find(&name) {
+                    Some(entry) => Securable::Table(entry.id),
+                    None => return plan_err!("{table} is not a Quokka table"),
+                }
+            }
+            GrantObject::Schema(schema) => {
+                let catalog = options.default_catalog.clone();
+                if ctx
+                    .catalog(&catalog)
+                    .and_then(|c| c.schema(schema))
+                    .is_none()
+                {
+                    return plan_err!("Schema {schema} does not exist");
+                }
+                Securable::Schema {
+                    catalog,
+                    schema: schema.clone(),
+                }
+            }
+        });
+    }
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut roles = system_catalog.roles_mut();
+            let mut records = vec![];
+            for grantee in &grantees {
+                for securable in &securables {
+                    if revoke {
+                        roles.revoke(grantee, securable.clone(), &privileges);
+                    } else {
+                        roles.grant(grantee, securable.clone(), &privileges)?;
+                    }
+                    if log {
+                        records.push(LogRecord::grant(grantee, securable, &privileges, revoke));
+                    }
+                }
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Make `members` members of `roles`, or with `revoke` no longer members, on the session's writer
+pub async fn grant_roles(
+    ctx: &SessionContext,
+    roles: Vec<String>,
+    members: Vec<String>,
+    revoke: bool,
+) -> Result<()> {
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(ctx.state().config());
+    let log = writer.is_logged();
+    writer
+        .submit(move |_| async move {
+            let mut all_roles = system_catalog.roles_mut();
+            let mut records = vec![];
+            for role in &roles {
+                for member in &members {
+                    if revoke {
+                        all_roles.revoke_role(role, member);
+                    } else {
+                        all_roles.grant_role(role, member)?;
+                    }
+                    if log {
+                        records.push(LogRecord::grant_role(role, member, revoke));
+                    }
+                }
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// What a statement needs to be allowed
+#[derive(Debug)]
+enum Required {
+    Table(Privilege, OwnedTableReference),
+    /// `CREATE` on a schema, or the default schema
+    Schema(Option<String>),
+    /// Only superusers may run the statement, described for the error
+    Superuser(String),
+}
+
+/// Check that the session of `ctx`, whose user is `quokka.user`, may run `plan`
+pub fn check_privileges(ctx: &SessionContext, plan: &LogicalPlan) -> Result<()> {
+    let state = ctx.state();
+    let user = QuokkaOptions::from_config(state.config_options()).user;
+    let system_catalog = system_catalog(ctx)?;
+    let roles = system_catalog.roles();
+    if !roles.has_logins() || roles.is_superuser(&user) {
+        return Ok(());
+    }
+    if roles.get(&user).is_none() {
+        return exec_err!("Permission denied: the session isn't authenticated");
+    }
+    let mut required = vec![];
+    requirements(plan, &mut required)?;
+    let options = &state.config_options().catalog;
+    for required in required {
+        match required {
+            Required::Table(privilege, table) => {
+                let name = table
+                    .clone()
+                    .resolve(&options.default_catalog, &options.default_schema);
+                // Like Postgres' catalogs, these only describe what's there
+                if privilege == Privilege::Select
+                    && (name.schema == SYSTEM_SCHEMA || name.schema == "information_schema")
+                {
+                    continue;
+                }
+                let mut securables = vec![Securable::Schema {
+                    catalog: name.catalog.to_string(),
+                    schema: name.schema.to_string(),
+                }];
+                if let Some(entry) = system_catalog.find(&name) {
+                    securables.push(Securable::Table(entry.id));
+                }
+                if !roles.has_privilege(&user, privilege, &securables) {
+                    return exec_err!("Permission denied: {user} can't {privilege} table {table}");
+                }
+            }
+            Required::Schema(schema) => {
+                let schema = schema.unwrap_or_else(|| options.default_schema.clone());
+                let securable = Securable::Schema {
+                    catalog: options.default_catalog.clone(),
+                    schema: schema.clone(),
+                };
+                if !roles.has_privilege(&user, Privilege::Create, &[securable]) {
+                    return exec_err!("Permission denied: {user} can't CREATE in schema {schema}");
+                }
+            }
+            Required::Superuser(statement) => {
+                return exec_err!("Permission denied: only superusers can {statement}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add what running `plan` requires to `required`
+fn requirements(plan: &LogicalPlan, required: &mut Vec<Required>) -> Result<()> {
+    let schema_of = |table: &OwnedTableReference| table.schema().map(str::to_string);
+    match plan {
+        LogicalPlan::TableScan(scan) => {
+            required.push(Required::Table(Privilege::Select, scan.table_name.clone()));
+        }
+        LogicalPlan::Dml(dml) => {
+            let privilege = match dml.op {
+                WriteOp::InsertInto | WriteOp::InsertOverwrite => Privilege::Insert,
+                WriteOp::Update => Privilege::Update,
+                WriteOp::Delete => Privilege::Delete,
+                WriteOp::Ctas => Privilege::Insert,
+            };
+            required.push(Required::Table(privilege, dml.table_name.clone()));
+            let mut input = vec![];
+            requirements(&dml.input, &mut input)?;
+            // Finding the rows to update or delete is part of the statement
+            input.retain(|required| {
+                !matches!(required, Required::Table(Privilege::Select, table)
+                    if dml.op != WriteOp::InsertInto && *table == dml.table_name)
+            });
+            required.extend(input);
+            return Ok(());
+        }
+        LogicalPlan::Ddl(ddl) => match ddl {
+            DdlStatement::CreateMemoryTable(cmd) => {
+                required.push(Required::Schema(schema_of(&cmd.name)))
+            }
+            DdlStatement::CreateView(cmd) => required.push(Required::Schema(schema_of(&cmd.name))),
+            DdlStatement::DropTable(cmd) => required.push(Required::Schema(schema_of(&cmd.name))),
+            DdlStatement::DropView(cmd) => required.push(Required::Schema(schema_of(&cmd.name))),
+            ddl => required.push(Required::Superuser(ddl.name().to_string())),
+        },
+        LogicalPlan::Statement(Statement::SetVariable(set)) => {
+            let variable = set.variable.to_lowercase();
+            if ["quokka.user", "quokka.tenant_id"].contains(&variable.as_str()) {
+                required.push(Required::Superuser(format!("set {variable}")));
+            }
+        }
+        LogicalPlan::Copy(_) => required.push(Required::Superuser("COPY".to_string())),
+        LogicalPlan::Extension(extension) => {
+            if let Some(node) = extension
+                .node
+                .as_any()
+                .downcast_ref::<QuokkaStatementNode>()
+            {
+                statement_requirements(&node.statement, required)?;
+            }
+        }
+        _ => {}
+    }
+    for expr in plan.expressions() {
+        expr.apply(&mut |expr| {
+            match expr {
+                Expr::ScalarSubquery(subquery)
+                | Expr::Exists(Exists { subquery, .. })
+                | Expr::InSubquery(InSubquery { subquery, .. }) => {
+                    requirements(&subquery.subquery, required)?
+                }
+                _ => {}
+            }
+            Ok(VisitRecursion::Continue)
+        })?;
+    }
+    for input in plan.inputs() {
+        requirements(input, required)?;
+    }
+    Ok(())
+}
+
+fn statement_requirements(statement: &QuokkaStatement, required: &mut Vec<Required>) -> Result<()> {
+    let schema_of = |table: &OwnedTableReference| table.schema().map(str::to_string);
+    match statement {
+        QuokkaStatement::CreateIndex { table, .. }
+        | QuokkaStatement::AlterTable { table, .. }
+        | QuokkaStatement::CreatePolicy { table, .. }
+        | QuokkaStatement::DropPolicy { table, .. } => {
+            required.push(Required::Schema(schema_of(table)))
+        }
+        QuokkaStatement::DropIndex { schema, .. } => {
+            required.push(Required::Schema(schema.clone()))
+        }
+        QuokkaStatement::CreateTable { plan, .. } => requirements(plan, required)?,
+        QuokkaStatement::DropTable { names, .. } => {
+            required.extend(names.iter().map(|name| Required::Schema(schema_of(name))))
+        }
+        QuokkaStatement::TruncatePartitions { table, .. } => {
+            required.push(Required::Table(Privilege::Delete, table.clone()))
+        }
+        statement @ (QuokkaStatement::Checkpoint
+        | QuokkaStatement::CreateRole { .. }
+        | QuokkaStatement::AlterRole { .. }
+        | QuokkaStatement::DropRole { .. }
+        | QuokkaStatement::Grant { .. }
+        | QuokkaStatement::GrantRole { .. }
+        | QuokkaStatement::CreateTextAnalyzer { .. }
+        | QuokkaStatement::DropTextAnalyzer { .. }) => {
+            required.push(Required::Superuser(statement.to_string()))
+        }
+    }
+    Ok(())
+}
+
+/// The records that recreate the roles and grants of `system_catalog`, for a checkpoint. Table
+/// grants refer to the tables by id, so they're replayed after the tables.
+pub(crate) fn capture(system_catalog: &SystemCatalog) -> Vec<LogRecord> {
+    let roles = system_catalog.roles();
+    let mut records: Vec<LogRecord> = roles.roles().map(LogRecord::create_role).collect();
+    for role in roles.roles() {
+        for member_of in &role.member_of {
+            records.push(LogRecord::grant_role(member_of, &role.name, false));
+        }
+    }
+    for (grantee, securable, privilege) in roles.grants() {
+        records.push(LogRecord::grant(grantee, securable, &[privilege], false));
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::new_context;
+    use crate::sql::{execute_logical_plan, sql_to_plan};
+    use crate::test_util::query;
+
+    use super::*;
+
+    /// Run `sql` as the session's user, checking its privileges first like the server does
+    async fn run_as(ctx: &SessionContext, sql: &str) -> Result<()> {
+        let plan = sql_to_plan(&ctx.state(), sql).await?;
+        check_privileges(ctx, &plan)?;
+        execute_logical_plan(ctx, plan).await?.collect().await?;
+        Ok(())
+    }
+
+    /// A session of `user` sharing the catalog of `ctx`
+    async fn session(ctx: &SessionContext, user: &str) -> Result<SessionContext> {
+        let session = SessionContext::new_with_state(ctx.state());
+        query(&session, &format!("SET quokka.user = '{user}'")).await?;
+        Ok(session)
+    }
+
+    #[tokio::test]
+    async fn privileges_limit_what_users_can_do() -> Result<()> {
+        let ctx = new_context();
+        // Until a role can log in, anyone can do anything
+        run_as(
+            &ctx,
+            "CREATE TABLE events (id INT PRIMARY KEY, kind VARCHAR)",
+        )
+        .await?;
+        for sql in [
+            "CREATE TABLE secrets (id INT PRIMARY KEY)",
+            "CREATE ROLE analysts",
+            "GRANT SELECT ON events TO analysts",
+            "CREATE USER admin WITH SUPERUSER PASSWORD 'admin'",
+            "CREATE USER alice PASSWORD 'alice'",
+            "CREATE USER ingest PASSWORD 'ingest'",
+            "GRANT analysts TO alice",
+            "GRANT INSERT, UPDATE ON events TO ingest",
+        ] {
+            query(&ctx, sql).await?;
+        }
+        let denied = |result: Result<()>| {
+            let error = result.expect_err("should be denied").to_string();
+            assert!(error.contains("Permission denied"), "{error}");
+        };
+
+        let alice = session(&ctx, "alice").await?;
+        run_as(&alice, "SELECT * FROM events").await?;
+        run_as(
+            &alice,
+            "SELECT COUNT(*) FROM events WHERE id IN (SELECT id FROM events)",
+        )
+        .await?;
+        run_as(&alice, "SELECT * FROM qs_catalog.qs_tables").await?;
+        denied(run_as(&alice, "SELECT * FROM secrets").await);
+        denied(
+            run_as(
+                &alice,
+                "SELECT * FROM events WHERE id IN (SELECT id FROM secrets)",
+            )
+            .await,
+        );
+        denied(run_as(&alice, "INSERT INTO events VALUES (1, 'click')").await);
+        denied(run_as(&alice, "DELETE FROM events").await);
+        denied(run_as(&alice, "CREATE TABLE mine (id INT PRIMARY KEY)").await);
+        denied(run_as(&alice, "SET quokka.tenant_id = 'acme'").await);
+        denied(run_as(&alice, "ALTER USER alice TENANT 'acme'").await);
+        denied(run_as(&alice, "GRANT SELECT ON secrets TO alice").await);
+        denied(run_as(&alice, "CHECKPOINT").await);
+
+        let ingest = session(&ctx, "ingest").await?;
+        run_as(&ingest, "INSERT INTO events VALUES (1, 'click')").await?;
+        // Finding the rows to update doesn't need SELECT
+        run_as(&ingest, "UPDATE events SET kind = 'view' WHERE id = 1").await?;
+        denied(run_as(&ingest, "SELECT * FROM events").await);
+        denied(run_as(&ingest, "DELETE FROM events WHERE id = 1").await);
+
+        // Sessions without a role, or of a role dropped since, can't do anything
+        denied(run_as(&session(&ctx, "").await?, "SELECT 1").await);
+        let admin = session(&ctx, "admin").await?;
+        run_as(&admin, "CREATE ROLE temporary").await?;
+        run_as(&admin, "GRANT temporary TO alice").await?;
+        run_as(&admin, "DROP ROLE temporary").await?;
+        assert!(system_catalog(&ctx)?.roles().get("temporary").is_none());
+
+        // Schema grants cover every table of the schema
+        run_as(&admin, "GRANT CREATE, SELECT ON SCHEMA public TO alice").await?;
+        run_as(&alice, "CREATE TABLE mine (id INT PRIMARY KEY)").await?;
+        run_as(&alice, "CREATE INDEX mine_id ON mine (id)").await?;
+        run_as(&alice, "SELECT * FROM secrets").await?;
+        run_as(&admin, "REVOKE SELECT ON SCHEMA public FROM alice").await?;
+        denied(run_as(&alice, "SELECT * FROM secrets").await);
+
+        // Revoking the role takes its privileges away
+        run_as(&admin, "REVOKE analysts FROM alice").await?;
+        denied(run_as(&alice, "SELECT * FROM events").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roles_are_managed_through_the_catalog() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE events (id INT PRIMARY KEY)",
+            "CREATE ROLE analysts",
+            "CREATE ROLE IF NOT EXISTS analysts",
+            "CREATE USER alice PASSWORD 'secret'",
+            "GRANT analysts TO alice",
+            "GRANT SELECT ON events TO analysts",
+            "GRANT ALL ON ALL TABLES IN SCHEMA public TO alice",
+            "REVOKE UPDATE, DELETE, CREATE ON SCHEMA public FROM alice",
+            "ALTER USER alice WITH TENANT 'acme'",
+        ] {
+            query(&ctx, sql).await?;
+        }
+        assert!(query(&ctx, "CREATE ROLE analysts").await.is_err());
+        assert!(query(&ctx, "GRANT alice TO analysts").await.is_err());
+        assert!(query(&ctx, "GRANT SELECT ON events TO nobody")
+            .await
+            .is_err());
+        assert!(query(&ctx, "DROP USER nobody").await.is_err());
+        assert!(query(&ctx, "ALTER USER nobody LOGIN").await.is_err());
+        query(&ctx, "DROP USER IF EXISTS nobody").await?;
+
+        assert_eq!(
+            query(&ctx, "SELECT * FROM qs_catalog.qs_roles").await?,
+            "+-----------+-----------+-----------+-----------+------------+\n\
+             | role_name | can_login | superuser | tenant_id | member_of  |\n\
+             +-----------+-----------+-----------+-----------+------------+\n\
+             | alice     | true      | false     | acme      | [analysts] |\n\
+             | analysts  | false     | false     |           | []         |\n\
+             +-----------+-----------+-----------+-----------+------------+"
+        );
+        assert_eq!(
+            query(
+                &ctx,
+                "SELECT grantee, privilege, table_id IS NOT NULL AS on_table, schema_name \
+                 FROM qs_catalog.qs_grants ORDER BY grantee, privilege"
+            )
+            .await?,
+            "+----------+-----------+----------+-------------+\n\
+             | grantee  | privilege | on_table | schema_name |\n\
+             +----------+-----------+----------+-------------+\n\
+             | alice    | INSERT    | false    | public      |\n\
+             | alice    | SELECT    | false    | public      |\n\
+             | analysts | SELECT    | true     |             |\n\
+             +----------+-----------+----------+-------------+"
+        );
+
+        // Only a salted hash of the password is kept, with the cost it was made with
+        let system_catalog = system_catalog(&ctx)?;
+        {
+            let roles = system_catalog.roles();
+            let alice = roles.get("alice").expect("alice was created");
+            let password_hash = alice.password_hash().unwrap_or_default();
+            assert!(!password_hash.contains("secret"));
+            assert!(
+                password_hash.starts_with("$argon2id$v=19$m="),
+                "{password_hash}"
+            );
+            roles.authenticate("alice", "secret")?;
+            assert!(roles.authenticate("alice", "guess").is_err());
+            assert!(roles.authenticate("analysts", "").is_err());
+        }
+
+        // Dropping a table or role drops what was granted on or to it
+        query(&ctx, "DROP TABLE events").await?;
+        query(&ctx, "DROP ROLE analysts").await?;
+        let roles = system_catalog.roles();
+        assert_eq!(roles.grants().count(), 2);
+        assert!(roles.get("alice").unwrap().member_of.is_empty());
+        Ok(())
+    }
+}
@@ -1,17 +1,625 @@
-// What operations do we need to support?
-// * get
-// * get_key_value?
-// * get_mut
-// * iterator
-// * append
-// * range
-// * range_mut?
-// * remove
-//
-// To start, let's just use RwLock<BTreeMap>
-
-use std::{collections::BTreeMap, sync::RwLock};
-
-use crate::table::TupleSlot;
-
-pub type BTreeIndex<K> = RwLock<BTreeMap<K, TupleSlot>>;
+//! Concurrent B+tree for indexes, as described in `docs/index-design.md`.
+//!
+//! Keys and values are stored inline in nodes with a fixed capacity, and leaves are linked to
+//! their right sibling so range scans walk the leaf level without going back through the tree.
+//! How a leaf stores its keys is up to the [`LeafKeys`] type parameter, so leaves of byte string
+//! keys can store them prefix compressed.
+//!
+//! Every node is behind its own `parking_lot::RwLock`. Readers crab down the tree with shared
+//! latches, releasing each parent once the child is latched. Writers first try optimistic latch
+//! crabbing: shared latches down to the leaf's parent and an exclusive latch on the leaf. If the
+//! leaf would split, the writer starts over and crabs down with exclusive latches, releasing its
+//! ancestors whenever it reaches a node that has room for another entry and so can't split.
+//!
+//! Keys are unique. Indexes that allow duplicate keys append the row's location to the key, the
+//! "unique identifier" suffix from Graefe's B-tree survey. Removing entries doesn't merge
+//! underfull nodes; their space is reused by later inserts into the same key range.
+
+use std::fmt::{self, Debug};
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+use parking_lot::{RawRwLock, RwLock, RwLockWriteGuard};
+
+/// Default maximum number of keys in a node
+pub const DEFAULT_NODE_CAPACITY: usize = 64;
+
+type NodeRef<K, V, S> = Arc<RwLock<Node<K, V, S>>>;
+type ReadGuard<K, V, S> = ArcRwLockReadGuard<RawRwLock, Node<K, V, S>>;
+type WriteGuard<K, V, S> = ArcRwLockWriteGuard<RawRwLock, Node<K, V, S>>;
+/// Entries copied out of a leaf and the leaf to continue a range scan with
+type LeafCopy<K, V, S> = (Vec<(K, V)>, Option<NodeRef<K, V, S>>);
+
+/// Ordered storage for the keys of a leaf. `Vec<K>` stores the keys as they are, other
+/// implementations can store them compressed.
+pub trait LeafKeys<K>: Send + Sync {
+    fn with_capacity(capacity: usize) -> Self;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Search for `key` like [`slice::binary_search`]
+    fn binary_search(&self, key: &K) -> Result<usize, usize>;
+
+    /// A copy of the key at `idx`
+    fn get(&self, idx: usize) -> K;
+
+    fn insert(&mut self, idx: usize, key: K);
+
+    fn remove(&mut self, idx: usize);
+
+    /// Move the keys from `at` on into a new instance with the same capacity
+    fn split_off(&mut self, at: usize) -> Self;
+
+    /// Bytes allocated for the keys, including memory the keys point to
+    fn allocated_size(&self) -> usize;
+
+    /// Bytes `key` points to when it is stored on its own, as internal nodes store keys
+    fn key_heap_size(_key: &K) -> usize {
+        0
+    }
+}
+
+impl<K: Ord + Clone + Send + Sync> LeafKeys<K> for Vec<K> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn binary_search(&self, key: &K) -> Result<usize, usize> {
+        self.as_slice().binary_search(key)
+    }
+
+    fn get(&self, idx: usize) -> K {
+        self[idx].clone()
+    }
+
+    fn insert(&mut self, idx: usize, key: K) {
+        Vec::insert(self, idx, key)
+    }
+
+    fn remove(&mut self, idx: usize) {
+        Vec::remove(self, idx);
+    }
+
+    fn split_off(&mut self, at: usize) -> Self {
+        let mut other = Vec::with_capacity(self.capacity());
+        other.extend(self.drain(at..));
+        other
+    }
+
+    fn allocated_size(&self) -> usize {
+        // Memory the keys point to isn't known for an arbitrary `K`
+        self.capacity() * size_of::<K>()
+    }
+}
+
+enum Node<K, V, S> {
+    /// `children[i]` holds the keys in `[keys[i - 1], keys[i])`
+    Internal {
+        keys: Vec<K>,
+        children: Vec<NodeRef<K, V, S>>,
+    },
+    Leaf {
+        keys: S,
+        values: Vec<V>,
+        next: Option<NodeRef<K, V, S>>,
+    },
+}
+
+impl<K, V, S: LeafKeys<K>> Node<K, V, S> {
+    fn new_leaf(capacity: usize) -> Self {
+        // One extra slot so a full node can take an entry before it is split
+        Node::Leaf {
+            keys: S::with_capacity(capacity + 1),
+            values: Vec::with_capacity(capacity + 1),
+            next: None,
+        }
+    }
+
+    fn new_internal(capacity: usize) -> Self {
+        Node::Internal {
+            keys: Vec::with_capacity(capacity + 1),
+            children: Vec::with_capacity(capacity + 2),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Node::Internal { keys, .. } => keys.len(),
+            Node::Leaf { keys, .. } => keys.len(),
+        }
+    }
+
+    /// Bytes used by this node and its descendants
+    fn memory_usage(&self) -> usize {
+        let node_size = size_of::<RwLock<Self>>() + 2 * size_of::<usize>();
+        match self {
+            Node::Internal { keys, children } => {
+                node_size
+                    + keys.capacity() * size_of::<K>()
+                    + keys.iter().map(S::key_heap_size).sum::<usize>()
+                    + children.capacity() * size_of::<NodeRef<K, V, S>>()
+                    + children
+                        .iter()
+                        .map(|child| child.read().memory_usage())
+                        .sum::<usize>()
+            }
+            Node::Leaf { keys, values, .. } => {
+                node_size + keys.allocated_size() + values.capacity() * size_of::<V>()
+            }
+        }
+    }
+}
+
+impl<K: Ord, V, S> Node<K, V, S> {
+    /// The child of an internal node that may contain `key`
+    fn child(&self, key: Bound<&K>) -> NodeRef<K, V, S> {
+        match self {
+            Node::Internal { keys, children } => {
+                let idx = match key {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        keys.partition_point(|k| k <= key)
+                    }
+                    Bound::Unbounded => 0,
+                };
+                children[idx].clone()
+            }
+            Node::Leaf { .. } => unreachable!("leaves have no children"),
+        }
+    }
+}
+
+struct Root<K, V, S> {
+    node: NodeRef<K, V, S>,
+    /// Number of internal levels above the leaves
+    height: usize,
+}
+
+/// Concurrent ordered map from `K` to `V`, whose leaves store their keys in `S`
+pub struct BPlusTree<K, V, S = Vec<K>> {
+    root: RwLock<Root<K, V, S>>,
+    len: AtomicUsize,
+    node_capacity: usize,
+}
+
+impl<K, V, S> Debug for BPlusTree<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BPlusTree")
+            .field("len", &self.len.load(Ordering::Acquire))
+            .field("height", &self.root.read().height)
+            .field("node_capacity", &self.node_capacity)
+            .finish()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, S: LeafKeys<K>> Default for BPlusTree<K, V, S> {
+    fn default() -> Self {
+        Self::with_leaf_keys(DEFAULT_NODE_CAPACITY)
+    }
+}
+
+impl<K: Ord + Clone + Send + Sync, V: Clone> BPlusTree<K, V> {
+    pub fn new() -> Self {
+        Self::with_node_capacity(DEFAULT_NODE_CAPACITY)
+    }
+
+    /// Create a tree whose nodes hold at most `node_capacity` keys. Small capacities are mostly
+    /// useful for exercising splits in tests.
+    pub fn with_node_capacity(node_capacity: usize) -> Self {
+        Self::with_leaf_keys(node_capacity)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, S: LeafKeys<K>> BPlusTree<K, V, S> {
+    /// Create a tree whose leaves store their keys in `S`, with at most `node_capacity` keys in
+    /// each node
+    pub fn with_leaf_keys(node_capacity: usize) -> Self {
+        let node_capacity = node_capacity.max(3);
+        Self {
+            root: RwLock::new(Root {
+                node: Arc::new(RwLock::new(Node::new_leaf(node_capacity))),
+                height: 0,
+            }),
+            len: AtomicUsize::new(0),
+            node_capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate bytes of memory used by the tree's nodes, keys and values. Memory the values
+    /// point to is not included, and neither is memory the keys point to unless `S` counts it.
+    pub fn memory_usage(&self) -> usize {
+        let root = self.root.read();
+        let node = root.node.read_arc();
+        drop(root);
+        size_of::<Self>() + node.memory_usage()
+    }
+
+    /// Latch the leaf that may contain `key` for reading
+    fn read_leaf(&self, key: Bound<&K>) -> ReadGuard<K, V, S> {
+        let root = self.root.read();
+        let mut guard = root.node.read_arc();
+        let mut height = root.height;
+        drop(root);
+        while height > 0 {
+            let child = guard.child(key);
+            // Latch the child before the parent is released
+            guard = child.read_arc();
+            height -= 1;
+        }
+        guard
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let leaf = self.read_leaf(Bound::Included(key));
+        match &*leaf {
+            Node::Leaf { keys, values, .. } => {
+                keys.binary_search(key).ok().map(|idx| values[idx].clone())
+            }
+            Node::Internal { .. } => unreachable!("expected a leaf"),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert `value` at `key`, returning the previous value if there was one
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        match self.insert_optimistic(&key, &value) {
+            Ok(previous) => previous,
+            Err(()) => self.insert_pessimistic(key, value),
+        }
+    }
+
+    /// Latch the leaf that may contain `key` for writing, with shared latches above it
+    fn write_leaf(&self, key: &K) -> WriteGuard<K, V, S> {
+        let root = self.root.read();
+        if root.height == 0 {
+            return root.node.write_arc();
+        }
+        let mut guard = root.node.read_arc();
+        let mut height = root.height;
+        drop(root);
+        loop {
+            let child = guard.child(Bound::Included(key));
+            height -= 1;
+            if height == 0 {
+                return child.write_arc();
+            }
+            guard = child.read_arc();
+        }
+    }
+
+    /// Insert into the leaf without latching its ancestors exclusively. Fails if the leaf would
+    /// have to be split.
+    fn insert_optimistic(&self, key: &K, value: &V) -> Result<Option<V>, ()> {
+        let mut leaf = self.write_leaf(key);
+        let Node::Leaf { keys, values, .. } = &mut *leaf else {
+            unreachable!("expected a leaf");
+        };
+        match keys.binary_search(key) {
+            Ok(idx) => Ok(Some(std::mem::replace(&mut values[idx], value.clone()))),
+            Err(_) if keys.len() >= self.node_capacity => Err(()),
+            Err(idx) => {
+                keys.insert(idx, key.clone());
+                values.insert(idx, value.clone());
+                self.len.fetch_add(1, Ordering::AcqRel);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Insert with exclusive latch crabbing, splitting nodes on the way back up
+    fn insert_pessimistic(&self, key: K, value: V) -> Option<V> {
+        let mut root: Option<RwLockWriteGuard<Root<K, V, S>>> = Some(self.root.write());
+        let (root_node, height) = {
+            let root = root.as_ref().expect("root is latched");
+            (root.node.clone(), root.height)
+        };
+        // Exclusively latched nodes that may be split, from the top down
+        let mut path: Vec<WriteGuard<K, V, S>> = vec![root_node.write_arc()];
+        for _ in 0..height {
+            let child = path
+                .last()
+                .expect("path is never empty")
+                .child(Bound::Included(&key));
+            let child = child.write_arc();
+            if child.len() < self.node_capacity {
+                // The child can't split, so its ancestors won't change
+                path.clear();
+                root = None;
+            }
+            path.push(child);
+        }
+
+        let mut leaf = path.pop().expect("path ends at a leaf");
+        let Node::Leaf { keys, values, next } = &mut *leaf else {
+            unreachable!("expected a leaf");
+        };
+        let idx = match keys.binary_search(&key) {
+            Ok(idx) => return Some(std::mem::replace(&mut values[idx], value)),
+            Err(idx) => idx,
+        };
+        keys.insert(idx, key);
+        values.insert(idx, value);
+        self.len.fetch_add(1, Ordering::AcqRel);
+        if keys.len() <= self.node_capacity {
+            return None;
+        }
+
+        // Split the leaf, moving its upper half to a new right sibling
+        let mid = keys.len() / 2;
+        let mut sibling_values = Vec::with_capacity(values.capacity());
+        sibling_values.extend(values.drain(mid..));
+        let sibling_keys = keys.split_off(mid);
+        let mut separator = sibling_keys.get(0);
+        let sibling = Arc::new(RwLock::new(Node::Leaf {
+            keys: sibling_keys,
+            values: sibling_values,
+            next: next.take(),
+        }));
+        *next = Some(sibling.clone());
+        let mut new_child = sibling;
+        drop(leaf);
+
+        // Add the new node to its parent, splitting the parent in turn if it is full
+        while let Some(mut parent) = path.pop() {
+            let Node::Internal { keys, children } = &mut *parent else {
+                unreachable!("expected an internal node");
+            };
+            let idx = keys.partition_point(|k| k <= &separator);
+            keys.insert(idx, separator);
+            children.insert(idx + 1, new_child);
+            if keys.len() <= self.node_capacity {
+                return None;
+            }
+            let mid = keys.len() / 2;
+            let mut sibling = Node::new_internal(self.node_capacity);
+            let Node::Internal {
+                keys: sibling_keys,
+                children: sibling_children,
+            } = &mut sibling
+            else {
+                unreachable!("expected an internal node");
+            };
+            sibling_keys.extend(keys.drain(mid + 1..));
+            sibling_children.extend(children.drain(mid + 1..));
+            separator = keys.pop().expect("split node has a middle key");
+            new_child = Arc::new(RwLock::new(sibling));
+        }
+
+        // The root was split, so grow the tree by one level
+        let mut root = root.expect("root is latched when it may split");
+        let mut new_root = Node::new_internal(self.node_capacity);
+        if let Node::Internal { keys, children } = &mut new_root {
+            keys.push(separator);
+            children.push(root.node.clone());
+            children.push(new_child);
+        }
+        root.node = Arc::new(RwLock::new(new_root));
+        root.height += 1;
+        None
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut leaf = self.write_leaf(key);
+        let Node::Leaf { keys, values, .. } = &mut *leaf else {
+            unreachable!("expected a leaf");
+        };
+        let idx = keys.binary_search(key).ok()?;
+        keys.remove(idx);
+        self.len.fetch_sub(1, Ordering::AcqRel);
+        Some(values.remove(idx))
+    }
+
+    /// Iterate over the entries in `range`, in key order.
+    ///
+    /// The iterator copies one leaf at a time and holds no latches between calls to `next`, so
+    /// it doesn't block writers. Entries inserted or removed during the iteration may or may not
+    /// be seen.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<K, V, S> {
+        let lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+        if let (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) =
+            (&lower, &upper)
+        {
+            let empty = match (&lower, &upper) {
+                (Bound::Included(_), Bound::Included(_)) => l > u,
+                _ => l >= u,
+            };
+            if empty {
+                return Range {
+                    buffer: vec![].into_iter(),
+                    next: None,
+                    upper: Bound::Unbounded,
+                };
+            }
+        }
+        let leaf = self.read_leaf(lower.as_ref());
+        let (buffer, next) = copy_leaf(&leaf, lower.as_ref(), upper.as_ref());
+        Range {
+            buffer: buffer.into_iter(),
+            next,
+            upper,
+        }
+    }
+
+    /// Iterate over every entry in key order
+    pub fn iter(&self) -> Range<K, V, S> {
+        self.range(..)
+    }
+}
+
+/// Copy the entries of `leaf` within the bounds, along with the next leaf to read if the range
+/// continues past this one.
+fn copy_leaf<K, V: Clone, S: LeafKeys<K>>(
+    leaf: &Node<K, V, S>,
+    lower: Bound<&K>,
+    upper: Bound<&K>,
+) -> LeafCopy<K, V, S> {
+    let Node::Leaf { keys, values, next } = leaf else {
+        unreachable!("expected a leaf");
+    };
+    let start = match lower {
+        Bound::Included(key) => keys.binary_search(key).unwrap_or_else(|idx| idx),
+        Bound::Excluded(key) => keys
+            .binary_search(key)
+            .map_or_else(|idx| idx, |idx| idx + 1),
+        Bound::Unbounded => 0,
+    };
+    let end = match upper {
+        Bound::Included(key) => keys
+            .binary_search(key)
+            .map_or_else(|idx| idx, |idx| idx + 1),
+        Bound::Excluded(key) => keys.binary_search(key).unwrap_or_else(|idx| idx),
+        Bound::Unbounded => keys.len(),
+    };
+    let entries = (start..end.max(start))
+        .map(|idx| (keys.get(idx), values[idx].clone()))
+        .collect();
+    let next = if end < keys.len() { None } else { next.clone() };
+    (entries, next)
+}
+
+/// Iterator over a range of a [`BPlusTree`], returned by [`BPlusTree::range`]
+pub struct Range<K, V, S = Vec<K>> {
+    buffer: std::vec::IntoIter<(K, V)>,
+    next: Option<NodeRef<K, V, S>>,
+    upper: Bound<K>,
+}
+
+impl<K: Ord + Clone, V: Clone, S: LeafKeys<K>> Iterator for Range<K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.buffer.next() {
+                return Some(entry);
+            }
+            let leaf = self.next.take()?;
+            let leaf = leaf.read();
+            let (buffer, next) = copy_leaf(&leaf, Bound::Unbounded, self.upper.as_ref());
+            self.buffer = buffer.into_iter();
+            self.next = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(u16, u32),
+        Remove(u16),
+        Range(u16, u16),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (any::<u16>(), any::<u32>()).prop_map(|(k, v)| Op::Insert(k % 512, v)),
+            1 => any::<u16>().prop_map(|k| Op::Remove(k % 512)),
+            1 => (any::<u16>(), any::<u16>()).prop_map(|(a, b)| Op::Range(a % 512, b % 512)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn matches_btree_map(ops in proptest::collection::vec(op(), 1..500)) {
+            let tree = BPlusTree::with_node_capacity(4);
+            let mut expected = BTreeMap::new();
+            for op in ops {
+                match op {
+                    Op::Insert(k, v) => prop_assert_eq!(expected.insert(k, v), tree.insert(k, v)),
+                    Op::Remove(k) => prop_assert_eq!(expected.remove(&k), tree.remove(&k)),
+                    Op::Range(a, b) => {
+                        let (a, b) = (a.min(b), a.max(b));
+                        let actual: Vec<_> = tree.range(a..b).collect();
+                        let wanted: Vec<_> = expected.range(a..b).map(|(k, v)| (*k, *v)).collect();
+                        prop_assert_eq!(wanted, actual);
+                        let actual: Vec<_> = tree
+                            .range((Bound::Excluded(a), Bound::Included(b)))
+                            .collect();
+                        let wanted: Vec<_> = expected
+                            .range((Bound::Excluded(a), Bound::Included(b)))
+                            .map(|(k, v)| (*k, *v))
+                            .collect();
+                        prop_assert_eq!(wanted, actual);
+                    }
+                }
+                prop_assert_eq!(expected.len(), tree.len());
+            }
+            let actual: Vec<_> = tree.iter().collect();
+            let wanted: Vec<_> = expected.into_iter().collect();
+            prop_assert_eq!(wanted, actual);
+        }
+    }
+
+    #[test]
+    fn duplicate_keys_with_row_suffix() {
+        let tree = BPlusTree::with_node_capacity(3);
+        for row in 0..10 {
+            tree.insert((row % 3, row), ());
+        }
+        let rows: Vec<u32> = tree
+            .range((1, u32::MIN)..=(1, u32::MAX))
+            .map(|((_, row), _)| row)
+            .collect();
+        assert_eq!(vec![1, 4, 7], rows);
+    }
+
+    #[test]
+    fn concurrent_readers_and_writers() {
+        let tree = Arc::new(BPlusTree::with_node_capacity(8));
+        let writers: Vec<_> = (0..4u32)
+            .map(|thread| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for i in 0..2_000u32 {
+                        tree.insert(i * 4 + thread, i);
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        let keys: Vec<u32> = tree.iter().map(|(k, _)| k).collect();
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(8_000, tree.len());
+        let keys: Vec<u32> = tree.iter().map(|(k, _)| k).collect();
+        assert_eq!((0..8_000).collect::<Vec<_>>(), keys);
+        assert_eq!(Some(1_999), tree.get(&7_999));
+    }
+}
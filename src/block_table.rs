@@ -0,0 +1,1874 @@
+//! [`BlockTable`] for querying the fixed-slot blocks of a [`Table`] with DataFusion.
+//!
+//! Rows are appended to the table's last block, and a new block is added once it is full. Full
+//! blocks are frozen, and scans build their `RecordBatch`es from Arrow buffers that share the
+//! blocks' memory, using the validity bitmaps for nulls. Updates and deletes change rows in
+//! place, thawing the blocks they touch until the statement is done. Scans of the hot last block
+//! copy its rows. Primitive, boolean, string and binary columns are supported; strings and
+//! binary values are stored as variable-length values.
+//!
+//! Each `INSERT`, `UPDATE` and `DELETE` is applied by the writer (see [`crate::writer`]) as part
+//! of a write transaction, keeping the old versions of the rows it changes, and is rolled back
+//! if it fails. Scans read the blocks when they are executed and see the rows as of the
+//! [`ActiveSnapshot`](crate::transaction::ActiveSnapshot) pinned in the session's config, or the
+//! latest committed rows. Old versions are garbage collected once no active snapshot can see
+//! them.
+//!
+//! Secondary indexes locate rows by their slot, as the tuple offset `(0, block, row)`. They have
+//! an entry for the key of every version of a row that is still kept, so snapshots that don't
+//! see a write can still find the versions they do see. Index scans read the version of each row
+//! found that their snapshot sees and drop it if its key is out of the range looked up. Entries
+//! are removed once garbage collection frees the last version with their key, and compaction
+//! points the entries of the rows it moves at their new slots.
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arrow::array::{
+    make_array, Array, ArrayData, ArrayRef, AsArray, BooleanArray, GenericBinaryArray,
+    OffsetSizeTrait, RecordBatchOptions,
+};
+use arrow::compute::{concat_batches, filter_record_batch, prep_null_mask_filter};
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow_buffer::{BooleanBuffer, MutableBuffer, NullBuffer};
+use async_trait::async_trait;
+use datafusion::datasource::{TableProvider, TableType};
+use datafusion::error::Result;
+use datafusion::execution::context::SessionState;
+use datafusion::logical_expr::Expr;
+use datafusion::logical_expr::TableProviderFilterPushDown;
+use datafusion::physical_expr::{create_physical_expr, PhysicalExpr};
+use datafusion::physical_plan::insert::{DataSink, FileSinkExec};
+use datafusion::physical_plan::metrics::MetricsSet;
+use datafusion::physical_plan::{
+    DisplayAs, DisplayFormatType, ExecutionPlan, SendableRecordBatchStream,
+};
+use datafusion_common::cast::as_boolean_array;
+use datafusion_common::{
+    exec_err, not_impl_err, plan_err, Constraints, DFSchema, DataFusionError, SchemaExt,
+};
+use datafusion_execution::TaskContext;
+use datafusion_expr::expr_rewriter::unnormalize_col;
+use futures::StreamExt;
+use log::warn;
+use parking_lot::RwLock;
+
+use crate::catalog::{next_table_id, reserve_table_id, Retired, TableId, TableLock};
+use crate::index::{best_index, IndexMethod, KeyRange, RowEntry, SecondaryIndex};
+use crate::index_scan::{BlockTableScanExec, IndexLookup, IndexScanExec, ScannedTable};
+use crate::table::{
+    Block, ColumnSize, CompactionStats, GcStats, ProjectedRow, Table, TupleSlot, SLOTS_PER_BLOCK,
+};
+use crate::table_provider::{SecondaryIndexes, TupletOffset};
+use crate::transaction::{
+    last_committed, oldest_active, Snapshot, TransactionId, WriteTransaction,
+};
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied, Writer};
+
+/// Table stored in the blocks of a [`Table`]
+#[derive(Debug)]
+pub struct BlockTable {
+    id: TableId,
+    schema: SchemaRef,
+    constraints: Constraints,
+    column_defaults: HashMap<String, Expr>,
+    table: Arc<RwLock<Table>>,
+    /// Locked after `table` by anything that locks both
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+    retired: Retired,
+    lock: TableLock,
+    compaction_metrics: Arc<CompactionMetrics>,
+    gc_metrics: Arc<GcMetrics>,
+}
+
+/// Totals of every compaction run of a [`BlockTable`]
+#[derive(Debug, Default)]
+pub struct CompactionMetrics {
+    runs: AtomicUsize,
+    blocks_freed: AtomicUsize,
+    tuples_moved: AtomicUsize,
+}
+
+impl CompactionMetrics {
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_freed(&self) -> usize {
+        self.blocks_freed.load(Ordering::Relaxed)
+    }
+
+    pub fn tuples_moved(&self) -> usize {
+        self.tuples_moved.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, stats: CompactionStats) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.blocks_freed
+            .fetch_add(stats.blocks_freed, Ordering::Relaxed);
+        self.tuples_moved
+            .fetch_add(stats.tuples_moved, Ordering::Relaxed);
+    }
+}
+
+/// Totals of every garbage collection run of a [`BlockTable`]
+#[derive(Debug, Default)]
+pub struct GcMetrics {
+    runs: AtomicUsize,
+    transactions_freed: AtomicUsize,
+    reclaimed_bytes: AtomicUsize,
+    lag: AtomicU64,
+}
+
+impl GcMetrics {
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    /// Transactions whose undo records were freed
+    pub fn transactions_freed(&self) -> usize {
+        self.transactions_freed.load(Ordering::Relaxed)
+    }
+
+    /// Approximate bytes of undo records freed
+    pub fn reclaimed_bytes(&self) -> usize {
+        self.reclaimed_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of committed transactions the oldest active snapshot didn't see at the last run,
+    /// whose old versions had to be kept
+    pub fn lag(&self) -> u64 {
+        self.lag.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, stats: GcStats, lag: u64) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.transactions_freed
+            .fetch_add(stats.transactions_freed, Ordering::Relaxed);
+        self.reclaimed_bytes
+            .fetch_add(stats.bytes_freed, Ordering::Relaxed);
+        self.lag.store(lag, Ordering::Relaxed);
+    }
+}
+
+impl BlockTable {
+    /// Create a block table with the provided schema, holding the rows of `partitions`
+    pub fn try_new(schema: SchemaRef, partitions: Vec<Vec<RecordBatch>>) -> Result<Self> {
+        let column_sizes = schema
+            .fields()
+            .iter()
+            .map(|field| match column_size(field.data_type()) {
+                Some(size) => Ok(size),
+                None => not_impl_err!(
+                    "Column {} has type {}, which block tables don't support",
+                    field.name(),
+                    field.data_type()
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut table = Table::new(column_sizes);
+        for batch in partitions.iter().flatten() {
+            for row in projected_rows(batch) {
+                // Rows the table is created with don't belong to a transaction
+                table.insert(&row, 0).map_err(external)?;
+            }
+        }
+        Ok(Self {
+            id: next_table_id(),
+            schema,
+            constraints: Constraints::empty(),
+            column_defaults: HashMap::new(),
+            table: Arc::new(RwLock::new(table)),
+            indexes: Arc::new(RwLock::new(SecondaryIndexes::new())),
+            retired: Retired::default(),
+            lock: TableLock::default(),
+            compaction_metrics: Arc::new(CompactionMetrics::default()),
+            gc_metrics: Arc::new(GcMetrics::default()),
+        })
+    }
+
+    /// Assign the id of a table recovered from the write-ahead log
+    pub fn with_id(mut self, id: TableId) -> Self {
+        reserve_table_id(id);
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> TableId {
+        self.id
+    }
+
+    /// Make writes that haven't been applied yet fail, since the table has been replaced or
+    /// dropped
+    pub fn retire(&self) {
+        self.retired.retire();
+    }
+
+    /// The lock that keeps writes away from the table while it's altered
+    pub fn table_lock(&self) -> &TableLock {
+        &self.lock
+    }
+
+    /// Assign constraints
+    pub fn with_constraints(mut self, constraints: Constraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    /// Assign column defaults
+    pub fn with_column_defaults(mut self, column_defaults: HashMap<String, Expr>) -> Self {
+        self.column_defaults = column_defaults;
+        self
+    }
+
+    pub fn column_defaults(&self) -> &HashMap<String, Expr> {
+        &self.column_defaults
+    }
+
+    /// Add the empty secondary index `index` and index every version of the rows still kept.
+    /// Only ordered indexes that allow duplicates are supported.
+    pub fn create_index(&self, index: SecondaryIndex) -> Result<()> {
+        if index.method() != IndexMethod::BTree {
+            return not_impl_err!("Block tables only have btree indexes");
+        }
+        if index.is_unique() {
+            return not_impl_err!("Block tables can't have unique indexes");
+        }
+        let table = self.table.read();
+        let mut indexes = self.indexes.write();
+        let name = index.name().to_string();
+        if indexes.contains_key(&name) {
+            return exec_err!("Index {name} already exists");
+        }
+        let column_ids: Vec<usize> = (0..self.schema.fields().len()).collect();
+        for (block_index, block) in table.blocks() {
+            let mut slots = vec![];
+            let mut rows = vec![];
+            for row_index in 0..block.num_records() {
+                let slot = TupleSlot::new(block_index, row_index);
+                for row in table.versions(slot) {
+                    slots.push(slot);
+                    rows.push(row);
+                }
+            }
+            let batch = rows_batch(&table, &self.schema, &column_ids, &rows)?;
+            index.insert_rows(&batch, &offsets(&slots))?;
+        }
+        indexes.insert(name, index);
+        Ok(())
+    }
+
+    /// Drop the secondary index named `name`. Returns whether the index existed.
+    pub fn drop_index(&self, name: &str) -> bool {
+        self.indexes.write().remove(name).is_some()
+    }
+
+    /// Names of this table's secondary indexes
+    pub fn index_names(&self) -> Vec<String> {
+        self.indexes.read().keys().cloned().collect()
+    }
+
+    fn handle(&self) -> BlockTableHandle {
+        BlockTableHandle {
+            schema: self.schema.clone(),
+            table: self.table.clone(),
+            indexes: self.indexes.clone(),
+        }
+    }
+
+    /// Build an index scan for the rows that may satisfy `filters`, using the secondary index
+    /// matching the most filters. Returns `None` if no index applies.
+    fn index_scan(
+        &self,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+    ) -> Result<Option<IndexScanExec>> {
+        let indexes = self.indexes.read();
+        let Some((index, range)) = best_index(indexes.values(), filters)? else {
+            return Ok(None);
+        };
+        let estimated_rows = index.count(&range);
+        let bounds = index
+            .bounding_filters(filters)
+            .into_iter()
+            .cloned()
+            .collect();
+        IndexScanExec::try_new(
+            ScannedTable::Block(self.handle()),
+            IndexLookup::Secondary {
+                name: index.name().to_string(),
+                range,
+            },
+            bounds,
+            estimated_rows,
+            projection.cloned(),
+        )
+        .map(Some)
+    }
+
+    /// The rows `snapshot` sees, as a batch per block with the columns `column_ids`. The snapshot
+    /// must be registered as active so the versions it sees aren't collected meanwhile.
+    pub(crate) fn batches(
+        &self,
+        column_ids: &[usize],
+        snapshot: Snapshot,
+    ) -> Result<Vec<RecordBatch>> {
+        self.handle().batches(column_ids, snapshot)
+    }
+
+    /// Number of blocks in the table, including full and partly deleted ones
+    pub fn num_blocks(&self) -> usize {
+        self.table.read().num_blocks()
+    }
+
+    /// Move the rows of blocks with at most `max_fill` of their slots still holding a row into
+    /// denser blocks, freeing the sparse blocks. Blocks with old versions that haven't been
+    /// garbage collected are skipped.
+    pub fn compact(&self, max_fill: f64) -> Result<CompactionStats> {
+        compact(
+            &self.table,
+            &self.indexes,
+            &self.schema,
+            max_fill,
+            &self.compaction_metrics,
+        )
+    }
+
+    /// Free the old versions of rows that no active snapshot sees, and the index entries only
+    /// they had
+    pub fn collect_garbage(&self) -> GcStats {
+        collect_garbage(&self.table, &self.indexes, &self.schema, &self.gc_metrics)
+    }
+
+    /// Garbage collect and then compact the table every `interval` in a background task, until
+    /// the table is dropped. Must be called from within a tokio runtime.
+    pub fn start_maintenance(&self, interval: Duration, max_fill: f64) {
+        let table = Arc::downgrade(&self.table);
+        let indexes = self.indexes.clone();
+        let schema = self.schema.clone();
+        let metrics = self.compaction_metrics.clone();
+        let gc_metrics = self.gc_metrics.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(table) = table.upgrade() else {
+                    return;
+                };
+                collect_garbage(&table, &indexes, &schema, &gc_metrics);
+                if let Err(e) = compact(&table, &indexes, &schema, max_fill, &metrics) {
+                    warn!("Failed to compact block table: {e}");
+                }
+            }
+        });
+    }
+
+    pub fn compaction_metrics(&self) -> &CompactionMetrics {
+        &self.compaction_metrics
+    }
+
+    pub fn gc_metrics(&self) -> &GcMetrics {
+        &self.gc_metrics
+    }
+
+    /// Delete every row matching `predicate`, or every row if there is no predicate. Returns the
+    /// number of rows deleted.
+    pub async fn delete(&self, state: &SessionState, predicate: Option<&Expr>) -> Result<u64> {
+        let predicate = self.physical_expr(state, predicate)?;
+        let schema = self.schema.clone();
+        self.write_transaction(state, move |table, version, log| {
+            let mut changes = Changes::default();
+            for rows in matching_rows(table, &schema, predicate.as_ref())? {
+                for (idx, record) in rows.records.iter().enumerate() {
+                    if rows.mask.value(idx) {
+                        table
+                            .delete(TupleSlot::new(rows.block_index, *record as usize), version)
+                            .map_err(external)?;
+                        changes.row_count += 1;
+                    }
+                }
+                if log && rows.mask.true_count() > 0 {
+                    changes
+                        .deleted
+                        .push(filter_record_batch(&rows.batch, &rows.mask)?);
+                }
+            }
+            Ok(changes)
+        })
+        .await
+    }
+
+    /// Set every row matching `predicate`, or every row if there is no predicate, to the values
+    /// of `assignments`, which must have one expression per column of the table. Returns the
+    /// number of rows updated.
+    pub async fn update(
+        &self,
+        state: &SessionState,
+        predicate: Option<&Expr>,
+        assignments: &[Expr],
+    ) -> Result<u64> {
+        if assignments.len() != self.schema.fields().len() {
+            return plan_err!("Update must assign a value to every column of the table");
+        }
+        let predicate = self.physical_expr(state, predicate)?;
+        let assignments = assignments
+            .iter()
+            .map(|expr| {
+                self.physical_expr(state, Some(&expr.clone().unalias()))
+                    .map(|expr| expr.expect("expression is present"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let schema = self.schema.clone();
+        self.write_transaction(state, move |table, version, log| {
+            let mut changes = Changes::default();
+            for rows in matching_rows(table, &schema, predicate.as_ref())? {
+                if rows.mask.true_count() == 0 {
+                    continue;
+                }
+                let batch = &rows.batch;
+                let columns = assignments
+                    .iter()
+                    .zip(schema.fields())
+                    .map(|(expr, field)| {
+                        let array = expr.evaluate(batch)?.into_array(batch.num_rows())?;
+                        Ok(arrow::compute::cast(&array, field.data_type())?)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let new_batch = RecordBatch::try_new(schema.clone(), columns)?;
+                let mut slots = vec![];
+                for (idx, row) in projected_rows(&new_batch).into_iter().enumerate() {
+                    if rows.mask.value(idx) {
+                        let slot = TupleSlot::new(rows.block_index, rows.records[idx] as usize);
+                        table.update(slot, &row, version).map_err(external)?;
+                        slots.push(slot);
+                    }
+                }
+                changes.row_count += slots.len() as u64;
+                let new_rows = filter_record_batch(&new_batch, &rows.mask)?;
+                if log {
+                    changes
+                        .deleted
+                        .push(filter_record_batch(&rows.batch, &rows.mask)?);
+                    changes.inserted.push(new_rows.clone());
+                }
+                changes.versions.push((new_rows, slots));
+            }
+            Ok(changes)
+        })
+        .await
+    }
+
+    /// Submit `write` to the session's writer, see [`write_transaction`]
+    async fn write_transaction(
+        &self,
+        state: &SessionState,
+        write: impl FnOnce(&mut Table, TransactionId, bool) -> Result<Changes> + Send + 'static,
+    ) -> Result<u64> {
+        write_transaction(
+            writer_for(state.config()),
+            self.id,
+            self.schema.clone(),
+            self.table.clone(),
+            self.indexes.clone(),
+            self.retired.clone(),
+            &self.lock,
+            write,
+        )
+        .await
+    }
+
+    /// Redo a change read from the write-ahead log: delete a row equal to each row of `deleted`
+    /// and insert `inserted`, as a transaction of their own
+    pub(crate) fn redo(&self, deleted: &[RecordBatch], inserted: &[RecordBatch]) -> Result<()> {
+        let mut to_delete: HashMap<ProjectedRow, usize> = HashMap::new();
+        for row in deleted.iter().flat_map(projected_rows) {
+            *to_delete.entry(row).or_default() += 1;
+        }
+        let transaction = WriteTransaction::begin();
+        let version = transaction.id();
+        let mut table = self.table.write();
+        let indexes = self.indexes.read();
+        if !to_delete.is_empty() {
+            for rows in matching_rows(&table, &self.schema, None)? {
+                for (row, record) in projected_rows(&rows.batch).into_iter().zip(rows.records) {
+                    if let Some(count) = to_delete.get_mut(&row).filter(|count| **count > 0) {
+                        *count -= 1;
+                        let slot = TupleSlot::new(rows.block_index, record as usize);
+                        table.delete(slot, version).map_err(external)?;
+                    }
+                }
+            }
+        }
+        for batch in inserted {
+            let slots = projected_rows(batch)
+                .iter()
+                .map(|row| table.insert(row, version).map_err(external))
+                .collect::<Result<Vec<_>>>()?;
+            index_rows(&indexes, batch, &slots)?;
+        }
+        table.freeze_full_blocks();
+        transaction.commit();
+        Ok(())
+    }
+
+    fn physical_expr(
+        &self,
+        state: &SessionState,
+        expr: Option<&Expr>,
+    ) -> Result<Option<Arc<dyn PhysicalExpr>>> {
+        let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
+        expr.map(|expr| {
+            create_physical_expr(
+                &unnormalize_col(expr.clone()),
+                &df_schema,
+                state.execution_props(),
+            )
+        })
+        .transpose()
+    }
+}
+
+#[async_trait]
+impl TableProvider for BlockTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn constraints(&self) -> Option<&Constraints> {
+        Some(&self.constraints)
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    /// Filters are always re-applied to the rows returned by [`Self::scan`], but passing them
+    /// down lets [`IndexScanRule`](crate::index_scan::IndexScanRule) use a secondary index to
+    /// find the rows.
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> Result<Vec<TableProviderFilterPushDown>> {
+        Ok(vec![TableProviderFilterPushDown::Inexact; filters.len()])
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let column_ids = match projection {
+            Some(projection) => projection.clone(),
+            None => (0..self.schema.fields().len()).collect(),
+        };
+        let index_scan = self.index_scan(projection, filters)?;
+        Ok(Arc::new(
+            BlockTableScanExec::try_new(self.handle(), column_ids)?.with_index_scan(index_scan),
+        ))
+    }
+
+    async fn insert_into(
+        &self,
+        state: &SessionState,
+        input: Arc<dyn ExecutionPlan>,
+        overwrite: bool,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if !self
+            .schema()
+            .logically_equivalent_names_and_types(&input.schema())
+        {
+            return plan_err!("Inserting query must have the same schema with the table.");
+        }
+        if overwrite {
+            return not_impl_err!("Overwrite not implemented for BlockTable yet");
+        }
+        let sink = Arc::new(BlockSink {
+            writer: writer_for(state.config()),
+            id: self.id,
+            schema: self.schema.clone(),
+            table: self.table.clone(),
+            indexes: self.indexes.clone(),
+            retired: self.retired.clone(),
+            lock: self.lock.clone(),
+        });
+        Ok(Arc::new(FileSinkExec::new(
+            input,
+            sink,
+            self.schema.clone(),
+            None,
+        )))
+    }
+
+    fn get_column_default(&self, column: &str) -> Option<&Expr> {
+        self.column_defaults.get(column)
+    }
+}
+
+/// Shared handles to a block table's blocks and indexes, for plans that read the table when they
+/// are executed
+#[derive(Debug, Clone)]
+pub(crate) struct BlockTableHandle {
+    schema: SchemaRef,
+    table: Arc<RwLock<Table>>,
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+}
+
+impl BlockTableHandle {
+    pub(crate) fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    /// The rows `snapshot` sees, as a batch per block with the columns `column_ids`. The snapshot
+    /// must be registered as active so the versions it sees aren't collected meanwhile.
+    pub(crate) fn batches(
+        &self,
+        column_ids: &[usize],
+        snapshot: Snapshot,
+    ) -> Result<Vec<RecordBatch>> {
+        let schema = Arc::new(self.schema.project(column_ids)?);
+        let mut batches = vec![];
+        let table = self.table.read();
+        for (block_index, _) in table.blocks() {
+            let (batch, _) = block_batch(&table, block_index, &schema, column_ids, snapshot)?;
+            if batch.num_rows() > 0 {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// The rows with a key in `range` of index `name` as `snapshot` sees them, which must be
+    /// registered as active. Entries can lead to rows whose version the snapshot sees has another
+    /// key, since the index keeps the keys of older and newer versions, so the key of each
+    /// version is checked against the range.
+    pub(crate) fn lookup(
+        &self,
+        name: &str,
+        range: &KeyRange,
+        snapshot: Snapshot,
+    ) -> Result<RecordBatch> {
+        let table = self.table.read();
+        let indexes = self.indexes.read();
+        let Some(index) = indexes.get(name) else {
+            return exec_err!("Index {name} no longer exists");
+        };
+        let mut offsets = index.lookup(range);
+        // A row has an entry for each key in the range its versions have had
+        offsets.sort_unstable();
+        offsets.dedup();
+        let rows: Vec<ProjectedRow> = offsets
+            .into_iter()
+            .filter_map(|(_, block_index, row_index)| {
+                let slot = TupleSlot::new(block_index as usize, row_index as usize);
+                table.visible_row(slot, snapshot)
+            })
+            .collect();
+        let column_ids: Vec<usize> = (0..self.schema.fields().len()).collect();
+        let batch = rows_batch(&table, &self.schema, &column_ids, &rows)?;
+        let in_range = index.keys_in_range(&batch, range)?;
+        Ok(filter_record_batch(&batch, &in_range)?)
+    }
+}
+
+/// How a block stores values of `data_type`, or `None` if blocks can't store it
+fn column_size(data_type: &DataType) -> Option<ColumnSize> {
+    match data_type {
+        DataType::Boolean => Some(ColumnSize::Fixed(1)),
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Binary | DataType::LargeBinary => {
+            Some(ColumnSize::Varlen)
+        }
+        data_type => data_type.primitive_width().map(ColumnSize::Fixed),
+    }
+}
+
+/// What a write to a block table changed
+#[derive(Debug, Default)]
+struct Changes {
+    row_count: u64,
+    /// The rows deleted or updated, and their new values, if the write is logged
+    deleted: Vec<RecordBatch>,
+    inserted: Vec<RecordBatch>,
+    /// The versions the write added and the slots of their rows, to add to the indexes
+    versions: Vec<(RecordBatch, Vec<TupleSlot>)>,
+}
+
+/// Submit `write` to `writer`, which runs it as part of a write transaction and returns its
+/// row count once the transaction commits. `write` is told whether the writer logs it, in which
+/// case its changes are logged as a change to table `id`. The versions it adds are indexed by
+/// `indexes`. Its writes are rolled back if it fails, and full blocks it thawed are frozen again.
+/// It fails without running if the table has been retired by then, and holds `lock` until it
+/// completes.
+#[allow(clippy::too_many_arguments)]
+async fn write_transaction(
+    writer: Writer,
+    id: TableId,
+    schema: SchemaRef,
+    table: Arc<RwLock<Table>>,
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+    retired: Retired,
+    lock: &TableLock,
+    write: impl FnOnce(&mut Table, TransactionId, bool) -> Result<Changes> + Send + 'static,
+) -> Result<u64> {
+    let log = writer.is_logged();
+    let _lock = lock.lock_for_write().await;
+    writer
+        .submit(move |version| async move {
+            retired.check(id)?;
+            let mut guard = table.write();
+            let savepoint = guard.savepoint(version);
+            let changes = write(&mut guard, version, log).and_then(|changes| {
+                let index_guard = indexes.read();
+                // Rolling the write back doesn't remove index entries, so everything that can
+                // fail runs before the first one is added
+                let entries = version_entries(&index_guard, &changes.versions)?;
+                let log = if changes.deleted.is_empty() && changes.inserted.is_empty() {
+                    vec![]
+                } else {
+                    vec![LogRecord::change(
+                        id,
+                        &schema,
+                        &changes.deleted,
+                        &changes.inserted,
+                    )?]
+                };
+                let added: Vec<(String, Vec<RowEntry>)> = entries
+                    .into_iter()
+                    .map(|(index, entries)| {
+                        (index.name().to_string(), index.insert_entries(entries))
+                    })
+                    .collect();
+                Ok((Applied::new(changes.row_count, log), added))
+            });
+            match changes {
+                Ok((applied, added)) => {
+                    guard.freeze_full_blocks();
+                    drop(guard);
+                    // Undoes the write if it can't be logged, along with the index entries that
+                    // only it added
+                    let rollback = move || async move {
+                        let mut table = table.write();
+                        table.rollback(savepoint);
+                        table.freeze_full_blocks();
+                        let indexes = indexes.read();
+                        for (name, entries) in added {
+                            if let Some(index) = indexes.get(&name) {
+                                index.remove_entries(&entries);
+                            }
+                        }
+                        Ok(())
+                    };
+                    Ok(applied.with_rollback(rollback))
+                }
+                Err(e) => {
+                    guard.rollback(savepoint);
+                    Err(e)
+                }
+            }
+        })
+        .await
+}
+
+fn compact(
+    table: &RwLock<Table>,
+    indexes: &RwLock<SecondaryIndexes>,
+    schema: &SchemaRef,
+    max_fill: f64,
+    metrics: &CompactionMetrics,
+) -> Result<CompactionStats> {
+    let mut table = table.write();
+    let indexes = indexes.read();
+    let mut moves = vec![];
+    let stats = table
+        .compact(max_fill, oldest_active(), |old, new| {
+            if !indexes.is_empty() {
+                moves.push((old, new));
+            }
+        })
+        .map_err(external)?;
+    metrics.record(stats);
+    move_index_entries(&table, &indexes, schema, moves)?;
+    Ok(stats)
+}
+
+/// Point the index entries of the rows compaction moved at their new slots. Compacted blocks
+/// have no old versions left, so each row only has entries for its current version.
+fn move_index_entries(
+    table: &Table,
+    indexes: &SecondaryIndexes,
+    schema: &SchemaRef,
+    moves: Vec<(TupleSlot, TupleSlot)>,
+) -> Result<()> {
+    if moves.is_empty() {
+        return Ok(());
+    }
+    let column_ids: Vec<usize> = (0..schema.fields().len()).collect();
+    let mut old = vec![];
+    let mut new = vec![];
+    let mut rows = vec![];
+    for (old_slot, new_slot) in moves {
+        if let Some(row) = table.visible_row(new_slot, Snapshot::uncommitted()) {
+            old.push(old_slot);
+            new.push(new_slot);
+            rows.push(row);
+        }
+    }
+    let batch = rows_batch(table, schema, &column_ids, &rows)?;
+    for index in indexes.values() {
+        let old_entries = index.row_entries(&batch, &offsets(&old))?;
+        let new_entries = index.row_entries(&batch, &offsets(&new))?;
+        // A freed block can be reused within the same run, so every old entry goes before any
+        // new one is added
+        index.remove_entries(&old_entries);
+        index.insert_entries(new_entries);
+    }
+    Ok(())
+}
+
+fn collect_garbage(
+    table: &RwLock<Table>,
+    indexes: &RwLock<SecondaryIndexes>,
+    schema: &SchemaRef,
+    metrics: &GcMetrics,
+) -> GcStats {
+    let oldest = oldest_active();
+    let mut table = table.write();
+    let indexes = indexes.read();
+    let mut obsolete = vec![];
+    let stats = table.collect_garbage(oldest, |slot, row| {
+        if !indexes.is_empty() {
+            obsolete.push((slot, row.clone()));
+        }
+    });
+    if let Err(e) = prune_index_entries(&table, &indexes, schema, obsolete) {
+        warn!("Failed to prune the indexes of block table: {e}");
+    }
+    let lag = last_committed().saturating_sub(oldest.id());
+    metrics.record(stats, lag);
+    stats
+}
+
+/// Remove the index entries of the `obsolete` versions of rows, other than those for a key that
+/// a version still kept has too
+fn prune_index_entries(
+    table: &Table,
+    indexes: &SecondaryIndexes,
+    schema: &SchemaRef,
+    obsolete: Vec<(TupleSlot, ProjectedRow)>,
+) -> Result<()> {
+    if obsolete.is_empty() {
+        return Ok(());
+    }
+    let column_ids: Vec<usize> = (0..schema.fields().len()).collect();
+    let (slots, rows): (Vec<TupleSlot>, Vec<ProjectedRow>) = obsolete.into_iter().unzip();
+    let removed = rows_batch(table, schema, &column_ids, &rows)?;
+    let mut kept_slots = vec![];
+    let mut kept = vec![];
+    for slot in slots.iter().copied().collect::<HashSet<_>>() {
+        for row in table.versions(slot) {
+            kept_slots.push(slot);
+            kept.push(row);
+        }
+    }
+    let kept = rows_batch(table, schema, &column_ids, &kept)?;
+    for index in indexes.values() {
+        let removed_entries = index.row_entries(&removed, &offsets(&slots))?;
+        let kept_entries = index.row_entries(&kept, &offsets(&kept_slots))?;
+        index.remove_entries(&removed_entries);
+        // Entries the kept versions share with the obsolete ones are put back
+        index.insert_entries(kept_entries);
+    }
+    Ok(())
+}
+
+/// Add an entry for each row of `batch`, stored at `slots`, to every index of `indexes`
+fn index_rows(indexes: &SecondaryIndexes, batch: &RecordBatch, slots: &[TupleSlot]) -> Result<()> {
+    let offsets = offsets(slots);
+    for index in indexes.values() {
+        index.insert_rows(batch, &offsets)?;
+    }
+    Ok(())
+}
+
+/// The entries each index of `indexes` needs for `versions`, the rows a write added and their
+/// slots
+fn version_entries<'a>(
+    indexes: &'a SecondaryIndexes,
+    versions: &[(RecordBatch, Vec<TupleSlot>)],
+) -> Result<Vec<(&'a SecondaryIndex, Vec<RowEntry>)>> {
+    indexes
+        .values()
+        .map(|index| {
+            let mut entries = vec![];
+            for (batch, slots) in versions {
+                entries.extend(index.row_entries(batch, &offsets(slots))?);
+            }
+            Ok((index, entries))
+        })
+        .collect()
+}
+
+/// The tuple offsets the indexes of a block table store for `slots`
+fn offsets(slots: &[TupleSlot]) -> Vec<TupletOffset> {
+    slots
+        .iter()
+        .map(|slot| (0, slot.block_index() as i32, slot.row_index() as i32))
+        .collect()
+}
+
+fn external(e: anyhow::Error) -> DataFusionError {
+    DataFusionError::External(e.into())
+}
+
+/// The rows of a block, the record index of each row, and which rows match a predicate
+struct MatchingRows {
+    block_index: usize,
+    batch: RecordBatch,
+    records: Vec<u32>,
+    mask: BooleanArray,
+}
+
+fn matching_rows(
+    table: &Table,
+    schema: &SchemaRef,
+    predicate: Option<&Arc<dyn PhysicalExpr>>,
+) -> Result<Vec<MatchingRows>> {
+    let column_ids: Vec<usize> = (0..schema.fields().len()).collect();
+    table
+        .blocks()
+        .map(|(block_index, _)| {
+            let (batch, records) = block_batch(
+                table,
+                block_index,
+                schema,
+                &column_ids,
+                Snapshot::uncommitted(),
+            )?;
+            let mask = match predicate {
+                Some(predicate) => {
+                    let mask = predicate.evaluate(&batch)?.into_array(batch.num_rows())?;
+                    let mask = as_boolean_array(&mask)?;
+                    if mask.null_count() > 0 {
+                        prep_null_mask_filter(mask)
+                    } else {
+                        mask.clone()
+                    }
+                }
+                None => BooleanArray::from(vec![true; batch.num_rows()]),
+            };
+            Ok(MatchingRows {
+                block_index,
+                batch,
+                records,
+                mask,
+            })
+        })
+        .collect()
+}
+
+/// The rows of block `block_index` of `table` that `snapshot` sees, as a batch with the columns
+/// `column_ids`, whose types are given by `schema`. Also returns the record index of each row.
+///
+/// The columns of frozen blocks share their values with the block where Arrow's layout allows,
+/// and rows that were deleted since the block was frozen are filtered out. The rows of hot blocks
+/// are copied, as are the rows of blocks written to after the snapshot, whose older versions are
+/// rebuilt from their undo records.
+fn block_batch(
+    table: &Table,
+    block_index: usize,
+    schema: &SchemaRef,
+    column_ids: &[usize],
+    snapshot: Snapshot,
+) -> Result<(RecordBatch, Vec<u32>)> {
+    let block = table.block(block_index).expect("block exists");
+    if !snapshot.sees(block.max_version()) {
+        return versioned_batch(table, block_index, schema, column_ids, snapshot);
+    }
+    let present: Vec<u32> = block.present().iter().collect();
+    if !block.is_frozen() {
+        return Ok((records_batch(block, schema, column_ids, &present)?, present));
+    }
+    let records: Vec<u32> = (0..block.num_records() as u32).collect();
+    let columns = column_ids
+        .iter()
+        .zip(schema.fields())
+        .map(|(column_id, field)| {
+            match frozen_array(block, *column_id, field.data_type(), &records)? {
+                Some(array) => Ok(array),
+                None => column_array(block, *column_id, field.data_type(), &records),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let options = RecordBatchOptions::new().with_row_count(Some(records.len()));
+    let mut batch = RecordBatch::try_new_with_options(schema.clone(), columns, &options)?;
+    if present.len() < records.len() {
+        let present = block.present();
+        let mask = BooleanBuffer::collect_bool(records.len(), |idx| present.contains(idx as u32));
+        batch = filter_record_batch(&batch, &BooleanArray::new(mask, None))?;
+    }
+    Ok((batch, present))
+}
+
+/// The rows of block `block_index` that `snapshot` sees when some were written after it
+fn versioned_batch(
+    table: &Table,
+    block_index: usize,
+    schema: &SchemaRef,
+    column_ids: &[usize],
+    snapshot: Snapshot,
+) -> Result<(RecordBatch, Vec<u32>)> {
+    let block = table.block(block_index).expect("block exists");
+    let mut current = vec![];
+    let mut older = vec![];
+    let mut older_records = vec![];
+    for record in 0..block.num_records() {
+        if snapshot.sees(block.version(record)) {
+            if block.present().contains(record as u32) {
+                current.push(record as u32);
+            }
+        } else if let Some(row) = table.visible_row(TupleSlot::new(block_index, record), snapshot) {
+            older.push(row);
+            older_records.push(record as u32);
+        }
+    }
+    let batch = concat_batches(
+        schema,
+        &[
+            records_batch(block, schema, column_ids, &current)?,
+            rows_batch(table, schema, column_ids, &older)?,
+        ],
+    )?;
+    current.extend(older_records);
+    Ok((batch, current))
+}
+
+/// Copy `rows`, which have every column of `table`, into a batch with the columns `column_ids`
+fn rows_batch(
+    table: &Table,
+    schema: &SchemaRef,
+    column_ids: &[usize],
+    rows: &[ProjectedRow],
+) -> Result<RecordBatch> {
+    let batches = rows
+        .chunks(SLOTS_PER_BLOCK)
+        .map(|rows| {
+            // Copied into a scratch block so they can be read like stored rows
+            let mut block = Block::new(table.column_sizes().to_vec());
+            for row in rows {
+                block.insert(row, 0).map_err(external)?;
+            }
+            let records: Vec<u32> = (0..rows.len() as u32).collect();
+            records_batch(&block, schema, column_ids, &records)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(concat_batches(schema, &batches)?)
+}
+
+/// Copy `records` of `block` into a batch with the columns `column_ids`
+fn records_batch(
+    block: &Block,
+    schema: &SchemaRef,
+    column_ids: &[usize],
+    records: &[u32],
+) -> Result<RecordBatch> {
+    let columns = column_ids
+        .iter()
+        .zip(schema.fields())
+        .map(|(column_id, field)| column_array(block, *column_id, field.data_type(), records))
+        .collect::<Result<Vec<_>>>()?;
+    let options = RecordBatchOptions::new().with_row_count(Some(records.len()));
+    Ok(RecordBatch::try_new_with_options(
+        schema.clone(),
+        columns,
+        &options,
+    )?)
+}
+
+/// An array sharing the values of a column of a frozen block, or `None` if the block is hot or
+/// Arrow's layout for `data_type` doesn't match the block's
+fn frozen_array(
+    block: &Block,
+    column_id: usize,
+    data_type: &DataType,
+    records: &[u32],
+) -> Result<Option<ArrayRef>> {
+    let buffers = match data_type {
+        DataType::Boolean => return Ok(None),
+        DataType::Utf8 | DataType::Binary => match block.frozen_varlen(column_id) {
+            Some(varlen) => vec![varlen.offsets.clone(), varlen.values.clone()],
+            None => return Ok(None),
+        },
+        _ => match block.frozen_column(column_id) {
+            Some(buffer) => vec![buffer],
+            None => return Ok(None),
+        },
+    };
+    let data = ArrayData::builder(data_type.clone())
+        .len(records.len())
+        .buffers(buffers)
+        .nulls(null_buffer(block, column_id, records))
+        .build()?;
+    Ok(Some(make_array(data)))
+}
+
+/// Nulls of the values of `records` in a column. Deleted records aren't null, so batches of
+/// non-nullable columns stay valid until they are filtered out.
+fn null_buffer(block: &Block, column_id: usize, records: &[u32]) -> Option<NullBuffer> {
+    let (validity, present) = (block.validity(column_id), block.present());
+    let nulls = NullBuffer::from(
+        records
+            .iter()
+            .map(|record| validity.contains(*record) || !present.contains(*record))
+            .collect::<Vec<_>>(),
+    );
+    Some(nulls).filter(|nulls| nulls.null_count() > 0)
+}
+
+fn column_array(
+    block: &Block,
+    column_id: usize,
+    data_type: &DataType,
+    records: &[u32],
+) -> Result<ArrayRef> {
+    let nulls = null_buffer(block, column_id, records);
+    let bytes = block.column_bytes(column_id);
+    let size = match block.column_size(column_id) {
+        ColumnSize::Fixed(size) => size,
+        ColumnSize::Varlen => {
+            let values = records
+                .iter()
+                .map(|record| block.varlen_value(column_id, *record as usize));
+            return match data_type {
+                DataType::Utf8 | DataType::Binary => varlen_array::<i32>(values, data_type, nulls),
+                _ => varlen_array::<i64>(values, data_type, nulls),
+            };
+        }
+    };
+    if data_type == &DataType::Boolean {
+        let values = records
+            .iter()
+            .map(|record| bytes[*record as usize] != 0)
+            .collect::<BooleanBuffer>();
+        return Ok(Arc::new(BooleanArray::new(values, nulls)));
+    }
+    let mut values = MutableBuffer::with_capacity(records.len() * size);
+    for record in records {
+        let start = *record as usize * size;
+        values.extend_from_slice(&bytes[start..start + size]);
+    }
+    let data = ArrayData::builder(data_type.clone())
+        .len(records.len())
+        .add_buffer(values.into())
+        .nulls(nulls)
+        .build()?;
+    Ok(make_array(data))
+}
+
+/// A string or binary array of `data_type`, whose offsets are `O`, holding `values`
+fn varlen_array<'a, O: OffsetSizeTrait>(
+    values: impl Iterator<Item = &'a [u8]>,
+    data_type: &DataType,
+    nulls: Option<NullBuffer>,
+) -> Result<ArrayRef> {
+    let data = GenericBinaryArray::<O>::from_iter_values(values)
+        .into_data()
+        .into_builder()
+        .data_type(data_type.clone())
+        .nulls(nulls)
+        .build()?;
+    Ok(make_array(data))
+}
+
+/// Convert every row of `batch` to a row with a value for each column
+fn projected_rows(batch: &RecordBatch) -> Vec<ProjectedRow> {
+    let column_ids: Vec<usize> = (0..batch.num_columns()).collect();
+    let columns: Vec<ArrayData> = batch.columns().iter().map(|c| c.to_data()).collect();
+    (0..batch.num_rows())
+        .map(|row| {
+            let values = batch
+                .columns()
+                .iter()
+                .zip(columns.iter())
+                .map(|(array, data)| value_bytes(array, data, row))
+                .collect();
+            ProjectedRow::new(column_ids.clone(), values)
+        })
+        .collect()
+}
+
+/// The bytes a block stores for the value at `row`, or `None` if it is null
+fn value_bytes(array: &ArrayRef, data: &ArrayData, row: usize) -> Option<Vec<u8>> {
+    if array.is_null(row) {
+        return None;
+    }
+    if let Some(array) = array.as_boolean_opt() {
+        return Some(vec![u8::from(array.value(row))]);
+    }
+    let value = match array.data_type() {
+        DataType::Utf8 => array.as_string::<i32>().value(row).as_bytes(),
+        DataType::LargeUtf8 => array.as_string::<i64>().value(row).as_bytes(),
+        DataType::Binary => array.as_binary::<i32>().value(row),
+        DataType::LargeBinary => array.as_binary::<i64>().value(row),
+        data_type => {
+            let size = data_type
+                .primitive_width()
+                .expect("block tables only have primitive, boolean and varlen types");
+            let start = (data.offset() + row) * size;
+            &data.buffers()[0].as_slice()[start..start + size]
+        }
+    };
+    Some(value.to_vec())
+}
+
+/// Implements writing to a [`BlockTable`]
+struct BlockSink {
+    writer: Writer,
+    id: TableId,
+    schema: SchemaRef,
+    table: Arc<RwLock<Table>>,
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+    retired: Retired,
+    lock: TableLock,
+}
+
+impl Debug for BlockSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockSink").finish()
+    }
+}
+
+impl DisplayAs for BlockSink {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                write!(f, "BlockTable")
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DataSink for BlockSink {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        None
+    }
+
+    async fn write_all(
+        &self,
+        mut data: SendableRecordBatchStream,
+        _context: &Arc<TaskContext>,
+    ) -> Result<u64> {
+        let mut batches = vec![];
+        while let Some(batch) = data.next().await.transpose()? {
+            batches.push(batch);
+        }
+        write_transaction(
+            self.writer.clone(),
+            self.id,
+            self.schema.clone(),
+            self.table.clone(),
+            self.indexes.clone(),
+            self.retired.clone(),
+            &self.lock,
+            move |table, version, log| {
+                let mut changes = Changes::default();
+                for batch in batches {
+                    let slots = projected_rows(&batch)
+                        .iter()
+                        .map(|row| table.insert(row, version).map_err(external))
+                        .collect::<Result<Vec<_>>>()?;
+                    changes.row_count += slots.len() as u64;
+                    if log {
+                        changes.inserted.push(batch.clone());
+                    }
+                    changes.versions.push((batch, slots));
+                }
+                Ok(changes)
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, Int32Array, Int64Array, StringArray};
+    use arrow::datatypes::{Field, Schema};
+    use arrow::util::pretty::pretty_format_batches;
+    use datafusion::physical_plan::{collect, displayable};
+
+    use arrow::datatypes::Int64Type;
+    use datafusion::logical_expr::{col, lit};
+    use datafusion::prelude::{SessionConfig, SessionContext};
+
+    use crate::session::{new_context, new_context_with_config};
+    use crate::test_util::{self, run};
+    use crate::transaction::ActiveSnapshot;
+
+    fn test_table(num_rows: i32) -> Result<BlockTable> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("price", DataType::Float64, true),
+            Field::new("in_stock", DataType::Boolean, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from_iter_values(0..num_rows)),
+                Arc::new(Float64Array::from_iter(
+                    (0..num_rows).map(|id| (id % 10 != 0).then_some(id as f64 / 2.0)),
+                )),
+                Arc::new(BooleanArray::from_iter(
+                    (0..num_rows).map(|id| (id % 7 != 0).then_some(id % 2 == 0)),
+                )),
+            ],
+        )?;
+        BlockTable::try_new(schema, vec![vec![batch]])
+    }
+
+    async fn query(table: BlockTable, statements: &[&str], sql: &str) -> Result<String> {
+        let ctx = new_context();
+        ctx.register_table("items", Arc::new(table))?;
+        let state = ctx.state();
+        for statement in statements {
+            let plan = state.create_logical_plan(statement).await?;
+            crate::sql::execute_logical_plan(&ctx, plan)
+                .await?
+                .collect()
+                .await?;
+        }
+        let plan = ctx.sql(sql).await?.create_physical_plan().await?;
+        let batches = collect(plan, ctx.task_ctx()).await?;
+        Ok(pretty_format_batches(&batches)?.to_string())
+    }
+
+    #[tokio::test]
+    async fn appends_blocks_when_full() -> Result<()> {
+        let table = test_table(2 * SLOTS_PER_BLOCK as i32 + 5)?;
+        assert_eq!(3, table.num_blocks());
+        let result = query(
+            table,
+            &[],
+            "SELECT count(*), count(price), count(in_stock), sum(id), max(price) FROM items",
+        )
+        .await?;
+        assert_eq!(
+            "+----------+--------------------+-----------------------+---------------+------------------+\n\
+             | COUNT(*) | COUNT(items.price) | COUNT(items.in_stock) | SUM(items.id) | MAX(items.price) |\n\
+             +----------+--------------------+-----------------------+---------------+------------------+\n\
+             | 2005     | 1804               | 1718                  | 2009010       | 1002.0           |\n\
+             +----------+--------------------+-----------------------+---------------+------------------+",
+            result
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nulls_and_booleans() -> Result<()> {
+        let result = query(
+            test_table(10)?,
+            &[],
+            "SELECT id, price, in_stock FROM items WHERE id < 4 OR id = 7 ORDER BY id",
+        )
+        .await?;
+        assert_eq!(
+            "+----+-------+----------+\n\
+             | id | price | in_stock |\n\
+             +----+-------+----------+\n\
+             | 0  |       |          |\n\
+             | 1  | 0.5   | false    |\n\
+             | 2  | 1.0   | true     |\n\
+             | 3  | 1.5   | false    |\n\
+             | 7  | 3.5   |          |\n\
+             +----+-------+----------+",
+            result
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_update_and_delete_in_place() -> Result<()> {
+        let table = test_table(SLOTS_PER_BLOCK as i32)?;
+        let blocks = table.table.clone();
+        let result = query(
+            table,
+            &[
+                "INSERT INTO items VALUES (5000, 1.25, true), (5001, NULL, NULL)",
+                "UPDATE items SET price = price * 2, in_stock = NULL WHERE id = 1 OR id = 5001",
+                "DELETE FROM items WHERE id >= 2 AND id < 999",
+            ],
+            "SELECT id, price, in_stock FROM items ORDER BY id",
+        )
+        .await?;
+        assert_eq!(
+            "+------+-------+----------+\n\
+             | id   | price | in_stock |\n\
+             +------+-------+----------+\n\
+             | 0    |       |          |\n\
+             | 1    | 1.0   |          |\n\
+             | 999  | 499.5 | false    |\n\
+             | 5000 | 1.25  | true     |\n\
+             | 5001 |       |          |\n\
+             +------+-------+----------+",
+            result
+        );
+        // The deleted slots aren't reused and the inserted rows went to a new block
+        let blocks = blocks.read();
+        assert_eq!(2, blocks.num_blocks());
+        assert_eq!(SLOTS_PER_BLOCK, blocks.block(0).unwrap().num_records());
+        assert_eq!(3, blocks.block(0).unwrap().present().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn string_and_binary_columns() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("description", DataType::LargeUtf8, true),
+            Field::new("sku", DataType::Binary, true),
+        ]));
+        let table = BlockTable::try_new(schema, vec![])?;
+        let blocks = table.table.clone();
+        let result = query(
+            table,
+            &[
+                "INSERT INTO items VALUES \
+                 (1, 'quokka', 'A small marsupial found on Rottnest Island', X'0102'), \
+                 (2, 'wallaby', NULL, NULL), \
+                 (3, 'kangaroo', 'Tall', X'03')",
+                "UPDATE items SET name = name || ' plush toy', description = NULL WHERE id = 1",
+                "UPDATE items SET description = 'Much taller than a quokka' WHERE id = 3",
+                "DELETE FROM items WHERE id = 2",
+            ],
+            "SELECT id, name, description, sku FROM items WHERE name LIKE '%o%' ORDER BY id",
+        )
+        .await?;
+        assert_eq!(
+            "+----+------------------+---------------------------+------+\n\
+             | id | name             | description               | sku  |\n\
+             +----+------------------+---------------------------+------+\n\
+             | 1  | quokka plush toy |                           | 0102 |\n\
+             | 3  | kangaroo         | Much taller than a quokka | 03   |\n\
+             +----+------------------+---------------------------+------+",
+            result
+        );
+        // Only the long values still in use are kept in the arena
+        let blocks = blocks.read();
+        assert_eq!(
+            "quokka plush toy".len() + "Much taller than a quokka".len(),
+            blocks.block(0).unwrap().varlen_size()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frozen_blocks_share_their_values() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        let num_rows = SLOTS_PER_BLOCK as i64 + 1;
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from_iter_values(0..num_rows)),
+                Arc::new(StringArray::from_iter((0..num_rows).map(|id| {
+                    (id % 3 != 0).then(|| format!("quokka product number {id}"))
+                }))),
+            ],
+        )?;
+        let table = BlockTable::try_new(schema.clone(), vec![vec![batch]])?;
+        {
+            let blocks = table.table.read();
+            let block = blocks.block(0).unwrap();
+            assert!(block.is_frozen());
+            assert!(!blocks.block(1).unwrap().is_frozen());
+            let (batch, _) = block_batch(&blocks, 0, &schema, &[0, 1], Snapshot::latest())?;
+            assert_eq!(
+                block.frozen_column(0).unwrap().as_ptr(),
+                batch.column(0).to_data().buffers()[0].as_ptr()
+            );
+            let varlen = block.frozen_varlen(1).unwrap();
+            let name = batch.column(1).to_data();
+            assert_eq!(varlen.values.as_ptr(), name.buffers()[1].as_ptr());
+            assert_eq!(
+                "quokka product number 998",
+                batch.column(1).as_string::<i32>().value(998)
+            );
+            assert!(batch.column(1).is_null(999));
+        }
+        let blocks = table.table.clone();
+        let result = query(
+            table,
+            &[
+                "DELETE FROM items WHERE id < 995",
+                "UPDATE items SET name = 'renamed' WHERE id = 996",
+            ],
+            "SELECT id, name FROM items ORDER BY id",
+        )
+        .await?;
+        assert_eq!(
+            "+------+----------------------------+\n\
+             | id   | name                       |\n\
+             +------+----------------------------+\n\
+             | 995  | quokka product number 995  |\n\
+             | 996  | renamed                    |\n\
+             | 997  | quokka product number 997  |\n\
+             | 998  | quokka product number 998  |\n\
+             | 999  |                            |\n\
+             | 1000 | quokka product number 1000 |\n\
+             +------+----------------------------+",
+            result
+        );
+        // Deleting and updating thawed the block until the statement was done
+        assert!(blocks.read().block(0).unwrap().is_frozen());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_frees_sparse_blocks() -> Result<()> {
+        let table = test_table(3 * SLOTS_PER_BLOCK as i32)?;
+        let blocks = table.table.clone();
+        let ctx = new_context();
+        let table = Arc::new(table);
+        ctx.register_table("items", table.clone())?;
+        let plan = ctx
+            .state()
+            .create_logical_plan("DELETE FROM items WHERE id % 5 <> 0 AND id < 2000")
+            .await?;
+        crate::sql::execute_logical_plan(&ctx, plan)
+            .await?
+            .collect()
+            .await?;
+
+        assert_eq!(0, table.compact(0.5)?.blocks_freed);
+        collect_all_garbage(&table).await;
+        let stats = table.compact(0.5)?;
+        assert_eq!(2, stats.blocks_freed);
+        assert_eq!(400, stats.tuples_moved);
+        assert_eq!(2, table.num_blocks());
+        assert_eq!(0, table.compact(0.5)?.blocks_freed);
+        let metrics = table.compaction_metrics();
+        assert_eq!(
+            (3, 2, 400),
+            (
+                metrics.runs(),
+                metrics.blocks_freed(),
+                metrics.tuples_moved()
+            )
+        );
+        assert!(blocks
+            .read()
+            .blocks()
+            .all(|(_, block)| block.is_frozen() || block.num_records() == 400));
+
+        let batches = ctx
+            .sql("SELECT count(*), sum(id), count(price), count(in_stock) FROM items")
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(
+            "+----------+---------------+--------------------+-----------------------+\n\
+             | COUNT(*) | SUM(items.id) | COUNT(items.price) | COUNT(items.in_stock) |\n\
+             +----------+---------------+--------------------+-----------------------+\n\
+             | 1400     | 2898500       | 1100               | 1199                  |\n\
+             +----------+---------------+--------------------+-----------------------+",
+            pretty_format_batches(&batches)?.to_string()
+        );
+        Ok(())
+    }
+
+    /// Tuple offsets the index `name` of `table` finds for `filter`
+    fn index_lookup(table: &BlockTable, name: &str, filter: Expr) -> Result<Vec<TupletOffset>> {
+        let indexes = table.indexes.read();
+        let index = &indexes[name];
+        let range = index.key_range(&[filter])?.expect("index applies");
+        Ok(index.lookup(&range))
+    }
+
+    #[tokio::test]
+    async fn compaction_moves_index_entries() -> Result<()> {
+        let table = Arc::new(test_table(3 * SLOTS_PER_BLOCK as i32)?);
+        let ctx = new_context();
+        ctx.register_table("items", table.clone())?;
+        run(&ctx, "CREATE INDEX items_price ON items (price)").await?;
+        run(&ctx, "DELETE FROM items WHERE id % 5 <> 0 AND id < 2000").await?;
+        collect_all_garbage(&table).await;
+        let before = index_lookup(&table, "items_price", col("price").eq(lit(607.5)))?;
+        assert_eq!(vec![(0, 1, 215)], before);
+
+        assert_eq!(400, table.compact(0.5)?.tuples_moved);
+        let after = index_lookup(&table, "items_price", col("price").eq(lit(607.5)))?;
+        assert_eq!(1, after.len());
+        assert_ne!(before, after);
+        let sql = "SELECT id FROM items WHERE price = 607.5";
+        let plan = ctx.sql(sql).await?.create_physical_plan().await?;
+        let plan = displayable(plan.as_ref()).indent(true).to_string();
+        assert!(plan.contains("IndexScanExec: index=items_price"), "{plan}");
+        assert_eq!(
+            "+------+\n\
+             | id   |\n\
+             +------+\n\
+             | 1215 |\n\
+             +------+",
+            test_util::query(&ctx, sql).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn background_compaction() -> Result<()> {
+        let table = test_table(2 * SLOTS_PER_BLOCK as i32 + 1)?;
+        let ctx = new_context();
+        let state = ctx.state();
+        table.delete(&state, None).await?;
+        table.start_maintenance(Duration::from_millis(10), 0.5);
+        // Other tests' snapshots can keep the deleted rows from being collected for a while
+        for _ in 0..1000 {
+            if table.compaction_metrics().blocks_freed() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(2, table.compaction_metrics().blocks_freed());
+        assert_eq!(0, table.compaction_metrics().tuples_moved());
+        assert_eq!(1, table.num_blocks());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_scans_read_the_versions_their_snapshot_sees() -> Result<()> {
+        let (ctx, table) = accounts(SLOTS_PER_BLOCK as i64 + 10).await?;
+        run(&ctx, "CREATE INDEX accounts_balance ON accounts (balance)").await?;
+        let snapshot = Arc::new(ActiveSnapshot::begin());
+        let pinned = new_context_with_config(SessionConfig::new().with_extension(snapshot.clone()));
+        pinned.register_table("accounts", table.clone())?;
+        execute(&ctx, "UPDATE accounts SET balance = 500 WHERE id = 3").await?;
+        execute(&ctx, "DELETE FROM accounts WHERE id = 4").await?;
+        execute(&ctx, "INSERT INTO accounts VALUES (5000, 500)").await?;
+
+        let sql = "SELECT id FROM accounts WHERE balance = 500 ORDER BY id";
+        let plan = ctx.sql(sql).await?.create_physical_plan().await?;
+        let plan = displayable(plan.as_ref()).indent(true).to_string();
+        assert!(
+            plan.contains("IndexScanExec: index=accounts_balance"),
+            "{plan}"
+        );
+        assert_eq!(
+            "+------+\n\
+             | id   |\n\
+             +------+\n\
+             | 3    |\n\
+             | 5000 |\n\
+             +------+",
+            test_util::query(&ctx, sql).await?
+        );
+        // The entry for the new key of row 3 leads to a version the older reader sees with its
+        // old key, which the lookup leaves out
+        let range = table.indexes.read()["accounts_balance"]
+            .key_range(&[col("balance").eq(lit(500))])?
+            .expect("index applies");
+        let lookup = |snapshot| table.handle().lookup("accounts_balance", &range, snapshot);
+        assert_eq!(2, lookup(Snapshot::latest())?.num_rows());
+        assert_eq!(0, lookup(snapshot.snapshot())?.num_rows());
+
+        // The older reader finds neither the update nor the insert through the index, but still
+        // finds the versions it sees by their old keys
+        assert_eq!("++\n++", test_util::query(&pinned, sql).await?);
+        let count = "SELECT count(*) AS count FROM accounts WHERE balance = 100";
+        assert_eq!(
+            "+-------+\n\
+             | count |\n\
+             +-------+\n\
+             | 1010  |\n\
+             +-------+",
+            test_util::query(&pinned, count).await?
+        );
+        assert_eq!(
+            "+-------+\n\
+             | count |\n\
+             +-------+\n\
+             | 1008  |\n\
+             +-------+",
+            test_util::query(&ctx, count).await?
+        );
+        Ok(())
+    }
+
+    /// Collect garbage until every old version is freed, once other tests' snapshots are dropped
+    async fn collect_all_garbage(table: &BlockTable) {
+        for _ in 0..1000 {
+            table.collect_garbage();
+            if table.table.read().undo_size() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("old versions weren't collected");
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_prunes_index_entries() -> Result<()> {
+        let table = Arc::new(test_table(10)?);
+        let ctx = new_context();
+        ctx.register_table("items", table.clone())?;
+        run(&ctx, "CREATE INDEX items_price ON items (price)").await?;
+        let num_entries = || table.indexes.read()["items_price"].num_entries();
+        assert_eq!(10, num_entries());
+
+        // Old snapshots may still look the old keys up
+        execute(
+            &ctx,
+            "UPDATE items SET price = price + 100 WHERE id BETWEEN 1 AND 3",
+        )
+        .await?;
+        execute(&ctx, "UPDATE items SET price = price - 100 WHERE id = 1").await?;
+        execute(&ctx, "DELETE FROM items WHERE id = 5").await?;
+        assert_eq!(13, num_entries());
+        assert_eq!(
+            vec![(0, 0, 3)],
+            index_lookup(&table, "items_price", col("price").eq(lit(101.5)))?
+        );
+
+        // Keys only freed versions had are pruned, and keys the current version shares are kept
+        collect_all_garbage(&table).await;
+        assert_eq!(9, num_entries());
+        assert_eq!(
+            vec![(0, 0, 1)],
+            index_lookup(&table, "items_price", col("price").eq(lit(0.5)))?
+        );
+        assert!(index_lookup(&table, "items_price", col("price").eq(lit(100.5)))?.is_empty());
+        assert!(index_lookup(&table, "items_price", col("price").eq(lit(2.5)))?.is_empty());
+        Ok(())
+    }
+
+    async fn sum_and_count(ctx: &SessionContext) -> Result<(i64, i64)> {
+        let batches = ctx
+            .sql("SELECT sum(balance), count(*) FROM accounts")
+            .await?
+            .collect()
+            .await?;
+        let sum = batches[0].column(0).as_primitive::<Int64Type>().value(0);
+        let count = batches[0].column(1).as_primitive::<Int64Type>().value(0);
+        Ok((sum, count))
+    }
+
+    async fn accounts(num_rows: i64) -> Result<(SessionContext, Arc<BlockTable>)> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("balance", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from_iter_values(0..num_rows)),
+                Arc::new(Int64Array::from_iter_values((0..num_rows).map(|_| 100))),
+            ],
+        )?;
+        let table = Arc::new(BlockTable::try_new(schema, vec![vec![batch]])?);
+        let ctx = new_context();
+        ctx.register_table("accounts", table.clone())?;
+        Ok((ctx, table))
+    }
+
+    async fn execute(ctx: &SessionContext, sql: &str) -> Result<()> {
+        let plan = ctx.state().create_logical_plan(sql).await?;
+        crate::sql::execute_logical_plan(ctx, plan)
+            .await?
+            .collect()
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pinned_snapshots_see_older_versions() -> Result<()> {
+        let (ctx, table) = accounts(SLOTS_PER_BLOCK as i64 + 10).await?;
+        let pinned = new_context_with_config(
+            SessionConfig::new().with_extension(Arc::new(ActiveSnapshot::begin())),
+        );
+        pinned.register_table("accounts", table.clone())?;
+
+        execute(
+            &ctx,
+            "UPDATE accounts SET balance = balance + 1 WHERE id % 2 = 0",
+        )
+        .await?;
+        execute(&ctx, "DELETE FROM accounts WHERE id >= 1000").await?;
+        execute(&ctx, "INSERT INTO accounts VALUES (5000, 7)").await?;
+        assert_eq!((100_000 + 500 + 7, 1001), sum_and_count(&ctx).await?);
+        assert_eq!((101_000, 1010), sum_and_count(&pinned).await?);
+
+        // Garbage collection keeps the versions the pinned snapshot sees
+        table.collect_garbage();
+        assert!(table.gc_metrics().lag() >= 3);
+        assert_eq!((101_000, 1010), sum_and_count(&pinned).await?);
+
+        // A statement failing in the second block rolls back its writes to the first
+        let e = execute(&ctx, "UPDATE accounts SET balance = 1 / (id - 5000)")
+            .await
+            .unwrap_err();
+        assert!(e.strip_backtrace().contains("Divide by zero"), "{e}");
+        assert_eq!((100_000 + 500 + 7, 1001), sum_and_count(&ctx).await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn consistent_reads_during_concurrent_writes() -> Result<()> {
+        let (ctx, table) = accounts(2 * SLOTS_PER_BLOCK as i64).await?;
+        let pinned = new_context_with_config(
+            SessionConfig::new().with_extension(Arc::new(ActiveSnapshot::begin())),
+        );
+        pinned.register_table("accounts", table)?;
+        let writer = {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    // Move money between accounts in different blocks, one statement at a time
+                    execute(
+                        &ctx,
+                        &format!(
+                            "UPDATE accounts SET balance = balance - 10 + 20 * (id - {i}) / 1000 \
+                             WHERE id = {i} OR id = {i} + 1000"
+                        ),
+                    )
+                    .await?;
+                }
+                Result::<()>::Ok(())
+            })
+        };
+        while !writer.is_finished() {
+            // Every statement changes the balances of two accounts, and no scan sees half of one
+            assert_eq!((200_000, 2000), sum_and_count(&ctx).await?);
+            assert_eq!((200_000, 2000), sum_and_count(&pinned).await?);
+        }
+        writer.await.expect("writer doesn't panic")?;
+        let batches = pinned
+            .sql("SELECT count(*) FROM accounts WHERE balance <> 100")
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(0, batches[0].column(0).as_primitive::<Int64Type>().value(0));
+        assert_eq!((200_000, 2000), sum_and_count(&ctx).await?);
+        Ok(())
+    }
+}
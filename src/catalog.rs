@@ -1,3 +1,4 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::{any::Any, sync::Arc};
 
 use async_trait::async_trait;
@@ -7,6 +8,63 @@ use datafusion::catalog::{schema::SchemaProvider, CatalogProvider};
 use datafusion::datasource::TableProvider;
 use datafusion::error::Result;
 use datafusion_common::{exec_err, DataFusionError};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Identifies a table for as long as it exists. The write-ahead log refers to tables by id.
+pub type TableId = u64;
+
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An id no other table has
+pub fn next_table_id() -> TableId {
+    NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Make sure ids handed out from now on are greater than `id`, which a table recovered from the
+/// write-ahead log uses
+pub fn reserve_table_id(id: TableId) {
+    NEXT_TABLE_ID.fetch_max(id + 1, Ordering::Relaxed);
+}
+
+/// Set once a version of a table is replaced by `ALTER TABLE` or the table is dropped. Writes
+/// planned against that version fail once the writer gets to them, rather than changing rows
+/// nothing reads anymore.
+#[derive(Debug, Clone, Default)]
+pub struct Retired(Arc<AtomicBool>);
+
+impl Retired {
+    pub fn retire(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Fail if table `id` has been retired
+    pub fn check(&self, id: TableId) -> Result<()> {
+        if self.0.load(Ordering::Acquire) {
+            return exec_err!("Table {id} was altered or dropped while the write was queued");
+        }
+        Ok(())
+    }
+}
+
+/// Keeps writes away from a version of a table while `ALTER TABLE` builds the next one. Writes
+/// to the table hold it shared from before they're submitted to the writer until they complete,
+/// so rebuilding a table only holds up the writes to that table.
+#[derive(Debug, Clone, Default)]
+pub struct TableLock(Arc<RwLock<()>>);
+
+impl TableLock {
+    /// Wait until the table isn't being altered, and keep it from being altered until the guard
+    /// is dropped
+    pub async fn lock_for_write(&self) -> OwnedRwLockReadGuard<()> {
+        self.0.clone().read_owned().await
+    }
+
+    /// Wait for the writes to the table to complete, and hold off new ones until the guard is
+    /// dropped
+    pub async fn lock_for_alter(&self) -> OwnedRwLockWriteGuard<()> {
+        self.0.clone().write_owned().await
+    }
+}
 
 /// Simple in-memory list of catalogs that can be shared across threads.
 pub struct MemoryCatalogProviderList {
@@ -0,0 +1,383 @@
+//! Checkpoints of every table, so that startup doesn't have to replay the write-ahead log from
+//! the beginning.
+//!
+//! A checkpoint is a directory in the log's directory, named after the first log segment it doesn't
+//! cover. It holds an Arrow IPC file of each table's rows and a manifest of the [`LogRecord`]s that
+//! recreate the system catalog's schemas and text analyzers, its entries for the tables and their
+//! indexes, and its roles. [`checkpoint`] has the writer capture the tables between two writes,
+//! which only clones the batches of memory tables and registers an [`ActiveSnapshot`] of block
+//! tables, then writes the files while the writer carries on. The manifest is written last and the
+//! directory renamed into place, so a checkpoint only exists once it is complete. Older checkpoints
+//! and the log segments the new one covers are then deleted.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions};
+use arrow::ipc::CompressionType;
+use arrow::record_batch::RecordBatch;
+use datafusion::datasource::TableProvider;
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion_common::{exec_err, DataFusionError};
+use log::info;
+use prost::Message;
+use tokio::sync::Mutex;
+
+use crate::analysis;
+use crate::auth;
+use crate::catalog::TableId;
+use crate::file_io::AppendWriter;
+use crate::sql::{as_block_table, as_mem_table};
+use crate::system_catalog::system_catalog;
+use crate::transaction::ActiveSnapshot;
+use crate::wal::{segments, LogRecord, WalOptions};
+use crate::writer::writer_for;
+
+/// Name of the file listing a checkpoint's tables
+const MANIFEST: &str = "manifest";
+
+/// Held while a checkpoint is written, so only one is written at a time
+static CHECKPOINTING: Mutex<()> = Mutex::const_new(());
+
+/// What taking a checkpoint did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointStats {
+    /// First log segment the checkpoint doesn't cover
+    pub wal_segment: u64,
+    pub tables: usize,
+    pub rows: usize,
+    /// Log segments deleted because the checkpoint covers them
+    pub segments_removed: usize,
+}
+
+/// Write a checkpoint of every table in `ctx`'s catalogs to the directory of the write-ahead log
+/// of `ctx`'s writer, then delete the older checkpoints and the log segments it covers
+pub async fn checkpoint(ctx: &SessionContext) -> Result<CheckpointStats> {
+    let _checkpointing = CHECKPOINTING.lock().await;
+    let writer = writer_for(ctx.state().config());
+    let Some(options) = writer.wal_options().cloned() else {
+        return exec_err!("CHECKPOINT needs a write-ahead log");
+    };
+    let session = ctx.clone();
+    let (wal_segment, (schemas, tables, roles)) = writer
+        .checkpoint(move || async move { capture(&session).await })
+        .await?;
+    let stats =
+        tokio::task::spawn_blocking(move || write(&options, wal_segment, schemas, tables, roles))
+            .await
+            .map_err(|e| DataFusionError::External(Box::new(e)))??;
+    info!(
+        "Checkpointed {} rows of {} tables up to log segment {wal_segment}",
+        stats.rows, stats.tables
+    );
+    Ok(stats)
+}
+
+/// The manifest of a checkpoint
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Manifest {
+    /// First log segment the checkpoint doesn't cover
+    #[prost(uint64, tag = "1")]
+    pub wal_segment: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub tables: Vec<CheckpointTable>,
+    /// Records that create the schemas made with `CREATE SCHEMA` and the text analyzers made with
+    /// `CREATE TEXT ANALYZER`, before any table is created
+    #[prost(message, repeated, tag = "3")]
+    pub schemas: Vec<LogRecord>,
+    /// Records that create the roles and their grants, after the tables they're granted on
+    #[prost(message, repeated, tag = "4")]
+    pub roles: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct CheckpointTable {
+    #[prost(uint64, tag = "1")]
+    pub id: TableId,
+    /// Records that create the table, followed by those that create its indexes
+    #[prost(message, repeated, tag = "2")]
+    pub records: Vec<LogRecord>,
+    /// The file in the checkpoint directory holding the table's rows
+    #[prost(string, tag = "3")]
+    pub file: String,
+}
+
+/// The latest complete checkpoint in `dir` and its manifest, if there is one
+pub(crate) fn latest(dir: &Path) -> Result<Option<(PathBuf, Manifest)>> {
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path.file_name().and_then(|name| {
+            name.to_str()?
+                .strip_prefix("checkpoint-")?
+                .parse::<u64>()
+                .ok()
+        });
+        if let Some(index) = index {
+            if latest.as_ref().is_none_or(|(latest, _)| index > *latest) {
+                latest = Some((index, path));
+            }
+        }
+    }
+    let Some((_, path)) = latest else {
+        return Ok(None);
+    };
+    let manifest = Manifest::decode(fs::read(path.join(MANIFEST))?.as_slice())
+        .map_err(|e| DataFusionError::External(Box::new(e)))?;
+    Ok(Some((path, manifest)))
+}
+
+/// The rows in `file` of the checkpoint in `path`
+pub(crate) fn read_rows(path: &Path, file: &str) -> Result<Vec<RecordBatch>> {
+    let reader = FileReader::try_new(File::open(path.join(file))?, None)?;
+    Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+/// A table as it was when the checkpoint was taken
+struct CapturedTable {
+    id: TableId,
+    schema: SchemaRef,
+    records: Vec<LogRecord>,
+    rows: CapturedRows,
+}
+
+enum CapturedRows {
+    Batches(Vec<RecordBatch>),
+    /// A block table and the snapshot of it to write
+    Block(Arc<dyn TableProvider>, ActiveSnapshot),
+}
+
+/// Capture the records that create the schemas and text analyzers in `ctx`'s system catalog, every
+/// table in it, and the records that create its roles. Runs on the writer, so nothing changes
+/// meanwhile.
+async fn capture(
+    ctx: &SessionContext,
+) -> Result<(Vec<LogRecord>, Vec<CapturedTable>, Vec<LogRecord>)> {
+    let system_catalog = system_catalog(ctx)?;
+    let mut schemas: Vec<LogRecord> = system_catalog
+        .schemas()
+        .iter()
+        .map(|(catalog, schema)| LogRecord::create_schema(catalog, schema))
+        .collect();
+    schemas.extend(analysis::capture(&system_catalog));
+    let mut tables = vec![];
+    for entry in system_catalog.entries() {
+        let table = ctx.table_provider(entry.name.clone()).await?;
+        let mut records = vec![LogRecord::create_table(&entry, false)?];
+        for index in &entry.indexes {
+            records.push(LogRecord::create_index(entry.id, index));
+        }
+        for policy in &entry.policies {
+            records.push(LogRecord::create_policy(
+                entry.id,
+                &policy.name,
+                &policy.sql,
+            ));
+        }
+        let rows = if let Some(mem_table) = as_mem_table(&table) {
+            CapturedRows::Batches(mem_table.all_batches().await)
+        } else if as_block_table(&table).is_some() {
+            CapturedRows::Block(table.clone(), ActiveSnapshot::begin())
+        } else {
+            return exec_err!("{} is not a Quokka table", entry.name);
+        };
+        tables.push(CapturedTable {
+            id: entry.id,
+            schema: entry.schema.clone(),
+            records,
+            rows,
+        });
+    }
+    Ok((schemas, tables, auth::capture(&system_catalog)))
+}
+
+/// Write the checkpoint of `schemas`, `tables` and `roles` covering the log segments before
+/// `wal_segment` to the log's directory, then delete what it replaces
+fn write(
+    options: &WalOptions,
+    wal_segment: u64,
+    schemas: Vec<LogRecord>,
+    tables: Vec<CapturedTable>,
+    roles: Vec<LogRecord>,
+) -> Result<CheckpointStats> {
+    let dir = options.dir.as_path();
+    let path = dir.join(format!("checkpoint-{wal_segment:016}"));
+    let partial = path.with_extension("partial");
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir(&partial)?;
+
+    let mut stats = CheckpointStats {
+        wal_segment,
+        tables: tables.len(),
+        ..Default::default()
+    };
+    let mut manifest = Manifest {
+        wal_segment,
+        tables: vec![],
+        schemas,
+        roles,
+    };
+    let ipc_options =
+        IpcWriteOptions::default().try_with_compression(Some(CompressionType::LZ4_FRAME))?;
+    for table in tables {
+        let batches = match table.rows {
+            CapturedRows::Batches(batches) => batches,
+            CapturedRows::Block(block_table, snapshot) => {
+                let column_ids: Vec<usize> = (0..table.schema.fields().len()).collect();
+                as_block_table(&block_table)
+                    .expect("captured as a block table")
+                    .batches(&column_ids, snapshot.snapshot())?
+            }
+        };
+        let file = format!("{}.arrow", table.id);
+        let mut writer = FileWriter::try_new_with_options(
+            AppendWriter(options.io.create(&partial.join(&file))?),
+            &table.schema,
+            ipc_options.clone(),
+        )?;
+        for batch in batches.iter() {
+            stats.rows += batch.num_rows();
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+        let AppendWriter(mut rows_file) = writer.into_inner()?;
+        rows_file.sync()?;
+        rows_file.finish()?;
+        manifest.tables.push(CheckpointTable {
+            id: table.id,
+            records: table.records,
+            file,
+        });
+    }
+    let mut manifest_file = options.io.create(&partial.join(MANIFEST))?;
+    manifest_file.append(&manifest.encode_to_vec())?;
+    manifest_file.sync()?;
+    manifest_file.finish()?;
+    File::open(&partial)?.sync_all()?;
+
+    if path.exists() {
+        // No writes were logged since the last checkpoint, which already holds these rows
+        fs::remove_dir_all(&partial)?;
+    } else {
+        fs::rename(&partial, &path)?;
+        File::open(dir)?.sync_all()?;
+    }
+
+    // Only the latest checkpoint and the segments after it are needed from now on
+    for entry in fs::read_dir(dir)? {
+        let older = entry?.path();
+        let is_older = older
+            .file_name()
+            .and_then(|name| {
+                name.to_str()?
+                    .strip_prefix("checkpoint-")?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .is_some_and(|index| index < wal_segment);
+        if is_older {
+            fs::remove_dir_all(older)?;
+        }
+    }
+    for (index, segment) in segments(dir)? {
+        if index < wal_segment {
+            fs::remove_file(segment)?;
+            stats.segments_removed += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::prelude::SessionConfig;
+
+    use super::*;
+    use crate::session::{new_context, new_context_with_config};
+    use crate::test_util::{query, run};
+    use crate::wal::{Wal, WalOptions};
+    use crate::writer::{Writer, MAX_WRITE_GROUP, WRITE_QUEUE_CAPACITY};
+
+    #[tokio::test]
+    async fn restart_from_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let replayed = new_context();
+        let (wal, _) = Wal::open(WalOptions::new(dir.path()), &replayed).await?;
+        let writer = Writer::start(WRITE_QUEUE_CAPACITY, MAX_WRITE_GROUP, Some(wal));
+        let ctx = new_context_with_config(SessionConfig::new().with_extension(Arc::new(writer)));
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+            "INSERT INTO products VALUES (1, 'apple'), (2, 'pear'), (3, 'plum')",
+            "CREATE UNIQUE INDEX products_name ON products (name)",
+            "CREATE TABLE t (id INT, price DOUBLE) WITH (storage = 'block')",
+            "INSERT INTO t VALUES (1, 1.5), (2, NULL), (3, 3.5)",
+            "UPDATE t SET price = 2.5 WHERE price IS NULL",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        run(&ctx, "CHECKPOINT").await?;
+        // Nothing was written since, so the checkpoint is kept as it is
+        let stats = checkpoint(&ctx).await?;
+        assert_eq!(2, stats.tables);
+        assert_eq!(6, stats.rows);
+        assert_eq!(0, stats.segments_removed);
+        assert_eq!(
+            vec![stats.wal_segment],
+            segments(dir.path())?
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>()
+        );
+
+        // Writes after the checkpoint are replayed from the log
+        for sql in [
+            "DELETE FROM products WHERE id = 1",
+            "INSERT INTO t VALUES (4, 4.5)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let products = query(&ctx, "SELECT * FROM products ORDER BY id").await?;
+        let t = query(&ctx, "SELECT * FROM t ORDER BY id").await?;
+        let catalog = query(&ctx, "SELECT * FROM qs_catalog.qs_tables").await?;
+        drop(ctx);
+
+        let ctx = new_context();
+        let (_wal, replay) = Wal::open(WalOptions::new(dir.path()), &ctx).await?;
+        assert_eq!(Some(stats.wal_segment), replay.checkpoint);
+        assert_eq!(2, replay.transactions);
+        assert_eq!(
+            products,
+            query(&ctx, "SELECT * FROM products ORDER BY id").await?
+        );
+        assert_eq!(t, query(&ctx, "SELECT * FROM t ORDER BY id").await?);
+        assert_eq!(
+            catalog,
+            query(&ctx, "SELECT * FROM qs_catalog.qs_tables").await?
+        );
+        // The unique index was rebuilt
+        let e = run(&ctx, "INSERT INTO products VALUES (5, 'pear')")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Execution error: Duplicate key value violates unique index products_name",
+            e.strip_backtrace()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checkpoint_needs_a_log() {
+        let ctx = new_context();
+        let e = run(&ctx, "checkpoint;").await.unwrap_err();
+        assert_eq!(
+            "Execution error: CHECKPOINT needs a write-ahead log",
+            e.strip_backtrace()
+        );
+    }
+}
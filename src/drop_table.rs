@@ -0,0 +1,440 @@
+//! `DROP TABLE`, and the `CREATE SCHEMA` and `DROP SCHEMA` statements that go with it.
+//!
+//! Dropping a Quokka table removes its entry from the system catalog and deregisters it on the
+//! writer, in the same step, and logs the drop. The table is retired, so writes still queued for
+//! it fail (see [`Retired`](crate::catalog::Retired)), and its rows and indexes are freed once
+//! the last query that planned a scan of it lets go of it.
+//!
+//! Views are planned against the tables they read, so a table can't be dropped while a view reads
+//! it, directly or through other views, unless the statement says `CASCADE`, which drops those
+//! views as well. DataFusion plans `CREATE MATERIALIZED VIEW` as a plain view, so the same goes for
+//! those. Views themselves aren't logged and don't survive a restart.
+//!
+//! Schemas created with `CREATE SCHEMA` are recorded in the system catalog and logged, so the
+//! tables in them can be recreated on replay. `DROP SCHEMA` refuses to drop a schema that isn't
+//! empty unless it says `CASCADE`, in which case its tables are dropped first, like `DROP TABLE`
+//! would.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use datafusion::common::tree_node::{TreeNode, VisitRecursion};
+use datafusion::datasource::{TableType, ViewTable};
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::{CreateCatalogSchema, DropCatalogSchema, LogicalPlan};
+use datafusion_common::{
+    exec_err, DataFusionError, OwnedTableReference, ResolvedTableReference, SchemaReference,
+};
+
+use crate::system_catalog::{system_catalog, SYSTEM_SCHEMA};
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied};
+
+/// Drop the tables called `names` on the session's writer
+pub async fn drop_tables(
+    ctx: &SessionContext,
+    names: &[OwnedTableReference],
+    if_exists: bool,
+    cascade: bool,
+) -> Result<()> {
+    let state = ctx.state();
+    let options = &state.config_options().catalog;
+    let names: Vec<_> = names
+        .iter()
+        .map(|name| {
+            owned(
+                name.clone()
+                    .resolve(&options.default_catalog, &options.default_schema),
+            )
+        })
+        .collect();
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    let session = ctx.clone();
+    writer
+        .submit(move |_| async move {
+            // Checked on the writer, so no other DDL changes the tables meanwhile
+            let mut dropped = vec![];
+            for name in names {
+                match session.table_provider(name.clone()).await {
+                    Ok(table) if table.as_any().is::<ViewTable>() => {
+                        return exec_err!("{name} is a view, use DROP VIEW to drop it");
+                    }
+                    Ok(table) if table.table_type() != TableType::Base => {
+                        return exec_err!("Can't drop {name}");
+                    }
+                    Ok(_) => dropped.push(name),
+                    Err(_) if if_exists => {}
+                    Err(_) => return exec_err!("Table '{name}' doesn't exist."),
+                }
+            }
+            let views = dependent_views(&session, &dropped).await?;
+            if !cascade && !views.is_empty() {
+                return exec_err!(
+                    "Cannot drop {} because views depend on it: {}",
+                    itertools::join(dropped.iter(), ", "),
+                    itertools::join(views.iter(), ", ")
+                );
+            }
+
+            let mut records = vec![];
+            for view in views {
+                session.deregister_table(view)?;
+            }
+            for name in dropped {
+                match system_catalog.find(&name) {
+                    Some(entry) => {
+                        system_catalog.drop_table(&session, entry.id)?;
+                        if log {
+                            records.push(LogRecord::drop_table(entry.id));
+                        }
+                    }
+                    // Registered through the API rather than created with SQL, so never logged
+                    None => {
+                        session.deregister_table(name)?;
+                    }
+                }
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Create the schema of `CREATE SCHEMA` on the session's writer
+pub async fn create_schema(ctx: &SessionContext, cmd: CreateCatalogSchema) -> Result<()> {
+    let CreateCatalogSchema {
+        schema_name,
+        if_not_exists,
+        ..
+    } = cmd;
+    // sqlparser doesn't take a catalog for CREATE SCHEMA, so DataFusion leaves it in the name
+    let state = ctx.state();
+    let (catalog, schema) = match schema_name.split('.').collect::<Vec<_>>().as_slice() {
+        [schema] => (
+            state.config_options().catalog.default_catalog.clone(),
+            schema.to_string(),
+        ),
+        [catalog, schema] => (catalog.to_string(), schema.to_string()),
+        _ => return exec_err!("Unable to parse catalog from {schema_name}"),
+    };
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    let session = ctx.clone();
+    writer
+        .submit(move |_| async move {
+            let exists = session
+                .catalog(&catalog)
+                .is_some_and(|provider| provider.schema(&schema).is_some());
+            if exists && if_not_exists {
+                return Ok(Applied::new(0, vec![]));
+            }
+            system_catalog.create_schema(&session, &catalog, &schema)?;
+            let records = if log {
+                vec![LogRecord::create_schema(&catalog, &schema)]
+            } else {
+                vec![]
+            };
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Drop the schema of `DROP SCHEMA` on the session's writer, and with `CASCADE` the tables in it
+/// and the views that read them
+pub async fn drop_schema(ctx: &SessionContext, cmd: DropCatalogSchema) -> Result<()> {
+    let DropCatalogSchema {
+        name,
+        if_exists,
+        cascade,
+        ..
+    } = cmd;
+    let state = ctx.state();
+    let catalog = match &name {
+        SchemaReference::Full { catalog, .. } => catalog.to_string(),
+        SchemaReference::Bare { .. } => state.config_options().catalog.default_catalog.clone(),
+    };
+    let schema = name.schema_name().to_string();
+    if schema == SYSTEM_SCHEMA {
+        return exec_err!("Can't drop {SYSTEM_SCHEMA}");
+    }
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    let session = ctx.clone();
+    writer
+        .submit(move |_| async move {
+            let Some(provider) = session
+                .catalog(&catalog)
+                .and_then(|provider| provider.schema(&schema))
+            else {
+                if if_exists {
+                    return Ok(Applied::new(0, vec![]));
+                }
+                return exec_err!("Schema '{name}' doesn't exist.");
+            };
+            let mut table_names = provider.table_names();
+            table_names.sort();
+            if !cascade && !table_names.is_empty() {
+                return exec_err!(
+                    "Cannot drop schema {schema} because other tables depend on it: {}",
+                    itertools::join(table_names.iter(), ", ")
+                );
+            }
+            let tables: Vec<_> = table_names
+                .into_iter()
+                .map(|table| ResolvedTableReference {
+                    catalog: Cow::Owned(catalog.clone()),
+                    schema: Cow::Owned(schema.clone()),
+                    table: Cow::Owned(table),
+                })
+                .collect();
+
+            let mut records = vec![];
+            // Views in the schema go with it, but those elsewhere have to be dropped one by one
+            for view in dependent_views(&session, &tables).await? {
+                if view.catalog != catalog || view.schema != schema {
+                    session.deregister_table(view)?;
+                }
+            }
+            for name in &tables {
+                if let Some(entry) = system_catalog.find(name) {
+                    system_catalog.drop_table(&session, entry.id)?;
+                    if log {
+                        records.push(LogRecord::drop_table(entry.id));
+                    }
+                }
+            }
+            system_catalog.drop_schema(&session, &catalog, &schema)?;
+            if log {
+                records.push(LogRecord::drop_schema(&catalog, &schema));
+            }
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    Ok(())
+}
+
+/// The views in `ctx`'s catalogs that read any of `tables`, directly or through other views, by
+/// name
+pub async fn dependent_views(
+    ctx: &SessionContext,
+    tables: &[ResolvedTableReference<'static>],
+) -> Result<Vec<ResolvedTableReference<'static>>> {
+    let state = ctx.state();
+    let options = &state.config_options().catalog;
+    let mut views = vec![];
+    for catalog_name in ctx.catalog_names() {
+        let Some(catalog) = ctx.catalog(&catalog_name) else {
+            continue;
+        };
+        for schema_name in catalog.schema_names() {
+            let Some(schema) = catalog.schema(&schema_name) else {
+                continue;
+            };
+            for table_name in schema.table_names() {
+                let Some(table) = schema.table(&table_name).await else {
+                    continue;
+                };
+                let Some(view) = table.as_any().downcast_ref::<ViewTable>() else {
+                    continue;
+                };
+                // A view's plan names the tables it reads as they were written in its query
+                let mut reads = HashSet::new();
+                view.logical_plan().apply(&mut |plan| {
+                    if let LogicalPlan::TableScan(scan) = plan {
+                        let read = scan
+                            .table_name
+                            .clone()
+                            .resolve(&options.default_catalog, &options.default_schema);
+                        reads.insert(read.to_string());
+                    }
+                    Ok(VisitRecursion::Continue)
+                })?;
+                let name = ResolvedTableReference {
+                    catalog: Cow::Owned(catalog_name.clone()),
+                    schema: Cow::Owned(schema_name.clone()),
+                    table: Cow::Owned(table_name),
+                };
+                views.push((name, reads));
+            }
+        }
+    }
+
+    let mut dropped: HashSet<String> = tables.iter().map(|table| table.to_string()).collect();
+    let mut dependents = vec![];
+    loop {
+        let found = dependents.len();
+        views.retain(|(name, reads)| {
+            if reads.is_disjoint(&dropped) {
+                return true;
+            }
+            dropped.insert(name.to_string());
+            dependents.push(name.clone());
+            false
+        });
+        if dependents.len() == found {
+            break;
+        }
+    }
+    dependents.sort_by_key(|view| view.to_string());
+    Ok(dependents)
+}
+
+fn owned(name: ResolvedTableReference) -> ResolvedTableReference<'static> {
+    ResolvedTableReference {
+        catalog: Cow::Owned(name.catalog.into_owned()),
+        schema: Cow::Owned(name.schema.into_owned()),
+        table: Cow::Owned(name.table.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow::util::pretty::pretty_format_batches;
+
+    use super::*;
+    use crate::session::new_context;
+    use crate::test_util::{query, run};
+
+    async fn error(ctx: &SessionContext, sql: &str) -> String {
+        run(ctx, sql)
+            .await
+            .expect_err("statement should fail")
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn dropped_tables_are_freed_once_queries_finish() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+            "INSERT INTO products VALUES (1, 'apple'), (2, 'pear')",
+            "CREATE INDEX products_name ON products (name)",
+            "CREATE TABLE events (id INT, kind VARCHAR) WITH (storage = 'block')",
+            "INSERT INTO events VALUES (1, 'click')",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let products = Arc::downgrade(&ctx.table_provider("products").await?);
+        let events = Arc::downgrade(&ctx.table_provider("events").await?);
+        // Planned before the drop, so it holds the table until it's done with it
+        let running = ctx
+            .state()
+            .create_logical_plan("SELECT name FROM products WHERE name = 'pear'")
+            .await?;
+
+        run(&ctx, "DROP TABLE products, events").await?;
+        assert_eq!(
+            "+------------+\n\
+             | table_name |\n\
+             +------------+\n\
+             +------------+",
+            query(&ctx, "SELECT table_name FROM qs_catalog.qs_tables").await?
+        );
+        assert!(run(&ctx, "SELECT * FROM products").await.is_err());
+        assert!(events.upgrade().is_none());
+        assert!(products.upgrade().is_some());
+        assert_eq!(
+            "+------+\n\
+             | name |\n\
+             +------+\n\
+             | pear |\n\
+             +------+",
+            pretty_format_batches(&ctx.execute_logical_plan(running).await?.collect().await?)?
+                .to_string()
+        );
+        assert!(products.upgrade().is_none());
+
+        assert_eq!(
+            "Execution error: Table 'datafusion.public.products' doesn't exist.",
+            error(&ctx, "DROP TABLE products").await
+        );
+        run(&ctx, "DROP TABLE IF EXISTS products").await?;
+        // The name can be used again
+        run(&ctx, "CREATE TABLE products (id INT PRIMARY KEY)").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn views_keep_their_tables_unless_cascading() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+            "CREATE TABLE orders (id INT PRIMARY KEY, product INT)",
+            "CREATE VIEW names AS SELECT name FROM products",
+            "CREATE VIEW pears AS SELECT * FROM names WHERE name = 'pear'",
+            "CREATE MATERIALIZED VIEW ordered AS \
+             SELECT * FROM orders WHERE product IN (SELECT id FROM products)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        assert_eq!(
+            "Execution error: Cannot drop datafusion.public.products because views depend on it: \
+             datafusion.public.names, datafusion.public.ordered, datafusion.public.pears",
+            error(&ctx, "DROP TABLE products RESTRICT").await
+        );
+        assert_eq!(
+            "Execution error: datafusion.public.names is a view, use DROP VIEW to drop it",
+            error(&ctx, "DROP TABLE names").await
+        );
+        run(&ctx, "SELECT * FROM pears").await?;
+
+        run(&ctx, "DROP TABLE products CASCADE").await?;
+        for table in ["products", "names", "pears", "ordered"] {
+            assert!(!ctx.table_exist(table)?);
+        }
+        run(&ctx, "DROP TABLE orders").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_schema_cascades_to_its_tables() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE SCHEMA shop",
+            "CREATE SCHEMA IF NOT EXISTS shop",
+            "CREATE TABLE shop.products (id INT PRIMARY KEY, name VARCHAR)",
+            "CREATE TABLE shop.events (id INT) WITH (storage = 'block')",
+            "CREATE TABLE customers (id INT PRIMARY KEY)",
+            "CREATE VIEW product_names AS SELECT name FROM shop.products",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        assert_eq!(
+            "Execution error: Schema 'shop' already exists",
+            error(&ctx, "CREATE SCHEMA shop").await
+        );
+        assert_eq!(
+            "Execution error: Cannot drop schema shop because other tables depend on it: \
+             events, products",
+            error(&ctx, "DROP SCHEMA shop").await
+        );
+        assert_eq!(
+            "Execution error: Can't drop qs_catalog",
+            error(&ctx, "DROP SCHEMA qs_catalog CASCADE").await
+        );
+
+        run(&ctx, "DROP SCHEMA shop CASCADE").await?;
+        assert!(!ctx.table_exist("product_names")?);
+        assert_eq!(
+            "+------------+\n\
+             | table_name |\n\
+             +------------+\n\
+             | customers  |\n\
+             +------------+",
+            query(&ctx, "SELECT table_name FROM qs_catalog.qs_tables").await?
+        );
+        assert!(system_catalog(&ctx)?.schemas().is_empty());
+        run(&ctx, "DROP SCHEMA IF EXISTS shop").await?;
+        run(&ctx, "CREATE SCHEMA shop").await?;
+        run(&ctx, "DROP SCHEMA shop").await?;
+        Ok(())
+    }
+}
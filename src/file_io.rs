@@ -0,0 +1,597 @@
+//! How the write-ahead log and checkpoints write their files.
+//!
+//! Both only ever append to new files and sync them, so an [`AppendFile`] is all they need. The
+//! [`IoBackend`] chosen at startup creates them: [`IoBackend::Std`] writes with `std::fs`
+//! everywhere, while on Linux [`IoBackend::IoUring`] copies appended bytes into buffers
+//! registered with an io_uring and submits their writes in batches, optionally opening files
+//! with `O_DIRECT` to bypass the page cache.
+
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use datafusion::error::Result;
+use datafusion_common::{plan_err, DataFusionError};
+#[cfg(target_os = "linux")]
+use log::warn;
+
+/// A new file that is only appended to
+pub trait AppendFile: Send + Debug {
+    /// Append `bytes`, which may only be handed to the OS by the next [`Self::flush`] or
+    /// [`Self::sync`]
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Hand the appended bytes to the OS, so they survive the process crashing
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Write the appended bytes to disk, so they survive the machine crashing
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Flush the appended bytes and close the file. Dropping a file finishes it, ignoring errors.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Adapts an [`AppendFile`] to [`Write`], for writers like Arrow's
+pub struct AppendWriter(pub Box<dyn AppendFile>);
+
+impl Write for AppendWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The implementation of [`AppendFile`] to use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IoBackend {
+    /// `std::fs`, which works everywhere
+    #[default]
+    Std,
+    /// io_uring with registered buffers, on Linux
+    IoUring {
+        /// Open files with `O_DIRECT`, bypassing the page cache
+        direct: bool,
+    },
+}
+
+impl FromStr for IoBackend {
+    type Err = DataFusionError;
+
+    /// Parse `std`, `io_uring` or `io_uring_direct`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "std" => Ok(IoBackend::Std),
+            "io_uring" => Ok(IoBackend::IoUring { direct: false }),
+            "io_uring_direct" => Ok(IoBackend::IoUring { direct: true }),
+            s => plan_err!("Unknown I/O backend {s}, expected std, io_uring or io_uring_direct"),
+        }
+    }
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoBackend::Std => write!(f, "std"),
+            IoBackend::IoUring { direct: false } => write!(f, "io_uring"),
+            IoBackend::IoUring { direct: true } => write!(f, "io_uring_direct"),
+        }
+    }
+}
+
+impl IoBackend {
+    /// This backend if the platform and kernel support it, or [`IoBackend::Std`] if not
+    pub fn or_fallback(self) -> Self {
+        match self {
+            IoBackend::Std => self,
+            #[cfg(target_os = "linux")]
+            IoBackend::IoUring { .. } => match io_uring::IoUring::new(RING_ENTRIES) {
+                Ok(_) => self,
+                Err(e) => {
+                    warn!("io_uring is unavailable, falling back to std::fs: {e}");
+                    IoBackend::Std
+                }
+            },
+            #[cfg(not(target_os = "linux"))]
+            IoBackend::IoUring { .. } => IoBackend::Std,
+        }
+    }
+
+    /// Create the file at `path`, which must not exist yet
+    pub fn create(self, path: &Path) -> io::Result<Box<dyn AppendFile>> {
+        match self {
+            IoBackend::Std => Ok(Box::new(StdFile(
+                OpenOptions::new()
+                    .append(true)
+                    .create_new(true)
+                    .open(path)?,
+            ))),
+            #[cfg(target_os = "linux")]
+            IoBackend::IoUring { direct } => Ok(Box::new(uring::UringFile::create(path, direct)?)),
+            #[cfg(not(target_os = "linux"))]
+            IoBackend::IoUring { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring is only available on Linux",
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StdFile(File);
+
+impl AppendFile for StdFile {
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+const RING_ENTRIES: u32 = 32;
+
+#[cfg(target_os = "linux")]
+mod uring {
+    use std::alloc::{self, Layout};
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+
+    use super::{AppendFile, RING_ENTRIES};
+
+    /// Size of each registered buffer
+    const BUFFER_SIZE: usize = 256 * 1024;
+
+    /// Number of registered buffers, so this many writes can be in flight at once
+    const BUFFER_COUNT: usize = 4;
+
+    /// Alignment of `O_DIRECT` writes' buffers, offsets and lengths
+    const ALIGN: usize = 4096;
+
+    /// `user_data` of the fsync, which isn't a write of a buffer
+    const SYNC: u64 = u64::MAX;
+
+    /// Memory aligned for `O_DIRECT`
+    struct AlignedBuffer(*mut u8);
+
+    // The buffer is owned, like a `Box`
+    unsafe impl Send for AlignedBuffer {}
+
+    impl AlignedBuffer {
+        fn new() -> Self {
+            // SAFETY: the layout has a non-zero size
+            let ptr = unsafe { alloc::alloc_zeroed(Self::layout()) };
+            if ptr.is_null() {
+                alloc::handle_alloc_error(Self::layout());
+            }
+            Self(ptr)
+        }
+
+        fn layout() -> Layout {
+            Layout::from_size_align(BUFFER_SIZE, ALIGN).expect("buffer layout is valid")
+        }
+
+        fn as_mut_slice(&mut self) -> &mut [u8] {
+            // SAFETY: the allocation is BUFFER_SIZE bytes and initialized
+            unsafe { std::slice::from_raw_parts_mut(self.0, BUFFER_SIZE) }
+        }
+    }
+
+    impl Drop for AlignedBuffer {
+        fn drop(&mut self) {
+            // SAFETY: allocated with the same layout
+            unsafe { alloc::dealloc(self.0, Self::layout()) }
+        }
+    }
+
+    /// A write of the bytes `start..end` of a registered buffer to the file at `offset`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct BufferWrite {
+        buffer: usize,
+        start: usize,
+        end: usize,
+        offset: u64,
+    }
+
+    /// Appends through an io_uring. Bytes are copied into the current registered buffer, and a
+    /// full buffer's write is queued while the next buffer fills. Queued writes are submitted
+    /// together when the file is flushed or synced, or when the buffers run out. A write that
+    /// completes having written only some of its bytes is submitted again for the rest.
+    ///
+    /// With `O_DIRECT`, writes must cover whole aligned blocks, so flushing a partly filled buffer
+    /// writes its last block padded with zeros, and the block is written again once more bytes
+    /// are appended. Finishing the file cuts the padding off.
+    pub(super) struct UringFile {
+        ring: IoUring,
+        file: File,
+        direct: bool,
+        buffers: Vec<AlignedBuffer>,
+        /// Writes in flight from each buffer, which mustn't be changed until they complete
+        in_flight: Vec<usize>,
+        /// The writes in flight by `user_data`, and the `user_data` of the next one
+        writes: HashMap<u64, BufferWrite>,
+        next_write: u64,
+        /// Whether an fsync is in flight
+        syncing: bool,
+        /// Entries pushed to the submission queue but not submitted
+        queued: usize,
+        current: usize,
+        /// Bytes appended to the current buffer, and how many of them have been written
+        filled: usize,
+        written: usize,
+        /// File offset of the start of the current buffer
+        offset: u64,
+        /// Error of a write that failed, returned by every call from then on
+        error: Option<io::Error>,
+        finished: bool,
+    }
+
+    impl std::fmt::Debug for UringFile {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("UringFile")
+                .field("direct", &self.direct)
+                .field("len", &self.len())
+                .finish()
+        }
+    }
+
+    impl UringFile {
+        pub(super) fn create(path: &Path, direct: bool) -> io::Result<Self> {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            if direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            let file = options.open(path)?;
+            let ring = IoUring::new(RING_ENTRIES)?;
+            let mut buffers: Vec<AlignedBuffer> =
+                (0..BUFFER_COUNT).map(|_| AlignedBuffer::new()).collect();
+            let iovecs: Vec<libc::iovec> = buffers
+                .iter_mut()
+                .map(|buffer| libc::iovec {
+                    iov_base: buffer.0.cast(),
+                    iov_len: BUFFER_SIZE,
+                })
+                .collect();
+            // SAFETY: the buffers live as long as the ring, which is dropped first
+            unsafe { ring.submitter().register_buffers(&iovecs)? };
+            Ok(Self {
+                ring,
+                file,
+                direct,
+                buffers,
+                in_flight: vec![0; BUFFER_COUNT],
+                writes: HashMap::new(),
+                next_write: 0,
+                syncing: false,
+                queued: 0,
+                current: 0,
+                filled: 0,
+                written: 0,
+                offset: 0,
+                error: None,
+                finished: false,
+            })
+        }
+
+        fn len(&self) -> u64 {
+            self.offset + self.filled as u64
+        }
+
+        fn check(&mut self) -> io::Result<()> {
+            match self.error.take() {
+                Some(e) => {
+                    let copy = io::Error::new(e.kind(), e.to_string());
+                    self.error = Some(e);
+                    Err(copy)
+                }
+                None => Ok(()),
+            }
+        }
+
+        /// Queue a write of the bytes of the current buffer that haven't been written
+        fn queue_write(&mut self) -> io::Result<()> {
+            if self.written == self.filled {
+                return Ok(());
+            }
+            let (start, end) = if self.direct {
+                (
+                    self.written / ALIGN * ALIGN,
+                    self.filled.div_ceil(ALIGN) * ALIGN,
+                )
+            } else {
+                (self.written, self.filled)
+            };
+            // The padding of an O_DIRECT write must not hold stale bytes
+            self.buffers[self.current].as_mut_slice()[self.filled..end].fill(0);
+            self.push_write(BufferWrite {
+                buffer: self.current,
+                start,
+                end,
+                offset: self.offset + start as u64,
+            })?;
+            self.written = self.filled;
+            Ok(())
+        }
+
+        /// Queue `write`, which is in flight from its buffer until it completes
+        fn push_write(&mut self, write: BufferWrite) -> io::Result<()> {
+            let user_data = self.next_write;
+            self.next_write += 1;
+            let entry = opcode::WriteFixed::new(
+                types::Fd(self.file.as_raw_fd()),
+                // SAFETY: start is within the buffer
+                unsafe { self.buffers[write.buffer].0.add(write.start) },
+                (write.end - write.start) as u32,
+                write.buffer as u16,
+            )
+            .offset(write.offset)
+            .build()
+            .user_data(user_data);
+            self.push(entry)?;
+            self.writes.insert(user_data, write);
+            self.in_flight[write.buffer] += 1;
+            Ok(())
+        }
+
+        fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+            // SAFETY: the buffers written from stay untouched until their writes complete
+            while unsafe { self.ring.submission().push(&entry) }.is_err() {
+                self.ring.submit()?;
+                self.queued = 0;
+            }
+            self.queued += 1;
+            Ok(())
+        }
+
+        /// Submit the queued entries and wait until `done` holds
+        fn wait_until(&mut self, done: impl Fn(&Self) -> bool) -> io::Result<()> {
+            while !done(self) {
+                self.ring.submit_and_wait(1)?;
+                self.queued = 0;
+                let completions: Vec<(u64, i32)> = self
+                    .ring
+                    .completion()
+                    .map(|entry| (entry.user_data(), entry.result()))
+                    .collect();
+                for (user_data, result) in completions {
+                    if user_data == SYNC {
+                        self.syncing = false;
+                        if result < 0 {
+                            self.fail(io::Error::from_raw_os_error(-result));
+                        }
+                        continue;
+                    }
+                    let write = self
+                        .writes
+                        .remove(&user_data)
+                        .expect("only submitted writes complete");
+                    self.in_flight[write.buffer] -= 1;
+                    self.complete(write, result);
+                }
+            }
+            self.check()
+        }
+
+        /// Handle `write` completing with `result`, the number of bytes written or an error.
+        /// The rest of a short write is submitted again.
+        fn complete(&mut self, write: BufferWrite, result: i32) {
+            let len = write.end - write.start;
+            match result {
+                ..=-1 => self.fail(io::Error::from_raw_os_error(-result)),
+                0 => self.fail(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("wrote 0 of {len} bytes at offset {}", write.offset),
+                )),
+                written if (written as usize) < len => {
+                    let rest = BufferWrite {
+                        start: write.start + written as usize,
+                        offset: write.offset + written as u64,
+                        ..write
+                    };
+                    if let Err(e) = self.push_write(rest) {
+                        self.fail(e);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        /// Keep the first error, which every call returns from then on
+        fn fail(&mut self, e: io::Error) {
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+        }
+
+        fn idle(&self) -> bool {
+            self.in_flight.iter().all(|writes| *writes == 0)
+        }
+    }
+
+    impl AppendFile for UringFile {
+        fn append(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+            self.check()?;
+            while !bytes.is_empty() {
+                // A flushed O_DIRECT write may still be reading the bytes after `filled`
+                let current = self.current;
+                self.wait_until(|file| file.in_flight[current] == 0)?;
+                let len = bytes.len().min(BUFFER_SIZE - self.filled);
+                self.buffers[current].as_mut_slice()[self.filled..self.filled + len]
+                    .copy_from_slice(&bytes[..len]);
+                self.filled += len;
+                bytes = &bytes[len..];
+                if self.filled == BUFFER_SIZE {
+                    self.queue_write()?;
+                    self.current = (self.current + 1) % BUFFER_COUNT;
+                    self.offset += BUFFER_SIZE as u64;
+                    self.filled = 0;
+                    self.written = 0;
+                }
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.check()?;
+            self.queue_write()?;
+            // The bytes are only the OS's once their writes complete
+            self.wait_until(Self::idle)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.flush()?;
+            let entry = opcode::Fsync::new(types::Fd(self.file.as_raw_fd()))
+                .flags(types::FsyncFlags::DATASYNC)
+                .build()
+                .user_data(SYNC);
+            self.push(entry)?;
+            self.syncing = true;
+            self.wait_until(|file| !file.syncing)
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            if self.finished {
+                return Ok(());
+            }
+            self.finished = true;
+            self.queue_write()?;
+            self.wait_until(Self::idle)?;
+            if self.direct {
+                self.file.set_len(self.len())?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for UringFile {
+        fn drop(&mut self) {
+            // The kernel may still be reading the buffers, which are freed after the ring
+            let _ = self.finish();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::file_io::IoBackend;
+
+        #[test]
+        fn short_writes_are_finished() -> io::Result<()> {
+            let backend = IoBackend::IoUring { direct: false };
+            if backend.or_fallback() != backend {
+                return Ok(());
+            }
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("short");
+            let mut file = UringFile::create(&path, false)?;
+            file.append(b"hello world")?;
+            file.flush()?;
+
+            // As if the kernel had only written "hello"
+            let write = BufferWrite {
+                buffer: 0,
+                start: 0,
+                end: 11,
+                offset: 0,
+            };
+            file.complete(write, 5);
+            let rest = BufferWrite {
+                start: 5,
+                offset: 5,
+                ..write
+            };
+            assert_eq!(vec![&rest], file.writes.values().collect::<Vec<_>>());
+            file.sync()?;
+            assert!(file.idle());
+            assert_eq!(b"hello world".to_vec(), std::fs::read(&path)?);
+
+            // A write that makes no progress fails the file
+            file.complete(write, 0);
+            assert_eq!(io::ErrorKind::WriteZero, file.sync().unwrap_err().kind());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends() -> Vec<IoBackend> {
+        let mut backends = vec![IoBackend::Std];
+        for direct in [false, true] {
+            let backend = IoBackend::IoUring { direct };
+            if backend.or_fallback() == backend {
+                backends.push(backend);
+            }
+        }
+        backends
+    }
+
+    #[test]
+    fn parse_backends() -> Result<()> {
+        for backend in [
+            IoBackend::Std,
+            IoBackend::IoUring { direct: false },
+            IoBackend::IoUring { direct: true },
+        ] {
+            assert_eq!(backend, backend.to_string().parse()?);
+        }
+        assert!("mmap".parse::<IoBackend>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn appends_survive_flushes_syncs_and_full_buffers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut expected = vec![];
+        let chunks: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| (0..(i * 37 % 5000)).map(|b| (b + i) as u8).collect())
+            .collect();
+        for backend in backends() {
+            let path = dir.path().join(backend.to_string());
+            let mut file = match backend.create(&path) {
+                Ok(file) => file,
+                // Some filesystems, like tmpfs, don't support O_DIRECT
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => continue,
+                Err(e) => return Err(e.into()),
+            };
+            expected.clear();
+            for (i, chunk) in chunks.iter().enumerate() {
+                file.append(chunk)?;
+                expected.extend_from_slice(chunk);
+                match i % 3 {
+                    0 => file.flush()?,
+                    1 => file.sync()?,
+                    _ => {}
+                }
+            }
+            file.finish()?;
+            drop(file);
+            assert_eq!(expected, std::fs::read(&path)?, "{backend}");
+        }
+        Ok(())
+    }
+}
@@ -15,6 +15,8 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use arrow::array::AsArray;
+use arrow::datatypes::UInt64Type;
 use arrow::ipc::writer::IpcWriteOptions;
 use arrow::record_batch::RecordBatch;
 use arrow_flight::encode::FlightDataEncoderBuilder;
@@ -38,15 +40,16 @@ use arrow_flight::{
     IpcMessage, SchemaAsIpc, Ticket,
 };
 use arrow_schema::Schema;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use dashmap::DashMap;
 use datafusion::catalog::{CatalogProvider, CatalogProviderList};
-use datafusion::execution::context::SessionState;
+use datafusion::error::DataFusionError;
 use datafusion::logical_expr::LogicalPlan;
 use datafusion::prelude::{SessionConfig, SessionContext};
 use datafusion_execution::runtime_env::{RuntimeConfig, RuntimeEnv};
 use datafusion_physical_plan::collect;
 use futures::{Stream, StreamExt, TryStreamExt};
-use log::info;
+use log::{error, info};
 use mimalloc::MiMalloc;
 use prost::Message;
 use std::pin::Pin;
@@ -56,6 +59,12 @@ use tonic::{Request, Response, Status, Streaming};
 use uuid::Uuid;
 
 use crate::catalog::{MemoryCatalogProvider, MemoryCatalogProviderList, MemorySchemaProvider};
+use crate::checkpoint::checkpoint;
+use crate::session::QuokkaOptions;
+use crate::system_catalog::{system_catalog, SystemSchema, SYSTEM_SCHEMA};
+use crate::wal::{Wal, WalOptions};
+use crate::writer::{Writer, MAX_WRITE_GROUP, WRITE_QUEUE_CAPACITY};
+use crate::{auth, session, sql};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -70,9 +79,15 @@ pub struct FlightSqlServiceImpl {
     catalog_list: Arc<MemoryCatalogProviderList>,
     contexts: Arc<DashMap<String, Arc<SessionContext>>>,
     statements: Arc<DashMap<String, LogicalPlan>>,
-    results: Arc<DashMap<String, Vec<RecordBatch>>>,
+    /// Results of prepared statements by session token and statement handle, so a session can
+    /// only fetch the results it executed
+    results: Arc<DashMap<(String, String), Vec<RecordBatch>>>,
+    /// Applies the writes of every session, logging them if the service has a write-ahead log
+    writer: Option<Writer>,
 }
 
+// tonic's `Status` is large, but it's what every `FlightSqlService` method returns
+#[allow(clippy::result_large_err)]
 impl FlightSqlServiceImpl {
     pub fn new() -> FlightSqlServiceImpl {
         let default_catalog = Arc::new(MemoryCatalogProvider::new());
@@ -80,6 +95,9 @@ impl FlightSqlServiceImpl {
         default_catalog
             .register_schema("public", Arc::new(MemorySchemaProvider::new()))
             .expect("memory catalog provider can register schema");
+        default_catalog
+            .register_schema(SYSTEM_SCHEMA, Arc::new(SystemSchema::new()))
+            .expect("memory catalog provider can register schema");
 
         let catalog_list = Arc::new(MemoryCatalogProviderList::new());
         catalog_list.register_catalog("datafusion".to_string(), default_catalog);
@@ -89,11 +107,87 @@ impl FlightSqlServiceImpl {
             contexts: Default::default(),
             statements: Default::default(),
             results: Default::default(),
+            writer: None,
+        }
+    }
+
+    /// Create a service whose writes are logged to the write-ahead log in `options.dir`, after
+    /// loading the latest checkpoint there and replaying the log after it. Checkpoints are also
+    /// taken every `options.checkpoint_interval`, if set.
+    pub async fn with_wal(options: WalOptions) -> datafusion::error::Result<Self> {
+        let mut service = Self::new();
+        let ctx = service
+            .new_session()
+            .map_err(|e| DataFusionError::Execution(e.message().to_string()))?;
+        let checkpoint_interval = options.checkpoint_interval;
+        let (wal, _) = Wal::open(options, &ctx).await?;
+        service.writer = Some(Writer::start(
+            WRITE_QUEUE_CAPACITY,
+            MAX_WRITE_GROUP,
+            Some(wal),
+        ));
+        if let Some(interval) = checkpoint_interval {
+            let ctx = service
+                .new_session()
+                .map_err(|e| DataFusionError::Execution(e.message().to_string()))?;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                // The first tick completes immediately
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = checkpoint(&ctx).await {
+                        error!("Failed to take a checkpoint: {e}");
+                    }
+                }
+            });
         }
+        Ok(service)
     }
-    async fn create_ctx(&self) -> Result<String, Status> {
+
+    /// Open a session for `options.user`, who has to log in with `password` once any role can
+    /// log in. The session then acts for the user's tenant, which only superusers can replace
+    /// with the tenant of `options`.
+    async fn create_ctx(
+        &self,
+        mut options: QuokkaOptions,
+        password: &str,
+    ) -> Result<String, Status> {
+        let user = options.user.clone();
+        let system_catalog = system_catalog(&self.new_session()?)
+            .map_err(|e| status!("Unable to open the system catalog", e))?;
+        let roles = system_catalog.roles();
+        if roles.has_logins() {
+            roles
+                .authenticate(&user, password)
+                .map_err(|_| Status::unauthenticated(format!("Invalid credentials for {user}")))?;
+            if options.tenant_id.is_empty() {
+                options.tenant_id = roles
+                    .get(&user)
+                    .and_then(|role| role.tenant_id.clone())
+                    .unwrap_or_default();
+            } else if !roles.is_superuser(&user) {
+                // Like `SET quokka.tenant_id`, or any user could read every tenant's rows
+                return Err(Status::permission_denied(format!(
+                    "Only superusers can choose their tenant with the {TENANT_ID_HEADER} header"
+                )));
+            }
+        }
+        drop(roles);
+        let ctx = Arc::new(self.new_session_with(options)?);
         let uuid = Uuid::new_v4().hyphenated().to_string();
-        let session_config = SessionConfig::from_env()
+        self.contexts.insert(uuid.clone(), ctx);
+        Ok(uuid)
+    }
+
+    fn new_session(&self) -> Result<SessionContext, Status> {
+        self.new_session_with(QuokkaOptions::default())
+    }
+
+    /// Create a session starting with the Quokka settings `options`
+    fn new_session_with(&self, options: QuokkaOptions) -> Result<SessionContext, Status> {
+        let mut session_config = SessionConfig::from_env()
             .map(|c| {
                 c.set_bool(
                     "datafusion.catalog.create_default_catalog_and_schema",
@@ -102,22 +196,19 @@ impl FlightSqlServiceImpl {
             })
             .map_err(|e| Status::internal(format!("Error building plan: {e}")))?
             .with_information_schema(true);
+        if let Some(writer) = &self.writer {
+            session_config = session_config.with_extension(Arc::new(writer.clone()));
+        }
+        session_config.options_mut().extensions.insert(options);
         let rt_config = RuntimeConfig::new();
         let rt = RuntimeEnv::new(rt_config).expect("Can create runtime env");
         let catalog_list = Arc::clone(&self.catalog_list);
-        let state = SessionState::new_with_config_rt_and_catalog_list(
-            session_config,
-            Arc::new(rt),
-            catalog_list,
-        );
-        let ctx = Arc::new(SessionContext::new_with_state(state));
-
-        self.contexts.insert(uuid.clone(), ctx);
-        Ok(uuid)
+        let state = session::new_session_state(session_config, Arc::new(rt), catalog_list);
+        Ok(SessionContext::new_with_state(state))
     }
 
-    fn get_ctx<T>(&self, req: &Request<T>) -> Result<Arc<SessionContext>, Status> {
-        // get the token from the authorization header on Request
+    /// The session token of `req`'s `Bearer` authorization header
+    fn session_token<T>(&self, req: &Request<T>) -> Result<String, Status> {
         let auth = req
             .metadata()
             .get("authorization")
@@ -130,8 +221,12 @@ impl FlightSqlServiceImpl {
         if !authorization.starts_with(bearer) {
             Err(Status::internal("Invalid auth header!"))?;
         }
-        let auth = authorization[bearer.len()..].to_string();
+        Ok(authorization[bearer.len()..].to_string())
+    }
 
+    fn get_ctx<T>(&self, req: &Request<T>) -> Result<Arc<SessionContext>, Status> {
+        // get the token from the authorization header on Request
+        let auth = self.session_token(req)?;
         if let Some(context) = self.contexts.get(&auth) {
             Ok(context.clone())
         } else {
@@ -149,8 +244,9 @@ impl FlightSqlServiceImpl {
         }
     }
 
-    fn get_result(&self, handle: &str) -> Result<Vec<RecordBatch>, Status> {
-        if let Some(result) = self.results.get(handle) {
+    /// The results of statement `handle` executed by session `token`
+    fn get_result(&self, token: &str, handle: &str) -> Result<Vec<RecordBatch>, Status> {
+        if let Some(result) = self.results.get(&(token.to_string(), handle.to_string())) {
             Ok(result.clone())
         } else {
             Err(Status::internal(format!(
@@ -164,29 +260,88 @@ impl FlightSqlServiceImpl {
         Ok(())
     }
 
+    /// Drop the results of statement `handle` in every session
     fn remove_result(&self, handle: &str) -> Result<(), Status> {
-        self.results.remove(&handle.to_string());
+        self.results
+            .retain(|(_, result_handle), _| result_handle != handle);
         Ok(())
     }
 }
 
+/// Header of the handshake naming the tenant the session acts for instead of the user's
+pub const TENANT_ID_HEADER: &str = "quokka-tenant-id";
+
+/// The value of header `name` of `request`, if present
+#[allow(clippy::result_large_err)]
+fn header<T>(request: &Request<T>, name: &str) -> Result<Option<String>, Status> {
+    request
+        .metadata()
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .map(str::to_string)
+                .map_err(|e| Status::invalid_argument(format!("Error parsing header {name}: {e}")))
+        })
+        .transpose()
+}
+
+/// The user name and password of `request`'s `Basic` authorization header, if it has one
+#[allow(clippy::result_large_err)]
+fn basic_auth<T>(request: &Request<T>) -> Result<Option<(String, String)>, Status> {
+    let Some(authorization) = header(request, "authorization")? else {
+        return Ok(None);
+    };
+    let Some(credentials) = authorization.strip_prefix("Basic ") else {
+        return Ok(None);
+    };
+    let credentials = BASE64_STANDARD
+        .decode(credentials)
+        .ok()
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .ok_or_else(|| Status::invalid_argument("authorization not parsable"))?;
+    let (user, password) = credentials
+        .split_once(':')
+        .unwrap_or((credentials.as_str(), ""));
+    Ok(Some((user.to_string(), password.to_string())))
+}
+
+/// Check that the session's user may run `plan`
+#[allow(clippy::result_large_err)]
+fn check_privileges(ctx: &SessionContext, plan: &LogicalPlan) -> Result<(), Status> {
+    auth::check_privileges(ctx, plan).map_err(|e| Status::permission_denied(e.to_string()))
+}
+
+impl Default for FlightSqlServiceImpl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[tonic::async_trait]
 impl FlightSqlService for FlightSqlServiceImpl {
     type FlightService = FlightSqlServiceImpl;
 
     async fn do_handshake(
         &self,
-        _request: Request<Streaming<HandshakeRequest>>,
+        request: Request<Streaming<HandshakeRequest>>,
     ) -> Result<
         Response<Pin<Box<dyn Stream<Item = Result<HandshakeResponse, Status>> + Send>>>,
         Status,
     > {
         info!("do_handshake");
-        // no authentication actually takes place here
-        // see Ballista implementation for example of basic auth
-        // in this case, we simply accept the connection and create a new SessionContext
-        // the SessionContext will be re-used within this same connection/session
-        let token = self.create_ctx().await?;
+        // Basic credentials are checked against the catalog's roles once any role can log in.
+        // The SessionContext will be re-used within this same connection/session.
+        // The session remembers the user and tenant it was opened for, which privilege checks
+        // and row-level security policies refer to. The tenant is the user's, unless a superuser
+        // names another.
+        let (user, password) = basic_auth(&request)?.unwrap_or_default();
+        let options = QuokkaOptions {
+            user,
+            tenant_id: header(&request, TENANT_ID_HEADER)?.unwrap_or_default(),
+            ..QuokkaOptions::default()
+        };
+        let token = self.create_ctx(options, &password).await?;
 
         let result = HandshakeResponse {
             protocol_version: 0,
@@ -205,7 +360,7 @@ impl FlightSqlService for FlightSqlServiceImpl {
 
     async fn do_get_fallback(
         &self,
-        _request: Request<Ticket>,
+        request: Request<Ticket>,
         message: Any,
     ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
         if !message.is::<FetchResults>() {
@@ -223,7 +378,10 @@ impl FlightSqlService for FlightSqlServiceImpl {
         let handle = fr.handle;
 
         info!("getting results for {handle}");
-        let result = self.get_result(&handle)?;
+        // Only the session that executed the statement can fetch its results
+        self.get_ctx(&request)?;
+        let token = self.session_token(&request)?;
+        let result = self.get_result(&token, &handle)?;
         // if we get an empty result, create an empty schema
         let (schema, batches) = match result.first() {
             None => (Arc::new(Schema::empty()), vec![]),
@@ -272,10 +430,10 @@ impl FlightSqlService for FlightSqlServiceImpl {
 
         let ctx = self.get_ctx(&request)?;
         let plan = self.get_plan(handle)?;
+        check_privileges(&ctx, &plan)?;
 
         let task_ctx = ctx.task_ctx();
-        let df = ctx
-            .execute_logical_plan(plan)
+        let df = sql::execute_logical_plan(&ctx, plan)
             .await
             .map_err(|e| status!("Unable to execute logical plan", e))?;
 
@@ -297,7 +455,8 @@ impl FlightSqlService for FlightSqlServiceImpl {
             Some(batch) => (*batch.schema()).clone(),
         };
 
-        self.results.insert(handle.to_string(), result);
+        let token = self.session_token(&request)?;
+        self.results.insert((token, handle.to_string()), result);
 
         // if we had multiple endpoints to connect to, we could use this Location
         // but in the case of standalone DataFusion, we don't
@@ -571,13 +730,32 @@ impl FlightSqlService for FlightSqlServiceImpl {
 
     async fn do_put_prepared_statement_update(
         &self,
-        _handle: CommandPreparedStatementUpdate,
-        _request: Request<PeekableFlightDataStream>,
+        cmd: CommandPreparedStatementUpdate,
+        request: Request<PeekableFlightDataStream>,
     ) -> Result<i64, Status> {
         info!("do_put_prepared_statement_update");
-        // statements like "CREATE TABLE.." or "SET datafusion.nnn.." call this function
-        // and we are required to return some row count here
-        Ok(-1)
+        let handle = std::str::from_utf8(&cmd.prepared_statement_handle)
+            .map_err(|e| status!("Unable to parse uuid", e))?;
+
+        let ctx = self.get_ctx(&request)?;
+        let plan = self.get_plan(handle)?;
+        check_privileges(&ctx, &plan)?;
+        let result = sql::execute_logical_plan(&ctx, plan)
+            .await
+            .map_err(|e| status!("Unable to execute logical plan", e))?
+            .collect()
+            .await
+            .map_err(|e| status!("Unable to run execute query", e))?;
+
+        // DML returns the number of rows it changed. Statements like "CREATE TABLE.." or
+        // "SET datafusion.nnn.." don't, but we are required to return some row count here
+        let count = result
+            .first()
+            .and_then(|batch| batch.column_by_name("count"))
+            .and_then(|count| count.as_primitive_opt::<UInt64Type>())
+            .map(|count| count.value(0) as i64)
+            .unwrap_or(-1);
+        Ok(count)
     }
 
     async fn do_put_substrait_plan(
@@ -600,17 +778,16 @@ impl FlightSqlService for FlightSqlServiceImpl {
         info!("do_action_create_prepared_statement: {user_query}");
 
         let ctx = self.get_ctx(&request)?;
-        let task_ctx = ctx.task_ctx();
-        let dialect = &task_ctx.session_config().options().sql_parser.dialect;
-        let statement = ctx
-            .state()
-            .sql_to_statement(user_query, dialect)
-            .map_err(|e| Status::internal(format!("Error parsing sql {e}")))?;
-        let plan = ctx
-            .state()
-            .statement_to_plan(statement)
+        let plan = sql::sql_to_plan(&ctx.state(), user_query)
             .await
-            .map_err(|e| Status::internal(format!("Error creating plan {e}")))?;
+            .map_err(|e| match e {
+                // Mistakes in the statement, such as the syntax of a text query
+                DataFusionError::Plan(_) | DataFusionError::SQL(..) => {
+                    Status::invalid_argument(format!("Error creating plan {e}"))
+                }
+                e => Status::internal(format!("Error creating plan {e}")),
+            })?;
+        check_privileges(&ctx, &plan)?;
 
         // store a copy of the plan,  it will be used for execution
         let plan_uuid = Uuid::new_v4().hyphenated().to_string();
@@ -725,3 +902,141 @@ impl ProstMessageExt for FetchResults {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use arrow_flight::sql::ActionCreatePreparedStatementRequest;
+
+    use super::*;
+    use crate::test_util::{query, run};
+
+    /// A request of the session with `token`
+    fn request<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        let authorization = MetadataValue::try_from(format!("Bearer {token}")).expect("ASCII");
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+        request
+    }
+
+    fn login(user: &str, tenant_id: &str) -> QuokkaOptions {
+        QuokkaOptions {
+            user: user.to_string(),
+            tenant_id: tenant_id.to_string(),
+            ..QuokkaOptions::default()
+        }
+    }
+
+    async fn service_with_users() -> datafusion::error::Result<FlightSqlServiceImpl> {
+        let service = FlightSqlServiceImpl::new();
+        let ctx = service.new_session().expect("session");
+        for sql in [
+            "CREATE USER admin WITH SUPERUSER PASSWORD 'admin'",
+            "CREATE USER alice PASSWORD 'alice'",
+            "CREATE USER bob PASSWORD 'bob'",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        Ok(service)
+    }
+
+    #[tokio::test]
+    async fn only_superusers_choose_their_tenant() -> Result<(), Box<dyn Error>> {
+        let service = service_with_users().await?;
+        let e = service
+            .create_ctx(login("alice", "globex"), "alice")
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::PermissionDenied, e.code());
+        assert!(service
+            .create_ctx(login("alice", ""), "alice")
+            .await
+            .is_ok());
+        assert!(service
+            .create_ctx(login("admin", "globex"), "admin")
+            .await
+            .is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_act_for_their_users_tenant() -> Result<(), Box<dyn Error>> {
+        let service = service_with_users().await?;
+        let ctx = service.new_session()?;
+        for sql in [
+            "CREATE TABLE docs (id INT PRIMARY KEY, tenant_id VARCHAR)",
+            "INSERT INTO docs VALUES (1, 'acme'), (2, 'globex')",
+            "CREATE POLICY tenant_isolation ON docs \
+             USING (tenant_id = current_setting('quokka.tenant_id'))",
+            "GRANT SELECT ON docs TO alice",
+            "ALTER USER alice TENANT 'acme'",
+            "ALTER USER admin TENANT 'acme'",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let ids = "SELECT id FROM docs ORDER BY id";
+        let session = |token: String| {
+            service
+                .contexts
+                .get(&token)
+                .expect("session exists")
+                .clone()
+        };
+        let acme = "+----+\n| id |\n+----+\n| 1  |\n+----+";
+        let alice = service.create_ctx(login("alice", ""), "alice").await?;
+        assert_eq!(acme, query(&session(alice), ids).await?);
+        let admin = service.create_ctx(login("admin", ""), "admin").await?;
+        assert_eq!(acme, query(&session(admin), ids).await?);
+        // Superusers can act for another tenant
+        let admin = service
+            .create_ctx(login("admin", "globex"), "admin")
+            .await?;
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 2  |\n+----+",
+            query(&session(admin), ids).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_only_fetch_their_own_results() -> Result<(), Box<dyn Error>> {
+        let service = service_with_users().await?;
+        let alice = service.create_ctx(login("alice", ""), "alice").await?;
+        let bob = service.create_ctx(login("bob", ""), "bob").await?;
+        let prepared = ActionCreatePreparedStatementRequest {
+            query: "SELECT 1 AS one".to_string(),
+            transaction_id: None,
+        };
+        let handle = service
+            .do_action_create_prepared_statement(prepared, request(Action::default(), &alice))
+            .await?
+            .prepared_statement_handle;
+        let cmd = CommandPreparedStatementQuery {
+            prepared_statement_handle: handle.clone(),
+        };
+        service
+            .get_flight_info_prepared_statement(cmd, request(FlightDescriptor::default(), &alice))
+            .await?;
+
+        let fetch = FetchResults {
+            handle: String::from_utf8(handle.to_vec()).expect("UUID"),
+        };
+        let e = service
+            .do_get_fallback(request(Ticket::default(), &bob), fetch.as_any())
+            .await
+            .err()
+            .expect("bob didn't execute the statement");
+        assert!(e.message().contains("Request handle not found"), "{e}");
+        let batches: Vec<_> = service
+            .do_get_fallback(request(Ticket::default(), &alice), fetch.as_any())
+            .await?
+            .into_inner()
+            .try_collect()
+            .await?;
+        assert!(!batches.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Typo-tolerant matching.
+//!
+//! `addidas~` in a query of `match()` matches the terms at most a few edits away from
+//! `adidas`, by [Levenshtein distance](https://en.wikipedia.org/wiki/Levenshtein_distance) in
+//! characters. [`Fuzziness::Auto`] allows no edits for terms of up to 2 characters, 1 for up to
+//! 5 and 2 for longer ones, and `addidas~1` sets the number. A fulltext index finds the terms
+//! with [`fuzzy_terms`], which walks its sorted term dictionary like a trie: the distances to a
+//! prefix are shared by every term starting with it, and once a prefix is too far from the
+//! query term the terms after it are skipped. Terms that are edits away score less than exact
+//! ones, see [`fuzzy_weight`].
+//!
+//! `similarity(a, b)` compares texts by their trigrams like PostgreSQL's `pg_trgm`: the number
+//! of trigrams they share divided by the number of distinct trigrams in either, from 0 to 1.
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, Float32Array};
+use arrow::datatypes::DataType;
+use datafusion::logical_expr::{ColumnarValue, ScalarUDFImpl, Signature, Volatility};
+use datafusion_common::{exec_err, DataFusionError, Result};
+
+use crate::text_index::text_values;
+
+/// The most edits a fuzzy term can allow
+pub const MAX_EDITS: u32 = 2;
+/// Name of the trigram similarity function
+pub const SIMILARITY: &str = "similarity";
+
+/// How many edits away from a query term the terms it matches can be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fuzziness {
+    /// Scaled by the length of the term
+    Auto,
+    Edits(u32),
+}
+
+impl Fuzziness {
+    /// The most edits from `term` allowed
+    pub fn distance(&self, term: &str) -> u32 {
+        match self {
+            Fuzziness::Auto => match term.chars().count() {
+                0..=2 => 0,
+                3..=5 => 1,
+                _ => 2,
+            },
+            Fuzziness::Edits(edits) => *edits,
+        }
+    }
+}
+
+/// How much a term `distance` edits away from a query term counts compared to an exact match
+pub fn fuzzy_weight(distance: u32) -> f64 {
+    1.0 / (1.0 + distance as f64)
+}
+
+/// The edit distances from the prefix of a word ending with `c` to each prefix of `query`, given
+/// the distances `previous` from the prefix before `c`
+fn next_row(previous: &[u32], c: char, query: &[char]) -> Vec<u32> {
+    let mut row = Vec::with_capacity(previous.len());
+    row.push(previous[0] + 1);
+    for (i, q) in query.iter().enumerate() {
+        let substitution = previous[i] + u32::from(*q != c);
+        row.push(substitution.min(previous[i + 1] + 1).min(row[i] + 1));
+    }
+    row
+}
+
+/// The Levenshtein distance between `a` and `b` in characters, if it is at most `max`
+pub fn levenshtein(a: &str, b: &str, max: u32) -> Option<u32> {
+    let query: Vec<char> = b.chars().collect();
+    let mut row: Vec<u32> = (0..=query.len() as u32).collect();
+    for c in a.chars() {
+        row = next_row(&row, c, &query);
+        // Distances only grow from the smallest one in the row
+        if row.iter().min().is_some_and(|min| *min > max) {
+            return None;
+        }
+    }
+    row.last().copied().filter(|distance| *distance <= max)
+}
+
+/// The smallest string greater than every string starting with `prefix`, if there is one
+fn prefix_successor(prefix: &[char]) -> Option<String> {
+    let mut prefix = prefix.to_vec();
+    while let Some(last) = prefix.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            prefix.push(next);
+            return Some(prefix.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// The terms of `dictionary` at most `max` edits away from `term`, with their distances
+pub fn fuzzy_terms<'a, V>(
+    dictionary: &'a BTreeMap<String, V>,
+    term: &str,
+    max: u32,
+) -> Vec<(&'a str, u32)> {
+    let query: Vec<char> = term.chars().collect();
+    // The distances of every prefix of the last term seen, the empty one first
+    let mut rows: Vec<Vec<u32>> = vec![(0..=query.len() as u32).collect()];
+    let mut prefix: Vec<char> = vec![];
+    let mut matches = vec![];
+    let mut from = Bound::Unbounded;
+    while let Some((key, _)) = dictionary.range::<str, _>((from, Bound::Unbounded)).next() {
+        let chars: Vec<char> = key.chars().collect();
+        let common = prefix
+            .iter()
+            .zip(chars.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        prefix.truncate(common);
+        rows.truncate(common + 1);
+        let mut too_far = false;
+        for c in chars[common..].iter() {
+            let row = next_row(rows.last().expect("has the empty prefix"), *c, &query);
+            prefix.push(*c);
+            too_far = row.iter().min().is_some_and(|min| *min > max);
+            rows.push(row);
+            if too_far {
+                break;
+            }
+        }
+        if too_far {
+            // No term starting with this prefix can be close enough
+            match prefix_successor(&prefix) {
+                Some(successor) => {
+                    let next = dictionary
+                        .range::<str, _>((Bound::Included(successor.as_str()), Bound::Unbounded))
+                        .next();
+                    match next {
+                        Some((next, _)) => from = Bound::Included(next.as_str()),
+                        None => break,
+                    }
+                }
+                None => break,
+            }
+            continue;
+        }
+        let distance = rows.last().and_then(|row| row.last()).copied();
+        if let Some(distance) = distance.filter(|distance| *distance <= max) {
+            matches.push((key.as_str(), distance));
+        }
+        from = Bound::Excluded(key.as_str());
+    }
+    matches
+}
+
+/// The trigrams of `text` as `pg_trgm` makes them: the words of letters and digits are
+/// lowercased and padded with two spaces in front and one after
+pub fn trigrams(text: &str) -> BTreeSet<[char; 3]> {
+    let mut trigrams = BTreeSet::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let padded: Vec<char> = "  "
+            .chars()
+            .chain(word.chars().flat_map(char::to_lowercase))
+            .chain([' '])
+            .collect();
+        for trigram in padded.windows(3) {
+            trigrams.insert([trigram[0], trigram[1], trigram[2]]);
+        }
+    }
+    trigrams
+}
+
+/// How alike `a` and `b` are by their trigrams, from 0 to 1
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let (a, b) = (trigrams(a), trigrams(b));
+    let shared = a.intersection(&b).count();
+    let all = a.len() + b.len() - shared;
+    if all == 0 {
+        return 0.0;
+    }
+    shared as f32 / all as f32
+}
+
+/// `similarity(a, b)`, the trigram similarity of two texts
+#[derive(Debug)]
+pub struct SimilarityFunction {
+    signature: Signature,
+}
+
+impl SimilarityFunction {
+    pub fn new() -> Self {
+        Self {
+            signature: Signature::uniform(2, vec![DataType::Utf8], Volatility::Immutable),
+        }
+    }
+}
+
+impl Default for SimilarityFunction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScalarUDFImpl for SimilarityFunction {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        SIMILARITY
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType> {
+        Ok(DataType::Float32)
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        let [_, _] = args else {
+            return exec_err!("{SIMILARITY}() takes two texts");
+        };
+        let arrays: Vec<ArrayRef> = ColumnarValue::values_to_arrays(args)?;
+        let similarities: Float32Array = text_values(&arrays[0])?
+            .into_iter()
+            .zip(text_values(&arrays[1])?)
+            .map(|(a, b)| Some(similarity(a?, b?)))
+            .collect();
+        Ok(ColumnarValue::Array(Arc::new(similarities)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(Some(1), levenshtein("addidas", "adidas", 2));
+        assert_eq!(Some(2), levenshtein("nkie", "nike", 2));
+        assert_eq!(None, levenshtein("puma", "nike", 2));
+        assert_eq!(Some(0), levenshtein("", "", 0));
+        assert_eq!(0, Fuzziness::Auto.distance("tv"));
+        assert_eq!(1, Fuzziness::Auto.distance("nike"));
+        assert_eq!(2, Fuzziness::Auto.distance("adidas"));
+        assert!(fuzzy_weight(1) < fuzzy_weight(0));
+
+        let dictionary: BTreeMap<String, ()> = [
+            "adidas",
+            "adida",
+            "addidas",
+            "acids",
+            "ad",
+            "b",
+            "nike",
+            "zadidas",
+            "\u{10FFFF}",
+        ]
+        .into_iter()
+        .map(|term| (term.to_string(), ()))
+        .collect();
+        assert_eq!(
+            vec![
+                ("acids", 2),
+                ("addidas", 1),
+                ("adida", 1),
+                ("adidas", 0),
+                ("zadidas", 1)
+            ],
+            fuzzy_terms(&dictionary, "adidas", 2)
+        );
+        // The same as comparing with every term
+        for max in 0..=MAX_EDITS {
+            for query in ["adidas", "nike", "", "x", "\u{10FFFF}"] {
+                let expected: Vec<(&str, u32)> = dictionary
+                    .keys()
+                    .filter_map(|term| Some((term.as_str(), levenshtein(term, query, max)?)))
+                    .collect();
+                assert_eq!(
+                    expected,
+                    fuzzy_terms(&dictionary, query, max),
+                    "{query} {max}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trigram_similarity() {
+        assert_eq!(
+            vec!["  c", " ca", "at ", "cat"],
+            trigrams("Cat")
+                .into_iter()
+                .map(|t| t.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1.0, similarity("Adidas", "adidas"));
+        assert_eq!(0.0, similarity("nike", "puma"));
+        assert_eq!(0.0, similarity("", ""));
+        let typo = similarity("addidas", "adidas");
+        assert!(typo > 0.5 && typo < 1.0, "{typo}");
+    }
+}
@@ -0,0 +1,890 @@
+//! Ordered secondary indexes over the columns of a [`MemTable`](crate::table_provider::MemTable).
+//!
+//! Each index stores the tuple offset of every row alongside its key, as described in
+//! `docs/index-design.md`. Keys are encoded with arrow's row format, which compares byte-wise in
+//! the same order as the column values, so a single ordered map works for any column type and
+//! for composite keys. Duplicate keys are supported by making the tuple offset part of each
+//! entry, the "unique identifier" suffix from Graefe's B-tree survey.
+//!
+//! The encoded keys are stored as compact [`IndexKey`]s in leaves that store the prefix shared
+//! by their keys once, which keeps indexes on `Utf8` and `Binary` columns small.
+//!
+//! Indexes created `USING fulltext` are [`TextIndex`]es instead, which find rows by the terms of
+//! their text columns as their [`Analyzer`] splits them. They are maintained the same way but
+//! can't be used for key lookups.
+
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arrow::array::{new_null_array, Array, ArrayRef, BooleanArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow::row::{RowConverter, Rows, SortField};
+use datafusion::error::Result;
+use datafusion::logical_expr::{Between, BinaryExpr, Expr, Operator};
+use datafusion::scalar::ScalarValue;
+use datafusion_common::{exec_err, plan_err, DataFusionError};
+
+use crate::analysis::Analyzer;
+use crate::b_tree_index::{BPlusTree, Range};
+use crate::index_key::{IndexKey, PrefixKeys};
+use crate::table_provider::TupletOffset;
+use crate::text_index::TextIndex;
+
+const MIN_OFFSET: TupletOffset = (i32::MIN, i32::MIN, i32::MIN);
+const MAX_OFFSET: TupletOffset = (i32::MAX, i32::MAX, i32::MAX);
+
+type Entries = BPlusTree<RowEntry, (), PrefixKeys<TupletOffset>>;
+
+/// An entry of an ordered index: the key of a row and where the row is stored
+pub type RowEntry = (IndexKey, TupletOffset);
+
+/// A half-open range `[lower, upper)` of encoded index keys. `None` means unbounded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    pub lower: Option<Vec<u8>>,
+    pub upper: Option<Vec<u8>>,
+}
+
+/// How an index organizes its entries, as in `CREATE INDEX ... USING method`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IndexMethod {
+    /// Ordered keys, for equality and range filters
+    #[default]
+    BTree,
+    /// Inverted index of the terms of text columns, for `match()`
+    FullText,
+}
+
+impl Display for IndexMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexMethod::BTree => write!(f, "btree"),
+            IndexMethod::FullText => write!(f, "fulltext"),
+        }
+    }
+}
+
+impl FromStr for IndexMethod {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "btree" => Ok(IndexMethod::BTree),
+            "fulltext" => Ok(IndexMethod::FullText),
+            _ => plan_err!("Unsupported index method {s}"),
+        }
+    }
+}
+
+/// The entries of an ordered index
+struct BTreeEntries {
+    data_types: Vec<DataType>,
+    /// One converter per column so that a prefix of the key columns can be encoded on its own.
+    converters: Vec<RowConverter>,
+    entries: Entries,
+}
+
+enum IndexData {
+    BTree(BTreeEntries),
+    FullText(TextIndex),
+}
+
+/// Secondary index over one or more columns of a table.
+pub struct SecondaryIndex {
+    name: String,
+    columns: Vec<String>,
+    column_indices: Vec<usize>,
+    unique: bool,
+    data: IndexData,
+}
+
+impl Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("name", &self.name)
+            .field("method", &self.method())
+            .field("columns", &self.columns)
+            .field("unique", &self.unique)
+            .field("num_entries", &self.num_entries())
+            .field("memory_usage", &self.memory_usage())
+            .finish()
+    }
+}
+
+impl SecondaryIndex {
+    /// Create an empty index on `columns` of a table with the given schema. Fulltext indexes use
+    /// the standard analyzer unless given another with [`Self::with_analyzer`].
+    pub fn try_new(
+        name: impl Into<String>,
+        columns: Vec<String>,
+        unique: bool,
+        method: IndexMethod,
+        schema: &SchemaRef,
+    ) -> Result<Self> {
+        let name = name.into();
+        if columns.is_empty() {
+            return plan_err!("Index {name} must have at least one column");
+        }
+        if unique && method == IndexMethod::FullText {
+            return plan_err!("Fulltext index {name} can't be unique");
+        }
+        let mut column_indices = Vec::with_capacity(columns.len());
+        let mut data_types = Vec::with_capacity(columns.len());
+        let mut converters = Vec::with_capacity(columns.len());
+        for column in columns.iter() {
+            let column_index = schema.index_of(column)?;
+            if column_indices.contains(&column_index) {
+                return plan_err!("Column {column} appears more than once in index {name}");
+            }
+            let data_type = schema.field(column_index).data_type().clone();
+            converters.push(RowConverter::new(vec![SortField::new(data_type.clone())])?);
+            data_types.push(data_type);
+            column_indices.push(column_index);
+        }
+        let data = match method {
+            IndexMethod::BTree => IndexData::BTree(BTreeEntries {
+                data_types,
+                converters,
+                entries: Entries::default(),
+            }),
+            IndexMethod::FullText => IndexData::FullText(TextIndex::try_new(
+                &name,
+                column_indices.clone(),
+                Analyzer::standard(),
+                schema,
+            )?),
+        };
+        Ok(Self {
+            name,
+            columns,
+            column_indices,
+            unique,
+            data,
+        })
+    }
+
+    /// Have an empty fulltext index split its values into terms with `analyzer`
+    pub fn with_analyzer(mut self, analyzer: Arc<Analyzer>, schema: &SchemaRef) -> Result<Self> {
+        let IndexData::FullText(text_index) = &self.data else {
+            return plan_err!("Only fulltext indexes have an analyzer");
+        };
+        if text_index.num_postings() > 0 {
+            return exec_err!(
+                "Can't change the analyzer of index {} once built",
+                self.name
+            );
+        }
+        self.data = IndexData::FullText(TextIndex::try_new(
+            &self.name,
+            self.column_indices.clone(),
+            analyzer,
+            schema,
+        )?);
+        Ok(self)
+    }
+
+    /// Create an empty index with the same definition as this one
+    pub fn empty_like(&self, schema: &SchemaRef) -> Result<Self> {
+        let index = Self::try_new(
+            self.name.clone(),
+            self.columns.clone(),
+            self.unique,
+            self.method(),
+            schema,
+        )?;
+        match self.text_index() {
+            Some(text_index) => index.with_analyzer(text_index.analyzer().clone(), schema),
+            None => Ok(index),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    pub fn method(&self) -> IndexMethod {
+        match self.data {
+            IndexData::BTree(_) => IndexMethod::BTree,
+            IndexData::FullText(_) => IndexMethod::FullText,
+        }
+    }
+
+    /// The inverted index of a fulltext index
+    pub fn text_index(&self) -> Option<&TextIndex> {
+        match &self.data {
+            IndexData::BTree(_) => None,
+            IndexData::FullText(text_index) => Some(text_index),
+        }
+    }
+
+    /// Number of entries in the index, or of postings for a fulltext index
+    pub fn num_entries(&self) -> usize {
+        match &self.data {
+            IndexData::BTree(btree) => btree.entries.len(),
+            IndexData::FullText(text_index) => text_index.num_postings(),
+        }
+    }
+
+    /// Approximate bytes of memory used by the index
+    pub fn memory_usage(&self) -> usize {
+        match &self.data {
+            IndexData::BTree(btree) => btree.entries.memory_usage(),
+            IndexData::FullText(text_index) => text_index.memory_usage(),
+        }
+    }
+
+    fn btree(&self) -> Result<&BTreeEntries> {
+        match &self.data {
+            IndexData::BTree(btree) => Ok(btree),
+            IndexData::FullText(_) => {
+                exec_err!("Fulltext index {} can't look up keys", self.name)
+            }
+        }
+    }
+
+    /// Encode the key of every row in `batch`, along with whether any of its key columns is null
+    fn encode_batch(&self, batch: &RecordBatch) -> Result<Vec<(IndexKey, bool)>> {
+        let rows = self
+            .column_indices
+            .iter()
+            .zip(self.btree()?.converters.iter())
+            .map(|(column_index, converter)| {
+                Ok(converter.convert_columns(&[batch.column(*column_index).clone()])?)
+            })
+            .collect::<Result<Vec<Rows>>>()?;
+        let mut keys = Vec::with_capacity(batch.num_rows());
+        for row_idx in 0..batch.num_rows() {
+            let mut key = Vec::new();
+            for column_rows in rows.iter() {
+                key.extend_from_slice(column_rows.row(row_idx).as_ref());
+            }
+            let has_null = self
+                .column_indices
+                .iter()
+                .any(|column_index| batch.column(*column_index).is_null(row_idx));
+            keys.push((IndexKey::new(&key), has_null));
+        }
+        Ok(keys)
+    }
+
+    fn contains_key(entries: &Entries, key: &IndexKey) -> bool {
+        entries
+            .range((
+                Bound::Included((key.clone(), MIN_OFFSET)),
+                Bound::Included((key.clone(), MAX_OFFSET)),
+            ))
+            .next()
+            .is_some()
+    }
+
+    /// Check that adding `batches` would not violate a unique index. Does nothing for indexes
+    /// that allow duplicates.
+    pub fn check_unique(&self, batches: &[RecordBatch]) -> Result<()> {
+        self.check_unique_replacing(&[], batches)
+    }
+
+    /// Check that replacing the rows of `removed`, which are in the index, with the rows of
+    /// `added` would not violate a unique index. Does nothing for indexes that allow duplicates.
+    pub fn check_unique_replacing(
+        &self,
+        removed: &[RecordBatch],
+        added: &[RecordBatch],
+    ) -> Result<()> {
+        if !self.unique {
+            return Ok(());
+        }
+        let entries = &self.btree()?.entries;
+        let mut removed_keys = HashSet::new();
+        for batch in removed {
+            for (key, has_null) in self.encode_batch(batch)? {
+                if !has_null {
+                    removed_keys.insert(key);
+                }
+            }
+        }
+        let mut new_keys = HashSet::new();
+        for batch in added {
+            for (key, has_null) in self.encode_batch(batch)? {
+                // As in SQL, rows with null keys never conflict with each other
+                if has_null {
+                    continue;
+                }
+                let taken = Self::contains_key(entries, &key) && !removed_keys.contains(&key);
+                if taken || !new_keys.insert(key) {
+                    return exec_err!("Duplicate key value violates unique index {}", self.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add every row of `batch`, located at `batch_idx` in partition `partition_idx`, to the
+    /// index. Callers are expected to have called [`Self::check_unique`] first.
+    ///
+    /// The index can be read while rows are being added, so the batch must already be in the
+    /// table.
+    pub fn insert_batch(
+        &self,
+        batch: &RecordBatch,
+        partition_idx: usize,
+        batch_idx: usize,
+    ) -> Result<()> {
+        let btree = match &self.data {
+            IndexData::BTree(btree) => btree,
+            IndexData::FullText(text_index) => {
+                return text_index.insert_batch(batch, partition_idx, batch_idx)
+            }
+        };
+        for (value_idx, (key, _)) in self.encode_batch(batch)?.into_iter().enumerate() {
+            btree.entries.insert(
+                (
+                    key,
+                    (partition_idx as i32, batch_idx as i32, value_idx as i32),
+                ),
+                (),
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove every row of `batch`, located at `batch_idx` in partition `partition_idx`, from
+    /// the index
+    pub fn remove_batch(
+        &self,
+        batch: &RecordBatch,
+        partition_idx: usize,
+        batch_idx: usize,
+    ) -> Result<()> {
+        let btree = match &self.data {
+            IndexData::BTree(btree) => btree,
+            IndexData::FullText(text_index) => {
+                return text_index.remove_batch(batch, partition_idx, batch_idx)
+            }
+        };
+        for (value_idx, (key, _)) in self.encode_batch(batch)?.into_iter().enumerate() {
+            btree.entries.remove(&(
+                key,
+                (partition_idx as i32, batch_idx as i32, value_idx as i32),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add an entry for each row of `batch` at the matching tuple offset of `offsets`. Unlike
+    /// [`Self::insert_batch`], the rows don't have to be stored together.
+    pub fn insert_rows(&self, batch: &RecordBatch, offsets: &[TupletOffset]) -> Result<()> {
+        self.insert_entries(self.row_entries(batch, offsets)?);
+        Ok(())
+    }
+
+    /// Remove the entry of each row of `batch` at the matching tuple offset of `offsets`
+    pub fn remove_rows(&self, batch: &RecordBatch, offsets: &[TupletOffset]) -> Result<()> {
+        self.remove_entries(&self.row_entries(batch, offsets)?);
+        Ok(())
+    }
+
+    /// The entry of each row of `batch` at the matching tuple offset of `offsets`. Encoding the
+    /// entries is all that can fail, so callers that change several indexes encode every entry
+    /// before changing any index.
+    pub fn row_entries(
+        &self,
+        batch: &RecordBatch,
+        offsets: &[TupletOffset],
+    ) -> Result<Vec<RowEntry>> {
+        self.btree()?;
+        Ok(self
+            .encode_batch(batch)?
+            .into_iter()
+            .zip(offsets)
+            .map(|((key, _), offset)| (key, *offset))
+            .collect())
+    }
+
+    /// Add `entries`, which [`Self::row_entries`] encoded, and return the ones the index didn't
+    /// have yet
+    pub fn insert_entries(&self, entries: Vec<RowEntry>) -> Vec<RowEntry> {
+        let IndexData::BTree(btree) = &self.data else {
+            return vec![];
+        };
+        entries
+            .into_iter()
+            .filter(|entry| btree.entries.insert(entry.clone(), ()).is_none())
+            .collect()
+    }
+
+    /// Remove `entries`, which [`Self::row_entries`] encoded
+    pub fn remove_entries(&self, entries: &[RowEntry]) {
+        if let IndexData::BTree(btree) = &self.data {
+            for entry in entries {
+                btree.entries.remove(entry);
+            }
+        }
+    }
+
+    /// Which rows of `batch`, which has the table's columns, have a key in `range`
+    pub fn keys_in_range(&self, batch: &RecordBatch, range: &KeyRange) -> Result<BooleanArray> {
+        Ok(self
+            .encode_batch(batch)?
+            .into_iter()
+            .map(|(key, _)| {
+                let key = key.as_bytes();
+                let above = range.lower.as_deref().is_none_or(|lower| key >= lower);
+                let below = range.upper.as_deref().is_none_or(|upper| key < upper);
+                Some(above && below)
+            })
+            .collect())
+    }
+
+    /// Tuple offsets of every entry in `range`, in key order
+    pub fn lookup(&self, range: &KeyRange) -> Vec<TupletOffset> {
+        match &self.data {
+            IndexData::BTree(btree) => Self::range_entries(&btree.entries, range)
+                .map(|((_, offset), ())| offset)
+                .collect(),
+            IndexData::FullText(_) => vec![],
+        }
+    }
+
+    /// Tuple offsets of the entries whose leading column equals each of `keys`, paired with the
+    /// position of the key in `keys`. Null keys match nothing. `keys` must have the type of the
+    /// leading column.
+    pub fn probe(&self, keys: &ArrayRef) -> Result<Vec<(TupletOffset, u32)>> {
+        let btree = self.btree()?;
+        let rows = btree.converters[0].convert_columns(std::slice::from_ref(keys))?;
+        let mut matches = vec![];
+        for (key_idx, row) in rows.iter().enumerate() {
+            if keys.is_null(key_idx) {
+                continue;
+            }
+            let key = row.as_ref().to_vec();
+            let range = KeyRange {
+                upper: prefix_successor(&key),
+                lower: Some(key),
+            };
+            matches.extend(
+                Self::range_entries(&btree.entries, &range)
+                    .map(|((_, offset), ())| (offset, key_idx as u32)),
+            );
+        }
+        Ok(matches)
+    }
+
+    /// Number of entries in `range`
+    pub fn count(&self, range: &KeyRange) -> usize {
+        match &self.data {
+            IndexData::BTree(btree) => Self::range_entries(&btree.entries, range).count(),
+            IndexData::FullText(_) => 0,
+        }
+    }
+
+    fn range_entries(
+        entries: &Entries,
+        range: &KeyRange,
+    ) -> Range<(IndexKey, TupletOffset), (), PrefixKeys<TupletOffset>> {
+        let lower = match &range.lower {
+            Some(key) => Bound::Included((IndexKey::new(key), MIN_OFFSET)),
+            None => Bound::Unbounded,
+        };
+        let upper = match &range.upper {
+            Some(key) => Bound::Excluded((IndexKey::new(key), MIN_OFFSET)),
+            None => Bound::Unbounded,
+        };
+        entries.range((lower, upper))
+    }
+
+    /// Number of leading index columns constrained by an equality filter, and whether the next
+    /// column is constrained by a range filter. Used to pick the most selective index.
+    pub fn matched_columns(&self, filters: &[Expr]) -> (usize, bool) {
+        if self.method() != IndexMethod::BTree {
+            return (0, false);
+        }
+        let mut equalities = 0;
+        for column in self.columns.iter() {
+            let bounds = column_bounds(column, filters);
+            if bounds.equal.is_some() {
+                equalities += 1;
+            } else {
+                return (equalities, !bounds.is_unbounded());
+            }
+        }
+        (equalities, false)
+    }
+
+    /// The filters that bound the columns [`Self::key_range`] uses, for display
+    pub fn bounding_filters<'a>(&self, filters: &'a [Expr]) -> Vec<&'a Expr> {
+        let (equalities, range) = self.matched_columns(filters);
+        let used = &self.columns[..self.columns.len().min(equalities + usize::from(range))];
+        filters
+            .iter()
+            .filter(|filter| {
+                used.iter().any(|column| {
+                    let bounds = column_bounds(column, std::slice::from_ref(*filter));
+                    bounds.equal.is_some() || !bounds.is_unbounded()
+                })
+            })
+            .collect()
+    }
+
+    /// Compute the range of keys that can satisfy `filters`, or `None` if the filters don't
+    /// constrain the leading column of the index.
+    ///
+    /// The range may contain keys that don't satisfy the filters, so they must still be applied
+    /// to the rows that are returned.
+    pub fn key_range(&self, filters: &[Expr]) -> Result<Option<KeyRange>> {
+        let IndexData::BTree(btree) = &self.data else {
+            return Ok(None);
+        };
+        let mut prefix = Vec::new();
+        for ((column, converter), data_type) in self
+            .columns
+            .iter()
+            .zip(btree.converters.iter())
+            .zip(btree.data_types.iter())
+        {
+            let bounds = column_bounds(column, filters);
+            if let Some(value) = &bounds.equal {
+                match encode_scalar(converter, data_type, value)? {
+                    Some(key) => {
+                        prefix.extend_from_slice(&key);
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            if bounds.is_unbounded() {
+                break;
+            }
+            // SQL comparisons never match nulls, so start the range after any null keys
+            let null_key = [prefix.as_slice(), &encode_null(converter, data_type)?].concat();
+            let mut lower = prefix_successor(&null_key);
+            let mut upper = prefix_successor(&prefix);
+            for (value, inclusive) in bounds.lower.iter() {
+                if let Some(key) = encode_scalar(converter, data_type, value)? {
+                    let key = [prefix.as_slice(), &key].concat();
+                    let bound = if *inclusive {
+                        Some(key)
+                    } else {
+                        prefix_successor(&key)
+                    };
+                    if let Some(bound) = bound {
+                        lower = lower.max(Some(bound));
+                    }
+                }
+            }
+            for (value, inclusive) in bounds.upper.iter() {
+                if let Some(key) = encode_scalar(converter, data_type, value)? {
+                    let key = [prefix.as_slice(), &key].concat();
+                    let bound = if *inclusive {
+                        prefix_successor(&key)
+                    } else {
+                        Some(key)
+                    };
+                    upper = match (upper, bound) {
+                        (Some(upper), Some(bound)) => Some(upper.min(bound)),
+                        (upper, bound) => upper.or(bound),
+                    };
+                }
+            }
+            return Ok(Some(KeyRange { lower, upper }));
+        }
+        if prefix.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(KeyRange {
+            upper: prefix_successor(&prefix),
+            lower: Some(prefix),
+        }))
+    }
+}
+
+/// The ordered index of `indexes` whose leading columns `filters` constrain the most, with the
+/// range of keys that can satisfy them, or `None` if no index applies
+pub fn best_index<'a>(
+    indexes: impl Iterator<Item = &'a SecondaryIndex>,
+    filters: &[Expr],
+) -> Result<Option<(&'a SecondaryIndex, KeyRange)>> {
+    let best_index = indexes
+        .map(|index| (index.matched_columns(filters), index))
+        .filter(|((equalities, range), _)| *equalities > 0 || *range)
+        .max_by_key(|(matched, _)| *matched);
+    let Some((_, index)) = best_index else {
+        return Ok(None);
+    };
+    Ok(index.key_range(filters)?.map(|range| (index, range)))
+}
+
+/// Smallest byte string greater than every string starting with `prefix`, or `None` if there is
+/// no such string.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+fn encode_null(converter: &RowConverter, data_type: &DataType) -> Result<Vec<u8>> {
+    let rows = converter.convert_columns(&[new_null_array(data_type, 1)])?;
+    Ok(rows.row(0).as_ref().to_vec())
+}
+
+/// Encode a literal as a key for a column of `data_type`, or `None` if the literal is null or
+/// can't be represented in the column's type.
+fn encode_scalar(
+    converter: &RowConverter,
+    data_type: &DataType,
+    value: &ScalarValue,
+) -> Result<Option<Vec<u8>>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let array: ArrayRef = match cast(&value.to_array()?, data_type) {
+        Ok(array) if array.null_count() == 0 => array,
+        _ => return Ok(None),
+    };
+    let rows = converter.convert_columns(&[array])?;
+    Ok(Some(rows.row(0).as_ref().to_vec()))
+}
+
+/// Constraints on a single column extracted from a set of filters
+#[derive(Debug, Default)]
+struct ColumnBounds {
+    equal: Option<ScalarValue>,
+    /// Lower bounds and whether they are inclusive
+    lower: Vec<(ScalarValue, bool)>,
+    /// Upper bounds and whether they are inclusive
+    upper: Vec<(ScalarValue, bool)>,
+}
+
+impl ColumnBounds {
+    fn is_unbounded(&self) -> bool {
+        self.lower.is_empty() && self.upper.is_empty()
+    }
+}
+
+fn column_bounds(column: &str, filters: &[Expr]) -> ColumnBounds {
+    let mut bounds = ColumnBounds::default();
+    for filter in filters {
+        match filter {
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                let (value, op) = match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(c), Expr::Literal(value)) if c.name == column => (value, *op),
+                    (Expr::Literal(value), Expr::Column(c)) if c.name == column => {
+                        match op.swap() {
+                            Some(op) => (value, op),
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                match op {
+                    Operator::Eq => bounds.equal = Some(value.clone()),
+                    Operator::Gt => bounds.lower.push((value.clone(), false)),
+                    Operator::GtEq => bounds.lower.push((value.clone(), true)),
+                    Operator::Lt => bounds.upper.push((value.clone(), false)),
+                    Operator::LtEq => bounds.upper.push((value.clone(), true)),
+                    _ => {}
+                }
+            }
+            Expr::Between(Between {
+                expr,
+                negated: false,
+                low,
+                high,
+            }) => {
+                if let (Expr::Column(c), Expr::Literal(low), Expr::Literal(high)) =
+                    (expr.as_ref(), low.as_ref(), high.as_ref())
+                {
+                    if c.name == column {
+                        bounds.lower.push((low.clone(), true));
+                        bounds.upper.push((high.clone(), true));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int32Array, StringArray};
+    use arrow::datatypes::{Field, Schema};
+    use datafusion::logical_expr::{col, lit};
+    use std::sync::Arc;
+
+    fn test_batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("brand", DataType::Utf8, true),
+            Field::new("price", DataType::Int32, true),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3, 4, 5])),
+                Arc::new(StringArray::from(vec![
+                    Some("acme"),
+                    Some("zeta"),
+                    Some("acme"),
+                    None,
+                    Some("beta"),
+                ])),
+                Arc::new(Int32Array::from(vec![
+                    Some(10),
+                    Some(20),
+                    Some(30),
+                    Some(40),
+                    None,
+                ])),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn lookup(index: &SecondaryIndex, filters: &[Expr]) -> Vec<i32> {
+        let range = index.key_range(filters).unwrap().expect("index applies");
+        index
+            .lookup(&range)
+            .into_iter()
+            .map(|(_, _, row)| row)
+            .collect()
+    }
+
+    #[test]
+    fn equality_lookup_with_duplicates() -> Result<()> {
+        let batch = test_batch();
+        let index = SecondaryIndex::try_new(
+            "brand_idx",
+            vec!["brand".into()],
+            false,
+            IndexMethod::BTree,
+            &batch.schema(),
+        )?;
+        index.insert_batch(&batch, 0, 0)?;
+        assert_eq!(5, index.num_entries());
+        assert_eq!(vec![0, 2], lookup(&index, &[col("brand").eq(lit("acme"))]));
+        assert_eq!(vec![4], lookup(&index, &[lit("beta").eq(col("brand"))]));
+        assert!(lookup(&index, &[col("brand").eq(lit("nope"))]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn range_lookup_skips_nulls() -> Result<()> {
+        let batch = test_batch();
+        let index = SecondaryIndex::try_new(
+            "price_idx",
+            vec!["price".into()],
+            false,
+            IndexMethod::BTree,
+            &batch.schema(),
+        )?;
+        index.insert_batch(&batch, 0, 0)?;
+        assert_eq!(vec![0, 1], lookup(&index, &[col("price").lt_eq(lit(20))]));
+        assert_eq!(vec![2, 3], lookup(&index, &[col("price").gt(lit(20))]));
+        assert_eq!(
+            vec![1, 2],
+            lookup(
+                &index,
+                &[col("price").gt_eq(lit(15)), col("price").lt(lit(40))]
+            )
+        );
+        assert_eq!(
+            vec![1, 2],
+            lookup(&index, &[col("price").between(lit(20), lit(30))])
+        );
+        assert!(index.key_range(&[col("id").eq(lit(1))])?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn composite_key_prefix_lookup() -> Result<()> {
+        let batch = test_batch();
+        let index = SecondaryIndex::try_new(
+            "brand_price_idx",
+            vec!["brand".into(), "price".into()],
+            false,
+            IndexMethod::BTree,
+            &batch.schema(),
+        )?;
+        index.insert_batch(&batch, 0, 0)?;
+        assert_eq!(vec![0, 2], lookup(&index, &[col("brand").eq(lit("acme"))]));
+        assert_eq!(
+            vec![2],
+            lookup(
+                &index,
+                &[col("brand").eq(lit("acme")), col("price").gt(lit(10))]
+            )
+        );
+        assert_eq!(
+            (1, true),
+            index.matched_columns(&[col("brand").eq(lit("acme")), col("price").gt(lit(10))])
+        );
+        let keys: ArrayRef = Arc::new(StringArray::from(vec![Some("beta"), None, Some("acme")]));
+        let rows: Vec<(i32, u32)> = index
+            .probe(&keys)?
+            .into_iter()
+            .map(|((_, _, row), key_idx)| (row, key_idx))
+            .collect();
+        assert_eq!(vec![(4, 0), (0, 2), (2, 2)], rows);
+
+        let filters = [
+            col("id").eq(lit(1)),
+            col("price").gt(lit(10)),
+            col("brand").eq(lit("acme")),
+        ];
+        assert_eq!(
+            vec![&filters[1], &filters[2]],
+            index.bounding_filters(&filters)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unique_index_rejects_duplicates() -> Result<()> {
+        let batch = test_batch();
+        let index = SecondaryIndex::try_new(
+            "id_idx",
+            vec!["id".into()],
+            true,
+            IndexMethod::BTree,
+            &batch.schema(),
+        )?;
+        index.check_unique(std::slice::from_ref(&batch))?;
+        index.insert_batch(&batch, 0, 0)?;
+        let e = index.check_unique(&[batch.slice(1, 1)]).unwrap_err();
+        assert_eq!(
+            "Execution error: Duplicate key value violates unique index id_idx",
+            e.strip_backtrace()
+        );
+
+        // Null keys never conflict
+        let brand_index = SecondaryIndex::try_new(
+            "brand_idx",
+            vec!["brand".into()],
+            true,
+            IndexMethod::BTree,
+            &batch.schema(),
+        )?;
+        brand_index.check_unique(&[batch.slice(3, 1), batch.slice(3, 1)])?;
+        Ok(())
+    }
+}
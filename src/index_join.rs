@@ -0,0 +1,418 @@
+//! Index nested-loop joins.
+//!
+//! [`IndexJoinRule`] replaces an inner equi-join with an [`IndexJoinExec`] when one side is a
+//! scan of a [`MemTable`](crate::table_provider::MemTable) with an index on the join column and
+//! the other side is expected to be smaller than the table. Instead of hashing the table, each
+//! row of the smaller side probes the index. The rule can be turned off with
+//! `SET quokka.enable_index_join = false`.
+
+use std::any::Any;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, AsArray, UInt32Array};
+use arrow::compute::{cast, filter, filter_record_batch, take};
+use arrow::datatypes::{DataType, Int32Type, SchemaRef, UInt32Type};
+use arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::logical_expr::JoinType;
+use datafusion::physical_expr::expressions::Column;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::joins::HashJoinExec;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet,
+};
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PhysicalExpr,
+    SendableRecordBatchStream,
+};
+use datafusion_common::cast::as_boolean_array;
+use datafusion_common::config::ConfigOptions;
+use datafusion_common::tree_node::{Transformed, TreeNode};
+use datafusion_common::{exec_err, internal_err, DataFusionError};
+use datafusion_execution::TaskContext;
+use futures::StreamExt;
+
+use crate::index_scan::{take_rows, MemTableScanExec};
+use crate::session::QuokkaOptions;
+use crate::table_provider::{TableHandle, TupletOffset};
+
+/// An index that can find the rows of a table by the value of a single column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinIndex {
+    /// The primary key index
+    PrimaryKey,
+    /// The named secondary index, whose leading column is the join column
+    Secondary(String),
+}
+
+impl fmt::Display for JoinIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinIndex::PrimaryKey => write!(f, "primary_key"),
+            JoinIndex::Secondary(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Inner join of `outer` with a table, probing an index of the table with the join key of each
+/// row of `outer`.
+///
+/// The output has the columns of the join it replaced, so the table's columns come first if it
+/// was the left side of the join.
+#[derive(Debug)]
+pub struct IndexJoinExec {
+    outer: Arc<dyn ExecutionPlan>,
+    /// Position of the join key in the output of `outer`
+    outer_key: Column,
+    probe: IndexProbe,
+    /// Whether the table was the left side of the join
+    table_is_left: bool,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl IndexJoinExec {
+    /// The side of the join whose rows probe the index
+    pub fn outer(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.outer
+    }
+
+    pub fn index(&self) -> &JoinIndex {
+        &self.probe.index
+    }
+}
+
+/// How an [`IndexJoinExec`] finds the table rows matching a join key
+#[derive(Debug, Clone)]
+struct IndexProbe {
+    table: TableHandle,
+    index: JoinIndex,
+    /// Name of the join column in the table, for display
+    key: String,
+    /// Projection of the table's columns, as in the scan the join replaced
+    projection: Option<Vec<usize>>,
+    /// Filter on the projected table rows, from the scan the join replaced
+    predicate: Option<Arc<dyn PhysicalExpr>>,
+    /// Columns of the filtered rows to output, if the scan was followed by a projection
+    output_columns: Option<Vec<usize>>,
+}
+
+impl IndexProbe {
+    /// Find the table rows matching each of `keys`. Returns the number of rows found in the
+    /// index, and the position in `keys` of each match along with the matching rows, projected
+    /// and filtered.
+    async fn probe(&self, keys: &ArrayRef) -> Result<(usize, UInt32Array, RecordBatch)> {
+        let (key_indices, rows) = self.lookup(keys).await?;
+        let probed = rows.num_rows();
+        let rows = match &self.projection {
+            Some(projection) => rows.project(projection)?,
+            None => rows,
+        };
+        let (key_indices, rows) = match &self.predicate {
+            Some(predicate) => {
+                let mask = predicate.evaluate(&rows)?.into_array(rows.num_rows())?;
+                let mask = as_boolean_array(&mask)?;
+                let key_indices = filter(&key_indices, mask)?;
+                (
+                    key_indices.as_primitive::<UInt32Type>().clone(),
+                    filter_record_batch(&rows, mask)?,
+                )
+            }
+            None => (key_indices, rows),
+        };
+        let rows = match &self.output_columns {
+            Some(output_columns) => rows.project(output_columns)?,
+            None => rows,
+        };
+        Ok((probed, key_indices, rows))
+    }
+
+    /// The secondary indexes are locked while the rows are read, which keeps the table from being
+    /// rewritten and so keeps the tuple offsets valid.
+    async fn lookup(&self, keys: &ArrayRef) -> Result<(UInt32Array, RecordBatch)> {
+        let indexes = self.table.indexes.read().await;
+        let mut matches: Vec<(TupletOffset, u32)> = match &self.index {
+            JoinIndex::PrimaryKey => {
+                let primary_key_index = self.table.primary_key_index.read().await;
+                let keys = cast(keys, &DataType::Int32)?;
+                keys.as_primitive::<Int32Type>()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(key_idx, key)| {
+                        Some((*primary_key_index.get(&key?)?, key_idx as u32))
+                    })
+                    .collect()
+            }
+            JoinIndex::Secondary(name) => match indexes.get(name) {
+                Some(index) => index.probe(keys)?,
+                None => return exec_err!("Index {name} no longer exists"),
+            },
+        };
+        // take_rows returns the rows in offset order, so sort the matches the same way
+        matches.sort_unstable();
+        let offsets = matches.iter().map(|(offset, _)| *offset).collect();
+        let rows = take_rows(&self.table.batches.get(), &self.table.schema, offsets).await?;
+        let key_indices = UInt32Array::from_iter_values(matches.into_iter().map(|(_, i)| i));
+        // Rows the table's policy hides don't match any key
+        match self.table.visible(&rows)? {
+            Some(mask) => {
+                let key_indices = filter(&key_indices, &mask)?;
+                Ok((
+                    key_indices.as_primitive::<UInt32Type>().clone(),
+                    filter_record_batch(&rows, &mask)?,
+                ))
+            }
+            None => Ok((key_indices, rows)),
+        }
+    }
+}
+
+/// Pair the rows of `outer` at `outer_indices` with `table_rows`
+fn join_rows(
+    outer: &RecordBatch,
+    outer_indices: &UInt32Array,
+    table_rows: &RecordBatch,
+    table_is_left: bool,
+    schema: &SchemaRef,
+) -> Result<RecordBatch> {
+    let outer_rows = outer
+        .columns()
+        .iter()
+        .map(|column| take(column, outer_indices, None))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let columns = if table_is_left {
+        [table_rows.columns(), &outer_rows].concat()
+    } else {
+        [outer_rows.as_slice(), table_rows.columns()].concat()
+    };
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+impl DisplayAs for IndexJoinExec {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                let side = if self.table_is_left { "left" } else { "right" };
+                write!(
+                    f,
+                    "IndexJoinExec: index={}, on=[({}, {})], table_side={side}",
+                    self.probe.index, self.outer_key, self.probe.key
+                )?;
+                if let Some(predicate) = &self.probe.predicate {
+                    write!(f, ", predicate={predicate}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExecutionPlan for IndexJoinExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(self.outer.output_partitioning().partition_count())
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.outer.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let [outer] = children.as_slice() else {
+            return internal_err!("IndexJoinExec should have one child");
+        };
+        Ok(Arc::new(IndexJoinExec {
+            outer: outer.clone(),
+            outer_key: self.outer_key.clone(),
+            probe: self.probe.clone(),
+            table_is_left: self.table_is_left,
+            schema: self.schema.clone(),
+            metrics: ExecutionPlanMetricsSet::new(),
+        }))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let rows_probed = MetricBuilder::new(&self.metrics).counter("rows_probed", partition);
+        let outer = self.outer.execute(partition, context)?;
+        let probe = Arc::new(self.probe.clone());
+        let outer_key = self.outer_key.index();
+        let table_is_left = self.table_is_left;
+        let schema = self.schema.clone();
+        let stream = outer.then(move |batch| {
+            let probe = probe.clone();
+            let schema = schema.clone();
+            let baseline_metrics = baseline_metrics.clone();
+            let rows_probed = rows_probed.clone();
+            async move {
+                let batch = batch?;
+                let (probed, outer_indices, table_rows) =
+                    probe.probe(batch.column(outer_key)).await?;
+                let joined =
+                    join_rows(&batch, &outer_indices, &table_rows, table_is_left, &schema)?;
+                rows_probed.add(probed);
+                baseline_metrics.record_output(joined.num_rows());
+                Ok(joined)
+            }
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema.clone(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+}
+
+/// Physical optimizer rule that replaces an inner [`HashJoinExec`] on a single pair of columns with
+/// an [`IndexJoinExec`] when one side is a [`MemTableScanExec`], possibly filtered and projected to
+/// a subset of its columns, with an index on its join column, and the other side is estimated to
+/// have fewer rows. It must run before [`IndexScanRule`](crate::index_scan::IndexScanRule), which
+/// would otherwise hide the filtered scans.
+#[derive(Debug, Default)]
+pub struct IndexJoinRule {}
+
+impl IndexJoinRule {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PhysicalOptimizerRule for IndexJoinRule {
+    fn optimize(
+        &self,
+        plan: Arc<dyn ExecutionPlan>,
+        config: &ConfigOptions,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if !QuokkaOptions::from_config(config).enable_index_join {
+            return Ok(plan);
+        }
+        plan.transform_up(&|plan| {
+            let Some(join) = plan.as_any().downcast_ref::<HashJoinExec>() else {
+                return Ok(Transformed::No(plan));
+            };
+            match index_join(join)? {
+                Some(index_join) => Ok(Transformed::Yes(Arc::new(index_join))),
+                None => Ok(Transformed::No(plan)),
+            }
+        })
+    }
+
+    fn name(&self) -> &str {
+        "index_join"
+    }
+
+    fn schema_check(&self) -> bool {
+        true
+    }
+}
+
+/// Build an index join to replace `join`, if it is eligible
+fn index_join(join: &HashJoinExec) -> Result<Option<IndexJoinExec>> {
+    if join.join_type() != &JoinType::Inner
+        || join.filter().is_some()
+        || join.null_equals_null()
+        || join.on().len() != 1
+    {
+        return Ok(None);
+    }
+    let (left_key, right_key) = &join.on()[0];
+    let (Some(left_key), Some(right_key)) = (
+        left_key.as_any().downcast_ref::<Column>(),
+        right_key.as_any().downcast_ref::<Column>(),
+    ) else {
+        return Ok(None);
+    };
+
+    // Prefer probing the right side, which is the side a hash join would not build
+    for table_is_left in [false, true] {
+        let (outer, table_side, outer_key, table_key) = if table_is_left {
+            (join.right(), join.left(), right_key, left_key)
+        } else {
+            (join.left(), join.right(), left_key, right_key)
+        };
+        let (output_columns, filtered) = match table_side.as_any().downcast_ref::<ProjectionExec>()
+        {
+            Some(projection) => match projected_columns(projection) {
+                Some(columns) => (Some(columns), projection.input()),
+                None => continue,
+            },
+            None => (None, table_side),
+        };
+        let (scan, predicate) = match filtered.as_any().downcast_ref::<FilterExec>() {
+            Some(filter) => (filter.input(), Some(filter.predicate().clone())),
+            None => (filtered, None),
+        };
+        let Some(scan) = scan.as_any().downcast_ref::<MemTableScanExec>() else {
+            continue;
+        };
+        let scan_key = match &output_columns {
+            Some(columns) => columns[table_key.index()],
+            None => table_key.index(),
+        };
+        let Some(index) = scan.join_index(scan_key) else {
+            continue;
+        };
+        let key_type = outer.schema().field(outer_key.index()).data_type().clone();
+        if &key_type != scan.schema().field(scan_key).data_type() {
+            continue;
+        }
+        // Only probe when there are fewer rows to look up than rows to hash
+        let outer_rows = outer.statistics()?.num_rows;
+        let table_rows = table_side.statistics()?.num_rows;
+        match (outer_rows.get_value(), table_rows.get_value()) {
+            (Some(outer_rows), Some(table_rows)) if outer_rows < table_rows => {}
+            _ => continue,
+        }
+        return Ok(Some(IndexJoinExec {
+            outer: outer.clone(),
+            outer_key: outer_key.clone(),
+            probe: IndexProbe {
+                table: scan.table().clone(),
+                index: index.clone(),
+                key: table_key.to_string(),
+                projection: scan.projection().cloned(),
+                predicate,
+                output_columns,
+            },
+            table_is_left,
+            schema: join.schema(),
+            metrics: ExecutionPlanMetricsSet::new(),
+        }));
+    }
+    Ok(None)
+}
+
+/// The input column of each output column of `projection`, or `None` if it computes anything
+fn projected_columns(projection: &ProjectionExec) -> Option<Vec<usize>> {
+    projection
+        .expr()
+        .iter()
+        .map(|(expr, _)| Some(expr.as_any().downcast_ref::<Column>()?.index()))
+        .collect()
+}
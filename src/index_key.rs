@@ -0,0 +1,325 @@
+//! Compact keys for secondary indexes.
+//!
+//! Index keys are the arrow row format encoding of the key columns, which can be long for `Utf8`
+//! and `Binary` columns. [`IndexKey`] stores a key in 16 bytes with the "German style" layout
+//! from section 3.1 of the Umbra paper linked in `docs/index-design.md`: keys of up to 14 bytes
+//! are stored inline, and longer keys store their first 4 bytes next to a pointer to the whole
+//! key. Comparisons look at the first 4 bytes first and only compare whole keys when those tie.
+//!
+//! [`PrefixKeys`] stores the keys of a B+tree leaf with the prefix they all share stored once, so
+//! the remaining suffixes are more likely to fit inline.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Debug};
+use std::mem::size_of;
+
+use thin_vec::ThinVec;
+
+use crate::b_tree_index::LeafKeys;
+
+/// Longest key that is stored inline
+pub const INLINE_LEN: usize = 14;
+
+/// Number of leading bytes stored inline in keys that are too long to store inline
+const PREFIX_LEN: usize = 4;
+
+/// Encoded index key, ordered byte-wise
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct IndexKey(Repr);
+
+// Keys are always stored inline when they fit, so the derived equality is byte equality
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Repr {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_LEN],
+    },
+    Heap {
+        prefix: [u8; PREFIX_LEN],
+        bytes: ThinVec<u8>,
+    },
+}
+
+impl IndexKey {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self::concat(&[], bytes)
+    }
+
+    /// The key made of `prefix` followed by `suffix`
+    pub fn concat(prefix: &[u8], suffix: &[u8]) -> Self {
+        let len = prefix.len() + suffix.len();
+        if len <= INLINE_LEN {
+            let mut bytes = [0; INLINE_LEN];
+            bytes[..prefix.len()].copy_from_slice(prefix);
+            bytes[prefix.len()..len].copy_from_slice(suffix);
+            return IndexKey(Repr::Inline {
+                len: len as u8,
+                bytes,
+            });
+        }
+        let mut bytes = ThinVec::with_capacity(len);
+        bytes.extend_from_slice(prefix);
+        bytes.extend_from_slice(suffix);
+        let mut prefix = [0; PREFIX_LEN];
+        prefix.copy_from_slice(&bytes[..PREFIX_LEN]);
+        IndexKey(Repr::Heap { prefix, bytes })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, bytes } => &bytes[..*len as usize],
+            Repr::Heap { bytes, .. } => bytes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes allocated outside of the key itself
+    pub fn heap_size(&self) -> usize {
+        match &self.0 {
+            Repr::Inline { .. } => 0,
+            // The length and capacity are stored in the allocation
+            Repr::Heap { bytes, .. } => 2 * size_of::<usize>() + bytes.capacity(),
+        }
+    }
+
+    /// Up to the first 4 bytes, without following the pointer of long keys
+    fn prefix(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, bytes } => &bytes[..(*len as usize).min(PREFIX_LEN)],
+            Repr::Heap { prefix, .. } => prefix,
+        }
+    }
+
+    /// Compare with an encoded key, reading the whole key only if the prefixes tie
+    pub fn cmp_bytes(&self, other: &[u8]) -> Ordering {
+        match self.prefix().cmp(&other[..other.len().min(PREFIX_LEN)]) {
+            Ordering::Equal => self.as_bytes().cmp(other),
+            ordering => ordering,
+        }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.prefix().cmp(other.prefix()) {
+            Ordering::Equal => self.as_bytes().cmp(other.as_bytes()),
+            ordering => ordering,
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Debug for IndexKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IndexKey({:?})", self.as_bytes())
+    }
+}
+
+/// Keys of a B+tree leaf whose entries are an [`IndexKey`] and a tie breaker such as the row's
+/// location. The bytes every key in the leaf starts with are stored once, and each entry stores
+/// the rest of its key.
+///
+/// Inserting a key that doesn't start with the prefix shortens it, which rewrites the leaf's
+/// suffixes. Splitting a leaf lengthens the prefix of both halves.
+pub struct PrefixKeys<T> {
+    prefix: Vec<u8>,
+    suffixes: Vec<(IndexKey, T)>,
+}
+
+impl<T> PrefixKeys<T> {
+    /// Move the leading bytes that every suffix shares into the prefix
+    fn extend_prefix(&mut self) {
+        let (Some((first, _)), Some((last, _))) = (self.suffixes.first(), self.suffixes.last())
+        else {
+            return;
+        };
+        // The suffixes are sorted, so whatever the first and last share, every suffix shares
+        let shared = common_prefix_len(first.as_bytes(), last.as_bytes());
+        if shared == 0 {
+            return;
+        }
+        self.prefix.extend_from_slice(&first.as_bytes()[..shared]);
+        for (suffix, _) in self.suffixes.iter_mut() {
+            *suffix = IndexKey::new(&suffix.as_bytes()[shared..]);
+        }
+    }
+
+    /// Move the bytes of the prefix after `len` back into the suffixes
+    fn shorten_prefix(&mut self, len: usize) {
+        let removed = self.prefix.split_off(len);
+        for (suffix, _) in self.suffixes.iter_mut() {
+            *suffix = IndexKey::concat(&removed, suffix.as_bytes());
+        }
+    }
+}
+
+impl<T: Ord + Clone + Send + Sync> LeafKeys<(IndexKey, T)> for PrefixKeys<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            prefix: vec![],
+            suffixes: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.suffixes.len()
+    }
+
+    fn binary_search(&self, (key, tie_breaker): &(IndexKey, T)) -> Result<usize, usize> {
+        let bytes = key.as_bytes();
+        match bytes.strip_prefix(self.prefix.as_slice()) {
+            Some(rest) => self.suffixes.binary_search_by(|(suffix, t)| {
+                suffix.cmp_bytes(rest).then_with(|| t.cmp(tie_breaker))
+            }),
+            // Every key in the leaf starts with the prefix
+            None if bytes < self.prefix.as_slice() => Err(0),
+            None => Err(self.suffixes.len()),
+        }
+    }
+
+    fn get(&self, idx: usize) -> (IndexKey, T) {
+        let (suffix, tie_breaker) = &self.suffixes[idx];
+        (
+            IndexKey::concat(&self.prefix, suffix.as_bytes()),
+            tie_breaker.clone(),
+        )
+    }
+
+    fn insert(&mut self, idx: usize, (key, tie_breaker): (IndexKey, T)) {
+        let bytes = key.as_bytes();
+        if self.suffixes.is_empty() {
+            self.prefix.clear();
+            self.prefix.extend_from_slice(bytes);
+        }
+        let shared = common_prefix_len(&self.prefix, bytes);
+        if shared < self.prefix.len() {
+            self.shorten_prefix(shared);
+        }
+        self.suffixes
+            .insert(idx, (IndexKey::new(&bytes[shared..]), tie_breaker));
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.suffixes.remove(idx);
+    }
+
+    fn split_off(&mut self, at: usize) -> Self {
+        let mut other = Self {
+            prefix: self.prefix.clone(),
+            suffixes: Vec::with_capacity(self.suffixes.capacity()),
+        };
+        other.suffixes.extend(self.suffixes.drain(at..));
+        self.extend_prefix();
+        other.extend_prefix();
+        other
+    }
+
+    fn allocated_size(&self) -> usize {
+        self.prefix.capacity()
+            + self.suffixes.capacity() * size_of::<(IndexKey, T)>()
+            + self
+                .suffixes
+                .iter()
+                .map(|(suffix, _)| suffix.heap_size())
+                .sum::<usize>()
+    }
+
+    fn key_heap_size((key, _): &(IndexKey, T)) -> usize {
+        key.heap_size()
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::b_tree_index::BPlusTree;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    /// Byte strings that often share long prefixes, like encoded string keys
+    fn key_bytes() -> impl Strategy<Value = Vec<u8>> {
+        (0..4usize, proptest::collection::vec(0..4u8, 0..24))
+            .prop_map(|(shared, tail)| [&b"quokka-product-"[..shared * 5], &tail].concat())
+    }
+
+    #[test]
+    fn keys_are_16_bytes() {
+        assert_eq!(16, size_of::<IndexKey>());
+        assert_eq!(0, IndexKey::new(&[7; INLINE_LEN]).heap_size());
+        assert!(IndexKey::new(&[7; INLINE_LEN + 1]).heap_size() > INLINE_LEN);
+    }
+
+    proptest! {
+        #[test]
+        fn ordered_like_bytes(a in key_bytes(), b in key_bytes()) {
+            let (key_a, key_b) = (IndexKey::new(&a), IndexKey::new(&b));
+            prop_assert_eq!(a.as_slice(), key_a.as_bytes());
+            prop_assert_eq!(a.cmp(&b), key_a.cmp(&key_b));
+            prop_assert_eq!(a.cmp(&b), key_a.cmp_bytes(&b));
+            prop_assert_eq!(a == b, key_a == key_b);
+        }
+
+        #[test]
+        fn prefix_compressed_tree_matches_btree_map(
+            ops in proptest::collection::vec((key_bytes(), 0..4u32, any::<bool>()), 1..300)
+        ) {
+            let tree: BPlusTree<(IndexKey, u32), (), PrefixKeys<u32>> =
+                BPlusTree::with_leaf_keys(4);
+            let mut expected = BTreeMap::new();
+            for (bytes, row, insert) in ops {
+                let key = (IndexKey::new(&bytes), row);
+                if insert {
+                    prop_assert_eq!(expected.insert((bytes, row), ()), tree.insert(key, ()));
+                } else {
+                    prop_assert_eq!(expected.remove(&(bytes, row)), tree.remove(&key));
+                }
+            }
+            let actual: Vec<(Vec<u8>, u32)> = tree
+                .iter()
+                .map(|((key, row), ())| (key.as_bytes().to_vec(), row))
+                .collect();
+            let wanted: Vec<(Vec<u8>, u32)> = expected.into_keys().collect();
+            prop_assert_eq!(wanted, actual);
+        }
+    }
+
+    #[test]
+    fn shared_prefixes_are_stored_once() {
+        let prefix = b"quokka-product-name-";
+        let keys: Vec<Vec<u8>> = (0..1_000u32)
+            .map(|i| [&prefix[..], format!("{i:04}").as_bytes()].concat())
+            .collect();
+        let uncompressed: BPlusTree<(IndexKey, u32), ()> = BPlusTree::new();
+        let compressed: BPlusTree<(IndexKey, u32), (), PrefixKeys<u32>> = BPlusTree::default();
+        for (row, key) in keys.iter().enumerate() {
+            uncompressed.insert((IndexKey::new(key), row as u32), ());
+            compressed.insert((IndexKey::new(key), row as u32), ());
+        }
+        // Uncompressed, every key is too long to store inline, and `Vec` leaves don't count the
+        // memory their keys point to
+        let key_heap_size: usize = keys.iter().map(|key| IndexKey::new(key).heap_size()).sum();
+        assert!(key_heap_size > 0);
+        assert!(compressed.memory_usage() * 3 < (uncompressed.memory_usage() + key_heap_size) * 2);
+        let rows: Vec<u32> = compressed
+            .range((IndexKey::new(&keys[10]), 0)..(IndexKey::new(&keys[13]), 0))
+            .map(|((_, row), ())| row)
+            .collect();
+        assert_eq!(vec![10, 11, 12], rows);
+    }
+}
@@ -0,0 +1,617 @@
+//! Index scans as physical plan nodes.
+//!
+//! [`MemTable::scan`](crate::table_provider::MemTable) returns a [`MemTableScanExec`], which
+//! reads the whole table but also carries the best index lookup for the filters pushed down to
+//! it. [`IndexScanRule`] replaces a `FilterExec` over such a scan with that [`IndexScanExec`],
+//! which reads only the rows found in the index and applies the filter itself. Index use is then
+//! visible in `EXPLAIN`, and it can be turned off with `SET quokka.enable_index_scan = false`.
+//! [`BlockTable`](crate::block_table::BlockTable)s do the same with a [`BlockTableScanExec`],
+//! whose index scans read the versions of the rows the session's snapshot sees.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use arrow::array::UInt32Array;
+use arrow::compute::{concat_batches, filter_record_batch, take};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::logical_expr::Expr;
+use datafusion::physical_expr::{EquivalenceProperties, PhysicalSortExpr};
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::memory::{MemoryExec, MemoryStream};
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PhysicalExpr,
+    SendableRecordBatchStream,
+};
+use datafusion_common::cast::as_boolean_array;
+use datafusion_common::config::ConfigOptions;
+use datafusion_common::stats::Precision;
+use datafusion_common::tree_node::{Transformed, TreeNode};
+use datafusion_common::{exec_err, project_schema, DataFusionError, Statistics};
+use datafusion_execution::TaskContext;
+
+use crate::block_table::BlockTableHandle;
+use crate::index::KeyRange;
+use crate::index_join::JoinIndex;
+use crate::session::QuokkaOptions;
+use crate::table_provider::{PartitionData, TableHandle, TupletOffset};
+use crate::text_query::TextQuery;
+use crate::transaction::ActiveSnapshot;
+
+/// How an [`IndexScanExec`] finds its rows
+#[derive(Debug, Clone)]
+pub enum IndexLookup {
+    /// Point lookup of a key in the primary key index, which writes keep unique
+    PrimaryKey(i32),
+    /// Range scan of the named secondary index
+    Secondary { name: String, range: KeyRange },
+    /// Rows of the named fulltext index matching `query`, whose columns are at `fields` in the
+    /// index
+    Text {
+        name: String,
+        fields: Vec<usize>,
+        query: TextQuery,
+    },
+}
+
+impl fmt::Display for IndexLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexLookup::PrimaryKey(_) => write!(f, "primary_key"),
+            IndexLookup::Secondary { name, .. } | IndexLookup::Text { name, .. } => {
+                write!(f, "{name}")
+            }
+        }
+    }
+}
+
+/// The table an [`IndexScanExec`] reads
+#[derive(Debug, Clone)]
+pub(crate) enum ScannedTable {
+    Memory(TableHandle),
+    Block(BlockTableHandle),
+}
+
+impl ScannedTable {
+    fn schema(&self) -> &SchemaRef {
+        match self {
+            ScannedTable::Memory(table) => &table.schema,
+            ScannedTable::Block(table) => table.schema(),
+        }
+    }
+}
+
+/// Reads the rows of a [`MemTable`](crate::table_provider::MemTable) or
+/// [`BlockTable`](crate::block_table::BlockTable) found by an index lookup, optionally filtering
+/// them with a predicate.
+///
+/// The lookup happens when the plan is executed, so the plan sees rows written after it was
+/// created, or for a block table the versions the snapshot pinned in the session's config sees.
+/// The number of rows is estimated when the plan is created.
+pub struct IndexScanExec {
+    table: ScannedTable,
+    lookup: IndexLookup,
+    /// The filters that bound the lookup, for display
+    bounds: Vec<Expr>,
+    estimated_rows: usize,
+    projection: Option<Vec<usize>>,
+    projected_schema: SchemaRef,
+    predicate: Option<Arc<dyn PhysicalExpr>>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl Debug for IndexScanExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexScanExec")
+            .field("lookup", &self.lookup)
+            .field("bounds", &self.bounds)
+            .field("estimated_rows", &self.estimated_rows)
+            .field("projection", &self.projection)
+            .field("predicate", &self.predicate)
+            .finish()
+    }
+}
+
+impl IndexScanExec {
+    pub(crate) fn try_new(
+        table: ScannedTable,
+        lookup: IndexLookup,
+        bounds: Vec<Expr>,
+        estimated_rows: usize,
+        projection: Option<Vec<usize>>,
+    ) -> Result<Self> {
+        let projected_schema = project_schema(table.schema(), projection.as_ref())?;
+        Ok(Self {
+            table,
+            lookup,
+            bounds,
+            estimated_rows,
+            projection,
+            projected_schema,
+            predicate: None,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    /// A copy of this scan that only returns the rows matching `predicate`, which must refer to
+    /// the columns of the scan's output.
+    pub fn with_predicate(&self, predicate: Arc<dyn PhysicalExpr>) -> Self {
+        Self {
+            table: self.table.clone(),
+            lookup: self.lookup.clone(),
+            bounds: self.bounds.clone(),
+            estimated_rows: self.estimated_rows,
+            projection: self.projection.clone(),
+            projected_schema: self.projected_schema.clone(),
+            predicate: Some(predicate),
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+
+    pub fn lookup(&self) -> &IndexLookup {
+        &self.lookup
+    }
+
+    pub fn estimated_rows(&self) -> usize {
+        self.estimated_rows
+    }
+}
+
+impl DisplayAs for IndexScanExec {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                let bounds = self
+                    .bounds
+                    .iter()
+                    .map(|expr| expr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                write!(
+                    f,
+                    "IndexScanExec: index={}, bounds=[{bounds}], estimated_rows={}",
+                    self.lookup, self.estimated_rows
+                )?;
+                if let Some(predicate) = &self.predicate {
+                    write!(f, ", predicate={predicate}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExecutionPlan for IndexScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.projected_schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let rows_probed = MetricBuilder::new(&self.metrics).counter("rows_probed", partition);
+        let table = self.table.clone();
+        let lookup = self.lookup.clone();
+        let projection = self.projection.clone();
+        let predicate = self.predicate.clone();
+        let stream = futures::stream::once(async move {
+            let batch = match &table {
+                ScannedTable::Memory(table) => read_rows(table, &lookup).await?,
+                ScannedTable::Block(table) => {
+                    let IndexLookup::Secondary { name, range } = &lookup else {
+                        return exec_err!("Block tables can only look up keys of btree indexes");
+                    };
+                    // Registered until the rows are read, so the versions it sees aren't collected
+                    let snapshot = ActiveSnapshot::from_config(context.session_config());
+                    table.lookup(name, range, snapshot.snapshot())?
+                }
+            };
+            rows_probed.add(batch.num_rows());
+            let batch = match &projection {
+                Some(projection) => batch.project(projection)?,
+                None => batch,
+            };
+            let batch = match &predicate {
+                Some(predicate) => {
+                    let mask = predicate.evaluate(&batch)?.into_array(batch.num_rows())?;
+                    filter_record_batch(&batch, as_boolean_array(&mask)?)?
+                }
+                None => batch,
+            };
+            baseline_metrics.record_output(batch.num_rows());
+            Ok(batch)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.projected_schema.clone(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        let mut statistics = Statistics::new_unknown(&self.projected_schema);
+        statistics.num_rows = Precision::Inexact(self.estimated_rows);
+        Ok(statistics)
+    }
+}
+
+/// Read the rows `lookup` finds that the table's policy lets the scan see. The secondary indexes
+/// are locked while the rows are read, which keeps the table from being rewritten and so keeps
+/// the tuple offsets valid.
+async fn read_rows(table: &TableHandle, lookup: &IndexLookup) -> Result<RecordBatch> {
+    let indexes = table.indexes.read().await;
+    let offsets = match lookup {
+        IndexLookup::PrimaryKey(key) => {
+            let primary_key_index = table.primary_key_index.read().await;
+            primary_key_index.get(key).copied().into_iter().collect()
+        }
+        IndexLookup::Secondary { name, range } => match indexes.get(name) {
+            Some(index) => index.lookup(range),
+            None => return exec_err!("Index {name} no longer exists"),
+        },
+        IndexLookup::Text {
+            name,
+            fields,
+            query,
+        } => match indexes.get(name).and_then(|index| index.text_index()) {
+            Some(text_index) => text_index.search(query, fields),
+            None => return exec_err!("Index {name} no longer exists"),
+        },
+    };
+    let rows = take_rows(&table.batches.get(), &table.schema, offsets).await?;
+    match table.visible(&rows)? {
+        Some(mask) => Ok(filter_record_batch(&rows, &mask)?),
+        None => Ok(rows),
+    }
+}
+
+/// Gather the rows at `offsets` into a single batch, in offset order
+pub(crate) async fn take_rows(
+    partitions: &[PartitionData],
+    schema: &SchemaRef,
+    offsets: Vec<TupletOffset>,
+) -> Result<RecordBatch> {
+    // Group the rows by the batch they're in so each batch is only read once
+    let mut batch_rows: BTreeMap<(i32, i32), Vec<u32>> = BTreeMap::new();
+    for (partition_idx, batch_idx, row_idx) in offsets {
+        batch_rows
+            .entry((partition_idx, batch_idx))
+            .or_default()
+            .push(row_idx as u32);
+    }
+    let mut batches = vec![];
+    for ((partition_idx, batch_idx), mut rows) in batch_rows {
+        rows.sort_unstable();
+        let partition = partitions[partition_idx as usize].read().await;
+        let batch = &partition[batch_idx as usize];
+        let indices = UInt32Array::from(rows);
+        let columns = batch
+            .columns()
+            .iter()
+            .map(|column| take(column, &indices, None))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        batches.push(RecordBatch::try_new(schema.clone(), columns)?);
+    }
+    Ok(concat_batches(schema, &batches)?)
+}
+
+/// Full scan of a [`MemTable`](crate::table_provider::MemTable), along with the index scan that
+/// [`IndexScanRule`] may replace it with and the indexes
+/// [`IndexJoinRule`](crate::index_join::IndexJoinRule) can probe.
+#[derive(Debug)]
+pub struct MemTableScanExec {
+    scan: MemoryExec,
+    table: TableHandle,
+    projection: Option<Vec<usize>>,
+    index_scan: Option<IndexScanExec>,
+    /// Indexes whose leading column is in the output, keyed by the output column's position
+    join_indexes: BTreeMap<usize, JoinIndex>,
+}
+
+impl MemTableScanExec {
+    pub(crate) fn new(
+        scan: MemoryExec,
+        table: TableHandle,
+        projection: Option<Vec<usize>>,
+    ) -> Self {
+        Self {
+            scan,
+            table,
+            projection,
+            index_scan: None,
+            join_indexes: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_index_scan(mut self, index_scan: Option<IndexScanExec>) -> Self {
+        self.index_scan = index_scan;
+        self
+    }
+
+    pub fn with_join_indexes(mut self, join_indexes: BTreeMap<usize, JoinIndex>) -> Self {
+        self.join_indexes = join_indexes;
+        self
+    }
+
+    /// The index scan that can find the rows matching the filters pushed down to this scan
+    pub fn index_scan(&self) -> Option<&IndexScanExec> {
+        self.index_scan.as_ref()
+    }
+
+    /// The index that can find rows by the value of output column `column`
+    pub fn join_index(&self, column: usize) -> Option<&JoinIndex> {
+        self.join_indexes.get(&column)
+    }
+
+    pub(crate) fn table(&self) -> &TableHandle {
+        &self.table
+    }
+
+    pub fn projection(&self) -> Option<&Vec<usize>> {
+        self.projection.as_ref()
+    }
+}
+
+impl DisplayAs for MemTableScanExec {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                let partition_sizes: Vec<_> =
+                    self.scan.partitions().iter().map(|b| b.len()).collect();
+                write!(
+                    f,
+                    "MemTableScanExec: partitions={}, partition_sizes={partition_sizes:?}",
+                    partition_sizes.len()
+                )?;
+                if let Some(ordering) = self.scan.output_ordering() {
+                    write!(
+                        f,
+                        ", output_ordering={}",
+                        PhysicalSortExpr::format_list(ordering)
+                    )?;
+                }
+                if let Some(index_scan) = &self.index_scan {
+                    write!(f, ", index_candidate={}", index_scan.lookup)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExecutionPlan for MemTableScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.scan.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.scan.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.scan.output_ordering()
+    }
+
+    fn equivalence_properties(&self) -> EquivalenceProperties {
+        self.scan.equivalence_properties()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        self.scan.execute(partition, context)
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        self.scan.statistics()
+    }
+}
+
+/// Scan of a [`BlockTable`](crate::block_table::BlockTable), along with the index scan that
+/// [`IndexScanRule`] may replace it with. The blocks are read when the scan is executed, as the
+/// session's snapshot sees them.
+pub struct BlockTableScanExec {
+    table: BlockTableHandle,
+    column_ids: Vec<usize>,
+    schema: SchemaRef,
+    index_scan: Option<IndexScanExec>,
+}
+
+impl BlockTableScanExec {
+    pub(crate) fn try_new(table: BlockTableHandle, column_ids: Vec<usize>) -> Result<Self> {
+        let schema = Arc::new(table.schema().project(&column_ids)?);
+        Ok(Self {
+            table,
+            column_ids,
+            schema,
+            index_scan: None,
+        })
+    }
+
+    pub fn with_index_scan(mut self, index_scan: Option<IndexScanExec>) -> Self {
+        self.index_scan = index_scan;
+        self
+    }
+
+    /// The index scan that can find the rows matching the filters pushed down to this scan
+    pub fn index_scan(&self) -> Option<&IndexScanExec> {
+        self.index_scan.as_ref()
+    }
+}
+
+impl Debug for BlockTableScanExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockTableScanExec")
+            .field("column_ids", &self.column_ids)
+            .field("schema", &self.schema)
+            .field("index_scan", &self.index_scan)
+            .finish()
+    }
+}
+
+impl DisplayAs for BlockTableScanExec {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                write!(f, "BlockTableScanExec")?;
+                if let Some(index_scan) = &self.index_scan {
+                    write!(f, ": index_candidate={}", index_scan.lookup)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExecutionPlan for BlockTableScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(self)
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        // Registered until the blocks are read, so the versions it sees aren't collected
+        let snapshot = ActiveSnapshot::from_config(context.session_config());
+        let batches = self.table.batches(&self.column_ids, snapshot.snapshot())?;
+        Ok(Box::pin(MemoryStream::try_new(
+            batches,
+            self.schema.clone(),
+            None,
+        )?))
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        Ok(Statistics::new_unknown(&self.schema))
+    }
+}
+
+/// Physical optimizer rule that replaces a `FilterExec` over a [`MemTableScanExec`] or
+/// [`BlockTableScanExec`] with the scan's [`IndexScanExec`], if it has one. It must run before
+/// the scan is repartitioned.
+#[derive(Debug, Default)]
+pub struct IndexScanRule {}
+
+impl IndexScanRule {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PhysicalOptimizerRule for IndexScanRule {
+    fn optimize(
+        &self,
+        plan: Arc<dyn ExecutionPlan>,
+        config: &ConfigOptions,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if !QuokkaOptions::from_config(config).enable_index_scan {
+            return Ok(plan);
+        }
+        plan.transform_up(&|plan| {
+            let Some(filter) = plan.as_any().downcast_ref::<FilterExec>() else {
+                return Ok(Transformed::No(plan));
+            };
+            let input = filter.input().as_any();
+            let Some(index_scan) = input
+                .downcast_ref::<MemTableScanExec>()
+                .and_then(MemTableScanExec::index_scan)
+                .or_else(|| {
+                    input
+                        .downcast_ref::<BlockTableScanExec>()
+                        .and_then(BlockTableScanExec::index_scan)
+                })
+            else {
+                return Ok(Transformed::No(plan));
+            };
+            let index_scan = index_scan.with_predicate(filter.predicate().clone());
+            Ok(Transformed::Yes(Arc::new(index_scan)))
+        })
+    }
+
+    fn name(&self) -> &str {
+        "index_scan"
+    }
+
+    fn schema_check(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,30 @@
+pub mod alter_table;
+pub mod analysis;
+pub mod auth;
+pub mod b_tree_index;
+pub mod block_table;
+pub mod catalog;
+pub mod checkpoint;
+pub mod drop_table;
+pub mod file_io;
+pub mod flight_sql_server;
+pub mod fuzzy;
+pub mod index;
+pub mod index_join;
+pub mod index_key;
+pub mod index_scan;
+pub mod partition;
+pub mod policy;
+pub mod session;
+pub mod sql;
+pub mod system_catalog;
+pub mod table;
+pub mod table_provider;
+#[cfg(test)]
+mod test_util;
+pub mod text_index;
+pub mod text_query;
+pub mod text_search;
+pub mod transaction;
+pub mod wal;
+pub mod writer;
@@ -1,15 +1,11 @@
-#![feature(is_sorted)]
-#![feature(btree_cursors)]
-
-mod catalog;
-mod flight_sql_server;
-mod table_provider;
+use std::time::Duration;
 
 use arrow_flight::flight_service_server::FlightServiceServer;
 use log::info;
 use tonic::transport::Server;
 
-use crate::flight_sql_server::FlightSqlServiceImpl;
+use quokka_rs::flight_sql_server::FlightSqlServiceImpl;
+use quokka_rs::wal::WalOptions;
 
 /// This example shows how to wrap DataFusion with `FlightSqlService` to support connecting
 /// to a standalone DataFusion-based server with a JDBC client, using the open source "JDBC Driver
@@ -28,7 +24,27 @@ use crate::flight_sql_server::FlightSqlServiceImpl;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let addr = "0.0.0.0:50051".parse()?;
-    let service = FlightSqlServiceImpl::new();
+    // Writes are only durable if they're logged to a write-ahead log directory
+    let service = match std::env::var("QUOKKA_WAL_DIR") {
+        Ok(dir) => {
+            let mut options = WalOptions::new(dir);
+            if let Ok(sync) = std::env::var("QUOKKA_WAL_SYNC") {
+                options = options.with_sync(sync.parse()?);
+            }
+            if let Ok(size) = std::env::var("QUOKKA_WAL_SEGMENT_SIZE") {
+                options = options.with_segment_size(size.parse()?);
+            }
+            if let Ok(interval) = std::env::var("QUOKKA_CHECKPOINT_INTERVAL_MS") {
+                options =
+                    options.with_checkpoint_interval(Duration::from_millis(interval.parse()?));
+            }
+            if let Ok(backend) = std::env::var("QUOKKA_IO_BACKEND") {
+                options = options.with_io(backend.parse()?);
+            }
+            FlightSqlServiceImpl::with_wal(options).await?
+        }
+        Err(_) => FlightSqlServiceImpl::new(),
+    };
     info!("Listening on {addr:?}");
     let svc = FlightServiceServer::new(service);
 
@@ -0,0 +1,465 @@
+//! Partitioning memory tables by the value of a column, for tables shared by many tenants.
+//!
+//! `CREATE TABLE ... PARTITION BY (column)` keeps the rows with each value of `column` together in
+//! a [`MemTable`](crate::table_provider::MemTable) partition of their own. Scans with an equality
+//! or `IN` filter on the column only read the partitions of the values they filter on, and a
+//! value's rows can be truncated or dropped by emptying its partition. Partitions are added as new
+//! values are inserted, and the partition of a dropped value is reused for the next new one, so a
+//! partition's index, which the table's indexes locate rows by, never changes.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::array::{Array, UInt32Array};
+use arrow::compute::take;
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow::row::{RowConverter, SortField};
+use datafusion::error::Result;
+use datafusion::logical_expr::{Expr, Operator};
+use datafusion::scalar::ScalarValue;
+use datafusion_common::{plan_err, DataFusionError};
+use datafusion_expr::expr::InList;
+use tokio::sync::RwLock;
+
+use crate::table_provider::PartitionData;
+
+/// The partitions of a memory table. Unpartitioned tables have a fixed number of partitions that
+/// inserts spread their batches over, while partitioned tables add one for each new value of their
+/// partition key.
+#[derive(Debug, Clone)]
+pub(crate) struct Partitions {
+    list: Arc<parking_lot::RwLock<Vec<PartitionData>>>,
+    key: Option<Arc<parking_lot::RwLock<PartitionKey>>>,
+}
+
+/// Which partition holds the rows of each value of a partitioned table's partition key
+#[derive(Debug, Clone)]
+pub(crate) struct PartitionKey {
+    /// Name and position of the key column
+    name: String,
+    column: usize,
+    data_type: DataType,
+    partitions: HashMap<ScalarValue, usize>,
+    /// Partitions of dropped values, reused for new ones
+    free: Vec<usize>,
+}
+
+/// The rows and memory of the partition of one value of a table's partition key
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionStats {
+    pub value: ScalarValue,
+    pub rows: usize,
+    /// Approximate bytes of memory used by the partition's batches
+    pub memory: usize,
+}
+
+impl Partitions {
+    /// Unpartitioned partitions holding `partitions`
+    pub(crate) fn new(partitions: Vec<Vec<RecordBatch>>) -> Self {
+        Self {
+            list: Arc::new(parking_lot::RwLock::new(
+                partitions
+                    .into_iter()
+                    .map(|batches| Arc::new(RwLock::new(batches)))
+                    .collect(),
+            )),
+            key: None,
+        }
+    }
+
+    /// Partitions holding the rows of `batches` grouped by the value of column `column`
+    pub(crate) fn partitioned(
+        schema: &SchemaRef,
+        column: &str,
+        batches: Vec<RecordBatch>,
+    ) -> Result<(Self, Vec<Vec<RecordBatch>>)> {
+        let Ok(position) = schema.index_of(column) else {
+            return plan_err!("Partition key {column} is not a column of the table");
+        };
+        let mut key = PartitionKey {
+            name: column.to_string(),
+            column: position,
+            data_type: schema.field(position).data_type().clone(),
+            partitions: HashMap::new(),
+            free: vec![],
+        };
+        let mut partitions: Vec<Vec<RecordBatch>> = vec![];
+        for (partition, batch) in key.assign(&batches, 0)? {
+            if partition == partitions.len() {
+                partitions.push(vec![]);
+            }
+            partitions[partition].push(batch);
+        }
+        let list = partitions
+            .iter()
+            .map(|batches| Arc::new(RwLock::new(batches.clone())))
+            .collect();
+        let partitions_of_key = Self {
+            list: Arc::new(parking_lot::RwLock::new(list)),
+            key: Some(Arc::new(parking_lot::RwLock::new(key))),
+        };
+        Ok((partitions_of_key, partitions))
+    }
+
+    /// The current partitions, which only grow
+    pub(crate) fn get(&self) -> Vec<PartitionData> {
+        self.list.read().clone()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.list.read().len()
+    }
+
+    /// Name of the partition key column, if the table is partitioned
+    pub(crate) fn key_name(&self) -> Option<String> {
+        self.key.as_ref().map(|key| key.read().name.clone())
+    }
+
+    /// Position of the partition key column, if the table is partitioned
+    pub(crate) fn key_column(&self) -> Option<usize> {
+        self.key.as_ref().map(|key| key.read().column)
+    }
+
+    /// Split `batches` into the batches to append to each partition, adding partitions for new
+    /// values of the partition key. Unpartitioned tables spread the batches round robin.
+    pub(crate) fn route(&self, batches: Vec<RecordBatch>) -> Result<Vec<Vec<RecordBatch>>> {
+        let Some(key) = &self.key else {
+            let num_partitions = self.len();
+            let mut routed = vec![vec![]; num_partitions];
+            for (i, batch) in batches.into_iter().enumerate() {
+                routed[i % num_partitions].push(batch);
+            }
+            return Ok(routed);
+        };
+        let mut key = key.write();
+        let mut list = self.list.write();
+        let assigned = key.assign(&batches, list.len())?;
+        let mut routed = vec![vec![]; list.len()];
+        for (partition, batch) in assigned {
+            if partition == list.len() {
+                list.push(Arc::new(RwLock::new(vec![])));
+                routed.push(vec![]);
+            }
+            routed[partition].push(batch);
+        }
+        Ok(routed)
+    }
+
+    /// The partitions holding the rows that may match `filters`, or `None` if every partition
+    /// may hold some
+    pub(crate) fn matching(&self, filters: &[Expr]) -> Option<Vec<usize>> {
+        let key = self.key.as_ref()?.read();
+        let values = filters
+            .iter()
+            .find_map(|filter| key.filtered_values(filter))?;
+        let mut partitions: Vec<usize> = values
+            .iter()
+            .filter_map(|value| key.partition(value))
+            .collect();
+        partitions.sort_unstable();
+        partitions.dedup();
+        Some(partitions)
+    }
+
+    /// The partitions of `values`, skipping values without one
+    pub(crate) fn partitions_of(&self, values: &[ScalarValue]) -> Vec<(ScalarValue, usize)> {
+        let Some(key) = &self.key else {
+            return vec![];
+        };
+        let key = key.read();
+        values
+            .iter()
+            .filter_map(|value| {
+                let value = value.cast_to(&key.data_type).ok()?;
+                let partition = key.partitions.get(&value)?;
+                Some((value, *partition))
+            })
+            .collect()
+    }
+
+    /// Forget the partitions of `values`, which must have been emptied, so they're reused
+    pub(crate) fn forget(&self, values: &[ScalarValue]) {
+        if let Some(key) = &self.key {
+            let mut key = key.write();
+            for value in values {
+                if let Some(partition) = key.partitions.remove(value) {
+                    key.free.push(partition);
+                }
+            }
+        }
+    }
+
+    /// Rows and memory of the partition of each value of the partition key, by value
+    pub(crate) async fn stats(&self) -> Vec<PartitionStats> {
+        let Some(key) = &self.key else {
+            return vec![];
+        };
+        let values: Vec<(ScalarValue, usize)> = key
+            .read()
+            .partitions
+            .iter()
+            .map(|(value, partition)| (value.clone(), *partition))
+            .collect();
+        let list = self.get();
+        let mut stats = Vec::with_capacity(values.len());
+        for (value, partition) in values {
+            let batches = list[partition].read().await;
+            stats.push(PartitionStats {
+                value,
+                rows: batches.iter().map(RecordBatch::num_rows).sum(),
+                memory: batches.iter().map(RecordBatch::get_array_memory_size).sum(),
+            });
+        }
+        stats.sort_by(|a, b| a.value.partial_cmp(&b.value).unwrap_or(Ordering::Equal));
+        stats
+    }
+}
+
+impl PartitionKey {
+    fn partition(&self, value: &ScalarValue) -> Option<usize> {
+        let value = value.cast_to(&self.data_type).ok()?;
+        self.partitions.get(&value).copied()
+    }
+
+    /// Split `batches` by the value of the key, returning the partition of each piece. Values
+    /// without a partition get a free one or the next after `num_partitions`, in order.
+    fn assign(
+        &mut self,
+        batches: &[RecordBatch],
+        mut num_partitions: usize,
+    ) -> Result<Vec<(usize, RecordBatch)>> {
+        let mut assigned = vec![];
+        for batch in batches {
+            for (value, piece) in split(batch, self.column)? {
+                let partition = match self.partitions.get(&value) {
+                    Some(partition) => *partition,
+                    None => {
+                        let partition = self.free.pop().unwrap_or_else(|| {
+                            num_partitions += 1;
+                            num_partitions - 1
+                        });
+                        self.partitions.insert(value, partition);
+                        partition
+                    }
+                };
+                assigned.push((partition, piece));
+            }
+        }
+        Ok(assigned)
+    }
+
+    /// The values of the key that `filter` restricts it to, if it's an equality or `IN` filter
+    /// on the key
+    fn filtered_values(&self, filter: &Expr) -> Option<Vec<ScalarValue>> {
+        let is_key = |expr: &Expr| matches!(expr, Expr::Column(column) if column.name == self.name);
+        match filter {
+            Expr::BinaryExpr(binary) if binary.op == Operator::Eq => {
+                match (binary.left.as_ref(), binary.right.as_ref()) {
+                    (column, Expr::Literal(value)) | (Expr::Literal(value), column)
+                        if is_key(column) =>
+                    {
+                        Some(vec![value.clone()])
+                    }
+                    _ => None,
+                }
+            }
+            Expr::InList(InList {
+                expr,
+                list,
+                negated: false,
+            }) if is_key(expr) => list
+                .iter()
+                .map(|value| match value {
+                    Expr::Literal(value) => Some(value.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+/// Split `batch` into a batch for each value of column `column`, in the order the values first
+/// appear
+fn split(batch: &RecordBatch, column: usize) -> Result<Vec<(ScalarValue, RecordBatch)>> {
+    let keys = batch.column(column);
+    if batch.num_rows() == 0 {
+        return Ok(vec![]);
+    }
+    let converter = RowConverter::new(vec![SortField::new(keys.data_type().clone())])?;
+    let rows = converter.convert_columns(std::slice::from_ref(keys))?;
+    let mut groups: Vec<Vec<u32>> = vec![];
+    let mut group_of = HashMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        let group = *group_of.entry(row).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[group].push(i as u32);
+    }
+    if groups.len() == 1 {
+        return Ok(vec![(ScalarValue::try_from_array(keys, 0)?, batch.clone())]);
+    }
+    groups
+        .into_iter()
+        .map(|rows| {
+            let value = ScalarValue::try_from_array(keys, rows[0] as usize)?;
+            let indices = UInt32Array::from(rows);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|column| take(column, &indices, None))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let piece = RecordBatch::try_new(batch.schema(), columns)?;
+            Ok::<_, DataFusionError>((value, piece))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{Int32Array, StringArray};
+    use arrow::datatypes::{Field, Schema};
+    use datafusion::logical_expr::{col, lit};
+    use datafusion::physical_plan::collect;
+
+    use super::*;
+    use crate::session::new_context;
+    use crate::test_util::query;
+
+    fn batch(tenants: Vec<&str>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("tenant_id", DataType::Utf8, true),
+        ]));
+        let ids = Int32Array::from_iter_values(0..tenants.len() as i32);
+        RecordBatch::try_new(
+            schema,
+            vec![Arc::new(ids), Arc::new(StringArray::from(tenants))],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn values_get_partitions_of_their_own() -> Result<()> {
+        let first = batch(vec!["a", "b", "a", "c"]);
+        let (partitions, routed) =
+            Partitions::partitioned(&first.schema(), "tenant_id", vec![first])?;
+        let rows: Vec<usize> = routed
+            .iter()
+            .map(|batches| batches.iter().map(RecordBatch::num_rows).sum())
+            .collect();
+        assert_eq!(vec![2, 1, 1], rows);
+
+        // New values get new partitions, and dropped values' partitions are reused
+        let routed = partitions.route(vec![batch(vec!["c", "d"])])?;
+        assert_eq!(4, partitions.len());
+        assert_eq!(
+            vec![0, 0, 1, 1],
+            routed.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        partitions.forget(&[ScalarValue::from("b")]);
+        let routed = partitions.route(vec![batch(vec!["e"])])?;
+        assert_eq!(
+            vec![0, 1, 0, 0],
+            routed.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            Some(vec![1, 2]),
+            partitions.matching(&[col("tenant_id").in_list(vec![lit("c"), lit("e")], false)])
+        );
+        assert_eq!(
+            Some(vec![]),
+            partitions.matching(&[lit("b").eq(col("tenant_id"))])
+        );
+        assert_eq!(None, partitions.matching(&[col("id").eq(lit(1))]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenants_are_pruned_truncated_and_dropped() -> Result<()> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE orders (id INT PRIMARY KEY, tenant_id VARCHAR, total INT) \
+             PARTITION BY (tenant_id)",
+            "INSERT INTO orders VALUES (1, 'a', 10), (2, 'b', 20), (3, 'a', 30), (4, 'c', 40)",
+            "CREATE INDEX orders_total ON orders (total)",
+        ] {
+            query(&ctx, sql).await?;
+        }
+
+        // Scans only read the partitions of the tenants they filter on
+        let table = ctx.table_provider("orders").await?;
+        let filter = col("tenant_id").eq(lit("a"));
+        let scan = table.scan(&ctx.state(), None, &[filter], None).await?;
+        let batches = collect(scan, ctx.task_ctx()).await?;
+        assert_eq!(2, batches.iter().map(RecordBatch::num_rows).sum::<usize>());
+
+        assert_eq!(
+            "+-----------------+-----------+\n\
+             | partition_value | row_count |\n\
+             +-----------------+-----------+\n\
+             | a               | 2         |\n\
+             | b               | 1         |\n\
+             | c               | 1         |\n\
+             +-----------------+-----------+",
+            query(
+                &ctx,
+                "SELECT partition_value, row_count FROM qs_catalog.qs_partitions"
+            )
+            .await?
+        );
+
+        query(&ctx, "TRUNCATE TABLE orders PARTITION ('a')").await?;
+        query(&ctx, "ALTER TABLE orders DROP PARTITION (tenant_id = 'b')").await?;
+        query(&ctx, "INSERT INTO orders VALUES (5, 'd', 50)").await?;
+        assert_eq!(
+            "+-----------------+-----------+\n\
+             | partition_value | row_count |\n\
+             +-----------------+-----------+\n\
+             | a               | 0         |\n\
+             | c               | 1         |\n\
+             | d               | 1         |\n\
+             +-----------------+-----------+",
+            query(
+                &ctx,
+                "SELECT partition_value, row_count FROM qs_catalog.qs_partitions"
+            )
+            .await?
+        );
+        // The removed rows are gone from the indexes
+        assert_eq!(
+            "+----+-----------+-------+\n\
+             | id | tenant_id | total |\n\
+             +----+-----------+-------+\n\
+             | 5  | d         | 50    |\n\
+             +----+-----------+-------+",
+            query(&ctx, "SELECT * FROM orders WHERE total = 50").await?
+        );
+        assert_eq!(
+            "+----+-----------+-------+\n\
+             | id | tenant_id | total |\n\
+             +----+-----------+-------+\n\
+             | 4  | c         | 40    |\n\
+             | 5  | d         | 50    |\n\
+             +----+-----------+-------+",
+            query(&ctx, "SELECT * FROM orders ORDER BY id").await?
+        );
+
+        assert!(
+            query(&ctx, "UPDATE orders SET tenant_id = 'e' WHERE id = 4")
+                .await
+                .is_err()
+        );
+        assert!(query(&ctx, "TRUNCATE orders PARTITION (total = 40)")
+            .await
+            .is_err());
+        assert!(query(&ctx, "ALTER TABLE orders DROP COLUMN tenant_id")
+            .await
+            .is_err());
+        Ok(())
+    }
+}
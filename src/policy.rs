@@ -0,0 +1,460 @@
+//! Row-level security: policies that limit the rows of a memory table a session can see and
+//! change, based on the session's settings.
+//!
+//! `CREATE POLICY name ON table USING (expr)` adds a policy to a table. Once a table has a
+//! policy, every scan of it only returns the rows for which one of its policies is true, and
+//! `UPDATE`, `DELETE` and `INSERT` can only change and add such rows. Policies refer to the
+//! session with `current_setting('quokka.tenant_id')`, which is replaced by the value of the
+//! setting when a scan is planned, so one policy serves every tenant. Since the
+//! [`MemTable`](crate::table_provider::MemTable) applies the policies itself, they hold for
+//! prepared statements, joins and subqueries alike.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use arrow::datatypes::{DataType, SchemaRef};
+use datafusion::execution::context::{ExecutionProps, SessionState};
+use datafusion::logical_expr::{
+    AggregateUDF, ColumnarValue, Expr, ScalarUDF, ScalarUDFImpl, Signature, TableSource,
+    Volatility, WindowUDF,
+};
+use datafusion::optimizer::analyzer::AnalyzerRule;
+use datafusion::optimizer::simplify_expressions::{ExprSimplifier, SimplifyContext};
+use datafusion::scalar::ScalarValue;
+use datafusion_common::config::{ConfigExtension, ConfigOptions};
+use datafusion_common::tree_node::{Transformed, TreeNode};
+use datafusion_common::{exec_err, plan_err, DFSchema, DataFusionError, Result, TableReference};
+use datafusion_expr::expr::{Exists, InSubquery, ScalarFunction, ScalarFunctionDefinition};
+use datafusion_expr::{LogicalPlan, Subquery};
+use datafusion_sql::planner::{ContextProvider, PlannerContext, SqlToRel};
+use datafusion_sql::sqlparser::dialect::dialect_from_str;
+use datafusion_sql::sqlparser::parser::Parser;
+
+use crate::session::QuokkaOptions;
+
+/// Name of the function that returns the value of a session setting
+pub const CURRENT_SETTING: &str = "current_setting";
+
+/// `current_setting(name)`, the value of the session setting `name` as a string, or null if it
+/// isn't set or is empty. Calls are replaced by the value before execution, by
+/// [`CurrentSettingRule`] in queries and when a scan applies a table's policies.
+#[derive(Debug)]
+pub struct CurrentSetting {
+    signature: Signature,
+}
+
+impl CurrentSetting {
+    pub fn new() -> Self {
+        Self {
+            signature: Signature::exact(vec![DataType::Utf8], Volatility::Stable),
+        }
+    }
+}
+
+impl Default for CurrentSetting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScalarUDFImpl for CurrentSetting {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        CURRENT_SETTING
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType> {
+        Ok(DataType::Utf8)
+    }
+
+    fn invoke(&self, _args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        exec_err!("{CURRENT_SETTING} takes a literal setting name")
+    }
+}
+
+/// Replace every `current_setting('name')` in `expr` with the value of setting `name` in
+/// `config`, including in subqueries
+pub fn with_settings(expr: Expr, config: &ConfigOptions) -> Result<Expr> {
+    expr.transform_up(&|expr| {
+        Ok(match expr {
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                args,
+            }) if udf.name() == CURRENT_SETTING => match args.as_slice() {
+                [Expr::Literal(ScalarValue::Utf8(Some(name)))] => {
+                    let value = setting(config, name).filter(|value| !value.is_empty());
+                    Transformed::Yes(Expr::Literal(ScalarValue::Utf8(value)))
+                }
+                _ => return plan_err!("{CURRENT_SETTING} takes a literal setting name"),
+            },
+            Expr::ScalarSubquery(subquery) => Transformed::Yes(Expr::ScalarSubquery(
+                subquery_with_settings(subquery, config)?,
+            )),
+            Expr::Exists(Exists { subquery, negated }) => Transformed::Yes(Expr::Exists(Exists {
+                subquery: subquery_with_settings(subquery, config)?,
+                negated,
+            })),
+            Expr::InSubquery(InSubquery {
+                expr,
+                subquery,
+                negated,
+            }) => Transformed::Yes(Expr::InSubquery(InSubquery {
+                expr,
+                subquery: subquery_with_settings(subquery, config)?,
+                negated,
+            })),
+            expr => Transformed::No(expr),
+        })
+    })
+}
+
+/// The value of setting `name` of `config`. Extensions don't list their settings under their
+/// full names, so Quokka's are looked up in [`QuokkaOptions`].
+fn setting(config: &ConfigOptions, name: &str) -> Option<String> {
+    if let Some(name) = name.strip_prefix(&format!("{}.", QuokkaOptions::PREFIX)) {
+        return QuokkaOptions::from_config(config).get(name);
+    }
+    config
+        .entries()
+        .into_iter()
+        .find(|entry| entry.key == name)
+        .and_then(|entry| entry.value)
+}
+
+fn subquery_with_settings(subquery: Subquery, config: &ConfigOptions) -> Result<Subquery> {
+    Ok(Subquery {
+        subquery: Arc::new(plan_with_settings(subquery.subquery.as_ref(), config)?),
+        outer_ref_columns: subquery.outer_ref_columns,
+    })
+}
+
+fn plan_with_settings(plan: &LogicalPlan, config: &ConfigOptions) -> Result<LogicalPlan> {
+    plan.clone().transform_up(&|plan| {
+        let exprs = plan.expressions();
+        let new_exprs = exprs
+            .iter()
+            .map(|expr| with_settings(expr.clone(), config))
+            .collect::<Result<Vec<_>>>()?;
+        if new_exprs == exprs {
+            return Ok(Transformed::No(plan));
+        }
+        let inputs = plan.inputs().into_iter().cloned().collect();
+        Ok(Transformed::Yes(plan.with_new_exprs(new_exprs, inputs)?))
+    })
+}
+
+/// Analyzer rule that replaces `current_setting('name')` in queries with the setting's value. It
+/// must run before type coercion.
+#[derive(Debug, Default)]
+pub struct CurrentSettingRule {}
+
+impl CurrentSettingRule {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl AnalyzerRule for CurrentSettingRule {
+    fn analyze(&self, plan: LogicalPlan, config: &ConfigOptions) -> Result<LogicalPlan> {
+        plan_with_settings(&plan, config)
+    }
+
+    fn name(&self) -> &str {
+        "current_setting"
+    }
+}
+
+/// A table's policy: rows for which `using` is true are visible
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub name: String,
+    /// The policy's SQL, as logged
+    pub sql: String,
+    pub using: Expr,
+}
+
+impl Policy {
+    /// Plan the SQL expression `sql` as a policy over the columns of `schema`
+    pub fn try_new(
+        state: &SessionState,
+        name: &str,
+        schema: &SchemaRef,
+        sql: &str,
+    ) -> Result<Self> {
+        let dialect_name = &state.config_options().sql_parser.dialect;
+        let Some(dialect) = dialect_from_str(dialect_name) else {
+            return plan_err!("Unsupported SQL dialect: {dialect_name}");
+        };
+        let expr = Parser::new(dialect.as_ref())
+            .try_with_sql(sql)
+            .and_then(|mut parser| parser.parse_expr())
+            .map_err(|e| DataFusionError::SQL(e, None))?;
+        let df_schema = DFSchema::try_from(schema.as_ref().clone())?;
+        let provider = PolicyContextProvider { state };
+        let using =
+            SqlToRel::new(&provider).sql_to_expr(expr, &df_schema, &mut PlannerContext::new())?;
+        Ok(Self {
+            name: name.to_string(),
+            sql: sql.to_string(),
+            using,
+        })
+    }
+
+    /// Columns the policy refers to
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns: Vec<String> = self
+            .using
+            .to_columns()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|column| column.name)
+            .collect();
+        columns.sort();
+        columns
+    }
+}
+
+/// The predicate a row of a table with `policies` must satisfy to be visible to a session with
+/// `config`, or `None` if the table has no policies. Setting calls are replaced and the result
+/// coerced and simplified, so a filter like `tenant_id = 'acme'` can prune partitions.
+pub fn visible_rows(
+    policies: &[Policy],
+    schema: &SchemaRef,
+    config: &ConfigOptions,
+    props: &ExecutionProps,
+) -> Result<Option<Expr>> {
+    let Some(predicate) = policies
+        .iter()
+        .map(|policy| policy.using.clone())
+        .reduce(Expr::or)
+    else {
+        return Ok(None);
+    };
+    let predicate = with_settings(predicate, config)?;
+    let df_schema = Arc::new(DFSchema::try_from(schema.as_ref().clone())?);
+    let simplifier =
+        ExprSimplifier::new(SimplifyContext::new(props).with_schema(df_schema.clone()));
+    let predicate = simplifier.coerce(predicate, df_schema)?;
+    Ok(Some(simplifier.simplify(predicate)?))
+}
+
+/// Plans policies, which may only use the session's functions and the table's columns
+struct PolicyContextProvider<'a> {
+    state: &'a SessionState,
+}
+
+impl ContextProvider for PolicyContextProvider<'_> {
+    fn get_table_source(&self, name: TableReference) -> Result<Arc<dyn TableSource>> {
+        plan_err!("Policies can't refer to other tables, like {name}")
+    }
+
+    fn get_function_meta(&self, name: &str) -> Option<Arc<ScalarUDF>> {
+        self.state.scalar_functions().get(name).cloned()
+    }
+
+    fn get_aggregate_meta(&self, name: &str) -> Option<Arc<AggregateUDF>> {
+        self.state.aggregate_functions().get(name).cloned()
+    }
+
+    fn get_window_meta(&self, name: &str) -> Option<Arc<WindowUDF>> {
+        self.state.window_functions().get(name).cloned()
+    }
+
+    fn get_variable_type(&self, _variable_names: &[String]) -> Option<DataType> {
+        None
+    }
+
+    fn options(&self) -> &ConfigOptions {
+        self.state.config_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::util::pretty::pretty_format_batches;
+    use datafusion::execution::context::SessionContext;
+
+    use crate::session::new_context;
+    use crate::sql::{execute_logical_plan, sql_to_plan};
+    use crate::test_util::query;
+
+    use super::*;
+
+    async fn tenant_context() -> Result<SessionContext> {
+        let ctx = new_context();
+        for sql in [
+            "CREATE TABLE docs (id INT PRIMARY KEY, tenant_id VARCHAR, body VARCHAR)",
+            "CREATE INDEX docs_body ON docs (body)",
+            "INSERT INTO docs VALUES (1, 'acme', 'a'), (2, 'acme', 'b'), (3, 'globex', 'a')",
+            "CREATE TABLE tags (id INT PRIMARY KEY, doc INT, tag VARCHAR)",
+            "INSERT INTO tags VALUES (1, 1, 'x'), (2, 3, 'y')",
+            "CREATE POLICY tenant_isolation ON docs \
+             USING (tenant_id = current_setting('quokka.tenant_id'))",
+        ] {
+            query(&ctx, sql).await?;
+        }
+        Ok(ctx)
+    }
+
+    #[tokio::test]
+    async fn policies_limit_reads_to_the_sessions_tenant() -> Result<()> {
+        let ctx = tenant_context().await?;
+        // Without a tenant, nothing is visible
+        assert_eq!(
+            query(&ctx, "SELECT COUNT(*) AS n FROM docs").await?,
+            "+---+\n| n |\n+---+\n| 0 |\n+---+"
+        );
+
+        // A plan prepared for one tenant sees the rows of the tenant it's executed for
+        let plan = sql_to_plan(&ctx.state(), "SELECT id FROM docs ORDER BY id").await?;
+        query(&ctx, "SET quokka.tenant_id = 'acme'").await?;
+        let expected = ["+----+", "| id |", "+----+", "| 1  |", "| 2  |", "+----+"];
+        let batches = execute_logical_plan(&ctx, plan.clone())
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(
+            pretty_format_batches(&batches)?.to_string(),
+            expected.join("\n")
+        );
+
+        // Index scans, index joins and subqueries are limited too
+        assert_eq!(
+            query(&ctx, "SELECT id FROM docs WHERE id = 3").await?,
+            "+----+\n| id |\n+----+\n+----+"
+        );
+        assert_eq!(
+            query(&ctx, "SELECT id FROM docs WHERE body = 'a'").await?,
+            "+----+\n| id |\n+----+\n| 1  |\n+----+"
+        );
+        assert_eq!(
+            query(
+                &ctx,
+                "SELECT tags.tag FROM tags JOIN docs ON tags.doc = docs.id"
+            )
+            .await?,
+            "+-----+\n| tag |\n+-----+\n| x   |\n+-----+"
+        );
+        assert_eq!(
+            query(&ctx, "SELECT (SELECT COUNT(*) FROM docs) AS n").await?,
+            "+---+\n| n |\n+---+\n| 2 |\n+---+"
+        );
+        assert_eq!(
+            query(&ctx, "SELECT current_setting('quokka.tenant_id') AS tenant").await?,
+            "+--------+\n| tenant |\n+--------+\n| acme   |\n+--------+"
+        );
+
+        query(&ctx, "SET quokka.tenant_id = 'globex'").await?;
+        let batches = execute_logical_plan(&ctx, plan).await?.collect().await?;
+        assert_eq!(
+            pretty_format_batches(&batches)?.to_string(),
+            "+----+\n| id |\n+----+\n| 3  |\n+----+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn policies_limit_writes_to_the_sessions_tenant() -> Result<()> {
+        let ctx = tenant_context().await?;
+        query(&ctx, "SET quokka.tenant_id = 'acme'").await?;
+
+        // Other tenants' rows can't be changed
+        query(&ctx, "DELETE FROM docs WHERE body = 'a'").await?;
+        query(&ctx, "UPDATE docs SET body = 'c'").await?;
+        let err = query(&ctx, "UPDATE docs SET tenant_id = 'globex'")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("row-level security"), "{err}");
+        let err = query(&ctx, "INSERT INTO docs VALUES (4, 'globex', 'd')")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("row-level security"), "{err}");
+        query(&ctx, "INSERT INTO docs VALUES (4, 'acme', 'd')").await?;
+
+        query(&ctx, "DROP POLICY tenant_isolation ON docs").await?;
+        let expected = [
+            "+----+-----------+------+",
+            "| id | tenant_id | body |",
+            "+----+-----------+------+",
+            "| 2  | acme      | c    |",
+            "| 3  | globex    | a    |",
+            "| 4  | acme      | d    |",
+            "+----+-----------+------+",
+        ];
+        assert_eq!(
+            query(&ctx, "SELECT * FROM docs ORDER BY id").await?,
+            expected.join("\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn policies_are_managed_through_the_catalog() -> Result<()> {
+        let ctx = tenant_context().await?;
+        let expected = [
+            "+------------------+-------------------------------------------------+",
+            "| policy_name      | using                                           |",
+            "+------------------+-------------------------------------------------+",
+            "| tenant_isolation | tenant_id = current_setting('quokka.tenant_id') |",
+            "+------------------+-------------------------------------------------+",
+        ];
+        assert_eq!(
+            query(
+                &ctx,
+                "SELECT policy_name, using FROM qs_catalog.qs_policies"
+            )
+            .await?,
+            expected.join("\n")
+        );
+
+        let err = query(&ctx, "ALTER TABLE docs DROP COLUMN tenant_id")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("policy tenant_isolation"), "{err}");
+        let err = query(
+            &ctx,
+            "CREATE POLICY tenant_isolation ON docs USING (tenant_id = 'acme')",
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+        let err = query(
+            &ctx,
+            "CREATE POLICY other ON docs USING (id IN (SELECT doc FROM tags))",
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("other tables"), "{err}");
+
+        // Policies are combined with OR, like Postgres' permissive policies
+        query(
+            &ctx,
+            "CREATE POLICY public ON docs FOR ALL USING (body = 'b')",
+        )
+        .await?;
+        query(&ctx, "SET quokka.tenant_id = 'globex'").await?;
+        assert_eq!(
+            query(&ctx, "SELECT id FROM docs ORDER BY id").await?,
+            "+----+\n| id |\n+----+\n| 2  |\n| 3  |\n+----+"
+        );
+
+        query(&ctx, "DROP POLICY tenant_isolation ON docs").await?;
+        query(&ctx, "DROP POLICY IF EXISTS tenant_isolation ON docs").await?;
+        assert!(query(&ctx, "DROP POLICY tenant_isolation ON docs")
+            .await
+            .is_err());
+        query(&ctx, "ALTER TABLE docs DROP COLUMN tenant_id").await?;
+        assert_eq!(
+            query(&ctx, "SELECT id FROM docs").await?,
+            "+----+\n| id |\n+----+\n| 2  |\n+----+"
+        );
+        Ok(())
+    }
+}
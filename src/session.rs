@@ -0,0 +1,147 @@
+//! Session setup shared by the Flight SQL server and tests: Quokka's configuration options and
+//! physical optimizer rules.
+
+use std::sync::Arc;
+
+use datafusion::catalog::CatalogProviderList;
+use datafusion::execution::context::{SessionContext, SessionState};
+use datafusion::logical_expr::ScalarUDF;
+use datafusion::optimizer::analyzer::{Analyzer, AnalyzerRule};
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::prelude::SessionConfig;
+use datafusion_common::config::{ConfigExtension, ConfigOptions};
+use datafusion_common::extensions_options;
+use datafusion_execution::registry::FunctionRegistry;
+use datafusion_execution::runtime_env::RuntimeEnv;
+
+use crate::analysis::TokenizeFunction;
+use crate::fuzzy::SimilarityFunction;
+use crate::index_join::IndexJoinRule;
+use crate::index_scan::IndexScanRule;
+use crate::policy::{CurrentSetting, CurrentSettingRule};
+use crate::system_catalog::system_catalog;
+use crate::text_search::{MatchFunction, ScoreFunction, TextSearchRule};
+
+extensions_options! {
+    /// Quokka specific settings, changed with `SET quokka.<name> = <value>`
+    pub struct QuokkaOptions {
+        /// Replace filtered table scans with index scans when an index matches the filters
+        pub enable_index_scan: bool, default = true
+        /// Replace equi-joins on an indexed column with index probes when the other side of the
+        /// join is smaller than the indexed table
+        pub enable_index_join: bool, default = true
+        /// How often block tables free old versions no snapshot sees and move the rows of sparse
+        /// blocks into dense ones, or 0 to never do either
+        pub block_compaction_interval_ms: u64, default = 1000
+        /// Fraction of a full block's slots that may still hold rows for the block to be
+        /// compacted
+        pub block_compaction_max_fill: f64, default = 0.5
+        /// Name the session authenticated as, set by the Flight SQL handshake, or empty
+        pub user: String, default = String::new()
+        /// Tenant the session acts for, which row-level security policies can refer to with
+        /// `current_setting('quokka.tenant_id')`, or empty. Set by the Flight SQL handshake
+        /// from the `quokka-tenant-id` header, which only superusers may send once roles exist.
+        pub tenant_id: String, default = String::new()
+    }
+}
+
+impl ConfigExtension for QuokkaOptions {
+    const PREFIX: &'static str = "quokka";
+}
+
+impl QuokkaOptions {
+    /// The options registered in `config`, or the defaults if there are none
+    pub fn from_config(config: &ConfigOptions) -> Self {
+        config
+            .extensions
+            .get::<QuokkaOptions>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The value of setting `quokka.<name>` as a string
+    pub fn get(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "enable_index_scan" => self.enable_index_scan.to_string(),
+            "enable_index_join" => self.enable_index_join.to_string(),
+            "block_compaction_interval_ms" => self.block_compaction_interval_ms.to_string(),
+            "block_compaction_max_fill" => self.block_compaction_max_fill.to_string(),
+            "user" => self.user.clone(),
+            "tenant_id" => self.tenant_id.clone(),
+            _ => return None,
+        })
+    }
+}
+
+/// Create a session state with Quokka's options, keeping any already in `config`, and optimizer
+/// rules
+pub fn new_session_state(
+    mut config: SessionConfig,
+    runtime: Arc<RuntimeEnv>,
+    catalog_list: Arc<dyn CatalogProviderList>,
+) -> SessionState {
+    let extensions = &mut config.options_mut().extensions;
+    if extensions.get::<QuokkaOptions>().is_none() {
+        extensions.insert(QuokkaOptions::default());
+    }
+    let state = SessionState::new_with_config_rt_and_catalog_list(config, runtime, catalog_list);
+    with_quokka_rules(state)
+}
+
+/// Create a session context with Quokka's options and optimizer rules and DataFusion's default
+/// catalog, holding the system catalog
+pub fn new_context() -> SessionContext {
+    new_context_with_config(SessionConfig::new())
+}
+
+/// Create a session context like [`new_context`] with the settings and extensions of `config`,
+/// such as a pinned [`ActiveSnapshot`](crate::transaction::ActiveSnapshot) or a
+/// [`Writer`](crate::writer::Writer)
+pub fn new_context_with_config(mut config: SessionConfig) -> SessionContext {
+    config
+        .options_mut()
+        .extensions
+        .insert(QuokkaOptions::default());
+    let state = SessionState::new_with_config_rt(config, Arc::new(RuntimeEnv::default()));
+    let ctx = SessionContext::new_with_state(with_quokka_rules(state));
+    system_catalog(&ctx).expect("the default catalog can hold the system catalog");
+    ctx
+}
+
+/// Run Quokka's rules before DataFusion's, which expect to see the final scans. Index joins and
+/// scans in particular must be chosen before the scans are repartitioned, and index joins before
+/// index scans so the joins can still see filtered scans. `current_setting` calls are replaced
+/// before anything else analyzes the plan, since type coercion needs their values, and
+/// `score()` is bound to its `match()` before coercion can change their arguments.
+fn with_quokka_rules(mut state: SessionState) -> SessionState {
+    let mut rules: Vec<Arc<dyn PhysicalOptimizerRule + Send + Sync>> = vec![
+        Arc::new(IndexJoinRule::new()),
+        Arc::new(IndexScanRule::new()),
+    ];
+    rules.extend(state.physical_optimizers().iter().cloned());
+    let mut analyzer_rules: Vec<Arc<dyn AnalyzerRule + Send + Sync>> = vec![
+        Arc::new(CurrentSettingRule::new()),
+        Arc::new(TextSearchRule::new()),
+    ];
+    analyzer_rules.extend(Analyzer::new().rules);
+    state
+        .register_udf(Arc::new(ScalarUDF::new_from_impl(CurrentSetting::new())))
+        .expect("current_setting can be registered");
+    state
+        .register_udf(Arc::new(ScalarUDF::new_from_impl(MatchFunction::new())))
+        .expect("match can be registered");
+    state
+        .register_udf(Arc::new(ScalarUDF::new_from_impl(ScoreFunction::new())))
+        .expect("score can be registered");
+    state
+        .register_udf(Arc::new(ScalarUDF::new_from_impl(TokenizeFunction::new())))
+        .expect("quokka_tokenize can be registered");
+    state
+        .register_udf(Arc::new(
+            ScalarUDF::new_from_impl(SimilarityFunction::new()),
+        ))
+        .expect("similarity can be registered");
+    state
+        .with_physical_optimizer_rules(rules)
+        .with_analyzer_rules(analyzer_rules)
+}
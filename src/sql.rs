@@ -0,0 +1,2139 @@
+//! Planning and execution of the SQL statements Quokka handles itself rather than leaving to
+//! DataFusion.
+//!
+//! DataFusion's SQL planner doesn't know about indexes, so statements like `CREATE INDEX` are
+//! planned here as a [`QuokkaStatementNode`] extension node. DataFusion also creates its own
+//! `MemTable` for `CREATE TABLE` and can't execute `UPDATE` or `DELETE`, so
+//! [`execute_logical_plan`] intercepts those plans and runs them against our
+//! [`MemTable`](crate::table_provider::MemTable) or [`BlockTable`] instead.
+//! `CREATE TABLE ... WITH (storage = 'block')` creates a [`BlockTable`], `CREATE TABLE ...
+//! PARTITION BY (column)` a memory table partitioned by the column (see [`crate::partition`]),
+//! whose partitions `TRUNCATE t PARTITION (values)` and `ALTER TABLE t DROP PARTITION (values)`
+//! empty and drop, and `ALTER TABLE` changes the columns of either kind of table (see
+//! [`crate::alter_table`]). `DROP TABLE`, `CREATE SCHEMA` and `DROP SCHEMA` go through the system
+//! catalog (see [`crate::drop_table`]). `CHECKPOINT`, which DataFusion can't even parse, is
+//! recognized by [`sql_to_plan`] and takes a checkpoint (see [`crate::checkpoint`]), as are
+//! `CREATE POLICY` and `DROP POLICY`, which manage a memory table's row-level security policies
+//! (see [`crate::policy`]). Roles, users and their privileges are managed with `CREATE ROLE`,
+//! `CREATE USER`, `ALTER ROLE`, `DROP ROLE`, `GRANT` and `REVOKE` (see [`crate::auth`]), and text
+//! analyzers with `CREATE TEXT ANALYZER` and `DROP TEXT ANALYZER` (see [`crate::analysis`]), which
+//! `CREATE INDEX ... WITH (analyzer = '...')` gives a fulltext index. Queries using `match()` and
+//! `score()` are prepared for planning by [`sql_to_plan`] as well (see [`crate::text_search`]),
+//! and it binds `quokka_tokenize()` to the analyzer it names.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arrow::array::UInt64Array;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use datafusion::catalog::schema::SchemaProvider;
+use datafusion::dataframe::DataFrame;
+use datafusion::datasource::TableProvider;
+use datafusion::error::Result;
+use datafusion::execution::context::{SessionContext, SessionState};
+use datafusion::logical_expr::{
+    CreateMemoryTable, DdlStatement, DmlStatement, Expr, Extension, LogicalPlan,
+    LogicalPlanBuilder, UserDefinedLogicalNodeCore, WriteOp,
+};
+use datafusion::optimizer::analyzer::type_coercion::TypeCoercion;
+use datafusion::optimizer::analyzer::AnalyzerRule;
+use datafusion::scalar::ScalarValue;
+use datafusion_common::{
+    exec_err, not_impl_err, plan_err, Constraint, DFSchema, DFSchemaRef, DataFusionError,
+    OwnedTableReference, ResolvedTableReference,
+};
+use datafusion_sql::parser::Statement as DFStatement;
+use datafusion_sql::planner::{object_name_to_table_reference, IdentNormalizer};
+use datafusion_sql::sqlparser::ast::helpers::stmt_create_table::CreateTableBuilder;
+use datafusion_sql::sqlparser::ast::{self, ObjectName, ObjectType};
+use datafusion_sql::sqlparser::dialect::dialect_from_str;
+use datafusion_sql::sqlparser::keywords::Keyword;
+use datafusion_sql::sqlparser::parser::Parser;
+use datafusion_sql::sqlparser::tokenizer::{Token, Tokenizer};
+use tokio::sync::OwnedRwLockReadGuard;
+
+use crate::alter_table::{alter_table, AlterTableOperation};
+use crate::analysis::{bind_analyzers, create_analyzer, drop_analyzers, Analyzer};
+use crate::auth::{
+    alter_role, create_role, drop_roles, grant, grant_roles, GrantObject, Privilege, Role,
+    RoleAttributes,
+};
+use crate::block_table::BlockTable;
+use crate::catalog::{next_table_id, TableId};
+use crate::checkpoint::checkpoint;
+use crate::drop_table::{create_schema, drop_schema, drop_tables};
+use crate::index::IndexMethod;
+use crate::session::QuokkaOptions;
+use crate::system_catalog::{
+    system_catalog, system_catalog_of, table_id, table_lock, IndexEntry, TableEntry,
+};
+use crate::table_provider::MemTable;
+use crate::text_search::{bind_scores, check_text_queries, quote_match_calls};
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied};
+
+/// How a table created with `CREATE TABLE` stores its rows, chosen with
+/// `WITH (storage = '...')`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TableStorage {
+    /// A [`MemTable`] of record batches, the default
+    #[default]
+    Memory,
+    /// A [`BlockTable`] of fixed-slot blocks
+    Block,
+}
+
+impl fmt::Display for TableStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableStorage::Memory => write!(f, "memory"),
+            TableStorage::Block => write!(f, "block"),
+        }
+    }
+}
+
+/// A statement that Quokka executes itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum QuokkaStatement {
+    /// `CREATE [UNIQUE] INDEX name ON table [USING method] (columns) [WITH (analyzer = '...')]`
+    CreateIndex {
+        name: String,
+        table: OwnedTableReference,
+        columns: Vec<String>,
+        unique: bool,
+        method: IndexMethod,
+        /// Text analyzer of a fulltext index, if not the standard one
+        analyzer: Option<String>,
+        if_not_exists: bool,
+    },
+    /// `DROP INDEX [IF EXISTS] name`
+    DropIndex {
+        /// Schema containing the index, if qualified
+        schema: Option<String>,
+        name: String,
+        if_exists: bool,
+    },
+    /// `CREATE TABLE ... [WITH (storage = '...')] [PARTITION BY (column)]`
+    CreateTable {
+        storage: TableStorage,
+        partition_by: Option<String>,
+        /// DataFusion's `CreateMemoryTable` plan for the statement without its options
+        plan: Box<LogicalPlan>,
+    },
+    /// `DROP TABLE [IF EXISTS] table, ... [CASCADE | RESTRICT]`
+    DropTable {
+        names: Vec<OwnedTableReference>,
+        if_exists: bool,
+        cascade: bool,
+    },
+    /// `ALTER TABLE [IF EXISTS] table operation, ...`
+    AlterTable {
+        table: OwnedTableReference,
+        if_exists: bool,
+        operations: Vec<AlterTableOperation>,
+    },
+    /// `TRUNCATE [TABLE] table PARTITION (values)`, or with `drop`
+    /// `ALTER TABLE table DROP [IF EXISTS] PARTITION (values)`. Each value may name the partition
+    /// key as `column = value`.
+    TruncatePartitions {
+        table: OwnedTableReference,
+        values: Vec<(Option<String>, ScalarValue)>,
+        drop: bool,
+    },
+    /// `CREATE POLICY name ON table [FOR ALL] USING (expr)`
+    CreatePolicy {
+        name: String,
+        table: OwnedTableReference,
+        /// SQL of the expression rows must satisfy to be visible
+        using: String,
+    },
+    /// `DROP POLICY [IF EXISTS] name ON table`
+    DropPolicy {
+        name: String,
+        table: OwnedTableReference,
+        if_exists: bool,
+    },
+    /// `CREATE ROLE [IF NOT EXISTS] name [WITH] [[NO]LOGIN] [[NO]SUPERUSER] [PASSWORD '...']
+    /// [TENANT '...']`, or `CREATE USER`, which defaults to `LOGIN`
+    CreateRole {
+        name: String,
+        login: bool,
+        superuser: bool,
+        password: Option<String>,
+        tenant_id: Option<String>,
+        if_not_exists: bool,
+    },
+    /// `ALTER ROLE name [WITH] options`, or `ALTER USER`, with the options of `CREATE ROLE`
+    AlterRole {
+        name: String,
+        attributes: RoleAttributes,
+    },
+    /// `DROP ROLE [IF EXISTS] name, ...` or `DROP USER`
+    DropRole { names: Vec<String>, if_exists: bool },
+    /// `GRANT privileges ON objects TO grantees`, or with `revoke`
+    /// `REVOKE privileges ON objects FROM grantees`
+    Grant {
+        privileges: Vec<Privilege>,
+        objects: Vec<GrantObject>,
+        grantees: Vec<String>,
+        revoke: bool,
+    },
+    /// `GRANT roles TO members`, or with `revoke` `REVOKE roles FROM members`
+    GrantRole {
+        roles: Vec<String>,
+        members: Vec<String>,
+        revoke: bool,
+    },
+    /// `CREATE TEXT ANALYZER [IF NOT EXISTS] name TOKENIZER tokenizer [FILTERS filter, ...]`
+    CreateTextAnalyzer {
+        name: String,
+        analyzer: Analyzer,
+        if_not_exists: bool,
+    },
+    /// `DROP TEXT ANALYZER [IF EXISTS] name, ...`
+    DropTextAnalyzer { names: Vec<String>, if_exists: bool },
+    /// `CHECKPOINT`
+    Checkpoint,
+}
+
+impl fmt::Display for QuokkaStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuokkaStatement::CreateIndex {
+                name,
+                table,
+                columns,
+                unique,
+                method,
+                analyzer,
+                ..
+            } => {
+                let unique = if *unique { "UNIQUE " } else { "" };
+                let method = match method {
+                    IndexMethod::BTree => String::new(),
+                    method => format!(" USING {method}"),
+                };
+                write!(
+                    f,
+                    "CREATE {unique}INDEX {name} ON {table}{method} ({})",
+                    columns.join(", ")
+                )?;
+                match analyzer {
+                    Some(analyzer) => write!(f, " WITH (analyzer = '{analyzer}')"),
+                    None => Ok(()),
+                }
+            }
+            QuokkaStatement::DropIndex { name, .. } => write!(f, "DROP INDEX {name}"),
+            QuokkaStatement::CreateTable {
+                storage,
+                partition_by,
+                plan,
+            } => match plan.as_ref() {
+                LogicalPlan::Ddl(DdlStatement::CreateMemoryTable(cmd)) => {
+                    write!(f, "CREATE TABLE {} WITH (storage = '{storage}')", cmd.name)?;
+                    match partition_by {
+                        Some(column) => write!(f, " PARTITION BY ({column})"),
+                        None => Ok(()),
+                    }
+                }
+                plan => write!(f, "{}", plan.display()),
+            },
+            QuokkaStatement::DropTable { names, cascade, .. } => {
+                let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+                let cascade = if *cascade { " CASCADE" } else { "" };
+                write!(f, "DROP TABLE {}{cascade}", names.join(", "))
+            }
+            QuokkaStatement::AlterTable {
+                table, operations, ..
+            } => {
+                let operations: Vec<String> = operations.iter().map(|o| o.to_string()).collect();
+                write!(f, "ALTER TABLE {table} {}", operations.join(", "))
+            }
+            QuokkaStatement::TruncatePartitions {
+                table,
+                values,
+                drop,
+            } => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|(column, value)| match column {
+                        Some(column) => format!("{column} = {value}"),
+                        None => value.to_string(),
+                    })
+                    .collect();
+                let values = values.join(", ");
+                if *drop {
+                    write!(f, "ALTER TABLE {table} DROP PARTITION ({values})")
+                } else {
+                    write!(f, "TRUNCATE TABLE {table} PARTITION ({values})")
+                }
+            }
+            QuokkaStatement::CreatePolicy { name, table, using } => {
+                write!(f, "CREATE POLICY {name} ON {table} USING ({using})")
+            }
+            QuokkaStatement::DropPolicy { name, table, .. } => {
+                write!(f, "DROP POLICY {name} ON {table}")
+            }
+            // Never show the password
+            QuokkaStatement::CreateRole {
+                name,
+                login,
+                superuser,
+                ..
+            } => {
+                let login = if *login { " LOGIN" } else { "" };
+                let superuser = if *superuser { " SUPERUSER" } else { "" };
+                write!(f, "CREATE ROLE {name}{login}{superuser}")
+            }
+            QuokkaStatement::AlterRole { name, .. } => write!(f, "ALTER ROLE {name}"),
+            QuokkaStatement::DropRole { names, .. } => write!(f, "DROP ROLE {}", names.join(", ")),
+            QuokkaStatement::Grant {
+                privileges,
+                objects,
+                grantees,
+                revoke,
+            } => {
+                let privileges: Vec<String> = privileges.iter().map(|p| p.to_string()).collect();
+                let objects: Vec<String> = objects.iter().map(|o| o.to_string()).collect();
+                let (verb, to) = if *revoke {
+                    ("REVOKE", "FROM")
+                } else {
+                    ("GRANT", "TO")
+                };
+                write!(
+                    f,
+                    "{verb} {} ON {} {to} {}",
+                    privileges.join(", "),
+                    objects.join(", "),
+                    grantees.join(", ")
+                )
+            }
+            QuokkaStatement::GrantRole {
+                roles,
+                members,
+                revoke,
+            } => {
+                let (verb, to) = if *revoke {
+                    ("REVOKE", "FROM")
+                } else {
+                    ("GRANT", "TO")
+                };
+                write!(f, "{verb} {} {to} {}", roles.join(", "), members.join(", "))
+            }
+            QuokkaStatement::CreateTextAnalyzer { name, analyzer, .. } => {
+                write!(f, "CREATE TEXT ANALYZER {name} {analyzer}")
+            }
+            QuokkaStatement::DropTextAnalyzer { names, .. } => {
+                write!(f, "DROP TEXT ANALYZER {}", names.join(", "))
+            }
+            QuokkaStatement::Checkpoint => write!(f, "CHECKPOINT"),
+        }
+    }
+}
+
+/// Logical plan node wrapping a [`QuokkaStatement`] so it can be prepared and executed like any
+/// other plan
+#[derive(Debug, PartialEq, Eq)]
+pub struct QuokkaStatementNode {
+    pub statement: QuokkaStatement,
+    schema: DFSchemaRef,
+}
+
+impl QuokkaStatementNode {
+    pub fn new(statement: QuokkaStatement) -> Self {
+        Self {
+            statement,
+            schema: Arc::new(DFSchema::empty()),
+        }
+    }
+}
+
+impl Hash for QuokkaStatementNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.statement.hash(state);
+    }
+}
+
+impl UserDefinedLogicalNodeCore for QuokkaStatementNode {
+    fn name(&self) -> &str {
+        "QuokkaStatement"
+    }
+
+    fn inputs(&self) -> Vec<&LogicalPlan> {
+        vec![]
+    }
+
+    fn schema(&self) -> &DFSchemaRef {
+        &self.schema
+    }
+
+    fn expressions(&self) -> Vec<Expr> {
+        vec![]
+    }
+
+    fn fmt_for_explain(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QuokkaStatement: {}", self.statement)
+    }
+
+    fn from_template(&self, _exprs: &[Expr], _inputs: &[LogicalPlan]) -> Self {
+        Self::new(self.statement.clone())
+    }
+}
+
+/// Parse and plan `sql`, handling the statements DataFusion's parser and planner don't support
+pub async fn sql_to_plan(state: &SessionState, sql: &str) -> Result<LogicalPlan> {
+    let dialect = state.config_options().sql_parser.dialect.clone();
+    let normalize = state.config_options().sql_parser.enable_ident_normalization;
+    let statement = if is_checkpoint(sql, &dialect)? {
+        Some(QuokkaStatement::Checkpoint)
+    } else if let Some(statement) = parse_policy(sql, &dialect, normalize)? {
+        Some(statement)
+    } else if let Some(statement) = parse_text_analyzer(sql, &dialect, normalize)? {
+        Some(statement)
+    } else if let Some(statement) = parse_create_index(sql, &dialect, normalize)? {
+        Some(statement)
+    } else {
+        parse_role(sql, &dialect, normalize)?
+    };
+    if let Some(statement) = statement {
+        return Ok(LogicalPlan::Extension(Extension {
+            node: Arc::new(QuokkaStatementNode::new(statement)),
+        }));
+    }
+    let sql = quote_match_calls(sql, &dialect)?;
+    let mut statement = state.sql_to_statement(&sql, &dialect)?;
+    bind_scores(&mut statement)?;
+    check_text_queries(&statement)?;
+    let plan = statement_to_plan(state, statement).await?;
+    let system_catalog = system_catalog_of(state)?;
+    bind_analyzers(plan, &system_catalog)
+}
+
+/// Whether `sql` is a `CHECKPOINT` statement, which `sqlparser` can't parse
+fn is_checkpoint(sql: &str, dialect: &str) -> Result<bool> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let tokens = Tokenizer::new(dialect.as_ref(), sql)
+        .tokenize()
+        .map_err(|e| DataFusionError::SQL(e.into(), None))?;
+    let mut words = tokens
+        .iter()
+        .filter(|token| !matches!(token, Token::Whitespace(_) | Token::SemiColon));
+    Ok(match (words.next(), words.next()) {
+        (Some(Token::Word(word)), None) => {
+            word.quote_style.is_none() && word.value.eq_ignore_ascii_case("checkpoint")
+        }
+        _ => false,
+    })
+}
+
+/// Parse `sql` if it's a `CREATE POLICY` or `DROP POLICY` statement, which `sqlparser` can't
+/// parse
+fn parse_policy(sql: &str, dialect: &str, normalize: bool) -> Result<Option<QuokkaStatement>> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let mut parser = Parser::new(dialect.as_ref())
+        .try_with_sql(sql)
+        .map_err(sql_error)?;
+    let create = if parser.parse_keyword(Keyword::CREATE) {
+        true
+    } else if parser.parse_keyword(Keyword::DROP) {
+        false
+    } else {
+        return Ok(None);
+    };
+    match parser.next_token().token {
+        Token::Word(word)
+            if word.quote_style.is_none() && word.value.eq_ignore_ascii_case("policy") => {}
+        _ => return Ok(None),
+    }
+    let if_exists = !create && parser.parse_keywords(&[Keyword::IF, Keyword::EXISTS]);
+    let name = IdentNormalizer::new(normalize)
+        .normalize(parser.parse_identifier(false).map_err(sql_error)?);
+    parser.expect_keyword(Keyword::ON).map_err(sql_error)?;
+    let table = object_name_to_table_reference(
+        parser.parse_object_name(false).map_err(sql_error)?,
+        normalize,
+    )?;
+    let statement = if create {
+        if parser.parse_keyword(Keyword::FOR) && !parser.parse_keyword(Keyword::ALL) {
+            return not_impl_err!("Policies only support FOR ALL");
+        }
+        parser.expect_keyword(Keyword::USING).map_err(sql_error)?;
+        parser.expect_token(&Token::LParen).map_err(sql_error)?;
+        let using = parser.parse_expr().map_err(sql_error)?;
+        parser.expect_token(&Token::RParen).map_err(sql_error)?;
+        QuokkaStatement::CreatePolicy {
+            name,
+            table,
+            using: using.to_string(),
+        }
+    } else {
+        QuokkaStatement::DropPolicy {
+            name,
+            table,
+            if_exists,
+        }
+    };
+    let _ = parser.consume_token(&Token::SemiColon);
+    parser.expect_token(&Token::EOF).map_err(sql_error)?;
+    Ok(Some(statement))
+}
+
+/// Parse `sql` if it's a `CREATE TEXT ANALYZER` or `DROP TEXT ANALYZER` statement, which
+/// `sqlparser` can't parse
+fn parse_text_analyzer(
+    sql: &str,
+    dialect: &str,
+    normalize: bool,
+) -> Result<Option<QuokkaStatement>> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let mut parser = Parser::new(dialect.as_ref())
+        .try_with_sql(sql)
+        .map_err(sql_error)?;
+    let create = if parser.parse_keyword(Keyword::CREATE) {
+        true
+    } else if parser.parse_keyword(Keyword::DROP) {
+        false
+    } else {
+        return Ok(None);
+    };
+    if !parser.parse_keyword(Keyword::TEXT) {
+        return Ok(None);
+    }
+    match parser.next_token().token {
+        Token::Word(word)
+            if word.quote_style.is_none() && word.value.eq_ignore_ascii_case("analyzer") => {}
+        _ => return Ok(None),
+    }
+    let normalizer = IdentNormalizer::new(normalize);
+    let statement = if create {
+        let if_not_exists = parser.parse_keywords(&[Keyword::IF, Keyword::NOT, Keyword::EXISTS]);
+        let name = normalizer.normalize(parser.parse_identifier(false).map_err(sql_error)?);
+        QuokkaStatement::CreateTextAnalyzer {
+            name,
+            analyzer: Analyzer::parse(&mut parser)?,
+            if_not_exists,
+        }
+    } else {
+        let if_exists = parser.parse_keywords(&[Keyword::IF, Keyword::EXISTS]);
+        let names = parser
+            .parse_comma_separated(|p| p.parse_identifier(false))
+            .map_err(sql_error)?;
+        QuokkaStatement::DropTextAnalyzer {
+            names: names.into_iter().map(|n| normalizer.normalize(n)).collect(),
+            if_exists,
+        }
+    };
+    let _ = parser.consume_token(&Token::SemiColon);
+    parser.expect_token(&Token::EOF).map_err(sql_error)?;
+    Ok(Some(statement))
+}
+
+/// Parse `sql` if it's a `CREATE INDEX` statement, which `sqlparser` parses without the
+/// `WITH (options)` that may follow it
+fn parse_create_index(
+    sql: &str,
+    dialect: &str,
+    normalize: bool,
+) -> Result<Option<QuokkaStatement>> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let mut parser = Parser::new(dialect.as_ref())
+        .try_with_sql(sql)
+        .map_err(sql_error)?;
+    if !parser.parse_keyword(Keyword::CREATE) {
+        return Ok(None);
+    }
+    let _ = parser.parse_keyword(Keyword::UNIQUE);
+    if !parser.parse_keyword(Keyword::INDEX) {
+        return Ok(None);
+    }
+    let mut parser = Parser::new(dialect.as_ref())
+        .try_with_sql(sql)
+        .map_err(sql_error)?;
+    let statement = parser.parse_statement().map_err(sql_error)?;
+    let options = parser.parse_options(Keyword::WITH).map_err(sql_error)?;
+    let _ = parser.consume_token(&Token::SemiColon);
+    parser.expect_token(&Token::EOF).map_err(sql_error)?;
+    create_index_statement(&statement, &options, normalize).map(Some)
+}
+
+/// Plan `CREATE INDEX` with the options of its `WITH` clause
+fn create_index_statement(
+    statement: &ast::Statement,
+    options: &[ast::SqlOption],
+    normalize: bool,
+) -> Result<QuokkaStatement> {
+    let ast::Statement::CreateIndex {
+        name,
+        table_name,
+        using,
+        columns,
+        unique,
+        if_not_exists,
+        ..
+    } = statement
+    else {
+        return plan_err!("Expected CREATE INDEX, not {statement}");
+    };
+    let method = match using {
+        Some(using) => using.value.parse()?,
+        None => IndexMethod::BTree,
+    };
+    let Some(name) = name else {
+        return plan_err!("CREATE INDEX requires an index name");
+    };
+    let columns = columns
+        .iter()
+        .map(|column| match &column.expr {
+            ast::Expr::Identifier(ident) => {
+                Ok(IdentNormalizer::new(normalize).normalize(ident.clone()))
+            }
+            expr => not_impl_err!("Unsupported index expression {expr}"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut analyzer = None;
+    for option in options {
+        let option_name = IdentNormalizer::new(normalize).normalize(option.name.clone());
+        if option_name != "analyzer" {
+            return not_impl_err!("Unsupported index option {option_name}");
+        }
+        if method != IndexMethod::FullText {
+            return plan_err!("Only fulltext indexes have an analyzer");
+        }
+        analyzer = Some(match &option.value {
+            ast::Expr::Value(ast::Value::SingleQuotedString(value)) => value.clone(),
+            ast::Expr::Identifier(ident) => {
+                IdentNormalizer::new(normalize).normalize(ident.clone())
+            }
+            value => return plan_err!("Expected the name of a text analyzer, not {value}"),
+        });
+    }
+    Ok(QuokkaStatement::CreateIndex {
+        name: object_name_last(name, normalize),
+        table: object_name_to_table_reference(table_name.clone(), normalize)?,
+        columns,
+        unique: *unique,
+        method,
+        analyzer,
+        if_not_exists: *if_not_exists,
+    })
+}
+
+/// Parse `sql` if it's a `CREATE`, `ALTER` or `DROP` of a role or user, or a `GRANT` or `REVOKE`
+/// of role membership, which `sqlparser` only parses partly or for some dialects
+fn parse_role(sql: &str, dialect: &str, normalize: bool) -> Result<Option<QuokkaStatement>> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let mut parser = Parser::new(dialect.as_ref())
+        .try_with_sql(sql)
+        .map_err(sql_error)?;
+    let normalizer = IdentNormalizer::new(normalize);
+    let statement = if parser.parse_keyword(Keyword::CREATE) {
+        let login = match parser.parse_one_of_keywords(&[Keyword::ROLE, Keyword::USER]) {
+            Some(Keyword::ROLE) => false,
+            Some(_) => true,
+            None => return Ok(None),
+        };
+        let if_not_exists = parser.parse_keywords(&[Keyword::IF, Keyword::NOT, Keyword::EXISTS]);
+        let name = normalizer.normalize(parser.parse_identifier(false).map_err(sql_error)?);
+        let attributes = parse_role_attributes(&mut parser)?;
+        QuokkaStatement::CreateRole {
+            name,
+            login: attributes.login.unwrap_or(login),
+            superuser: attributes.superuser.unwrap_or(false),
+            password: attributes.password.flatten(),
+            tenant_id: attributes.tenant_id.flatten(),
+            if_not_exists,
+        }
+    } else if parser.parse_keyword(Keyword::ALTER) {
+        if parser
+            .parse_one_of_keywords(&[Keyword::ROLE, Keyword::USER])
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let name = normalizer.normalize(parser.parse_identifier(false).map_err(sql_error)?);
+        QuokkaStatement::AlterRole {
+            name,
+            attributes: parse_role_attributes(&mut parser)?,
+        }
+    } else if parser.parse_keyword(Keyword::DROP) {
+        if parser
+            .parse_one_of_keywords(&[Keyword::ROLE, Keyword::USER])
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let if_exists = parser.parse_keywords(&[Keyword::IF, Keyword::EXISTS]);
+        let names = parser
+            .parse_comma_separated(|p| p.parse_identifier(false))
+            .map_err(sql_error)?;
+        QuokkaStatement::DropRole {
+            names: names.into_iter().map(|n| normalizer.normalize(n)).collect(),
+            if_exists,
+        }
+    } else {
+        let revoke = match parser.parse_one_of_keywords(&[Keyword::GRANT, Keyword::REVOKE]) {
+            Some(Keyword::GRANT) => false,
+            Some(_) => true,
+            None => return Ok(None),
+        };
+        // Privileges are followed by ON, which `sqlparser` handles
+        let Ok(roles) = parser.parse_comma_separated(|p| p.parse_identifier(false)) else {
+            return Ok(None);
+        };
+        if !parser.parse_keyword(if revoke { Keyword::FROM } else { Keyword::TO }) {
+            return Ok(None);
+        }
+        let members = parser
+            .parse_comma_separated(|p| p.parse_identifier(false))
+            .map_err(sql_error)?;
+        QuokkaStatement::GrantRole {
+            roles: roles.into_iter().map(|r| normalizer.normalize(r)).collect(),
+            members: members
+                .into_iter()
+                .map(|m| normalizer.normalize(m))
+                .collect(),
+            revoke,
+        }
+    };
+    let _ = parser.consume_token(&Token::SemiColon);
+    parser.expect_token(&Token::EOF).map_err(sql_error)?;
+    Ok(Some(statement))
+}
+
+/// Parse the options of `CREATE ROLE` or `ALTER ROLE` after the role's name, up to the end of
+/// the statement
+fn parse_role_attributes(parser: &mut Parser) -> Result<RoleAttributes> {
+    let sql_error = |e| DataFusionError::SQL(e, None);
+    let _ = parser.parse_keyword(Keyword::WITH);
+    let mut attributes = RoleAttributes::default();
+    while !matches!(parser.peek_token().token, Token::EOF | Token::SemiColon) {
+        // Not a keyword of `sqlparser`
+        let tenant = matches!(
+            &parser.peek_token().token,
+            Token::Word(word) if word.value.eq_ignore_ascii_case("TENANT")
+        );
+        if tenant {
+            parser.next_token();
+            attributes.tenant_id = Some(if parser.parse_keyword(Keyword::NULL) {
+                None
+            } else {
+                Some(parser.parse_literal_string().map_err(sql_error)?)
+            });
+            continue;
+        }
+        match parser.parse_one_of_keywords(&[
+            Keyword::LOGIN,
+            Keyword::NOLOGIN,
+            Keyword::SUPERUSER,
+            Keyword::NOSUPERUSER,
+            Keyword::PASSWORD,
+        ]) {
+            Some(Keyword::LOGIN) => attributes.login = Some(true),
+            Some(Keyword::NOLOGIN) => attributes.login = Some(false),
+            Some(Keyword::SUPERUSER) => attributes.superuser = Some(true),
+            Some(Keyword::NOSUPERUSER) => attributes.superuser = Some(false),
+            Some(_) if parser.parse_keyword(Keyword::NULL) => attributes.password = Some(None),
+            Some(_) => {
+                attributes.password = Some(Some(parser.parse_literal_string().map_err(sql_error)?))
+            }
+            None => return not_impl_err!("Unsupported role option {}", parser.peek_token()),
+        }
+    }
+    Ok(attributes)
+}
+
+/// Plan a parsed statement, handling the statements DataFusion doesn't support
+pub async fn statement_to_plan(
+    state: &SessionState,
+    statement: DFStatement,
+) -> Result<LogicalPlan> {
+    let normalize = state.config_options().sql_parser.enable_ident_normalization;
+    let quokka_statement = match &statement {
+        DFStatement::Statement(statement) => match statement.as_ref() {
+            statement @ ast::Statement::CreateIndex { .. } => {
+                Some(create_index_statement(statement, &[], normalize)?)
+            }
+            ast::Statement::Drop {
+                object_type: ObjectType::Index,
+                if_exists,
+                names,
+                ..
+            } => {
+                let [name] = names.as_slice() else {
+                    return not_impl_err!("DROP INDEX only supports dropping one index");
+                };
+                let schema = match object_name_to_table_reference(name.clone(), normalize)? {
+                    OwnedTableReference::Bare { .. } => None,
+                    OwnedTableReference::Partial { schema, .. } => Some(schema.to_string()),
+                    OwnedTableReference::Full { .. } => {
+                        return not_impl_err!("DROP INDEX doesn't support catalog qualifiers")
+                    }
+                };
+                Some(QuokkaStatement::DropIndex {
+                    schema,
+                    name: object_name_last(name, normalize),
+                    if_exists: *if_exists,
+                })
+            }
+            ast::Statement::Drop {
+                object_type: ObjectType::Table,
+                if_exists,
+                names,
+                cascade,
+                ..
+            } => Some(QuokkaStatement::DropTable {
+                names: names
+                    .iter()
+                    .map(|name| object_name_to_table_reference(name.clone(), normalize))
+                    .collect::<Result<_>>()?,
+                if_exists: *if_exists,
+                cascade: *cascade,
+            }),
+            ast::Statement::CreateTable {
+                with_options,
+                partition_by,
+                ..
+            } if !with_options.is_empty() || partition_by.is_some() => {
+                let storage = table_storage(with_options, normalize)?;
+                let partition_by = partition_by
+                    .as_ref()
+                    .map(|expr| partition_key(expr, normalize))
+                    .transpose()?;
+                let mut statement = statement.as_ref().clone();
+                if let ast::Statement::CreateTable {
+                    with_options,
+                    partition_by,
+                    ..
+                } = &mut statement
+                {
+                    with_options.clear();
+                    *partition_by = None;
+                }
+                let plan = state
+                    .statement_to_plan(DFStatement::Statement(Box::new(statement)))
+                    .await?;
+                if !matches!(plan, LogicalPlan::Ddl(DdlStatement::CreateMemoryTable(_))) {
+                    return not_impl_err!("Unsupported CREATE TABLE options");
+                }
+                Some(QuokkaStatement::CreateTable {
+                    storage,
+                    partition_by,
+                    plan: Box::new(plan),
+                })
+            }
+            ast::Statement::Truncate {
+                table_name,
+                partitions: Some(partitions),
+                ..
+            } => Some(QuokkaStatement::TruncatePartitions {
+                table: object_name_to_table_reference(table_name.clone(), normalize)?,
+                values: partition_values(partitions, normalize)?,
+                drop: false,
+            }),
+            ast::Statement::AlterTable {
+                name, operations, ..
+            } if operations.iter().any(|operation| {
+                matches!(operation, ast::AlterTableOperation::DropPartitions { .. })
+            }) =>
+            {
+                let [ast::AlterTableOperation::DropPartitions { partitions, .. }] =
+                    operations.as_slice()
+                else {
+                    return not_impl_err!("DROP PARTITION can't be combined with other operations");
+                };
+                Some(QuokkaStatement::TruncatePartitions {
+                    table: object_name_to_table_reference(name.clone(), normalize)?,
+                    values: partition_values(partitions, normalize)?,
+                    drop: true,
+                })
+            }
+            ast::Statement::AlterTable {
+                name,
+                if_exists,
+                operations,
+                ..
+            } => {
+                let mut planned = vec![];
+                for operation in operations {
+                    planned.push(alter_table_operation(state, name, operation, normalize).await?);
+                }
+                Some(QuokkaStatement::AlterTable {
+                    table: object_name_to_table_reference(name.clone(), normalize)?,
+                    if_exists: *if_exists,
+                    operations: planned,
+                })
+            }
+            ast::Statement::Grant {
+                privileges,
+                objects,
+                grantees,
+                ..
+            } => Some(grant_statement(
+                privileges, objects, grantees, false, normalize,
+            )?),
+            ast::Statement::Revoke {
+                privileges,
+                objects,
+                grantees,
+                ..
+            } => Some(grant_statement(
+                privileges, objects, grantees, true, normalize,
+            )?),
+            _ => None,
+        },
+        _ => None,
+    };
+    match quokka_statement {
+        Some(statement) => Ok(LogicalPlan::Extension(Extension {
+            node: Arc::new(QuokkaStatementNode::new(statement)),
+        })),
+        None => state.statement_to_plan(statement).await,
+    }
+}
+
+/// Plan a `GRANT` or `REVOKE` of privileges
+fn grant_statement(
+    privileges: &ast::Privileges,
+    objects: &ast::GrantObjects,
+    grantees: &[ast::Ident],
+    revoke: bool,
+    normalize: bool,
+) -> Result<QuokkaStatement> {
+    let privileges = match privileges {
+        ast::Privileges::All { .. } => Privilege::ALL.to_vec(),
+        ast::Privileges::Actions(actions) => actions
+            .iter()
+            .map(|action| match action {
+                ast::Action::Select { columns: None } => Ok(Privilege::Select),
+                ast::Action::Insert { columns: None } => Ok(Privilege::Insert),
+                ast::Action::Update { columns: None } => Ok(Privilege::Update),
+                ast::Action::Delete => Ok(Privilege::Delete),
+                ast::Action::Create => Ok(Privilege::Create),
+                action => not_impl_err!("Unsupported privilege {action}"),
+            })
+            .collect::<Result<_>>()?,
+    };
+    let objects = match objects {
+        ast::GrantObjects::Tables(names) => names
+            .iter()
+            .map(|name| {
+                object_name_to_table_reference(name.clone(), normalize).map(GrantObject::Table)
+            })
+            .collect::<Result<_>>()?,
+        ast::GrantObjects::Schemas(schemas) | ast::GrantObjects::AllTablesInSchema { schemas } => {
+            schemas
+                .iter()
+                .map(|schema| GrantObject::Schema(object_name_last(schema, normalize)))
+                .collect()
+        }
+        _ => return not_impl_err!("Privileges can only be granted on tables and schemas"),
+    };
+    let normalizer = IdentNormalizer::new(normalize);
+    Ok(QuokkaStatement::Grant {
+        privileges,
+        objects,
+        grantees: grantees
+            .iter()
+            .map(|grantee| normalizer.normalize(grantee.clone()))
+            .collect(),
+        revoke,
+    })
+}
+
+/// Plan an operation of `ALTER TABLE table`
+async fn alter_table_operation(
+    state: &SessionState,
+    table: &ObjectName,
+    operation: &ast::AlterTableOperation,
+    normalize: bool,
+) -> Result<AlterTableOperation> {
+    let normalizer = IdentNormalizer::new(normalize);
+    Ok(match operation {
+        ast::AlterTableOperation::AddColumn {
+            if_not_exists,
+            column_def,
+            ..
+        } => {
+            // DataFusion plans the column and its default as if it were creating a table
+            let statement = CreateTableBuilder::new(table.clone())
+                .columns(vec![column_def.clone()])
+                .build();
+            let plan = state
+                .statement_to_plan(DFStatement::Statement(Box::new(statement)))
+                .await?;
+            let LogicalPlan::Ddl(DdlStatement::CreateMemoryTable(cmd)) = plan else {
+                return not_impl_err!("Unsupported column definition {column_def}");
+            };
+            if !cmd.constraints.is_empty() {
+                return not_impl_err!("ALTER TABLE can't add a column with a constraint");
+            }
+            let schema = Schema::from(cmd.input.schema().as_ref());
+            AlterTableOperation::AddColumn {
+                field: schema.field(0).clone(),
+                default: cmd.column_defaults.into_iter().next().map(|(_, e)| e),
+                if_not_exists: *if_not_exists,
+            }
+        }
+        ast::AlterTableOperation::DropColumn {
+            column_name,
+            if_exists,
+            ..
+        } => AlterTableOperation::DropColumn {
+            name: normalizer.normalize(column_name.clone()),
+            if_exists: *if_exists,
+        },
+        ast::AlterTableOperation::RenameColumn {
+            old_column_name,
+            new_column_name,
+        } => AlterTableOperation::RenameColumn {
+            old: normalizer.normalize(old_column_name.clone()),
+            new: normalizer.normalize(new_column_name.clone()),
+        },
+        ast::AlterTableOperation::RenameTable { table_name } => AlterTableOperation::RenameTable {
+            name: object_name_to_table_reference(table_name.clone(), normalize)?,
+        },
+        operation => return not_impl_err!("Unsupported ALTER TABLE operation {operation}"),
+    })
+}
+
+/// The storage chosen by the options of `CREATE TABLE ... WITH (options)`
+fn table_storage(options: &[ast::SqlOption], normalize: bool) -> Result<TableStorage> {
+    let mut storage = TableStorage::default();
+    for option in options {
+        let name = IdentNormalizer::new(normalize).normalize(option.name.clone());
+        if name != "storage" {
+            return not_impl_err!("Unsupported table option {name}");
+        }
+        storage = match &option.value {
+            ast::Expr::Value(ast::Value::SingleQuotedString(value)) if value == "memory" => {
+                TableStorage::Memory
+            }
+            ast::Expr::Value(ast::Value::SingleQuotedString(value)) if value == "block" => {
+                TableStorage::Block
+            }
+            value => return plan_err!("Unknown table storage {value}"),
+        };
+    }
+    Ok(storage)
+}
+
+/// The column of `CREATE TABLE ... PARTITION BY (column)`
+fn partition_key(expr: &ast::Expr, normalize: bool) -> Result<String> {
+    match expr {
+        ast::Expr::Identifier(ident) => {
+            Ok(IdentNormalizer::new(normalize).normalize(ident.clone()))
+        }
+        ast::Expr::Nested(expr) => partition_key(expr, normalize),
+        expr => not_impl_err!("Tables can only be partitioned by a column, not {expr}"),
+    }
+}
+
+/// The values of the partition key in `PARTITION (values)`, each a literal or `column = literal`
+fn partition_values(
+    partitions: &[ast::Expr],
+    normalize: bool,
+) -> Result<Vec<(Option<String>, ScalarValue)>> {
+    partitions
+        .iter()
+        .map(|partition| match partition {
+            ast::Expr::BinaryOp {
+                left,
+                op: ast::BinaryOperator::Eq,
+                right,
+            } => Ok((Some(partition_key(left, normalize)?), literal(right)?)),
+            value => Ok((None, literal(value)?)),
+        })
+        .collect()
+}
+
+/// The value of a literal in a statement Quokka plans itself
+fn literal(expr: &ast::Expr) -> Result<ScalarValue> {
+    Ok(match expr {
+        ast::Expr::Value(ast::Value::Number(number, _)) => match number.parse::<i64>() {
+            Ok(number) => ScalarValue::Int64(Some(number)),
+            Err(_) => ScalarValue::Float64(Some(number.parse().map_err(|_| {
+                DataFusionError::Plan(format!("Can't parse {number} as a number"))
+            })?)),
+        },
+        ast::Expr::Value(ast::Value::SingleQuotedString(value)) => {
+            ScalarValue::from(value.as_str())
+        }
+        ast::Expr::Value(ast::Value::Boolean(value)) => ScalarValue::Boolean(Some(*value)),
+        ast::Expr::Value(ast::Value::Null) => ScalarValue::Null,
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Minus,
+            expr,
+        } => literal(expr)?.arithmetic_negate()?,
+        ast::Expr::Nested(expr) => literal(expr)?,
+        expr => return not_impl_err!("Expected a literal, not {expr}"),
+    })
+}
+
+fn object_name_last(name: &ObjectName, normalize: bool) -> String {
+    let ident = name.0.last().expect("object names are never empty").clone();
+    IdentNormalizer::new(normalize).normalize(ident)
+}
+
+/// Execute a logical plan, running the statements Quokka handles itself and handing everything
+/// else to [`SessionContext::execute_logical_plan`]
+pub async fn execute_logical_plan(ctx: &SessionContext, plan: LogicalPlan) -> Result<DataFrame> {
+    match plan {
+        LogicalPlan::Extension(Extension { node }) => {
+            match node.as_any().downcast_ref::<QuokkaStatementNode>() {
+                Some(node) => execute_statement(ctx, &node.statement).await,
+                None => {
+                    ctx.execute_logical_plan(LogicalPlan::Extension(Extension { node }))
+                        .await
+                }
+            }
+        }
+        LogicalPlan::Ddl(DdlStatement::CreateMemoryTable(cmd)) => {
+            create_table(ctx, cmd, TableStorage::Memory, None).await
+        }
+        LogicalPlan::Ddl(DdlStatement::CreateCatalogSchema(cmd)) => {
+            create_schema(ctx, cmd).await?;
+            empty_dataframe(ctx)
+        }
+        LogicalPlan::Ddl(DdlStatement::DropCatalogSchema(cmd)) => {
+            drop_schema(ctx, cmd).await?;
+            empty_dataframe(ctx)
+        }
+        LogicalPlan::Dml(dml) if matches!(dml.op, WriteOp::Update | WriteOp::Delete) => {
+            execute_dml(ctx, dml).await
+        }
+        plan => ctx.execute_logical_plan(plan).await,
+    }
+}
+
+fn empty_dataframe(ctx: &SessionContext) -> Result<DataFrame> {
+    let plan = LogicalPlanBuilder::empty(false).build()?;
+    Ok(DataFrame::new(ctx.state(), plan))
+}
+
+/// A one row dataframe with the number of rows affected by a statement, matching the output of
+/// DataFusion's `INSERT`
+fn count_dataframe(ctx: &SessionContext, count: u64) -> Result<DataFrame> {
+    let schema = Arc::new(Schema::new(vec![Field::new(
+        "count",
+        DataType::UInt64,
+        false,
+    )]));
+    let batch = RecordBatch::try_new(schema, vec![Arc::new(UInt64Array::from(vec![count]))])?;
+    ctx.read_batch(batch)
+}
+
+/// Downcast a table to a Quokka [`MemTable`]
+pub fn as_mem_table(table: &Arc<dyn TableProvider>) -> Option<&MemTable> {
+    table.as_any().downcast_ref::<MemTable>()
+}
+
+/// Downcast a table to a [`BlockTable`]
+pub fn as_block_table(table: &Arc<dyn TableProvider>) -> Option<&BlockTable> {
+    table.as_any().downcast_ref::<BlockTable>()
+}
+
+/// Hold off `ALTER TABLE` on a Quokka table until the returned guard is dropped, so the new
+/// version of the table doesn't miss a change to its indexes or policies
+async fn lock_for_write(table: &Arc<dyn TableProvider>) -> Option<OwnedRwLockReadGuard<()>> {
+    match table_lock(table) {
+        Some(lock) => Some(lock.lock_for_write().await),
+        None => None,
+    }
+}
+
+async fn mem_table(
+    ctx: &SessionContext,
+    table: &OwnedTableReference,
+) -> Result<Arc<dyn TableProvider>> {
+    let provider = ctx.table_provider(table.clone()).await?;
+    if as_mem_table(&provider).is_none() {
+        return plan_err!("{table} is not a Quokka table");
+    }
+    Ok(provider)
+}
+
+/// The Quokka table `table`, of either storage, and its id
+async fn quokka_table(
+    ctx: &SessionContext,
+    table: &OwnedTableReference,
+) -> Result<(Arc<dyn TableProvider>, TableId)> {
+    let provider = ctx.table_provider(table.clone()).await?;
+    match table_id(&provider) {
+        Some(id) => Ok((provider, id)),
+        None => plan_err!("{table} is not a Quokka table"),
+    }
+}
+
+/// Look up a schema in the session's default catalog, or the default schema if `name` is `None`
+fn schema_provider(ctx: &SessionContext, name: Option<&str>) -> Result<Arc<dyn SchemaProvider>> {
+    let state = ctx.state();
+    let options = &state.config_options().catalog;
+    let schema_name = name.unwrap_or(&options.default_schema);
+    ctx.catalog(&options.default_catalog)
+        .and_then(|catalog| catalog.schema(schema_name))
+        .ok_or_else(|| DataFusionError::Plan(format!("Schema {schema_name} does not exist")))
+}
+
+/// Find the Quokka table in `schema` with an index called `index_name`
+async fn find_index_table(
+    schema: &Arc<dyn SchemaProvider>,
+    index_name: &str,
+) -> Option<Arc<dyn TableProvider>> {
+    for table_name in schema.table_names() {
+        let Some(table) = schema.table(&table_name).await else {
+            continue;
+        };
+        let index_names = if let Some(mem_table) = as_mem_table(&table) {
+            mem_table.index_names().await
+        } else if let Some(block_table) = as_block_table(&table) {
+            block_table.index_names()
+        } else {
+            continue;
+        };
+        if index_names.iter().any(|i| i == index_name) {
+            return Some(table);
+        }
+    }
+    None
+}
+
+async fn execute_statement(ctx: &SessionContext, statement: &QuokkaStatement) -> Result<DataFrame> {
+    match statement {
+        QuokkaStatement::CreateIndex {
+            name,
+            table,
+            columns,
+            unique,
+            method,
+            analyzer,
+            if_not_exists,
+        } => {
+            let (provider, id) = quokka_table(ctx, table).await?;
+            // Index names are unique within a schema, like in Postgres
+            let schema = schema_provider(ctx, table.schema())?;
+            if find_index_table(&schema, name).await.is_some() {
+                if *if_not_exists {
+                    return empty_dataframe(ctx);
+                }
+                return exec_err!("Index {name} already exists");
+            }
+            let index = IndexEntry {
+                name: name.clone(),
+                columns: columns.clone(),
+                unique: *unique,
+                method: *method,
+                analyzer: analyzer.clone(),
+            };
+            let system_catalog = system_catalog(ctx)?;
+            let writer = writer_for(ctx.state().config());
+            let log = writer.is_logged();
+            let _lock = lock_for_write(&provider).await;
+            writer
+                .submit(move |_| async move {
+                    system_catalog
+                        .create_index(&provider, index.clone())
+                        .await?;
+                    let log = if log {
+                        vec![LogRecord::create_index(id, &index)]
+                    } else {
+                        vec![]
+                    };
+                    Ok(Applied::new(0, log))
+                })
+                .await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::DropIndex {
+            schema,
+            name,
+            if_exists,
+        } => {
+            let schema = schema_provider(ctx, schema.as_deref())?;
+            match find_index_table(&schema, name).await {
+                Some(table) => {
+                    let name = name.clone();
+                    let system_catalog = system_catalog(ctx)?;
+                    let writer = writer_for(ctx.state().config());
+                    let log = writer.is_logged();
+                    let _lock = lock_for_write(&table).await;
+                    writer
+                        .submit(move |_| async move {
+                            system_catalog.drop_index(&table, &name).await?;
+                            let id = table_id(&table).expect("only Quokka tables have indexes");
+                            let log = if log {
+                                vec![LogRecord::drop_index(id, &name)]
+                            } else {
+                                vec![]
+                            };
+                            Ok(Applied::new(0, log))
+                        })
+                        .await?;
+                    empty_dataframe(ctx)
+                }
+                None if *if_exists => empty_dataframe(ctx),
+                None => exec_err!("Index {name} does not exist"),
+            }
+        }
+        QuokkaStatement::CreateTable {
+            storage,
+            partition_by,
+            plan,
+        } => match plan.as_ref() {
+            LogicalPlan::Ddl(DdlStatement::CreateMemoryTable(cmd)) => {
+                create_table(ctx, cmd.clone(), *storage, partition_by.clone()).await
+            }
+            _ => unreachable!("only CREATE TABLE plans take a storage option"),
+        },
+        QuokkaStatement::DropTable {
+            names,
+            if_exists,
+            cascade,
+        } => {
+            drop_tables(ctx, names, *if_exists, *cascade).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::AlterTable {
+            table,
+            if_exists,
+            operations,
+        } => {
+            alter_table(ctx, table, *if_exists, operations).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::TruncatePartitions {
+            table,
+            values,
+            drop,
+        } => {
+            let provider = mem_table(ctx, table).await?;
+            let mem_table = as_mem_table(&provider).expect("mem_table returns a MemTable");
+            let Some(key) = mem_table.partition_by() else {
+                return plan_err!("{table} isn't partitioned");
+            };
+            if let Some(column) = values
+                .iter()
+                .find_map(|(column, _)| column.as_ref().filter(|column| **column != key))
+            {
+                return plan_err!("{column} isn't the partition key of {table}, {key} is");
+            }
+            let values = values.iter().map(|(_, value)| value.clone()).collect();
+            let count = mem_table
+                .truncate_partitions(&ctx.state(), values, *drop)
+                .await?;
+            count_dataframe(ctx, count)
+        }
+        QuokkaStatement::CreatePolicy { name, table, using } => {
+            let provider = mem_table(ctx, table).await?;
+            let (name, using) = (name.clone(), using.clone());
+            let state = ctx.state();
+            let system_catalog = system_catalog(ctx)?;
+            let writer = writer_for(state.config());
+            let log = writer.is_logged();
+            let _lock = lock_for_write(&provider).await;
+            writer
+                .submit(move |_| async move {
+                    system_catalog.create_policy(&state, &provider, &name, &using)?;
+                    let table = as_mem_table(&provider).expect("checked to be a Quokka table");
+                    let log = if log {
+                        vec![LogRecord::create_policy(table.id(), &name, &using)]
+                    } else {
+                        vec![]
+                    };
+                    Ok(Applied::new(0, log))
+                })
+                .await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::DropPolicy {
+            name,
+            table,
+            if_exists,
+        } => {
+            let provider = mem_table(ctx, table).await?;
+            let (policy, table, if_exists) = (name.clone(), table.clone(), *if_exists);
+            let system_catalog = system_catalog(ctx)?;
+            let writer = writer_for(ctx.state().config());
+            let log = writer.is_logged();
+            let _lock = lock_for_write(&provider).await;
+            writer
+                .submit(move |_| async move {
+                    if !system_catalog.drop_policy(&provider, &policy)? {
+                        if if_exists {
+                            return Ok(Applied::new(0, vec![]));
+                        }
+                        return exec_err!("Policy {policy} on {table} does not exist");
+                    }
+                    let table = as_mem_table(&provider).expect("checked to be a Quokka table");
+                    let log = if log {
+                        vec![LogRecord::drop_policy(table.id(), &policy)]
+                    } else {
+                        vec![]
+                    };
+                    Ok(Applied::new(0, log))
+                })
+                .await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::CreateRole {
+            name,
+            login,
+            superuser,
+            password,
+            tenant_id,
+            if_not_exists,
+        } => {
+            let role = Role::new(name, *login, *superuser).with_tenant_id(tenant_id.clone());
+            let role = match password {
+                Some(password) => role.with_password(password)?,
+                None => role,
+            };
+            create_role(ctx, role, *if_not_exists).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::AlterRole { name, attributes } => {
+            alter_role(ctx, name.clone(), attributes.clone()).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::DropRole { names, if_exists } => {
+            drop_roles(ctx, names.clone(), *if_exists).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::Grant {
+            privileges,
+            objects,
+            grantees,
+            revoke,
+        } => {
+            grant(ctx, privileges.clone(), objects, grantees.clone(), *revoke).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::GrantRole {
+            roles,
+            members,
+            revoke,
+        } => {
+            grant_roles(ctx, roles.clone(), members.clone(), *revoke).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::CreateTextAnalyzer {
+            name,
+            analyzer,
+            if_not_exists,
+        } => {
+            create_analyzer(ctx, name, analyzer.clone(), *if_not_exists).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::DropTextAnalyzer { names, if_exists } => {
+            drop_analyzers(ctx, names.clone(), *if_exists).await?;
+            empty_dataframe(ctx)
+        }
+        QuokkaStatement::Checkpoint => {
+            checkpoint(ctx).await?;
+            empty_dataframe(ctx)
+        }
+    }
+}
+
+/// Create a Quokka table for `CREATE TABLE`, partitioned by column `partition_by` if set. Every
+/// [`MemTable`] needs an `INT` primary key.
+async fn create_table(
+    ctx: &SessionContext,
+    cmd: CreateMemoryTable,
+    storage: TableStorage,
+    partition_by: Option<String>,
+) -> Result<DataFrame> {
+    let CreateMemoryTable {
+        name,
+        input,
+        if_not_exists,
+        or_replace,
+        constraints,
+        column_defaults,
+    } = cmd;
+
+    match (if_not_exists, or_replace, ctx.table_exist(name.clone())?) {
+        (true, false, true) => return empty_dataframe(ctx),
+        (true, true, _) => return exec_err!("'IF NOT EXISTS' cannot coexist with 'REPLACE'"),
+        (false, false, true) => return exec_err!("Table '{name}' already exists"),
+        _ => {}
+    }
+
+    let df_schema = input.schema();
+    let schema = match storage {
+        TableStorage::Memory => {
+            let primary_key = match constraints.iter().find_map(|constraint| match constraint {
+                Constraint::PrimaryKey(indices) => Some(indices),
+                Constraint::Unique(_) => None,
+            }) {
+                Some(indices) if indices.len() == 1 => df_schema.field(indices[0]),
+                Some(_) => return not_impl_err!("Composite primary keys are not supported"),
+                None => return plan_err!("Table '{name}' must have a primary key"),
+            };
+            if primary_key.data_type() != &DataType::Int32 {
+                return not_impl_err!("Primary key {} must be an INT", primary_key.name());
+            }
+            let metadata = HashMap::from([("primary_key".to_string(), primary_key.name().clone())]);
+            Arc::new(Schema::new_with_metadata(
+                Schema::from(df_schema.as_ref()).fields().clone(),
+                metadata,
+            ))
+        }
+        TableStorage::Block => Arc::new(Schema::from(df_schema.as_ref())),
+    };
+    if let Some(column) = &partition_by {
+        if storage == TableStorage::Block {
+            return not_impl_err!("Block tables can't be partitioned");
+        }
+        if schema.index_of(column).is_err() {
+            return plan_err!("Partition key {column} is not a column of {name}");
+        }
+    }
+
+    let input = Arc::try_unwrap(input).unwrap_or_else(|e| e.as_ref().clone());
+    let input = ctx.state().optimize(&input)?;
+    let batches = DataFrame::new(ctx.state(), input)
+        .collect_partitioned()
+        .await?;
+    let column_defaults: HashMap<String, Expr> = column_defaults.into_iter().collect();
+
+    // Registered by the writer, so the table is logged before any write to it
+    let state = ctx.state();
+    let catalog_options = state.config_options().catalog.clone();
+    let name = name.resolve(
+        &catalog_options.default_catalog,
+        &catalog_options.default_schema,
+    );
+    let name = ResolvedTableReference {
+        catalog: Cow::Owned(name.catalog.into_owned()),
+        schema: Cow::Owned(name.schema.into_owned()),
+        table: Cow::Owned(name.table.into_owned()),
+    };
+    let system_catalog = system_catalog(ctx)?;
+    let writer = writer_for(state.config());
+    let log = writer.is_logged();
+    let session = ctx.clone();
+    writer
+        .submit(move |_| async move {
+            let id = next_table_id();
+            let mut entry =
+                TableEntry::new(id, name, storage, schema, constraints, column_defaults);
+            entry.partition_by = partition_by;
+            let mut records = vec![];
+            if log {
+                records.push(LogRecord::create_table(&entry, or_replace)?);
+                let inserted: Vec<RecordBatch> = batches.iter().flatten().cloned().collect();
+                if !inserted.is_empty() {
+                    records.push(LogRecord::change(id, &entry.schema, &[], &inserted)?);
+                }
+            }
+            system_catalog.create_table(&session, entry, batches, or_replace)?;
+            Ok(Applied::new(0, records))
+        })
+        .await?;
+    empty_dataframe(ctx)
+}
+
+/// Create the table `entry` describes holding the rows of `batches`, starting the maintenance of
+/// block tables configured in the session's [`QuokkaOptions`]
+pub fn new_table(
+    ctx: &SessionContext,
+    entry: &TableEntry,
+    batches: Vec<Vec<RecordBatch>>,
+) -> Result<Arc<dyn TableProvider>> {
+    Ok(match entry.storage {
+        TableStorage::Memory => {
+            let mut table = MemTable::try_new(entry.schema.clone(), batches)?
+                .with_id(entry.id)
+                .with_constraints(entry.constraints.clone())
+                .with_column_defaults(entry.column_defaults.clone());
+            if let Some(column) = &entry.partition_by {
+                table = table.with_partition_by(column)?;
+            }
+            Arc::new(table.with_policies(entry.policies.clone()))
+        }
+        TableStorage::Block => {
+            if entry.partition_by.is_some() {
+                return not_impl_err!("Block tables can't be partitioned");
+            }
+            if !entry.policies.is_empty() {
+                return not_impl_err!("Block tables can't have policies");
+            }
+            let table = BlockTable::try_new(entry.schema.clone(), batches)?
+                .with_id(entry.id)
+                .with_constraints(entry.constraints.clone())
+                .with_column_defaults(entry.column_defaults.clone());
+            let options = QuokkaOptions::from_config(ctx.state().config_options());
+            if options.block_compaction_interval_ms > 0 {
+                table.start_maintenance(
+                    Duration::from_millis(options.block_compaction_interval_ms),
+                    options.block_compaction_max_fill,
+                );
+            }
+            Arc::new(table)
+        }
+    })
+}
+
+/// Run `UPDATE` and `DELETE` against a Quokka table
+async fn execute_dml(ctx: &SessionContext, dml: DmlStatement) -> Result<DataFrame> {
+    let provider = ctx.table_provider(dml.table_name.clone()).await?;
+    if as_mem_table(&provider).is_none() && as_block_table(&provider).is_none() {
+        return plan_err!("{} is not a Quokka table", dml.table_name);
+    }
+    let state = ctx.state();
+
+    // Prepared plans haven't been through the analyzer, so literals may not have been cast to
+    // the types of the columns they're compared with yet
+    let LogicalPlan::Dml(dml) =
+        TypeCoercion::new().analyze(LogicalPlan::Dml(dml), state.config_options())?
+    else {
+        unreachable!("type coercion doesn't change the kind of plan");
+    };
+
+    let (assignments, source) = match (&dml.op, dml.input.as_ref()) {
+        (WriteOp::Update, LogicalPlan::Projection(projection)) => {
+            (Some(&projection.expr), projection.input.as_ref())
+        }
+        (WriteOp::Update, _) => return not_impl_err!("Unsupported UPDATE plan"),
+        (_, input) => (None, input),
+    };
+    let (predicate, source) = match source {
+        LogicalPlan::Filter(filter) => (Some(&filter.predicate), filter.input.as_ref()),
+        source => (None, source),
+    };
+    if !matches!(source, LogicalPlan::TableScan(_)) {
+        return not_impl_err!("{} only supports filtering a single table", dml.op);
+    }
+
+    let count = match (as_mem_table(&provider), assignments) {
+        (Some(table), Some(assignments)) => table.update(&state, predicate, assignments).await?,
+        (Some(table), None) => table.delete(&state, predicate).await?,
+        (None, assignments) => {
+            let table = as_block_table(&provider).expect("checked to be a Quokka table");
+            match assignments {
+                Some(assignments) => table.update(&state, predicate, assignments).await?,
+                None => table.delete(&state, predicate).await?,
+            }
+        }
+    };
+    count_dataframe(ctx, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::AsArray;
+    use arrow::datatypes::{Int32Type, UInt64Type};
+    use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::display::DisplayableExecutionPlan;
+    use datafusion::physical_plan::displayable;
+
+    use crate::session::new_context;
+
+    async fn run(ctx: &SessionContext, sql: &str) -> Result<Vec<RecordBatch>> {
+        let state = ctx.state();
+        let dialect = state.config_options().sql_parser.dialect.clone();
+        let statement = state.sql_to_statement(sql, &dialect)?;
+        let plan = statement_to_plan(&state, statement).await?;
+        execute_logical_plan(ctx, plan).await?.collect().await
+    }
+
+    async fn ids(ctx: &SessionContext, sql: &str) -> Result<Vec<i32>> {
+        let mut ids: Vec<i32> = run(ctx, sql)
+            .await?
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column(0)
+                    .as_primitive::<Int32Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn count(batches: &[RecordBatch]) -> u64 {
+        batches[0].column(0).as_primitive::<UInt64Type>().value(0)
+    }
+
+    async fn products() -> Result<SessionContext> {
+        let ctx = new_context();
+        run(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, brand_id INT, name VARCHAR)",
+        )
+        .await?;
+        run(
+            &ctx,
+            "INSERT INTO products VALUES (1, 10, 'a'), (2, 20, 'b'), (3, 10, 'c'), (4, 30, 'd')",
+        )
+        .await?;
+        Ok(ctx)
+    }
+
+    #[tokio::test]
+    async fn create_table_requires_primary_key() -> Result<()> {
+        let ctx = new_context();
+        let e = run(&ctx, "CREATE TABLE t (a INT)").await.unwrap_err();
+        assert_eq!(
+            "Error during planning: Table 't' must have a primary key",
+            e.strip_backtrace()
+        );
+        run(&ctx, "CREATE TABLE t (a INT PRIMARY KEY)").await?;
+        let table = ctx.table_provider("t").await?;
+        assert!(as_mem_table(&table).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_block_table() -> Result<()> {
+        let ctx = new_context();
+        let e = run(&ctx, "CREATE TABLE t (a INT) WITH (storage = 'disk')")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Error during planning: Unknown table storage 'disk'",
+            e.strip_backtrace()
+        );
+
+        // Block tables don't need a primary key
+        run(
+            &ctx,
+            "CREATE TABLE t (id INT, price DOUBLE) WITH (storage = 'block')",
+        )
+        .await?;
+        let table = ctx.table_provider("t").await?;
+        assert!(as_block_table(&table).is_some());
+        run(&ctx, "INSERT INTO t VALUES (1, 1.5), (2, NULL), (3, 3.5)").await?;
+        let updated = run(&ctx, "UPDATE t SET price = 2.5 WHERE price IS NULL").await?;
+        assert_eq!(1, count(&updated));
+        let deleted = run(&ctx, "DELETE FROM t WHERE id = 1").await?;
+        assert_eq!(1, count(&deleted));
+        assert_eq!(
+            vec![2, 3],
+            ids(&ctx, "SELECT id FROM t WHERE price > 2").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_index_and_query() -> Result<()> {
+        let ctx = products().await?;
+        run(&ctx, "CREATE INDEX products_brand ON products (brand_id)").await?;
+        assert_eq!(
+            vec![1, 3],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 10").await?
+        );
+        assert_eq!(
+            vec![2, 4],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id > 10").await?
+        );
+
+        // Newly inserted rows are indexed
+        run(&ctx, "INSERT INTO products VALUES (5, 10, 'e')").await?;
+        assert_eq!(
+            vec![1, 3, 5],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 10").await?
+        );
+
+        // The scan only returns the rows found in the index
+        let plan = ctx
+            .sql("SELECT id FROM products WHERE brand_id = 20")
+            .await?
+            .create_physical_plan()
+            .await?;
+        let plan_str = format!("{}", displayable(plan.as_ref()).indent(false));
+        assert!(
+            plan_str.contains(
+                "IndexScanExec: index=products_brand, bounds=[brand_id = Int32(20)], \
+                 estimated_rows=1"
+            ),
+            "{plan_str}"
+        );
+        assert!(!plan_str.contains("FilterExec"), "{plan_str}");
+        assert_eq!(1, collect(plan, ctx.task_ctx()).await?[0].num_rows());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_scan_metrics_and_setting() -> Result<()> {
+        let ctx = products().await?;
+        run(
+            &ctx,
+            "CREATE INDEX products_brand_name ON products (brand_id, name)",
+        )
+        .await?;
+
+        let plan = ctx
+            .sql("SELECT id FROM products WHERE brand_id = 10 AND name > 'a'")
+            .await?
+            .create_physical_plan()
+            .await?;
+        let batches = collect(plan.clone(), ctx.task_ctx()).await?;
+        assert_eq!(1, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+        let plan_str = format!(
+            "{}",
+            DisplayableExecutionPlan::with_metrics(plan.as_ref()).indent(false)
+        );
+        assert!(plan_str.contains("estimated_rows=1"), "{plan_str}");
+        assert!(plan_str.contains("output_rows=1"), "{plan_str}");
+        assert!(plan_str.contains("rows_probed=1"), "{plan_str}");
+
+        // The index finds both brand 10 rows but only one passes the rest of the filter
+        let plan = ctx
+            .sql("SELECT id FROM products WHERE brand_id = 10 AND id > 1")
+            .await?
+            .create_physical_plan()
+            .await?;
+        let batches = collect(plan.clone(), ctx.task_ctx()).await?;
+        assert_eq!(1, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+        let plan_str = format!(
+            "{}",
+            DisplayableExecutionPlan::with_metrics(plan.as_ref()).indent(false)
+        );
+        assert!(plan_str.contains("estimated_rows=2"), "{plan_str}");
+        assert!(plan_str.contains("rows_probed=2"), "{plan_str}");
+
+        run(&ctx, "SET quokka.enable_index_scan = false").await?;
+        let plan = ctx
+            .sql("SELECT id FROM products WHERE brand_id = 10")
+            .await?
+            .create_physical_plan()
+            .await?;
+        let plan_str = format!("{}", displayable(plan.as_ref()).indent(false));
+        assert!(!plan_str.contains("IndexScanExec"), "{plan_str}");
+        assert!(
+            plan_str.contains("index_candidate=products_brand_name"),
+            "{plan_str}"
+        );
+        assert_eq!(
+            vec![1, 3],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 10").await?
+        );
+        Ok(())
+    }
+
+    async fn plan_string(ctx: &SessionContext, sql: &str) -> Result<String> {
+        let plan = ctx.sql(sql).await?.create_physical_plan().await?;
+        Ok(format!("{}", displayable(plan.as_ref()).indent(false)))
+    }
+
+    #[tokio::test]
+    async fn index_join() -> Result<()> {
+        let ctx = products().await?;
+        run(
+            &ctx,
+            "CREATE TABLE inventory (id INT PRIMARY KEY, product_id INT, qty INT)",
+        )
+        .await?;
+        run(
+            &ctx,
+            "INSERT INTO inventory VALUES (1, 1, 5), (2, 1, 6), (3, 2, 7), (4, 3, 8), (5, 4, 9), \
+             (6, 9, 1), (7, 9, 2)",
+        )
+        .await?;
+        run(
+            &ctx,
+            "CREATE INDEX inventory_product ON inventory (product_id)",
+        )
+        .await?;
+        run(
+            &ctx,
+            "CREATE TABLE prices (product_id INT PRIMARY KEY, price INT)",
+        )
+        .await?;
+        run(
+            &ctx,
+            "INSERT INTO prices VALUES (1, 100), (2, 200), (3, 300), (4, 400), (5, 500), (6, 600)",
+        )
+        .await?;
+
+        let sql = "SELECT p.id, i.qty FROM products p JOIN inventory i ON i.product_id = p.id \
+                   WHERE p.brand_id = 10";
+        let plan_str = plan_string(&ctx, sql).await?;
+        assert!(
+            plan_str.contains("IndexJoinExec: index=inventory_product, on=[(id@0, product_id@0)], table_side=right"),
+            "{plan_str}"
+        );
+        assert!(!plan_str.contains("HashJoinExec"), "{plan_str}");
+        assert_eq!(vec![1, 1, 3], ids(&ctx, sql).await?);
+
+        // The indexed table is on the left
+        let sql = "SELECT p.id, i.qty FROM inventory i JOIN products p ON i.product_id = p.id \
+                   WHERE p.brand_id = 10";
+        let plan_str = plan_string(&ctx, sql).await?;
+        assert!(plan_str.contains("table_side=left"), "{plan_str}");
+        assert_eq!(vec![1, 1, 3], ids(&ctx, sql).await?);
+
+        // Filters on the indexed table are applied to the probed rows
+        let sql = "SELECT p.id FROM products p JOIN inventory i ON i.product_id = p.id \
+                   WHERE p.brand_id = 10 AND i.qty > 5";
+        let plan_str = plan_string(&ctx, sql).await?;
+        assert!(plan_str.contains("predicate=qty@1 > 5"), "{plan_str}");
+        assert_eq!(vec![1, 3], ids(&ctx, sql).await?);
+
+        // Primary key joins
+        let sql = "SELECT pr.price FROM products p JOIN prices pr ON pr.product_id = p.id \
+                   WHERE p.brand_id = 10";
+        let plan_str = plan_string(&ctx, sql).await?;
+        assert!(
+            plan_str.contains("IndexJoinExec: index=primary_key"),
+            "{plan_str}"
+        );
+        assert_eq!(vec![100, 300], ids(&ctx, sql).await?);
+
+        run(&ctx, "SET quokka.enable_index_join = false").await?;
+        let plan_str = plan_string(&ctx, sql).await?;
+        assert!(!plan_str.contains("IndexJoinExec"), "{plan_str}");
+        assert!(plan_str.contains("HashJoinExec"), "{plan_str}");
+        assert_eq!(vec![100, 300], ids(&ctx, sql).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unique_index() -> Result<()> {
+        let ctx = products().await?;
+        let e = run(
+            &ctx,
+            "CREATE UNIQUE INDEX products_brand ON products (brand_id)",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            "Execution error: Duplicate key value violates unique index products_brand",
+            e.strip_backtrace()
+        );
+        run(&ctx, "CREATE UNIQUE INDEX products_name ON products (name)").await?;
+        let e = run(&ctx, "INSERT INTO products VALUES (5, 10, 'a')")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Execution error: Duplicate key value violates unique index products_name",
+            e.strip_backtrace()
+        );
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            ids(&ctx, "SELECT id FROM products").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn primary_key_is_unique_and_not_null() -> Result<()> {
+        let ctx = products().await?;
+        for sql in [
+            "INSERT INTO products VALUES (1, 40, 'e')",
+            "INSERT INTO products VALUES (5, 40, 'e'), (5, 50, 'f')",
+            "UPDATE products SET id = 2 WHERE id = 1",
+        ] {
+            let e = run(&ctx, sql).await.unwrap_err();
+            assert_eq!(
+                "Execution error: Duplicate key value violates primary key id",
+                e.strip_backtrace(),
+                "{sql}"
+            );
+        }
+        for sql in [
+            "INSERT INTO products VALUES (NULL, 40, 'e')",
+            "UPDATE products SET id = NULL WHERE id = 1",
+        ] {
+            let e = run(&ctx, sql).await.unwrap_err();
+            assert_eq!(
+                "Execution error: Primary key id can't be null",
+                e.strip_backtrace(),
+                "{sql}"
+            );
+        }
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            ids(&ctx, "SELECT id FROM products").await?
+        );
+        assert_eq!(
+            vec![10],
+            ids(&ctx, "SELECT brand_id FROM products WHERE id + 0 = 1").await?
+        );
+
+        // Keys only have to be distinct once every row is updated
+        run(&ctx, "UPDATE products SET id = id + 1").await?;
+        assert_eq!(
+            vec![2, 3, 4, 5],
+            ids(&ctx, "SELECT id FROM products").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_scan_matches_full_scan() -> Result<()> {
+        let ctx = products().await?;
+        run(&ctx, "CREATE INDEX products_brand ON products (brand_id)").await?;
+        for sql in [
+            "INSERT INTO products VALUES (1, 20, 'e')",
+            "UPDATE products SET id = 3 WHERE id = 1",
+        ] {
+            run(&ctx, sql).await.unwrap_err();
+        }
+        run(
+            &ctx,
+            "UPDATE products SET id = 5, brand_id = 10 WHERE id = 2",
+        )
+        .await?;
+        run(&ctx, "UPDATE products SET brand_id = 30 WHERE id = 1").await?;
+
+        let queries = [
+            "SELECT id FROM products WHERE id = 1",
+            "SELECT id FROM products WHERE id = 2",
+            "SELECT id FROM products WHERE id = 5",
+            "SELECT id FROM products WHERE brand_id = 10",
+            "SELECT id FROM products WHERE brand_id = 30",
+        ];
+        let mut with_index = vec![];
+        for sql in queries {
+            assert!(
+                plan_string(&ctx, sql).await?.contains("IndexScanExec"),
+                "{sql}"
+            );
+            with_index.push(ids(&ctx, sql).await?);
+        }
+        run(&ctx, "SET quokka.enable_index_scan = false").await?;
+        for (sql, with_index) in queries.into_iter().zip(with_index) {
+            assert_eq!(ids(&ctx, sql).await?, with_index, "{sql}");
+        }
+        for (id, expected) in [(1, vec![1]), (2, vec![]), (5, vec![5])] {
+            let sql = format!("SELECT id FROM products WHERE id + 0 = {id}");
+            assert_eq!(expected, ids(&ctx, &sql).await?, "{sql}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn string_index() -> Result<()> {
+        let ctx = new_context();
+        run(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, brand_id INT, name VARCHAR)",
+        )
+        .await?;
+        let values: Vec<String> = (0..500)
+            .map(|id| format!("({id}, {}, 'quokka product number {id:04}')", id % 7))
+            .collect();
+        run(
+            &ctx,
+            &format!("INSERT INTO products VALUES {}", values.join(", ")),
+        )
+        .await?;
+        run(&ctx, "CREATE INDEX products_name ON products (name)").await?;
+        run(
+            &ctx,
+            "CREATE INDEX products_brand_name ON products (brand_id, name)",
+        )
+        .await?;
+
+        let sql = "SELECT id FROM products WHERE name = 'quokka product number 0123'";
+        assert!(plan_string(&ctx, sql)
+            .await?
+            .contains("IndexScanExec: index=products_name"));
+        assert_eq!(vec![123], ids(&ctx, sql).await?);
+        assert_eq!(
+            vec![298, 299, 300],
+            ids(
+                &ctx,
+                "SELECT id FROM products WHERE name > 'quokka product number 0297' \
+                 AND name <= 'quokka product number 0300'"
+            )
+            .await?
+        );
+        assert_eq!(
+            vec![3, 10, 17],
+            ids(
+                &ctx,
+                "SELECT id FROM products WHERE brand_id = 3 AND name < 'quokka product number 0020'"
+            )
+            .await?
+        );
+
+        let table = ctx.table_provider("products").await?;
+        let memory_usage = as_mem_table(&table).unwrap().index_memory_usage().await;
+        assert_eq!(
+            vec!["products_brand_name", "products_name"],
+            memory_usage
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(memory_usage.iter().all(|(_, bytes)| *bytes > 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_index() -> Result<()> {
+        let ctx = products().await?;
+        run(&ctx, "CREATE INDEX products_brand ON products (brand_id)").await?;
+        let e = run(&ctx, "CREATE INDEX products_brand ON products (name)")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            "Execution error: Index products_brand already exists",
+            e.strip_backtrace()
+        );
+        run(&ctx, "DROP INDEX products_brand").await?;
+        run(&ctx, "DROP INDEX IF EXISTS products_brand").await?;
+        let e = run(&ctx, "DROP INDEX products_brand").await.unwrap_err();
+        assert_eq!(
+            "Execution error: Index products_brand does not exist",
+            e.strip_backtrace()
+        );
+        assert_eq!(
+            vec![1, 3],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 10").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_and_delete_maintain_indexes() -> Result<()> {
+        let ctx = products().await?;
+        run(&ctx, "CREATE INDEX products_brand ON products (brand_id)").await?;
+
+        let updated = run(&ctx, "UPDATE products SET brand_id = 20 WHERE id = 1").await?;
+        assert_eq!(1, count(&updated));
+        assert_eq!(
+            vec![3],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 10").await?
+        );
+        assert_eq!(
+            vec![1, 2],
+            ids(&ctx, "SELECT id FROM products WHERE brand_id = 20").await?
+        );
+
+        let deleted = run(&ctx, "DELETE FROM products WHERE brand_id = 20").await?;
+        assert_eq!(2, count(&deleted));
+        assert!(ids(&ctx, "SELECT id FROM products WHERE brand_id = 20")
+            .await?
+            .is_empty());
+        assert_eq!(vec![3, 4], ids(&ctx, "SELECT id FROM products").await?);
+        assert_eq!(
+            vec![4],
+            ids(&ctx, "SELECT id FROM products WHERE id = 4").await?
+        );
+
+        let names = run(&ctx, "SELECT name FROM products WHERE id = 3").await?;
+        assert_eq!("c", names[0].column(0).as_string::<i32>().value(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,1057 @@
+//! The system catalog: what Quokka knows about each table, queryable as the tables of the
+//! `qs_catalog` schema.
+//!
+//! [`SystemCatalog`] holds a [`TableEntry`] for every table created with `CREATE TABLE`: its
+//! name, storage, schema, constraints, column defaults, indexes, partition key, policies and
+//! creation time. It also knows
+//! the schemas created with `CREATE SCHEMA`, so they can be recreated too. DDL updates it
+//! on the writer in the same step that changes the table, and the write-ahead log and checkpoints
+//! record the entries, so replaying them rebuilds the catalog and the tables are recreated from
+//! it. The entries can be queried as
+//!
+//! * `qs_catalog.qs_tables`, one row per table
+//! * `qs_catalog.qs_columns`, one row per column of each table
+//! * `qs_catalog.qs_types`, the SQL types columns can have
+//! * `qs_catalog.qs_partitions`, the rows and memory of each value of a partitioned table's
+//!   partition key
+//! * `qs_catalog.qs_policies`, one row per row-level security policy of each table
+//! * `qs_catalog.qs_roles`, one row per role or user (see [`crate::auth`])
+//! * `qs_catalog.qs_grants`, one row per privilege granted to a role on a table or schema
+//! * `qs_catalog.qs_analyzers`, one row per text analyzer (see [`crate::analysis`])
+//!
+//! which are read-only views of the catalog. Password hashes aren't shown.
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arrow::array::{
+    ArrayRef, BooleanArray, ListBuilder, StringArray, StringBuilder, TimestampMicrosecondArray,
+    UInt32Array, UInt64Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::catalog::schema::SchemaProvider;
+use datafusion::datasource::{TableProvider, TableType};
+use datafusion::error::Result;
+use datafusion::execution::context::{SessionContext, SessionState};
+use datafusion::logical_expr::Expr;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion_common::{
+    exec_err, plan_err, Constraint, Constraints, DataFusionError, ResolvedTableReference,
+};
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::analysis::{Analyzer, Analyzers};
+use crate::auth::{Roles, Securable};
+use crate::block_table::BlockTable;
+use crate::catalog::{MemorySchemaProvider, TableId, TableLock};
+use crate::index::{IndexMethod, SecondaryIndex};
+use crate::policy::Policy;
+use crate::sql::{as_block_table, as_mem_table, new_table, TableStorage};
+
+/// Name of the schema holding the system tables
+pub const SYSTEM_SCHEMA: &str = "qs_catalog";
+
+/// The SQL types of `qs_types`: their ids, names and the Arrow types they're stored as
+const TYPES: &[(u32, &str, DataType)] = &[
+    (1, "BOOLEAN", DataType::Boolean),
+    (2, "TINYINT", DataType::Int8),
+    (3, "SMALLINT", DataType::Int16),
+    (4, "INT", DataType::Int32),
+    (5, "BIGINT", DataType::Int64),
+    (6, "REAL", DataType::Float32),
+    (7, "DOUBLE", DataType::Float64),
+    (8, "VARCHAR", DataType::Utf8),
+    (9, "BYTEA", DataType::Binary),
+    (10, "DATE", DataType::Date32),
+    (
+        11,
+        "TIMESTAMP",
+        DataType::Timestamp(TimeUnit::Nanosecond, None),
+    ),
+];
+
+/// What the catalog knows about a table
+#[derive(Debug, Clone)]
+pub struct TableEntry {
+    pub id: TableId,
+    pub name: ResolvedTableReference<'static>,
+    pub storage: TableStorage,
+    pub schema: SchemaRef,
+    pub constraints: Constraints,
+    pub column_defaults: HashMap<String, Expr>,
+    pub indexes: Vec<IndexEntry>,
+    /// Microseconds since the Unix epoch, or 0 if the log didn't record it
+    pub created_at: i64,
+    /// Column a memory table is partitioned by with `PARTITION BY`
+    pub partition_by: Option<String>,
+    /// Row-level security policies of a memory table
+    pub policies: Vec<Policy>,
+}
+
+impl TableEntry {
+    /// An entry for a table created now, without indexes
+    pub fn new(
+        id: TableId,
+        name: ResolvedTableReference<'static>,
+        storage: TableStorage,
+        schema: SchemaRef,
+        constraints: Constraints,
+        column_defaults: HashMap<String, Expr>,
+    ) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as i64);
+        Self {
+            id,
+            name,
+            storage,
+            schema,
+            constraints,
+            column_defaults,
+            indexes: vec![],
+            created_at,
+            partition_by: None,
+            policies: vec![],
+        }
+    }
+
+    /// Name of the primary key column, if the table has one
+    pub fn primary_key(&self) -> Option<&str> {
+        self.constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                Constraint::PrimaryKey(columns) => columns
+                    .first()
+                    .map(|column| self.schema.field(*column).name().as_str()),
+                Constraint::Unique(_) => None,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+    pub method: IndexMethod,
+    /// Text analyzer of a fulltext index, if not the standard one
+    pub analyzer: Option<String>,
+}
+
+/// The entries of every table, by id
+#[derive(Debug, Default)]
+pub struct SystemCatalog {
+    tables: RwLock<BTreeMap<TableId, TableEntry>>,
+    /// Catalog and schema names of the schemas created with `CREATE SCHEMA`
+    schemas: RwLock<BTreeSet<(String, String)>>,
+    roles: RwLock<Roles>,
+    /// Text analyzers made with `CREATE TEXT ANALYZER`
+    analyzers: RwLock<Analyzers>,
+}
+
+impl SystemCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the table `entry` describes, holding the rows of `batches`, and register it in
+    /// `ctx`'s catalog, replacing the table of the same name if `or_replace` is set
+    pub fn create_table(
+        &self,
+        ctx: &SessionContext,
+        entry: TableEntry,
+        batches: Vec<Vec<RecordBatch>>,
+        or_replace: bool,
+    ) -> Result<Arc<dyn TableProvider>> {
+        let table = new_table(ctx, &entry, batches)?;
+        if or_replace {
+            if let Some(replaced) = ctx.deregister_table(entry.name.clone())? {
+                retire(&replaced);
+            }
+            self.remove(&entry.name);
+        }
+        ctx.register_table(entry.name.clone(), table.clone())?;
+        self.tables.write().insert(entry.id, entry);
+        Ok(table)
+    }
+
+    /// Build a version of the table `entry` describes holding the rows of `partitions`, with the
+    /// indexes of `entry`. It isn't registered until it replaces the current version.
+    pub async fn build_table(
+        &self,
+        ctx: &SessionContext,
+        entry: &TableEntry,
+        partitions: Vec<Vec<RecordBatch>>,
+    ) -> Result<Arc<dyn TableProvider>> {
+        let table = new_table(ctx, entry, partitions)?;
+        for index in &entry.indexes {
+            self.add_index(&table, index).await?;
+        }
+        Ok(table)
+    }
+
+    /// Replace `table`, whose entry is `old`, with `new_version`, which `entry` describes and
+    /// which is `table` itself if it's only renamed. The old version is retired.
+    pub fn replace_table(
+        &self,
+        ctx: &SessionContext,
+        old: &TableEntry,
+        table: &Arc<dyn TableProvider>,
+        entry: TableEntry,
+        new_version: Arc<dyn TableProvider>,
+    ) -> Result<()> {
+        if old.name.to_string() == entry.name.to_string() {
+            ctx.deregister_table(old.name.clone())?;
+            ctx.register_table(entry.name.clone(), new_version.clone())?;
+        } else {
+            // Registering first fails if the new name is taken, before anything has changed
+            ctx.register_table(entry.name.clone(), new_version.clone())?;
+            ctx.deregister_table(old.name.clone())?;
+        }
+        if !Arc::ptr_eq(table, &new_version) {
+            retire(table);
+        }
+        self.tables.write().insert(entry.id, entry);
+        Ok(())
+    }
+
+    /// Build the index `index` describes on a Quokka table and add it to the table's entry
+    pub async fn create_index(
+        &self,
+        table: &Arc<dyn TableProvider>,
+        index: IndexEntry,
+    ) -> Result<()> {
+        let id = self.add_index(table, &index).await?;
+        if let Some(entry) = self.tables.write().get_mut(&id) {
+            entry.indexes.push(index);
+        }
+        Ok(())
+    }
+
+    /// Build the index `index` describes on a Quokka table, returning the table's id
+    async fn add_index(
+        &self,
+        table: &Arc<dyn TableProvider>,
+        index: &IndexEntry,
+    ) -> Result<TableId> {
+        let secondary_index = self.build_index(index, &table.schema())?;
+        if let Some(mem_table) = as_mem_table(table) {
+            mem_table.create_index(secondary_index).await?;
+            Ok(mem_table.id())
+        } else if let Some(block_table) = as_block_table(table) {
+            block_table.create_index(secondary_index)?;
+            Ok(block_table.id())
+        } else {
+            exec_err!("Only Quokka tables have indexes")
+        }
+    }
+
+    /// An empty index as `index` describes it, on a table with `schema`
+    fn build_index(&self, index: &IndexEntry, schema: &SchemaRef) -> Result<SecondaryIndex> {
+        let secondary_index = SecondaryIndex::try_new(
+            &index.name,
+            index.columns.clone(),
+            index.unique,
+            index.method,
+            schema,
+        )?;
+        match &index.analyzer {
+            Some(analyzer) => secondary_index.with_analyzer(self.analyzer(analyzer)?, schema),
+            None => Ok(secondary_index),
+        }
+    }
+
+    /// Drop index `name` of a Quokka table and remove it from the table's entry
+    pub async fn drop_index(&self, table: &Arc<dyn TableProvider>, name: &str) -> Result<()> {
+        let id = if let Some(mem_table) = as_mem_table(table) {
+            mem_table.drop_index(name).await;
+            mem_table.id()
+        } else if let Some(block_table) = as_block_table(table) {
+            block_table.drop_index(name);
+            block_table.id()
+        } else {
+            return exec_err!("Only Quokka tables have indexes");
+        };
+        if let Some(entry) = self.tables.write().get_mut(&id) {
+            entry.indexes.retain(|index| index.name != name);
+        }
+        Ok(())
+    }
+
+    /// Add policy `name`, which makes rows of a memory table visible when the SQL expression
+    /// `using` is true, to the table and its entry
+    pub fn create_policy(
+        &self,
+        state: &SessionState,
+        table: &Arc<dyn TableProvider>,
+        name: &str,
+        using: &str,
+    ) -> Result<()> {
+        let Some(mem_table) = as_mem_table(table) else {
+            return exec_err!("Only memory tables have policies");
+        };
+        let policy = Policy::try_new(state, name, &mem_table.schema(), using)?;
+        mem_table.create_policy(policy.clone())?;
+        if let Some(entry) = self.tables.write().get_mut(&mem_table.id()) {
+            entry.policies.push(policy);
+        }
+        Ok(())
+    }
+
+    /// Drop policy `name` of a memory table and remove it from the table's entry. Returns
+    /// whether the table had the policy.
+    pub fn drop_policy(&self, table: &Arc<dyn TableProvider>, name: &str) -> Result<bool> {
+        let Some(mem_table) = as_mem_table(table) else {
+            return exec_err!("Only memory tables have policies");
+        };
+        if !mem_table.drop_policy(name) {
+            return Ok(false);
+        }
+        if let Some(entry) = self.tables.write().get_mut(&mem_table.id()) {
+            entry.policies.retain(|policy| policy.name != name);
+        }
+        Ok(true)
+    }
+
+    /// Drop the table with id `id`: forget its entry, deregister it from `ctx`'s catalog and
+    /// retire it. Its rows and indexes are freed once the queries still holding it finish.
+    pub fn drop_table(&self, ctx: &SessionContext, id: TableId) -> Result<Option<TableEntry>> {
+        let Some(entry) = self.tables.write().remove(&id) else {
+            return Ok(None);
+        };
+        self.roles.write().forget(&Securable::Table(id));
+        if let Some(table) = ctx.deregister_table(entry.name.clone())? {
+            retire(&table);
+        }
+        Ok(Some(entry))
+    }
+
+    /// Create an empty schema called `schema` in catalog `catalog` of `ctx`
+    pub fn create_schema(&self, ctx: &SessionContext, catalog: &str, schema: &str) -> Result<()> {
+        let Some(provider) = ctx.catalog(catalog) else {
+            return exec_err!("Catalog {catalog} does not exist");
+        };
+        if provider.schema(schema).is_some() {
+            return exec_err!("Schema '{schema}' already exists");
+        }
+        provider.register_schema(schema, Arc::new(MemorySchemaProvider::new()))?;
+        self.schemas
+            .write()
+            .insert((catalog.to_string(), schema.to_string()));
+        Ok(())
+    }
+
+    /// Drop schema `schema` of catalog `catalog`, along with anything still in it. Its Quokka
+    /// tables should have been dropped with [`Self::drop_table`] first.
+    pub fn drop_schema(&self, ctx: &SessionContext, catalog: &str, schema: &str) -> Result<()> {
+        if schema == SYSTEM_SCHEMA {
+            return exec_err!("Can't drop {SYSTEM_SCHEMA}");
+        }
+        if let Some(provider) = ctx.catalog(catalog) {
+            provider.deregister_schema(schema, true)?;
+        }
+        self.schemas
+            .write()
+            .remove(&(catalog.to_string(), schema.to_string()));
+        self.roles.write().forget(&Securable::Schema {
+            catalog: catalog.to_string(),
+            schema: schema.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Catalog and schema names of the schemas created with `CREATE SCHEMA`
+    pub fn schemas(&self) -> Vec<(String, String)> {
+        self.schemas.read().iter().cloned().collect()
+    }
+
+    /// The roles and the privileges granted to them
+    pub fn roles(&self) -> RwLockReadGuard<'_, Roles> {
+        self.roles.read()
+    }
+
+    pub fn roles_mut(&self) -> RwLockWriteGuard<'_, Roles> {
+        self.roles.write()
+    }
+
+    /// The custom text analyzers
+    pub fn analyzers(&self) -> RwLockReadGuard<'_, Analyzers> {
+        self.analyzers.read()
+    }
+
+    pub fn analyzers_mut(&self) -> RwLockWriteGuard<'_, Analyzers> {
+        self.analyzers.write()
+    }
+
+    /// The text analyzer called `name`, built-in or custom
+    pub fn analyzer(&self, name: &str) -> Result<Arc<Analyzer>> {
+        match self.analyzers().get(name) {
+            Some(analyzer) => Ok(analyzer),
+            None => plan_err!("Text analyzer {name} does not exist"),
+        }
+    }
+
+    /// Forget the table called `name`, returning its entry
+    pub fn remove(&self, name: &ResolvedTableReference) -> Option<TableEntry> {
+        let mut tables = self.tables.write();
+        let id = tables
+            .values()
+            .find(|entry| entry.name.to_string() == name.to_string())?
+            .id;
+        tables.remove(&id)
+    }
+
+    /// The entry of the table called `name`
+    pub fn find(&self, name: &ResolvedTableReference) -> Option<TableEntry> {
+        self.tables
+            .read()
+            .values()
+            .find(|entry| entry.name.to_string() == name.to_string())
+            .cloned()
+    }
+
+    pub fn entry(&self, id: TableId) -> Option<TableEntry> {
+        self.tables.read().get(&id).cloned()
+    }
+
+    /// Every entry, ordered by table id
+    pub fn entries(&self) -> Vec<TableEntry> {
+        self.tables.read().values().cloned().collect()
+    }
+
+    fn tables_batch(&self) -> Result<RecordBatch> {
+        let entries = self.entries();
+        let mut indexes = ListBuilder::new(StringBuilder::new());
+        for entry in &entries {
+            for index in &entry.indexes {
+                indexes.values().append_value(&index.name);
+            }
+            indexes.append(true);
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from_iter_values(entries.iter().map(|e| e.id))),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.name.catalog.as_ref()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.name.schema.as_ref()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.name.table.as_ref()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.storage.to_string()),
+            )),
+            Arc::new(StringArray::from_iter(
+                entries.iter().map(|e| e.primary_key()),
+            )),
+            Arc::new(indexes.finish()),
+            Arc::new(
+                TimestampMicrosecondArray::from_iter(
+                    entries
+                        .iter()
+                        .map(|e| (e.created_at != 0).then_some(e.created_at)),
+                )
+                .with_timezone("UTC"),
+            ),
+            Arc::new(StringArray::from_iter(
+                entries.iter().map(|e| e.partition_by.as_deref()),
+            )),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Tables.schema(),
+            columns,
+        )?)
+    }
+
+    fn columns_batch(&self) -> Result<RecordBatch> {
+        let mut table_ids = vec![];
+        let mut names = vec![];
+        let mut ordinals = vec![];
+        let mut type_ids = vec![];
+        let mut data_types = vec![];
+        let mut nullable = vec![];
+        let mut defaults = vec![];
+        let mut primary_key = vec![];
+        for entry in self.entries() {
+            let key = entry.primary_key().map(str::to_string);
+            for (ordinal, field) in entry.schema.fields().iter().enumerate() {
+                table_ids.push(entry.id);
+                names.push(field.name().clone());
+                ordinals.push(ordinal as u32 + 1);
+                type_ids.push(
+                    TYPES
+                        .iter()
+                        .find(|(_, _, data_type)| data_type == field.data_type())
+                        .map(|(id, _, _)| *id),
+                );
+                data_types.push(field.data_type().to_string());
+                nullable.push(field.is_nullable());
+                defaults.push(entry.column_defaults.get(field.name()).map(
+                    |default| match default {
+                        Expr::Literal(value) => value.to_string(),
+                        default => default.to_string(),
+                    },
+                ));
+                primary_key.push(key.as_ref() == Some(field.name()));
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(table_ids)),
+            Arc::new(StringArray::from(names)),
+            Arc::new(UInt32Array::from(ordinals)),
+            Arc::new(UInt32Array::from(type_ids)),
+            Arc::new(StringArray::from(data_types)),
+            Arc::new(BooleanArray::from(nullable)),
+            Arc::new(StringArray::from(defaults)),
+            Arc::new(BooleanArray::from(primary_key)),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Columns.schema(),
+            columns,
+        )?)
+    }
+
+    async fn partitions_batch(&self, state: &SessionState) -> Result<RecordBatch> {
+        let mut table_ids = vec![];
+        let mut values = vec![];
+        let mut row_counts = vec![];
+        let mut memory = vec![];
+        for entry in self.entries() {
+            if entry.partition_by.is_none() {
+                continue;
+            }
+            let Some(schema) = state
+                .catalog_list()
+                .catalog(&entry.name.catalog)
+                .and_then(|catalog| catalog.schema(&entry.name.schema))
+            else {
+                continue;
+            };
+            let Some(table) = schema.table(&entry.name.table).await else {
+                continue;
+            };
+            let Some(mem_table) = as_mem_table(&table) else {
+                continue;
+            };
+            for stats in mem_table.partition_stats().await {
+                table_ids.push(entry.id);
+                values.push((!stats.value.is_null()).then(|| stats.value.to_string()));
+                row_counts.push(stats.rows as u64);
+                memory.push(stats.memory as u64);
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(table_ids)),
+            Arc::new(StringArray::from(values)),
+            Arc::new(UInt64Array::from(row_counts)),
+            Arc::new(UInt64Array::from(memory)),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Partitions.schema(),
+            columns,
+        )?)
+    }
+
+    fn policies_batch(&self) -> Result<RecordBatch> {
+        let mut table_ids = vec![];
+        let mut names = vec![];
+        let mut usings = vec![];
+        for entry in self.entries() {
+            for policy in &entry.policies {
+                table_ids.push(entry.id);
+                names.push(policy.name.clone());
+                usings.push(policy.sql.clone());
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(table_ids)),
+            Arc::new(StringArray::from(names)),
+            Arc::new(StringArray::from(usings)),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Policies.schema(),
+            columns,
+        )?)
+    }
+
+    fn roles_batch(&self) -> Result<RecordBatch> {
+        let roles = self.roles();
+        let mut member_of = ListBuilder::new(StringBuilder::new());
+        for role in roles.roles() {
+            for name in &role.member_of {
+                member_of.values().append_value(name);
+            }
+            member_of.append(true);
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(
+                roles.roles().map(|r| r.name.as_str()),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                roles.roles().map(|r| Some(r.login)),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                roles.roles().map(|r| Some(r.superuser)),
+            )),
+            Arc::new(StringArray::from_iter(
+                roles.roles().map(|r| r.tenant_id.as_deref()),
+            )),
+            Arc::new(member_of.finish()),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Roles.schema(),
+            columns,
+        )?)
+    }
+
+    fn grants_batch(&self) -> Result<RecordBatch> {
+        let mut grantees = vec![];
+        let mut privileges = vec![];
+        let mut table_ids = vec![];
+        let mut catalogs = vec![];
+        let mut schemas = vec![];
+        for (grantee, securable, privilege) in self.roles().grants() {
+            grantees.push(grantee.to_string());
+            privileges.push(privilege.to_string());
+            match securable {
+                Securable::Table(id) => {
+                    table_ids.push(Some(*id));
+                    catalogs.push(None);
+                    schemas.push(None);
+                }
+                Securable::Schema { catalog, schema } => {
+                    table_ids.push(None);
+                    catalogs.push(Some(catalog.clone()));
+                    schemas.push(Some(schema.clone()));
+                }
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(grantees)),
+            Arc::new(StringArray::from(privileges)),
+            Arc::new(UInt64Array::from(table_ids)),
+            Arc::new(StringArray::from(catalogs)),
+            Arc::new(StringArray::from(schemas)),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Grants.schema(),
+            columns,
+        )?)
+    }
+
+    fn analyzers_batch(&self) -> Result<RecordBatch> {
+        let analyzers = self.analyzers().all();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(
+                analyzers.iter().map(|(name, _, _)| name),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                analyzers
+                    .iter()
+                    .map(|(_, analyzer, _)| analyzer.to_string()),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                analyzers.iter().map(|(_, _, builtin)| Some(*builtin)),
+            )),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Analyzers.schema(),
+            columns,
+        )?)
+    }
+
+    fn types_batch() -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt32Array::from_iter_values(TYPES.iter().map(|t| t.0))),
+            Arc::new(StringArray::from_iter_values(TYPES.iter().map(|t| t.1))),
+            Arc::new(StringArray::from_iter_values(
+                TYPES.iter().map(|t| t.2.to_string()),
+            )),
+        ];
+        Ok(RecordBatch::try_new(
+            SystemTableKind::Types.schema(),
+            columns,
+        )?)
+    }
+}
+
+/// The id of `table`, if it's a Quokka table
+pub fn table_id(table: &Arc<dyn TableProvider>) -> Option<TableId> {
+    if let Some(mem_table) = as_mem_table(table) {
+        Some(mem_table.id())
+    } else {
+        as_block_table(table).map(BlockTable::id)
+    }
+}
+
+/// Make the writes still queued for a Quokka table fail, since it has been replaced or dropped
+pub fn retire(table: &Arc<dyn TableProvider>) {
+    if let Some(mem_table) = as_mem_table(table) {
+        mem_table.retire();
+    } else if let Some(block_table) = as_block_table(table) {
+        block_table.retire();
+    }
+}
+
+/// The lock that keeps writes away from `table` while it's altered, if it's a Quokka table
+pub fn table_lock(table: &Arc<dyn TableProvider>) -> Option<TableLock> {
+    if let Some(mem_table) = as_mem_table(table) {
+        Some(mem_table.table_lock().clone())
+    } else {
+        as_block_table(table).map(|block_table| block_table.table_lock().clone())
+    }
+}
+
+/// The system catalog of `ctx`'s default catalog, registering an empty one if it has none yet
+pub fn system_catalog(ctx: &SessionContext) -> Result<Arc<SystemCatalog>> {
+    system_catalog_of(&ctx.state())
+}
+
+/// The system catalog of the default catalog of a session's state, registering an empty one if
+/// it has none yet
+pub fn system_catalog_of(state: &SessionState) -> Result<Arc<SystemCatalog>> {
+    let catalog_name = &state.config_options().catalog.default_catalog;
+    let Some(catalog) = state.catalog_list().catalog(catalog_name) else {
+        return exec_err!("Catalog {catalog_name} does not exist");
+    };
+    if let Some(schema) = catalog.schema(SYSTEM_SCHEMA) {
+        if let Some(schema) = schema.as_any().downcast_ref::<SystemSchema>() {
+            return Ok(schema.catalog.clone());
+        }
+        return exec_err!("{SYSTEM_SCHEMA} is not the system catalog");
+    }
+    let schema = SystemSchema::new();
+    let system_catalog = schema.catalog.clone();
+    catalog.register_schema(SYSTEM_SCHEMA, Arc::new(schema))?;
+    Ok(system_catalog)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SystemTableKind {
+    Tables,
+    Columns,
+    Types,
+    Partitions,
+    Policies,
+    Roles,
+    Grants,
+    Analyzers,
+}
+
+impl SystemTableKind {
+    const ALL: [SystemTableKind; 8] = [
+        SystemTableKind::Tables,
+        SystemTableKind::Columns,
+        SystemTableKind::Types,
+        SystemTableKind::Partitions,
+        SystemTableKind::Policies,
+        SystemTableKind::Roles,
+        SystemTableKind::Grants,
+        SystemTableKind::Analyzers,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            SystemTableKind::Tables => "qs_tables",
+            SystemTableKind::Columns => "qs_columns",
+            SystemTableKind::Types => "qs_types",
+            SystemTableKind::Partitions => "qs_partitions",
+            SystemTableKind::Policies => "qs_policies",
+            SystemTableKind::Roles => "qs_roles",
+            SystemTableKind::Grants => "qs_grants",
+            SystemTableKind::Analyzers => "qs_analyzers",
+        }
+    }
+
+    fn schema(self) -> SchemaRef {
+        let fields = match self {
+            SystemTableKind::Tables => vec![
+                Field::new("table_id", DataType::UInt64, false),
+                Field::new("catalog_name", DataType::Utf8, false),
+                Field::new("schema_name", DataType::Utf8, false),
+                Field::new("table_name", DataType::Utf8, false),
+                Field::new("storage", DataType::Utf8, false),
+                Field::new("primary_key", DataType::Utf8, true),
+                Field::new(
+                    "indexes",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                    false,
+                ),
+                Field::new(
+                    "created_at",
+                    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                    true,
+                ),
+                Field::new("partition_by", DataType::Utf8, true),
+            ],
+            SystemTableKind::Columns => vec![
+                Field::new("table_id", DataType::UInt64, false),
+                Field::new("column_name", DataType::Utf8, false),
+                // Starts at 1, like Postgres' attnum
+                Field::new("ordinal", DataType::UInt32, false),
+                Field::new("type_id", DataType::UInt32, true),
+                Field::new("data_type", DataType::Utf8, false),
+                Field::new("nullable", DataType::Boolean, false),
+                Field::new("default_value", DataType::Utf8, true),
+                Field::new("primary_key", DataType::Boolean, false),
+            ],
+            SystemTableKind::Types => vec![
+                Field::new("type_id", DataType::UInt32, false),
+                Field::new("type_name", DataType::Utf8, false),
+                Field::new("data_type", DataType::Utf8, false),
+            ],
+            SystemTableKind::Partitions => vec![
+                Field::new("table_id", DataType::UInt64, false),
+                Field::new("partition_value", DataType::Utf8, true),
+                Field::new("row_count", DataType::UInt64, false),
+                // Approximate, from the sizes of the partition's Arrow buffers
+                Field::new("memory_bytes", DataType::UInt64, false),
+            ],
+            SystemTableKind::Policies => vec![
+                Field::new("table_id", DataType::UInt64, false),
+                Field::new("policy_name", DataType::Utf8, false),
+                Field::new("using", DataType::Utf8, false),
+            ],
+            SystemTableKind::Roles => vec![
+                Field::new("role_name", DataType::Utf8, false),
+                Field::new("can_login", DataType::Boolean, false),
+                Field::new("superuser", DataType::Boolean, false),
+                Field::new("tenant_id", DataType::Utf8, true),
+                Field::new(
+                    "member_of",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                    false,
+                ),
+            ],
+            // Either the table or the schema is set
+            SystemTableKind::Grants => vec![
+                Field::new("grantee", DataType::Utf8, false),
+                Field::new("privilege", DataType::Utf8, false),
+                Field::new("table_id", DataType::UInt64, true),
+                Field::new("catalog_name", DataType::Utf8, true),
+                Field::new("schema_name", DataType::Utf8, true),
+            ],
+            SystemTableKind::Analyzers => vec![
+                Field::new("analyzer_name", DataType::Utf8, false),
+                Field::new("definition", DataType::Utf8, false),
+                Field::new("builtin", DataType::Boolean, false),
+            ],
+        };
+        Arc::new(Schema::new(fields))
+    }
+}
+
+/// The `qs_catalog` schema, whose tables can't be created or dropped
+pub struct SystemSchema {
+    catalog: Arc<SystemCatalog>,
+}
+
+impl SystemSchema {
+    /// A schema over a new, empty system catalog
+    pub fn new() -> Self {
+        Self {
+            catalog: Arc::new(SystemCatalog::new()),
+        }
+    }
+}
+
+impl Default for SystemSchema {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl SchemaProvider for SystemSchema {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_names(&self) -> Vec<String> {
+        SystemTableKind::ALL
+            .iter()
+            .map(|kind| kind.name().to_string())
+            .collect()
+    }
+
+    async fn table(&self, name: &str) -> Option<Arc<dyn TableProvider>> {
+        let kind = SystemTableKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)?;
+        Some(Arc::new(SystemTable {
+            kind,
+            schema: kind.schema(),
+            catalog: self.catalog.clone(),
+        }))
+    }
+
+    fn register_table(
+        &self,
+        name: String,
+        _table: Arc<dyn TableProvider>,
+    ) -> Result<Option<Arc<dyn TableProvider>>> {
+        exec_err!("Can't create table {name} in {SYSTEM_SCHEMA}")
+    }
+
+    fn deregister_table(&self, name: &str) -> Result<Option<Arc<dyn TableProvider>>> {
+        exec_err!("Can't drop table {name} from {SYSTEM_SCHEMA}")
+    }
+
+    fn table_exist(&self, name: &str) -> bool {
+        SystemTableKind::ALL.iter().any(|kind| kind.name() == name)
+    }
+}
+
+/// A read-only view of the system catalog
+struct SystemTable {
+    kind: SystemTableKind,
+    schema: SchemaRef,
+    catalog: Arc<SystemCatalog>,
+}
+
+#[async_trait]
+impl TableProvider for SystemTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::View
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let batch = match self.kind {
+            SystemTableKind::Tables => self.catalog.tables_batch()?,
+            SystemTableKind::Columns => self.catalog.columns_batch()?,
+            SystemTableKind::Types => SystemCatalog::types_batch()?,
+            SystemTableKind::Partitions => self.catalog.partitions_batch(state).await?,
+            SystemTableKind::Policies => self.catalog.policies_batch()?,
+            SystemTableKind::Roles => self.catalog.roles_batch()?,
+            SystemTableKind::Grants => self.catalog.grants_batch()?,
+            SystemTableKind::Analyzers => self.catalog.analyzers_batch()?,
+        };
+        Ok(Arc::new(MemoryExec::try_new(
+            &[vec![batch]],
+            self.schema.clone(),
+            projection.cloned(),
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::new_context;
+    use crate::test_util::query;
+
+    #[tokio::test]
+    async fn ddl_updates_the_system_tables() -> Result<()> {
+        let ctx = new_context();
+        query(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, price DOUBLE DEFAULT 1.5)",
+        )
+        .await?;
+        query(
+            &ctx,
+            "CREATE TABLE events (at BIGINT) WITH (storage = 'block')",
+        )
+        .await?;
+        query(&ctx, "CREATE INDEX products_name ON products (name)").await?;
+
+        assert_eq!(
+            "+------------+-------------+---------+-----------------+----------------+\n\
+             | table_name | primary_key | storage | indexes         | has_created_at |\n\
+             +------------+-------------+---------+-----------------+----------------+\n\
+             | products   | id          | memory  | [products_name] | true           |\n\
+             +------------+-------------+---------+-----------------+----------------+",
+            query(
+                &ctx,
+                "SELECT table_name, primary_key, storage, indexes, \
+                 created_at IS NOT NULL AS has_created_at \
+                 FROM qs_catalog.qs_tables WHERE table_name = 'products'"
+            )
+            .await?
+        );
+        assert_eq!(
+            "+-------------+---------+-----------+----------+---------------+-------------+\n\
+             | column_name | ordinal | type_name | nullable | default_value | primary_key |\n\
+             +-------------+---------+-----------+----------+---------------+-------------+\n\
+             | id          | 1       | INT       | true     |               | true        |\n\
+             | name        | 2       | VARCHAR   | true     |               | false       |\n\
+             | price       | 3       | DOUBLE    | true     | 1.5           | false       |\n\
+             | at          | 1       | BIGINT    | true     |               | false       |\n\
+             +-------------+---------+-----------+----------+---------------+-------------+",
+            query(
+                &ctx,
+                "SELECT c.column_name, c.ordinal, ty.type_name, c.nullable, c.default_value, \
+                 c.primary_key \
+                 FROM qs_catalog.qs_columns c \
+                 JOIN qs_catalog.qs_tables t ON c.table_id = t.table_id \
+                 JOIN qs_catalog.qs_types ty ON c.type_id = ty.type_id \
+                 ORDER BY t.table_id, c.ordinal"
+            )
+            .await?
+        );
+
+        query(&ctx, "DROP INDEX products_name").await?;
+        query(
+            &ctx,
+            "CREATE OR REPLACE TABLE events (at BIGINT, kind VARCHAR) WITH (storage = 'block')",
+        )
+        .await?;
+        assert_eq!(
+            "+------------+---------+---------+\n\
+             | table_name | indexes | columns |\n\
+             +------------+---------+---------+\n\
+             | products   | []      | 3       |\n\
+             | events     | []      | 2       |\n\
+             +------------+---------+---------+",
+            query(
+                &ctx,
+                "SELECT t.table_name, t.indexes, count(*) AS columns \
+                 FROM qs_catalog.qs_tables t \
+                 JOIN qs_catalog.qs_columns c ON t.table_id = c.table_id \
+                 GROUP BY t.table_id, t.table_name, t.indexes ORDER BY t.table_id"
+            )
+            .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn system_tables_are_read_only() -> Result<()> {
+        let ctx = new_context();
+        assert!(
+            query(&ctx, "CREATE TABLE qs_catalog.mine (id INT PRIMARY KEY)")
+                .await
+                .is_err()
+        );
+        assert!(query(&ctx, "DROP TABLE qs_catalog.qs_tables")
+            .await
+            .is_err());
+        assert!(query(
+            &ctx,
+            "INSERT INTO qs_catalog.qs_types VALUES (99, 'X', 'X')"
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
+}
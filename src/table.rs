@@ -1,44 +1,627 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::ops::Deref;
+
 use anyhow::anyhow;
+use arrow_buffer::{Buffer, MutableBuffer};
 use roaring::RoaringBitmap;
 
-const SLOTS_PER_BLOCK: usize = 1000;
+use crate::transaction::{Snapshot, TransactionId};
+
+pub const SLOTS_PER_BLOCK: usize = 1000;
+
+/// Alignment of each column's values within a block, the alignment Arrow allocates buffers with
+const ALIGNMENT: usize = 64;
+
+/// Bytes a variable-length value takes in its column: a 4-byte length followed by either the
+/// value itself, or its first 4 bytes and its offset in the block's varlen arena
+pub const VARLEN_ENTRY_SIZE: usize = 16;
+
+/// Longest variable-length value stored in its column instead of the varlen arena
+const VARLEN_INLINE_LEN: usize = 12;
+
+/// Number of leading bytes of a long variable-length value stored in its column
+const VARLEN_PREFIX_LEN: usize = 4;
+
+/// How a block stores the values of a column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnSize {
+    /// Every value takes this many bytes
+    Fixed(usize),
+    /// Values of any length, stored "German style" as described in section 3.1 of the Umbra
+    /// paper linked in `docs/index-design.md`
+    Varlen,
+}
+
+impl ColumnSize {
+    /// Bytes each slot of the column takes
+    pub fn slot_size(&self) -> usize {
+        match self {
+            ColumnSize::Fixed(size) => *size,
+            ColumnSize::Varlen => VARLEN_ENTRY_SIZE,
+        }
+    }
+}
 
 type BlockIndex = usize;
 type RowIndex = usize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TupleSlot {
     block_index: BlockIndex,
     row_index: RowIndex,
 }
 
+impl TupleSlot {
+    pub fn new(block_index: BlockIndex, row_index: RowIndex) -> TupleSlot {
+        TupleSlot {
+            block_index,
+            row_index,
+        }
+    }
+
+    pub fn block_index(&self) -> BlockIndex {
+        self.block_index
+    }
+
+    pub fn row_index(&self) -> RowIndex {
+        self.row_index
+    }
+}
+
+/// What a compaction run did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Sparse blocks whose rows were moved out and whose memory was freed
+    pub blocks_freed: usize,
+    /// Rows moved to another slot
+    pub tuples_moved: usize,
+}
+
+/// The writes a transaction had made at some point, which [`Table::rollback`] undoes the writes
+/// after
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    transaction: TransactionId,
+    undo: usize,
+    inserted: usize,
+}
+
+/// What a garbage collection run did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    /// Transactions whose undo records were freed
+    pub transactions_freed: usize,
+    /// Approximate bytes of undo records freed
+    pub bytes_freed: usize,
+}
+
+/// The undo records one transaction wrote to a table. They are freed together once every active
+/// snapshot sees the transaction.
+#[derive(Debug)]
+struct DeltaBlock {
+    transaction: TransactionId,
+    undo: Vec<UndoRecord>,
+    /// Rows the transaction inserted, which rolling it back removes
+    inserted: Vec<TupleSlot>,
+    /// Approximate bytes used by the undo records and inserted slots
+    size: usize,
+}
+
+/// Restores the version of a record from before a write
+#[derive(Debug)]
+struct UndoRecord {
+    slot: TupleSlot,
+    /// Transaction that wrote the version this restores
+    version: TransactionId,
+    /// Values of the columns the write changed, from before it. Deletes change every column.
+    before: ProjectedRow,
+    /// The undo record restoring the version before `version`
+    next: Option<UndoRef>,
+}
+
+/// Location of an undo record: the transaction whose write it undoes, and its index in that
+/// transaction's delta block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UndoRef {
+    transaction: TransactionId,
+    index: usize,
+}
+
 #[derive(Debug)]
 pub struct Table {
-    blocks: Vec<Block>,
+    column_sizes: Vec<ColumnSize>,
+    /// Blocks by index. Compaction frees blocks, leaving a hole until the index is reused so the
+    /// slots of other blocks don't change.
+    blocks: Vec<Option<Block>>,
+    /// The block rows are inserted into
+    insert_block: Option<BlockIndex>,
+    /// Indexes of blocks freed by compaction, reused for new blocks
+    free_blocks: Vec<BlockIndex>,
+    /// Undo records of the transactions that wrote to the table and may still be needed, oldest
+    /// first
+    deltas: VecDeque<DeltaBlock>,
 }
 
 impl Table {
-    pub fn get_row(&self, tuple_slot: TupleSlot, column_ids: &[usize]) -> Option<ProjectedRow> {
+    pub fn new(column_sizes: Vec<ColumnSize>) -> Table {
+        Table {
+            column_sizes,
+            blocks: Vec::new(),
+            insert_block: None,
+            free_blocks: Vec::new(),
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Each block and its index, skipping freed blocks
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockIndex, &Block)> {
         self.blocks
-            .get(tuple_slot.block_index)?
+            .iter()
+            .enumerate()
+            .filter_map(|(block_index, block)| Some((block_index, block.as_ref()?)))
+    }
+
+    pub fn block(&self, block_index: BlockIndex) -> Option<&Block> {
+        self.blocks.get(block_index)?.as_ref()
+    }
+
+    /// Number of blocks that haven't been freed
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len() - self.free_blocks.len()
+    }
+
+    pub fn column_sizes(&self) -> &[ColumnSize] {
+        &self.column_sizes
+    }
+
+    /// Approximate bytes used by undo records that haven't been freed
+    pub fn undo_size(&self) -> usize {
+        self.deltas.iter().map(|delta| delta.size).sum()
+    }
+
+    pub fn get_row(&self, tuple_slot: TupleSlot, column_ids: &[usize]) -> Option<ProjectedRow> {
+        self.block(tuple_slot.block_index)?
             .row_at_index(tuple_slot.row_index, column_ids)
     }
+
+    /// Every column of the version of a row `snapshot` sees, or `None` if the row didn't exist or
+    /// was deleted at the snapshot
+    pub fn visible_row(&self, tuple_slot: TupleSlot, snapshot: Snapshot) -> Option<ProjectedRow> {
+        let block = self.block(tuple_slot.block_index)?;
+        let record_index = tuple_slot.row_index;
+        let mut row = block
+            .present()
+            .contains(record_index as u32)
+            .then(|| block.present_row(record_index));
+        if snapshot.sees(block.version(record_index)) {
+            return row;
+        }
+        let mut undo = block.undo.get(&record_index).copied();
+        while let Some(record) = undo.and_then(|undo| self.undo_record(undo)) {
+            let row = row.get_or_insert_with(|| self.empty_row());
+            for (column_id, value) in record.before.values() {
+                row.column_values[column_id].clone_from(value);
+            }
+            if snapshot.sees(record.version) {
+                return Some(row.clone());
+            }
+            undo = record.next;
+        }
+        // The row was inserted after the snapshot
+        None
+    }
+
+    /// Every column of each version of a row that is still kept, newest first. The current
+    /// version of a deleted row is left out.
+    pub fn versions(&self, tuple_slot: TupleSlot) -> Vec<ProjectedRow> {
+        let Some(block) = self.block(tuple_slot.block_index) else {
+            return vec![];
+        };
+        let record_index = tuple_slot.row_index;
+        let mut row = block
+            .present()
+            .contains(record_index as u32)
+            .then(|| block.present_row(record_index));
+        let mut versions: Vec<ProjectedRow> = row.iter().cloned().collect();
+        let mut undo = block.undo.get(&record_index).copied();
+        while let Some(record) = undo.and_then(|undo| self.undo_record(undo)) {
+            let row = row.get_or_insert_with(|| self.empty_row());
+            for (column_id, value) in record.before.values() {
+                row.column_values[column_id].clone_from(value);
+            }
+            versions.push(row.clone());
+            undo = record.next;
+        }
+        versions
+    }
+
+    /// Insert `row` as written by transaction `version` into the block rows are being inserted
+    /// into. If it is full, it is frozen and rows go to a new block, which reuses the index of a
+    /// freed block if there is one.
+    pub fn insert(
+        &mut self,
+        row: &ProjectedRow,
+        version: TransactionId,
+    ) -> anyhow::Result<TupleSlot> {
+        let slot = self.insert_version(row, version)?;
+        let delta = self.delta_block(version);
+        delta.inserted.push(slot);
+        delta.size += size_of::<TupleSlot>();
+        Ok(slot)
+    }
+
+    /// Change the columns of a row to the values in `row` as transaction `version`, keeping the
+    /// old values for snapshots that don't see it
+    pub fn update(
+        &mut self,
+        tuple_slot: TupleSlot,
+        row: &ProjectedRow,
+        version: TransactionId,
+    ) -> anyhow::Result<()> {
+        let block = self.block_mut(tuple_slot.block_index)?;
+        let old_version = block.version(tuple_slot.row_index);
+        let before = block.update(tuple_slot.row_index, row, version)?;
+        self.push_undo(tuple_slot, old_version, before, version);
+        Ok(())
+    }
+
+    /// Delete a row as transaction `version`, keeping its values for snapshots that don't see
+    /// the delete
+    pub fn delete(&mut self, tuple_slot: TupleSlot, version: TransactionId) -> anyhow::Result<()> {
+        let block = self.block_mut(tuple_slot.block_index)?;
+        let old_version = block.version(tuple_slot.row_index);
+        let before = block.delete(tuple_slot.row_index, version)?;
+        self.push_undo(tuple_slot, old_version, before, version);
+        Ok(())
+    }
+
+    /// Mark the writes transaction `version`, which must be the newest to write, has made so
+    /// far, so the ones after can be rolled back
+    pub fn savepoint(&self, version: TransactionId) -> Savepoint {
+        match self.deltas.back() {
+            Some(delta) if delta.transaction == version => Savepoint {
+                transaction: version,
+                undo: delta.undo.len(),
+                inserted: delta.inserted.len(),
+            },
+            _ => Savepoint {
+                transaction: version,
+                undo: 0,
+                inserted: 0,
+            },
+        }
+    }
+
+    /// Undo every write made after `savepoint` by its transaction, which must not have committed
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        let Some(position) = self
+            .deltas
+            .iter()
+            .rposition(|delta| delta.transaction == savepoint.transaction)
+        else {
+            return;
+        };
+        let delta = &mut self.deltas[position];
+        let undo = delta.undo.split_off(savepoint.undo.min(delta.undo.len()));
+        let inserted = delta
+            .inserted
+            .split_off(savepoint.inserted.min(delta.inserted.len()));
+        delta.size -= undo
+            .iter()
+            .map(|record| size_of::<UndoRecord>() + record.before.size())
+            .sum::<usize>()
+            + inserted.len() * size_of::<TupleSlot>();
+        if delta.undo.is_empty() && delta.inserted.is_empty() {
+            self.deltas.remove(position);
+        }
+        for record in undo.into_iter().rev() {
+            let block = self
+                .block_mut(record.slot.block_index)
+                .expect("blocks with uncommitted writes aren't compacted");
+            block.restore(record.slot.row_index, &record.before, record.version);
+            match record.next {
+                Some(next) => block.undo.insert(record.slot.row_index, next),
+                None => block.undo.remove(&record.slot.row_index),
+            };
+        }
+        for slot in inserted {
+            let block = self
+                .block_mut(slot.block_index)
+                .expect("blocks with uncommitted writes aren't compacted");
+            block.remove(slot.row_index);
+        }
+    }
+
+    /// Free the undo records of the transactions `oldest`, the oldest snapshot still in use,
+    /// sees, since no snapshot needs the versions they restore. `on_obsolete` is called with the
+    /// slot and every column of each freed version, so anything that indexes old versions, like
+    /// a secondary index, can remove their entries.
+    pub fn collect_garbage(
+        &mut self,
+        oldest: Snapshot,
+        mut on_obsolete: impl FnMut(TupleSlot, &ProjectedRow),
+    ) -> GcStats {
+        let mut stats = GcStats::default();
+        while let Some(delta) = self
+            .deltas
+            .front()
+            .filter(|delta| oldest.sees(delta.transaction))
+        {
+            for (index, record) in delta.undo.iter().enumerate() {
+                let undo = UndoRef {
+                    transaction: delta.transaction,
+                    index,
+                };
+                if let Some(row) = self.restored_version(record.slot, undo) {
+                    on_obsolete(record.slot, &row);
+                }
+            }
+            let delta = self.deltas.pop_front().expect("front exists");
+            stats.transactions_freed += 1;
+            stats.bytes_freed += delta.size;
+        }
+        if stats.transactions_freed > 0 {
+            for block in self.blocks.iter_mut().flatten() {
+                block.undo.retain(|_, undo| !oldest.sees(undo.transaction));
+            }
+        }
+        stats
+    }
+
+    /// Freeze every full block, such as ones thawed by updates and deletes
+    pub fn freeze_full_blocks(&mut self) {
+        for block in self.blocks.iter_mut().flatten() {
+            if block.is_full() {
+                block.freeze();
+            }
+        }
+    }
+
+    /// Move the rows of every full block with at most `max_fill` of its slots still holding a
+    /// row into the block rows are inserted into, and free the emptied blocks. `on_move` is
+    /// called with the old and new slot of each moved row, so anything that stores slots, like
+    /// an index, can be updated. Only blocks whose versions `oldest`, the oldest snapshot still
+    /// in use, sees and whose undo records were garbage collected are compacted, and moved rows
+    /// keep their version.
+    pub fn compact(
+        &mut self,
+        max_fill: f64,
+        oldest: Snapshot,
+        mut on_move: impl FnMut(TupleSlot, TupleSlot),
+    ) -> anyhow::Result<CompactionStats> {
+        let sparse: Vec<BlockIndex> = self
+            .blocks()
+            .filter(|(block_index, block)| {
+                Some(*block_index) != self.insert_block
+                    && block.is_full()
+                    && oldest.sees(block.max_version)
+                    && block.undo.is_empty()
+                    && block.present().len() as f64 <= max_fill * block.num_slots as f64
+            })
+            .map(|(block_index, _)| block_index)
+            .collect();
+        let mut stats = CompactionStats::default();
+        for block_index in sparse {
+            let block = self.blocks[block_index]
+                .take()
+                .expect("sparse blocks haven't been freed");
+            self.free_blocks.push(block_index);
+            for row_index in block.present().iter() {
+                let row = block.present_row(row_index as usize);
+                let new_slot = self.insert_version(&row, block.version(row_index as usize))?;
+                on_move(TupleSlot::new(block_index, row_index as usize), new_slot);
+                stats.tuples_moved += 1;
+            }
+            stats.blocks_freed += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Insert a version of a row without keeping what's needed to roll it back
+    fn insert_version(
+        &mut self,
+        row: &ProjectedRow,
+        version: TransactionId,
+    ) -> anyhow::Result<TupleSlot> {
+        let block_index = match self.insert_block {
+            Some(block_index)
+                if !self.blocks[block_index]
+                    .as_ref()
+                    .is_some_and(Block::is_full) =>
+            {
+                block_index
+            }
+            insert_block => {
+                if let Some(block) = insert_block.and_then(|idx| self.blocks[idx].as_mut()) {
+                    block.freeze();
+                }
+                let block = Some(Block::new(self.column_sizes.clone()));
+                let block_index = match self.free_blocks.pop() {
+                    Some(block_index) => {
+                        self.blocks[block_index] = block;
+                        block_index
+                    }
+                    None => {
+                        self.blocks.push(block);
+                        self.blocks.len() - 1
+                    }
+                };
+                self.insert_block = Some(block_index);
+                block_index
+            }
+        };
+        let row_index = self.block_mut(block_index)?.insert(row, version)?;
+        Ok(TupleSlot::new(block_index, row_index))
+    }
+
+    /// The delta block of transaction `version`, which is the newest transaction to write
+    fn delta_block(&mut self, version: TransactionId) -> &mut DeltaBlock {
+        if self
+            .deltas
+            .back()
+            .is_none_or(|delta| delta.transaction != version)
+        {
+            self.deltas.push_back(DeltaBlock {
+                transaction: version,
+                undo: Vec::new(),
+                inserted: Vec::new(),
+                size: 0,
+            });
+        }
+        self.deltas.back_mut().expect("delta block was just added")
+    }
+
+    /// Keep the values `before` a write of transaction `version` to a row changed them from
+    /// `old_version`
+    fn push_undo(
+        &mut self,
+        slot: TupleSlot,
+        old_version: TransactionId,
+        before: ProjectedRow,
+        version: TransactionId,
+    ) {
+        let index = self.delta_block(version).undo.len();
+        let undo = UndoRef {
+            transaction: version,
+            index,
+        };
+        let next = self
+            .block_mut(slot.block_index)
+            .expect("row was just written")
+            .undo
+            .insert(slot.row_index, undo);
+        let delta = self.delta_block(version);
+        delta.size += size_of::<UndoRecord>() + before.size();
+        delta.undo.push(UndoRecord {
+            slot,
+            version: old_version,
+            before,
+            next,
+        });
+    }
+
+    /// Every column of the version of a row that undo record `target` restores, rebuilt by
+    /// walking its undo chain from the current version
+    fn restored_version(&self, tuple_slot: TupleSlot, target: UndoRef) -> Option<ProjectedRow> {
+        let block = self.block(tuple_slot.block_index)?;
+        let record_index = tuple_slot.row_index;
+        let mut row = if block.present().contains(record_index as u32) {
+            block.present_row(record_index)
+        } else {
+            self.empty_row()
+        };
+        let mut undo = block.undo.get(&record_index).copied();
+        while let Some(current) = undo {
+            let record = self.undo_record(current)?;
+            for (column_id, value) in record.before.values() {
+                row.column_values[column_id].clone_from(value);
+            }
+            if current == target {
+                return Some(row);
+            }
+            undo = record.next;
+        }
+        None
+    }
+
+    /// A row with every column null
+    fn empty_row(&self) -> ProjectedRow {
+        ProjectedRow {
+            column_ids: (0..self.column_sizes.len()).collect(),
+            column_values: vec![None; self.column_sizes.len()],
+        }
+    }
+
+    fn undo_record(&self, undo: UndoRef) -> Option<&UndoRecord> {
+        let position = self
+            .deltas
+            .binary_search_by_key(&undo.transaction, |delta| delta.transaction)
+            .ok()?;
+        self.deltas[position].undo.get(undo.index)
+    }
+
+    fn block_mut(&mut self, block_index: BlockIndex) -> anyhow::Result<&mut Block> {
+        self.blocks
+            .get_mut(block_index)
+            .and_then(Option::as_mut)
+            .ok_or_else(|| anyhow!("block {block_index} doesn't exist"))
+    }
+}
+
+/// The column values of a block. They can only be changed while the block is hot; frozen blocks
+/// share them with the Arrow arrays scans build.
+#[derive(Debug)]
+enum ColumnBytes {
+    Hot(MutableBuffer),
+    Frozen(Buffer),
+}
+
+impl ColumnBytes {
+    /// The column values as a mutable buffer, copying them if a frozen buffer is shared
+    fn thawed(&mut self) -> &mut MutableBuffer {
+        if let ColumnBytes::Frozen(buffer) = self {
+            let buffer = std::mem::replace(buffer, Buffer::from_vec(Vec::<u8>::new()));
+            *self = ColumnBytes::Hot(buffer.into_mutable().unwrap_or_else(|buffer| {
+                let mut bytes = MutableBuffer::new(buffer.len());
+                bytes.extend_from_slice(buffer.as_slice());
+                bytes
+            }));
+        }
+        match self {
+            ColumnBytes::Hot(bytes) => bytes,
+            ColumnBytes::Frozen(_) => unreachable!("column bytes were just thawed"),
+        }
+    }
+}
+
+impl Deref for ColumnBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ColumnBytes::Hot(bytes) => bytes.as_slice(),
+            ColumnBytes::Frozen(buffer) => buffer.as_slice(),
+        }
+    }
+}
+
+/// A variable-length column of a frozen block in the layout of an Arrow `Utf8` or `Binary` array
+#[derive(Debug, Clone)]
+pub struct FrozenVarlen {
+    /// `i32` offset of each record's value in `values`, followed by the end of the last value
+    pub offsets: Buffer,
+    pub values: Buffer,
 }
 
 #[derive(Debug)]
 pub struct Block {
     num_slots: usize,
     num_records: usize,
-    column_sizes: Vec<usize>,
-    column_bytes: Vec<u8>,
+    column_sizes: Vec<ColumnSize>,
+    column_bytes: ColumnBytes,
     column_offsets: Vec<usize>,
     bitmaps: Vec<RoaringBitmap>,
     bitmap: RoaringBitmap,
+    /// Variable-length values too long to store in their column
+    varlen: Vec<u8>,
+    /// Bytes of `varlen` no longer referenced by any value
+    varlen_freed: usize,
+    /// Variable-length columns gathered into Arrow's layout when the block was frozen, or empty
+    /// if it is hot
+    frozen_varlen: Vec<Option<FrozenVarlen>>,
+    /// Transaction that wrote the current version of each record
+    versions: Vec<TransactionId>,
+    /// Newest transaction that wrote to the block
+    max_version: TransactionId,
+    /// Newest undo record of each record with older versions. The records themselves are kept
+    /// by the table, in the delta block of the transaction that wrote them.
+    undo: HashMap<RowIndex, UndoRef>,
 }
 
 impl Block {
     // For now, make all blocks have 1k slots
-    pub fn new(column_sizes: Vec<usize>) -> Block {
+    pub fn new(column_sizes: Vec<ColumnSize>) -> Block {
         let mut bitmaps = Vec::with_capacity(column_sizes.len());
         for _ in column_sizes.iter() {
             bitmaps.push(RoaringBitmap::new());
@@ -48,82 +631,202 @@ impl Block {
         let num_slots = SLOTS_PER_BLOCK;
         for size in column_sizes.iter() {
             column_offsets.push(column_offset);
-            column_offset += num_slots * size;
+            // Keep every column as aligned as the buffer so it can be shared with Arrow arrays
+            column_offset += (num_slots * size.slot_size()).next_multiple_of(ALIGNMENT);
         }
         Block {
             num_slots,
             num_records: 0,
             column_sizes,
-            column_bytes: vec![0; column_offset],
+            column_bytes: ColumnBytes::Hot(MutableBuffer::from_len_zeroed(column_offset)),
             column_offsets,
             bitmaps,
             bitmap: RoaringBitmap::new(),
+            varlen: Vec::new(),
+            varlen_freed: 0,
+            frozen_varlen: Vec::new(),
+            versions: vec![0; num_slots],
+            max_version: 0,
+            undo: HashMap::new(),
         }
     }
 
-    pub fn insert(&mut self, row: &ProjectedRow) -> anyhow::Result<()> {
-        if self.num_records == self.num_slots {
+    pub fn num_records(&self) -> usize {
+        self.num_records
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.num_records == self.num_slots
+    }
+
+    pub fn column_size(&self, column_id: usize) -> ColumnSize {
+        self.column_sizes[column_id]
+    }
+
+    pub fn column_sizes(&self) -> &[ColumnSize] {
+        &self.column_sizes
+    }
+
+    /// Transaction that wrote the current version of a record
+    pub fn version(&self, record_index: usize) -> TransactionId {
+        self.versions[record_index]
+    }
+
+    /// Newest transaction that wrote to the block. Snapshots that see it see the block as is.
+    pub fn max_version(&self) -> TransactionId {
+        self.max_version
+    }
+
+    /// The values of a column for every slot in the block, whether or not the slot holds a row.
+    /// Variable-length columns hold a [`VARLEN_ENTRY_SIZE`] byte entry per slot; use
+    /// [`Block::varlen_value`] to read their values.
+    pub fn column_bytes(&self, column_id: usize) -> &[u8] {
+        let start_offset = self.column_offsets[column_id];
+        &self.column_bytes
+            [start_offset..start_offset + self.num_slots * self.column_sizes[column_id].slot_size()]
+    }
+
+    /// The value of a variable-length column in a record, or an empty slice if it is null
+    pub fn varlen_value(&self, column_id: usize, record_index: usize) -> &[u8] {
+        let entry = self.slot(column_id, record_index);
+        let len = read_u32(&entry[..4]) as usize;
+        if len <= VARLEN_INLINE_LEN {
+            &entry[4..4 + len]
+        } else {
+            let offset = read_u64(&entry[8..16]) as usize;
+            &self.varlen[offset..offset + len]
+        }
+    }
+
+    /// Bytes allocated for variable-length values too long to store in their column, including
+    /// ones that haven't been reclaimed since their value was updated or deleted
+    pub fn varlen_size(&self) -> usize {
+        self.varlen.len()
+    }
+
+    /// Records whose value in a column isn't null
+    pub fn validity(&self, column_id: usize) -> &RoaringBitmap {
+        &self.bitmaps[column_id]
+    }
+
+    /// Records that haven't been deleted
+    pub fn present(&self) -> &RoaringBitmap {
+        &self.bitmap
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        matches!(self.column_bytes, ColumnBytes::Frozen(_))
+    }
+
+    /// Make the column values immutable so they can be shared with Arrow arrays. Changing the
+    /// block thaws it, which copies the values if they are still shared.
+    pub fn freeze(&mut self) {
+        if self.is_frozen() {
+            return;
+        }
+        self.frozen_varlen = (0..self.column_sizes.len())
+            .map(|column_id| self.gather_varlen(column_id))
+            .collect();
+        let ColumnBytes::Hot(bytes) = std::mem::replace(
+            &mut self.column_bytes,
+            ColumnBytes::Hot(MutableBuffer::new(0)),
+        ) else {
+            unreachable!("block is hot")
+        };
+        self.column_bytes = ColumnBytes::Frozen(bytes.into());
+    }
+
+    /// The values of a fixed-width column for the block's records, including deleted ones, if the
+    /// block is frozen. The buffer shares memory with the block.
+    pub fn frozen_column(&self, column_id: usize) -> Option<Buffer> {
+        let (ColumnBytes::Frozen(buffer), ColumnSize::Fixed(size)) =
+            (&self.column_bytes, self.column_sizes[column_id])
+        else {
+            return None;
+        };
+        Some(buffer.slice_with_length(self.column_offsets[column_id], self.num_records * size))
+    }
+
+    /// The values of a variable-length column for the block's records, including deleted ones,
+    /// if the block is frozen and they fit in an Arrow `Utf8` or `Binary` array
+    pub fn frozen_varlen(&self, column_id: usize) -> Option<&FrozenVarlen> {
+        self.frozen_varlen.get(column_id)?.as_ref()
+    }
+
+    /// Insert `row` into the next free slot as written by transaction `version`, returning the
+    /// slot's record index
+    pub fn insert(&mut self, row: &ProjectedRow, version: TransactionId) -> anyhow::Result<usize> {
+        if self.is_full() {
             return Err(anyhow!("cannot add a row to a full block"));
         }
+        self.thaw();
         let record_index = self.num_records;
         let mut row_index = 0;
         for col_index in 0..self.column_sizes.len() {
-            if row.column_ids[row_index] == col_index {
-                match &row.column_values[row_index] {
-                    Some(bytes) => {
-                        let byte_index = self.column_offsets[col_index]
-                            + record_index * self.column_sizes[col_index];
-                        self.column_bytes[byte_index..(self.column_sizes[col_index] + byte_index)]
-                            .copy_from_slice(&bytes[..self.column_sizes[col_index]]);
-                        self.bitmaps[col_index].insert(record_index as u32);
-                    }
-                    None => {}
+            if row.column_ids.get(row_index) == Some(&col_index) {
+                if let Some(bytes) = &row.column_values[row_index] {
+                    self.write_value(col_index, record_index, bytes)?;
                 }
                 row_index += 1;
             }
         }
         self.num_records += 1;
         self.bitmap.insert(record_index as u32);
-        Ok(())
+        self.set_version(record_index, version);
+        Ok(record_index)
     }
 
-    pub fn update(&mut self, record_index: usize, row: &ProjectedRow) -> anyhow::Result<()> {
-        if record_index >= self.num_records {
+    /// Change the columns of a record to the values in `row` as transaction `version`, returning
+    /// their old values
+    pub fn update(
+        &mut self,
+        record_index: usize,
+        row: &ProjectedRow,
+        version: TransactionId,
+    ) -> anyhow::Result<ProjectedRow> {
+        if record_index >= self.num_records || !self.bitmap.contains(record_index as u32) {
             return Err(anyhow!("cannot update a row that doesn't exist"));
         }
+        self.thaw();
+        let before = ProjectedRow {
+            column_ids: row.column_ids.clone(),
+            column_values: row
+                .column_ids
+                .iter()
+                .map(|column_id| self.value(*column_id, record_index))
+                .collect(),
+        };
+        self.set_version(record_index, version);
         for row_index in 0..row.column_ids.len() {
             let column_id = row.column_ids[row_index];
             match &row.column_values[row_index] {
-                Some(bytes) => {
-                    let byte_index = self.column_offsets[column_id]
-                        + record_index * self.column_sizes[column_id];
-                    self.column_bytes[byte_index..(self.column_sizes[column_id] + byte_index)]
-                        .copy_from_slice(&bytes[..self.column_sizes[column_id]]);
-                    self.bitmaps[column_id].insert(record_index as u32);
-                }
-                None => {
-                    self.bitmaps[column_id].remove(record_index as u32);
-                }
+                Some(bytes) => self.write_value(column_id, record_index, bytes)?,
+                None => self.clear_value(column_id, record_index),
             }
         }
-        Ok(())
+        self.reclaim_varlen();
+        Ok(before)
     }
 
-    pub fn delete(&mut self, record_index: usize) -> anyhow::Result<()> {
-        if record_index >= self.num_records {
+    /// Delete a record as transaction `version`, returning its values
+    pub fn delete(
+        &mut self,
+        record_index: usize,
+        version: TransactionId,
+    ) -> anyhow::Result<ProjectedRow> {
+        if record_index >= self.num_records || !self.bitmap.contains(record_index as u32) {
             return Err(anyhow!("cannot delete a row that doesn't exist"));
         }
-        for bitmap in self.bitmaps.iter_mut() {
-            bitmap.remove(record_index as u32);
-        }
+        self.thaw();
+        let before = self.present_row(record_index);
+        self.set_version(record_index, version);
         for column_id in 0..self.column_sizes.len() {
-            let size = self.column_sizes[column_id];
-            let start_offset = self.column_offsets[column_id] + size * record_index;
-            self.column_bytes[start_offset..start_offset + size].fill(0);
+            self.clear_value(column_id, record_index);
         }
         self.bitmap.remove(record_index as u32);
+        self.reclaim_varlen();
         // We do not decrement the number of records--that can be done during compaction
-        Ok(())
+        Ok(before)
     }
 
     pub fn row_at_index(&self, index: usize, column_ids: &[usize]) -> Option<ProjectedRow> {
@@ -137,15 +840,9 @@ impl Block {
         let mut column_values = Vec::new();
         let mut has_value = false;
         for column_id in column_ids.iter() {
-            if self.bitmaps[*column_id].contains(index as u32) {
-                has_value = true;
-                let size = self.column_sizes[*column_id];
-                let start_offset = self.column_offsets[*column_id] + index * size;
-                let value = self.column_bytes[start_offset..start_offset + size].to_vec();
-                column_values.push(Some(value));
-            } else {
-                column_values.push(None);
-            }
+            let value = self.value(*column_id, index);
+            has_value |= value.is_some();
+            column_values.push(value);
         }
         if has_value {
             Some(ProjectedRow {
@@ -156,9 +853,195 @@ impl Block {
             None
         }
     }
+
+    /// Every column of a record that hasn't been deleted, even if they are all null
+    fn present_row(&self, index: usize) -> ProjectedRow {
+        let column_ids: Vec<usize> = (0..self.column_sizes.len()).collect();
+        let column_values = column_ids
+            .iter()
+            .map(|column_id| self.value(*column_id, index))
+            .collect();
+        ProjectedRow {
+            column_ids,
+            column_values,
+        }
+    }
+
+    fn value(&self, column_id: usize, index: usize) -> Option<Vec<u8>> {
+        if !self.bitmaps[column_id].contains(index as u32) {
+            return None;
+        }
+        Some(match self.column_sizes[column_id] {
+            ColumnSize::Fixed(_) => self.slot(column_id, index).to_vec(),
+            ColumnSize::Varlen => self.varlen_value(column_id, index).to_vec(),
+        })
+    }
+
+    /// Put back the values `before` a write to a record, and the version it had
+    fn restore(&mut self, record_index: usize, before: &ProjectedRow, version: TransactionId) {
+        self.thaw();
+        for (column_id, value) in before.values() {
+            match value {
+                Some(bytes) => self
+                    .write_value(column_id, record_index, bytes)
+                    .expect("old values fit their columns"),
+                None => self.clear_value(column_id, record_index),
+            }
+        }
+        self.bitmap.insert(record_index as u32);
+        self.versions[record_index] = version;
+        self.reclaim_varlen();
+    }
+
+    /// Remove a record inserted by a transaction that is being rolled back
+    fn remove(&mut self, record_index: usize) {
+        self.thaw();
+        for column_id in 0..self.column_sizes.len() {
+            self.clear_value(column_id, record_index);
+        }
+        self.bitmap.remove(record_index as u32);
+        self.versions[record_index] = 0;
+        self.reclaim_varlen();
+    }
+
+    fn set_version(&mut self, record_index: usize, version: TransactionId) {
+        self.versions[record_index] = version;
+        self.max_version = self.max_version.max(version);
+    }
+
+    fn thaw(&mut self) {
+        self.column_bytes.thawed();
+        self.frozen_varlen.clear();
+    }
+
+    /// Copy the values of a variable-length column into Arrow's layout, with empty values for
+    /// nulls and deleted records
+    fn gather_varlen(&self, column_id: usize) -> Option<FrozenVarlen> {
+        if self.column_sizes[column_id] != ColumnSize::Varlen {
+            return None;
+        }
+        let mut offsets = MutableBuffer::new((self.num_records + 1) * size_of::<i32>());
+        let mut values = MutableBuffer::new(0);
+        offsets.push(0i32);
+        for record_index in 0..self.num_records {
+            if self.bitmaps[column_id].contains(record_index as u32) {
+                values.extend_from_slice(self.varlen_value(column_id, record_index));
+            }
+            offsets.push(i32::try_from(values.len()).ok()?);
+        }
+        Some(FrozenVarlen {
+            offsets: offsets.into(),
+            values: values.into(),
+        })
+    }
+
+    fn slot_range(&self, column_id: usize, record_index: usize) -> std::ops::Range<usize> {
+        let size = self.column_sizes[column_id].slot_size();
+        let start_offset = self.column_offsets[column_id] + record_index * size;
+        start_offset..start_offset + size
+    }
+
+    fn slot(&self, column_id: usize, record_index: usize) -> &[u8] {
+        &self.column_bytes[self.slot_range(column_id, record_index)]
+    }
+
+    /// Set the value of a column in a record, replacing any value it had
+    fn write_value(
+        &mut self,
+        column_id: usize,
+        record_index: usize,
+        bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let entry = match self.column_sizes[column_id] {
+            ColumnSize::Fixed(size) => {
+                if bytes.len() < size {
+                    return Err(anyhow!(
+                        "column {column_id} needs {size} bytes, got {}",
+                        bytes.len()
+                    ));
+                }
+                bytes[..size].to_vec()
+            }
+            ColumnSize::Varlen => {
+                let len = u32::try_from(bytes.len())
+                    .map_err(|_| anyhow!("{} byte value is too long", bytes.len()))?;
+                let mut entry = Vec::with_capacity(VARLEN_ENTRY_SIZE);
+                entry.extend_from_slice(&len.to_le_bytes());
+                if bytes.len() <= VARLEN_INLINE_LEN {
+                    entry.extend_from_slice(bytes);
+                } else {
+                    entry.extend_from_slice(&bytes[..VARLEN_PREFIX_LEN]);
+                    entry.extend_from_slice(&(self.varlen.len() as u64).to_le_bytes());
+                    self.varlen.extend_from_slice(bytes);
+                }
+                entry.resize(VARLEN_ENTRY_SIZE, 0);
+                self.free_varlen(column_id, record_index);
+                entry
+            }
+        };
+        let range = self.slot_range(column_id, record_index);
+        self.column_bytes.thawed()[range].copy_from_slice(&entry);
+        self.bitmaps[column_id].insert(record_index as u32);
+        Ok(())
+    }
+
+    /// Set the value of a column in a record to null
+    fn clear_value(&mut self, column_id: usize, record_index: usize) {
+        self.free_varlen(column_id, record_index);
+        let range = self.slot_range(column_id, record_index);
+        self.column_bytes.thawed()[range].fill(0);
+        self.bitmaps[column_id].remove(record_index as u32);
+    }
+
+    /// Count the bytes the current value of a column in a record takes in the varlen arena as
+    /// freed
+    fn free_varlen(&mut self, column_id: usize, record_index: usize) {
+        if self.column_sizes[column_id] == ColumnSize::Varlen
+            && self.bitmaps[column_id].contains(record_index as u32)
+        {
+            let len = read_u32(&self.slot(column_id, record_index)[..4]) as usize;
+            if len > VARLEN_INLINE_LEN {
+                self.varlen_freed += len;
+            }
+        }
+    }
+
+    /// Rewrite the varlen arena without freed values once they take up most of it
+    fn reclaim_varlen(&mut self) {
+        if self.varlen_freed * 2 <= self.varlen.len() {
+            return;
+        }
+        let mut varlen = Vec::with_capacity(self.varlen.len() - self.varlen_freed);
+        for column_id in 0..self.column_sizes.len() {
+            if self.column_sizes[column_id] != ColumnSize::Varlen {
+                continue;
+            }
+            for record_index in self.bitmaps[column_id].iter() {
+                let range = self.slot_range(column_id, record_index as usize);
+                let entry = &mut self.column_bytes.thawed()[range];
+                let len = read_u32(&entry[..4]) as usize;
+                if len <= VARLEN_INLINE_LEN {
+                    continue;
+                }
+                let offset = read_u64(&entry[8..16]) as usize;
+                entry[8..16].copy_from_slice(&(varlen.len() as u64).to_le_bytes());
+                varlen.extend_from_slice(&self.varlen[offset..offset + len]);
+            }
+        }
+        self.varlen = varlen;
+        self.varlen_freed = 0;
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().expect("4 bytes"))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("8 bytes"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ProjectedRow {
     column_ids: Vec<usize>,
     column_values: Vec<Option<Vec<u8>>>,
@@ -172,29 +1055,53 @@ impl ProjectedRow {
             column_values,
         }
     }
+
+    /// Approximate bytes used by the row's values
+    fn size(&self) -> usize {
+        self.column_ids.len() * size_of::<usize>()
+            + self
+                .column_values
+                .iter()
+                .map(|value| size_of::<Option<Vec<u8>>>() + value.as_ref().map_or(0, Vec::len))
+                .sum::<usize>()
+    }
+
+    /// Each column id and its value
+    fn values(&self) -> impl Iterator<Item = (usize, &Option<Vec<u8>>)> {
+        self.column_ids
+            .iter()
+            .copied()
+            .zip(self.column_values.iter())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::table::{Block, ProjectedRow};
+    use std::collections::HashMap;
+
+    use crate::table::{
+        Block, ColumnSize, ProjectedRow, Savepoint, Table, TupleSlot, SLOTS_PER_BLOCK,
+        VARLEN_INLINE_LEN,
+    };
+    use crate::transaction::Snapshot;
 
     #[test]
     fn insert_and_get_projected_row() {
-        let mut block = Block::new(vec![1, 2]);
+        let mut block = Block::new(vec![ColumnSize::Fixed(1), ColumnSize::Fixed(2)]);
         let row = ProjectedRow::new(vec![0, 1], vec![Some(vec![1]), Some(vec![1, 2])]);
-        block.insert(&row).expect("block has space for a row");
+        block.insert(&row, 1).expect("block has space for a row");
         let out_row = block.row_at_index(0, &[0, 1]);
         assert_eq!(Some(row), out_row);
     }
 
     #[test]
     fn update_projected_row() {
-        let mut block = Block::new(vec![1, 2]);
+        let mut block = Block::new(vec![ColumnSize::Fixed(1), ColumnSize::Fixed(2)]);
         let row = ProjectedRow::new(vec![0, 1], vec![Some(vec![1]), Some(vec![1, 2])]);
-        block.insert(&row).expect("block has space for a row");
+        block.insert(&row, 1).expect("block has space for a row");
         let updated_row = ProjectedRow::new(vec![0, 1], vec![Some(vec![2]), Some(vec![3, 2])]);
         block
-            .update(0, &updated_row)
+            .update(0, &updated_row, 2)
             .expect("can find record to update");
         let out_row = block.row_at_index(0, &[0, 1]);
         assert_eq!(Some(updated_row), out_row);
@@ -202,11 +1109,272 @@ mod tests {
 
     #[test]
     fn delete_projected_row() {
-        let mut block = Block::new(vec![1, 2]);
+        let mut block = Block::new(vec![ColumnSize::Fixed(1), ColumnSize::Fixed(2)]);
         let row = ProjectedRow::new(vec![0, 1], vec![Some(vec![1]), Some(vec![1, 2])]);
-        block.insert(&row).expect("block has space for a row");
-        block.delete(0).expect("can find a record to delete");
+        block.insert(&row, 1).expect("block has space for a row");
+        block.delete(0, 2).expect("can find a record to delete");
         let out_row = block.row_at_index(0, &[0, 1]);
         assert_eq!(None, out_row);
     }
+
+    #[test]
+    fn varlen_values_inline_and_in_arena() {
+        let mut block = Block::new(vec![ColumnSize::Fixed(4), ColumnSize::Varlen]);
+        let short = b"quokka".to_vec();
+        let long = b"quokka product with a long name".to_vec();
+        for (id, name) in [(1u32, Some(&short)), (2, Some(&long)), (3, None)] {
+            let row = ProjectedRow::new(
+                vec![0, 1],
+                vec![Some(id.to_le_bytes().to_vec()), name.cloned()],
+            );
+            block.insert(&row, 1).expect("block has space for a row");
+        }
+        assert_eq!(short.as_slice(), block.varlen_value(1, 0));
+        assert_eq!(long.as_slice(), block.varlen_value(1, 1));
+        assert_eq!(b"", block.varlen_value(1, 2));
+        assert_eq!(long.len(), block.varlen_size());
+        let out_row = block.row_at_index(1, &[1]);
+        assert_eq!(Some(ProjectedRow::new(vec![1], vec![Some(long)])), out_row);
+    }
+
+    #[test]
+    fn updated_and_deleted_varlen_values_are_reclaimed() {
+        let mut block = Block::new(vec![ColumnSize::Varlen]);
+        let value =
+            |i: usize| Some(format!("{i:0>width$}", width = 2 * VARLEN_INLINE_LEN).into_bytes());
+        for i in 0..10 {
+            block
+                .insert(&ProjectedRow::new(vec![0], vec![value(i)]), 1)
+                .expect("block has space for a row");
+        }
+        let size = block.varlen_size();
+        for round in 1..100 {
+            block
+                .update(3, &ProjectedRow::new(vec![0], vec![value(round)]), 2)
+                .expect("can find record to update");
+            assert!(block.varlen_size() <= 2 * size);
+        }
+        block.delete(4, 2).expect("can find a record to delete");
+        for i in 5..10 {
+            block
+                .update(i, &ProjectedRow::new(vec![0], vec![None]), 2)
+                .expect("can find record to update");
+        }
+        assert_eq!(4 * 2 * VARLEN_INLINE_LEN, block.varlen_size());
+        assert_eq!(value(99).unwrap(), block.varlen_value(0, 3));
+        assert_eq!(value(2).unwrap(), block.varlen_value(0, 2));
+    }
+
+    #[test]
+    fn frozen_blocks_copy_shared_values_when_thawed() {
+        let mut block = Block::new(vec![ColumnSize::Fixed(4), ColumnSize::Varlen]);
+        let row = ProjectedRow::new(
+            vec![0, 1],
+            vec![Some(vec![1, 0, 0, 0]), Some(b"quokka".to_vec())],
+        );
+        block.insert(&row, 1).expect("block has space for a row");
+        block.freeze();
+        let ids = block.frozen_column(0).expect("block is frozen");
+        assert_eq!(&[1, 0, 0, 0], ids.as_slice());
+        let names = block.frozen_varlen(1).expect("block is frozen").clone();
+        assert_eq!(b"quokka", names.values.as_slice());
+
+        let updated_row = ProjectedRow::new(vec![0], vec![Some(vec![2, 0, 0, 0])]);
+        block
+            .update(0, &updated_row, 2)
+            .expect("can find record to update");
+        assert!(!block.is_frozen());
+        assert_eq!(None, block.frozen_column(0));
+        assert!(block.frozen_varlen(1).is_none());
+        assert_eq!(&[1, 0, 0, 0], ids.as_slice());
+        assert_eq!(
+            Some(ProjectedRow::new(
+                vec![0, 1],
+                vec![Some(vec![2, 0, 0, 0]), Some(b"quokka".to_vec())]
+            )),
+            block.row_at_index(0, &[0, 1])
+        );
+    }
+
+    #[test]
+    fn compaction_moves_rows_out_of_sparse_blocks() {
+        let mut table = Table::new(vec![ColumnSize::Fixed(4), ColumnSize::Varlen]);
+        let row = |id: u32| {
+            ProjectedRow::new(
+                vec![0, 1],
+                vec![
+                    Some(id.to_le_bytes().to_vec()),
+                    Some(format!("quokka product {id}").into_bytes()),
+                ],
+            )
+        };
+        let mut slots = HashMap::new();
+        for id in 0..3 * SLOTS_PER_BLOCK as u32 {
+            slots.insert(id, table.insert(&row(id), 1).expect("row is inserted"));
+        }
+        // Keep a fifth of the first block, two fifths of the second and all of the third
+        for (id, slot) in slots.iter() {
+            let keep = match slot.block_index() {
+                0 => id % 5 == 0,
+                1 => id % 5 < 2,
+                _ => true,
+            };
+            if !keep {
+                table.delete(*slot, 2).expect("row is deleted");
+            }
+        }
+        // Blocks are only compacted once the old versions of their rows are freed
+        assert_eq!(
+            0,
+            table
+                .compact(0.3, Snapshot::at(2), |_, _| {})
+                .expect("compaction succeeds")
+                .blocks_freed
+        );
+        table.collect_garbage(Snapshot::at(2), |_, _| {});
+        let mut moves = HashMap::new();
+        let stats = table
+            .compact(0.3, Snapshot::at(2), |from, to| {
+                moves.insert(from, to);
+            })
+            .expect("compaction succeeds");
+        assert_eq!((1, 200), (stats.blocks_freed, stats.tuples_moved));
+        let stats = table
+            .compact(0.5, Snapshot::at(2), |from, to| {
+                moves.insert(from, to);
+            })
+            .expect("compaction succeeds");
+        assert_eq!((1, 400), (stats.blocks_freed, stats.tuples_moved));
+        assert_eq!(600, moves.len());
+        assert_eq!(2, table.num_blocks());
+
+        // The moved rows went to a new block that reused the index of the first freed block
+        for (id, slot) in slots.iter() {
+            let Some(new_slot) = moves.get(slot) else {
+                continue;
+            };
+            assert_eq!(0, new_slot.block_index());
+            assert_eq!(Some(row(*id)), table.get_row(*new_slot, &[0, 1]));
+        }
+        assert_eq!(None, table.get_row(TupleSlot::new(1, 0), &[0, 1]));
+
+        // Once that block fills up, new rows reuse the index of the second freed block
+        for id in 0..401 {
+            table.insert(&row(id), 1).expect("row is inserted");
+        }
+        assert_eq!(
+            vec![(0, 1000), (1, 1), (2, 1000)],
+            table
+                .blocks()
+                .map(|(block_index, block)| (block_index, block.num_records()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn snapshots_see_the_versions_committed_before_them() {
+        let mut table = Table::new(vec![ColumnSize::Fixed(1), ColumnSize::Varlen]);
+        let row = |id: u8, name: Option<&str>| {
+            ProjectedRow::new(
+                vec![0, 1],
+                vec![Some(vec![id]), name.map(|name| name.into())],
+            )
+        };
+        let slot = table
+            .insert(&row(1, Some("quokka")), 1)
+            .expect("row is inserted");
+        let rename = ProjectedRow::new(vec![1], vec![Some(b"quokka plush toy".to_vec())]);
+        table.update(slot, &rename, 2).expect("row is updated");
+        let clear = ProjectedRow::new(vec![1], vec![None]);
+        table.update(slot, &clear, 3).expect("row is updated");
+        table.delete(slot, 4).expect("row is deleted");
+
+        let versions: Vec<_> = (0..=4)
+            .map(|id| table.visible_row(slot, Snapshot::at(id)))
+            .collect();
+        assert_eq!(
+            vec![
+                None,
+                Some(row(1, Some("quokka"))),
+                Some(row(1, Some("quokka plush toy"))),
+                Some(row(1, None)),
+                None,
+            ],
+            versions
+        );
+        assert_eq!(4, table.block(0).expect("block exists").max_version());
+    }
+
+    #[test]
+    fn garbage_collection_frees_versions_no_snapshot_sees() {
+        let mut table = Table::new(vec![ColumnSize::Fixed(1)]);
+        let row = |value: u8| ProjectedRow::new(vec![0], vec![Some(vec![value])]);
+        let slot = table.insert(&row(1), 1).expect("row is inserted");
+        for version in 2..=4 {
+            table
+                .update(slot, &row(version as u8), version)
+                .expect("row is updated");
+        }
+        let undo_size = table.undo_size();
+
+        // The oldest snapshot still sees the second version, so only the first is obsolete
+        let mut obsolete = vec![];
+        let first = table.collect_garbage(Snapshot::at(2), |slot, before| {
+            obsolete.push((slot, before.clone()))
+        });
+        assert_eq!(2, first.transactions_freed);
+        assert_eq!(vec![(slot, row(1))], obsolete);
+        assert_eq!(Some(row(2)), table.visible_row(slot, Snapshot::at(2)));
+        assert_eq!(Some(row(3)), table.visible_row(slot, Snapshot::at(3)));
+        assert_eq!(vec![row(4), row(3), row(2)], table.versions(slot));
+
+        let second = table.collect_garbage(Snapshot::at(4), |_, _| {});
+        assert_eq!(2, second.transactions_freed);
+        assert_eq!(undo_size, first.bytes_freed + second.bytes_freed);
+        assert_eq!(0, table.undo_size());
+        assert_eq!(Some(row(4)), table.visible_row(slot, Snapshot::at(4)));
+        assert_eq!(vec![row(4)], table.versions(slot));
+        assert_eq!(
+            0,
+            table
+                .collect_garbage(Snapshot::at(4), |_, _| {})
+                .transactions_freed
+        );
+    }
+
+    #[test]
+    fn rollback_restores_the_previous_versions() {
+        let mut table = Table::new(vec![ColumnSize::Fixed(1)]);
+        let row = |value: u8| ProjectedRow::new(vec![0], vec![Some(vec![value])]);
+        let first = table.insert(&row(1), 1).expect("row is inserted");
+        let second = table.insert(&row(2), 1).expect("row is inserted");
+
+        // Writes made by the transaction before the savepoint are kept
+        table.update(second, &row(6), 2).expect("row is updated");
+        let savepoint = table.savepoint(2);
+        table.update(first, &row(3), 2).expect("row is updated");
+        table.update(first, &row(4), 2).expect("row is updated");
+        table.delete(second, 2).expect("row is deleted");
+        let third = table.insert(&row(5), 2).expect("row is inserted");
+        table.rollback(savepoint);
+
+        assert_eq!(Some(row(1)), table.get_row(first, &[0]));
+        assert_eq!(Some(row(6)), table.get_row(second, &[0]));
+        assert_eq!(None, table.get_row(third, &[0]));
+        let block = table.block(0).expect("block exists");
+        assert_eq!(
+            (1, 2, 0),
+            (block.version(0), block.version(1), block.version(2))
+        );
+        assert_eq!(Some(row(2)), table.visible_row(second, Snapshot::at(1)));
+
+        table.rollback(table.savepoint(3));
+        table.rollback(Savepoint {
+            transaction: 2,
+            undo: 0,
+            inserted: 0,
+        });
+        assert_eq!(Some(row(2)), table.get_row(second, &[0]));
+        assert_eq!(Some(row(2)), table.visible_row(second, Snapshot::at(2)));
+    }
 }
@@ -17,22 +17,28 @@
 
 //! [`MemTable`] for querying `Vec<RecordBatch>` by DataFusion.
 
+use arrow::array::{Array, ArrayRef, BooleanArray};
+use arrow::compute::kernels::zip::zip;
+use arrow::compute::{filter_record_batch, not, prep_null_mask_filter};
 use arrow_array::Int32Array;
-use datafusion_expr::{BinaryExpr, Operator};
-use datafusion_physical_plan::functions::create_physical_expr;
+use datafusion::physical_expr::{create_physical_expr, PhysicalExpr};
+use datafusion_expr::expr_rewriter::unnormalize_col;
+use datafusion_expr::Operator;
 use datafusion_physical_plan::metrics::MetricsSet;
 use futures::StreamExt;
 use log::debug;
 use std::any::Any;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::sync::Arc;
 
 use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
+use datafusion_common::cast::as_boolean_array;
 use datafusion_common::{
-    not_impl_err, plan_err, Constraints, DFSchema, DataFusionError, SchemaExt,
+    exec_err, not_impl_err, plan_err, Column, Constraints, DFSchema, DataFusionError, SchemaExt,
 };
 use datafusion_execution::TaskContext;
 use parking_lot::Mutex;
@@ -42,18 +48,430 @@ use tokio::task::JoinSet;
 use datafusion::datasource::{TableProvider, TableType};
 use datafusion::error::Result;
 use datafusion::execution::context::SessionState;
-use datafusion::logical_expr::Expr;
+use datafusion::logical_expr::{Expr, TableProviderFilterPushDown};
 use datafusion::physical_plan::insert::{DataSink, FileSinkExec};
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::{common, SendableRecordBatchStream};
 use datafusion::physical_plan::{repartition::RepartitionExec, Partitioning};
 use datafusion::physical_plan::{DisplayAs, DisplayFormatType, ExecutionPlan};
 use datafusion::physical_planner::create_physical_sort_expr;
+use datafusion::scalar::ScalarValue;
+
+use crate::analysis::Analyzer;
+use crate::catalog::{next_table_id, reserve_table_id, Retired, TableId, TableLock};
+use crate::index::{best_index, IndexMethod, SecondaryIndex};
+use crate::index_join::JoinIndex;
+use crate::index_scan::{IndexLookup, IndexScanExec, MemTableScanExec, ScannedTable};
+use crate::partition::{PartitionStats, Partitions};
+use crate::policy::{visible_rows, Policy};
+use crate::text_index::{Bm25Stats, TextIndex};
+use crate::text_search::{column_stats, match_filter, text_searches};
+use crate::wal::LogRecord;
+use crate::writer::{writer_for, Applied, Writer};
 
 /// Type alias for partition data
 pub type PartitionData = Arc<RwLock<Vec<RecordBatch>>>;
 
-type TupletOffset = (i32, i32, i32);
+/// Location of a tuple as (partition index, batch index, row index)
+pub(crate) type TupletOffset = (i32, i32, i32);
+
+pub(crate) type PrimaryKeyIndex = BTreeMap<i32, TupletOffset>;
+
+/// Secondary indexes of a table, keyed by index name
+pub(crate) type SecondaryIndexes = BTreeMap<String, SecondaryIndex>;
+
+/// A fulltext index on all the columns at `column_indices`, with the positions of the columns in
+/// the index
+fn covering_text_index<'a>(
+    indexes: &'a SecondaryIndexes,
+    column_indices: &[usize],
+) -> Option<(&'a SecondaryIndex, &'a TextIndex, Vec<usize>)> {
+    indexes.values().find_map(|index| {
+        let text_index = index.text_index()?;
+        let fields = column_indices
+            .iter()
+            .map(|column_index| text_index.field(*column_index))
+            .collect::<Option<Vec<_>>>()?;
+        Some((index, text_index, fields))
+    })
+}
+
+/// A batch a write replaces with `new`, at `batch_idx` in partition `partition_idx`
+struct Replacement {
+    partition_idx: usize,
+    batch_idx: usize,
+    old: RecordBatch,
+    new: RecordBatch,
+}
+
+/// Shared handles to a table's data and indexes, for plans that read the table when they are
+/// executed
+#[derive(Debug, Clone)]
+pub(crate) struct TableHandle {
+    pub(crate) schema: SchemaRef,
+    pub(crate) batches: Partitions,
+    pub(crate) primary_key_index: Arc<RwLock<PrimaryKeyIndex>>,
+    pub(crate) indexes: Arc<RwLock<SecondaryIndexes>>,
+    /// Predicate over the table's columns that the rows a scan reads must satisfy, from the
+    /// table's policies
+    pub(crate) policy: Option<Arc<dyn PhysicalExpr>>,
+}
+
+impl TableHandle {
+    /// The rows of `batch`, which has the table's columns, that the scan's policy lets it see,
+    /// or `None` if there's no policy
+    pub(crate) fn visible(&self, batch: &RecordBatch) -> Result<Option<BooleanArray>> {
+        self.policy
+            .as_ref()
+            .map(|policy| policy_mask(policy, batch))
+            .transpose()
+    }
+
+    /// Replace every batch with the result of `rewrite`, which receives a mask of the rows
+    /// matching `predicate`, and update the indexes for the batches that change. Nothing is
+    /// changed if the new rows would break the primary key or a unique index. `rewrite` must
+    /// either change the matching rows in place or remove them.
+    ///
+    /// Returns the number of matching rows and, if `log` is set, the matching rows before and
+    /// after the rewrite.
+    async fn rewrite_matching<F>(
+        &self,
+        predicate: Option<Arc<dyn PhysicalExpr>>,
+        rewrite: F,
+        log: bool,
+    ) -> Result<(u64, Vec<RecordBatch>, Vec<RecordBatch>)>
+    where
+        F: Fn(&RecordBatch, &BooleanArray) -> Result<RecordBatch>,
+    {
+        let indexes = self.indexes.write().await;
+        let mut primary_key_index = self.primary_key_index.write().await;
+        let list = self.batches.get();
+        let mut partitions = Vec::with_capacity(list.len());
+        for partition in list.iter() {
+            partitions.push(partition.write().await);
+        }
+
+        let mut row_count = 0;
+        let mut deleted = vec![];
+        let mut inserted = vec![];
+        let mut replacements = vec![];
+        for (partition_idx, partition) in partitions.iter().enumerate() {
+            for (batch_idx, batch) in partition.iter().enumerate() {
+                let mask = match &predicate {
+                    Some(predicate) => {
+                        let mask = predicate.evaluate(batch)?.into_array(batch.num_rows())?;
+                        let mask = as_boolean_array(&mask)?;
+                        if mask.null_count() > 0 {
+                            prep_null_mask_filter(mask)
+                        } else {
+                            mask.clone()
+                        }
+                    }
+                    None => BooleanArray::from(vec![true; batch.num_rows()]),
+                };
+                if mask.true_count() == 0 {
+                    continue;
+                }
+                row_count += mask.true_count();
+                let new_batch = rewrite(batch, &mask)?;
+                if log {
+                    deleted.push(filter_record_batch(batch, &mask)?);
+                    if new_batch.num_rows() == batch.num_rows() {
+                        inserted.push(filter_record_batch(&new_batch, &mask)?);
+                    }
+                }
+                replacements.push(Replacement {
+                    partition_idx,
+                    batch_idx,
+                    old: batch.clone(),
+                    new: new_batch,
+                });
+            }
+        }
+
+        let mut partitions: Vec<&mut Vec<RecordBatch>> = partitions
+            .iter_mut()
+            .map(|partition| &mut **partition)
+            .collect();
+        self.apply(
+            &mut partitions,
+            &mut primary_key_index,
+            &indexes,
+            replacements,
+            vec![],
+        )?;
+        Ok((row_count as u64, deleted, inserted))
+    }
+
+    /// Delete the row with the primary key of each row of `deleted` and append `inserted`, as
+    /// logged by a write, and update the indexes for the batches that change
+    async fn redo(&self, deleted: Vec<RecordBatch>, inserted: Vec<RecordBatch>) -> Result<()> {
+        let indexes = self.indexes.write().await;
+        let mut primary_key_index = self.primary_key_index.write().await;
+        // Routing may add partitions for the inserted rows, so it comes before locking them
+        let routed = self.batches.route(inserted)?;
+        let list = self.batches.get();
+        let mut partitions = Vec::with_capacity(list.len());
+        for partition in list.iter() {
+            partitions.push(partition.write().await);
+        }
+
+        // The primary key finds each deleted row, so only the batches holding them are touched
+        let mut deleted_rows: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+        for batch in deleted.iter() {
+            for key in primary_keys(batch, self.primary_key())? {
+                let Some((partition_idx, batch_idx, row_idx)) = primary_key_index.get(key) else {
+                    return exec_err!("Deleted row with primary key {key} isn't in the table");
+                };
+                deleted_rows
+                    .entry((*partition_idx as usize, *batch_idx as usize))
+                    .or_default()
+                    .push(*row_idx as usize);
+            }
+        }
+        let mut replacements = vec![];
+        for ((partition_idx, batch_idx), rows) in deleted_rows {
+            let batch = &partitions[partition_idx][batch_idx];
+            let mut keep = vec![true; batch.num_rows()];
+            for row_idx in rows {
+                keep[row_idx] = false;
+            }
+            replacements.push(Replacement {
+                partition_idx,
+                batch_idx,
+                old: batch.clone(),
+                new: filter_record_batch(batch, &BooleanArray::from(keep))?,
+            });
+        }
+
+        let mut partitions: Vec<&mut Vec<RecordBatch>> = partitions
+            .iter_mut()
+            .map(|partition| &mut **partition)
+            .collect();
+        self.apply(
+            &mut partitions,
+            &mut primary_key_index,
+            &indexes,
+            replacements,
+            routed,
+        )
+    }
+
+    /// Remove every row of the partitions of `values` of the partition key, and with `drop` also
+    /// forget the values so their partitions are reused, then remove the rows from the indexes.
+    /// Returns the number of rows removed.
+    async fn truncate_partitions(&self, values: &[ScalarValue], drop: bool) -> Result<u64> {
+        let indexes = self.indexes.write().await;
+        let mut primary_key_index = self.primary_key_index.write().await;
+        let truncated = self.batches.partitions_of(values);
+        let list = self.batches.get();
+        let mut partitions = Vec::with_capacity(list.len());
+        for partition in list.iter() {
+            partitions.push(partition.write().await);
+        }
+
+        let mut row_count = 0;
+        let mut replacements = vec![];
+        for (_, partition_idx) in truncated.iter() {
+            for (batch_idx, batch) in partitions[*partition_idx].iter().enumerate() {
+                row_count += batch.num_rows();
+                replacements.push(Replacement {
+                    partition_idx: *partition_idx,
+                    batch_idx,
+                    old: batch.clone(),
+                    new: RecordBatch::new_empty(batch.schema()),
+                });
+            }
+        }
+
+        let mut partitions: Vec<&mut Vec<RecordBatch>> = partitions
+            .iter_mut()
+            .map(|partition| &mut **partition)
+            .collect();
+        self.apply(
+            &mut partitions,
+            &mut primary_key_index,
+            &indexes,
+            replacements,
+            vec![],
+        )?;
+        if drop {
+            let values: Vec<ScalarValue> = truncated.into_iter().map(|(value, _)| value).collect();
+            self.batches.forget(&values);
+        }
+        Ok(row_count as u64)
+    }
+
+    /// The batches of every partition, for [`Self::restore`] to put back if the write that
+    /// follows is rolled back
+    async fn save(&self) -> Vec<Vec<RecordBatch>> {
+        let mut saved = vec![];
+        for partition in self.batches.get().iter() {
+            saved.push(partition.read().await.clone());
+        }
+        saved
+    }
+
+    /// Put back the batches [`Self::save`] returned, updating the indexes for the batches that
+    /// changed since
+    async fn restore(&self, saved: Vec<Vec<RecordBatch>>) -> Result<()> {
+        let indexes = self.indexes.write().await;
+        let mut primary_key_index = self.primary_key_index.write().await;
+        let list = self.batches.get();
+        let mut partitions = Vec::with_capacity(list.len());
+        for partition in list.iter() {
+            partitions.push(partition.write().await);
+        }
+
+        let mut replacements = vec![];
+        let mut appended = vec![vec![]; partitions.len()];
+        for (partition_idx, (partition, saved)) in partitions
+            .iter()
+            .zip(saved.into_iter().chain(std::iter::repeat(vec![])))
+            .enumerate()
+        {
+            let mut saved = saved.into_iter();
+            for (batch_idx, batch) in partition.iter().enumerate() {
+                let old = saved
+                    .next()
+                    .unwrap_or_else(|| RecordBatch::new_empty(batch.schema()));
+                // Batches the write didn't replace still share their arrays with the saved ones
+                let unchanged = batch.num_rows() == old.num_rows()
+                    && batch
+                        .columns()
+                        .iter()
+                        .zip(old.columns())
+                        .all(|(new, old)| Arc::ptr_eq(new, old));
+                if !unchanged {
+                    replacements.push(Replacement {
+                        partition_idx,
+                        batch_idx,
+                        old: batch.clone(),
+                        new: old,
+                    });
+                }
+            }
+            appended[partition_idx].extend(saved);
+        }
+
+        let mut partitions: Vec<&mut Vec<RecordBatch>> = partitions
+            .iter_mut()
+            .map(|partition| &mut **partition)
+            .collect();
+        self.apply(
+            &mut partitions,
+            &mut primary_key_index,
+            &indexes,
+            replacements,
+            appended,
+        )
+    }
+
+    /// Swap the batches of `replacements` into `partitions` and append `appended`, which has the
+    /// new batches of each partition, updating the indexes for only the rows that change. Fails
+    /// without changing anything if the new rows would break the primary key or a unique index.
+    ///
+    /// The indexes locate rows by batch position, so a batch emptied by a write stays in place
+    /// unless no batch follows it.
+    fn apply(
+        &self,
+        partitions: &mut [&mut Vec<RecordBatch>],
+        primary_key_index: &mut PrimaryKeyIndex,
+        indexes: &SecondaryIndexes,
+        replacements: Vec<Replacement>,
+        appended: Vec<Vec<RecordBatch>>,
+    ) -> Result<()> {
+        let removed: Vec<RecordBatch> = replacements.iter().map(|r| r.old.clone()).collect();
+        let added: Vec<RecordBatch> = replacements
+            .iter()
+            .map(|r| r.new.clone())
+            .chain(appended.iter().flatten().cloned())
+            .collect();
+        check_primary_keys(primary_key_index, &removed, &added, self.primary_key())?;
+        for index in indexes.values() {
+            index.check_unique_replacing(&removed, &added)?;
+        }
+
+        // Every old row leaves the indexes before the new ones come in, since a write can move a
+        // key from one batch to another
+        for replacement in replacements.iter() {
+            for key in primary_keys(&replacement.old, self.primary_key())? {
+                primary_key_index.remove(key);
+            }
+            for index in indexes.values() {
+                index.remove_batch(
+                    &replacement.old,
+                    replacement.partition_idx,
+                    replacement.batch_idx,
+                )?;
+            }
+        }
+        for replacement in replacements.iter() {
+            partitions[replacement.partition_idx][replacement.batch_idx] = replacement.new.clone();
+        }
+        for partition in partitions.iter_mut() {
+            while partition.last().is_some_and(|batch| batch.num_rows() == 0) {
+                partition.pop();
+            }
+        }
+        for replacement in replacements.iter() {
+            if replacement.new.num_rows() == 0 {
+                continue;
+            }
+            self.index_batch(
+                primary_key_index,
+                indexes,
+                &replacement.new,
+                replacement.partition_idx,
+                replacement.batch_idx,
+            )?;
+        }
+        for (partition_idx, batches) in appended.into_iter().enumerate() {
+            let partition = &mut partitions[partition_idx];
+            for batch in batches {
+                self.index_batch(
+                    primary_key_index,
+                    indexes,
+                    &batch,
+                    partition_idx,
+                    partition.len(),
+                )?;
+                partition.push(batch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the rows of `batch`, located at `batch_idx` in partition `partition_idx`, to the
+    /// primary key and secondary indexes
+    fn index_batch(
+        &self,
+        primary_key_index: &mut PrimaryKeyIndex,
+        indexes: &SecondaryIndexes,
+        batch: &RecordBatch,
+        partition_idx: usize,
+        batch_idx: usize,
+    ) -> Result<()> {
+        index_primary_key(
+            primary_key_index,
+            batch,
+            self.primary_key(),
+            partition_idx,
+            batch_idx,
+        )?;
+        for index in indexes.values() {
+            index.insert_batch(batch, partition_idx, batch_idx)?;
+        }
+        Ok(())
+    }
+
+    fn primary_key(&self) -> &str {
+        self.schema
+            .metadata()
+            .get("primary_key")
+            .expect("primary key is required")
+    }
+}
 
 /// In-memory data source for presenting a `Vec<RecordBatch>` as a
 /// data source that can be queried by DataFusion. This allows data to
@@ -61,12 +479,18 @@ type TupletOffset = (i32, i32, i32);
 /// incurring additional file I/O overhead.
 #[derive(Debug)]
 pub struct MemTable {
+    id: TableId,
     schema: SchemaRef,
-    pub(crate) batches: Vec<PartitionData>,
+    pub(crate) batches: Partitions,
     constraints: Constraints,
     column_defaults: HashMap<String, Expr>,
     // TODO: Allow primary key to be something other than i32
-    primary_key_index: Arc<RwLock<BTreeMap<i32, TupletOffset>>>,
+    primary_key_index: Arc<RwLock<PrimaryKeyIndex>>,
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+    /// Row-level security policies, see [`crate::policy`]
+    policies: Arc<Mutex<Vec<Policy>>>,
+    retired: Retired,
+    lock: TableLock,
     /// Optional pre-known sort order(s). Must be `SortExpr`s.
     /// inserting data into this table removes the order
     pub sort_order: Arc<Mutex<Vec<Vec<Expr>>>>,
@@ -87,87 +511,37 @@ impl MemTable {
         }
 
         let mut primary_key_index = BTreeMap::new();
-
         let primary_key_name = schema
             .metadata()
             .get("primary_key")
             .expect("every table must have a primary key");
-
-        for (partition_idx, batch_idx, batches) in
-            partitions
-                .iter()
-                .enumerate()
-                .flat_map(|(partition_idx, partition)| {
-                    partition
-                        .iter()
-                        .enumerate()
-                        .map(move |(batch_idx, batches)| (partition_idx, batch_idx, batches))
-                })
-        {
-            let values = batches
-                .column_by_name(primary_key_name)
-                .expect("table must have primary key column")
-                .as_any()
-                .downcast_ref::<Int32Array>()
-                .expect("failed to downcast")
-                .values();
-
-            for (value_idx, value) in values.iter().enumerate() {
-                if primary_key_index
-                    .insert(
-                        *value,
-                        (partition_idx as i32, batch_idx as i32, value_idx as i32),
-                    )
-                    .is_some()
-                {
-                    return plan_err!("Duplicate primary key value.");
-                }
+        for (partition_idx, partition) in partitions.iter().enumerate() {
+            for (batch_idx, batch) in partition.iter().enumerate() {
+                index_primary_key(
+                    &mut primary_key_index,
+                    batch,
+                    primary_key_name,
+                    partition_idx,
+                    batch_idx,
+                )?;
             }
         }
 
         Ok(Self {
+            id: next_table_id(),
             schema,
-            batches: partitions
-                .into_iter()
-                .map(|e| Arc::new(RwLock::new(e)))
-                .collect::<Vec<_>>(),
+            batches: Partitions::new(partitions),
             constraints: Constraints::empty(),
             column_defaults: HashMap::new(),
             primary_key_index: Arc::new(RwLock::new(primary_key_index)),
+            indexes: Arc::new(RwLock::new(BTreeMap::new())),
+            policies: Arc::new(Mutex::new(vec![])),
+            retired: Retired::default(),
+            lock: TableLock::default(),
             sort_order: Arc::new(Mutex::new(vec![])),
         })
     }
 
-    fn supported_filter(&self, expr: &Expr) -> bool {
-        if let Expr::BinaryExpr(binary_expr) = expr {
-            if let (lhs, Operator::Eq, rhs) =
-                (&binary_expr.left, binary_expr.op, &binary_expr.right)
-            {
-                if let (Expr::Column(c), Expr::Literal(_)) = (lhs.as_ref(), rhs.as_ref()) {
-                    &c.name
-                        == self
-                            .schema()
-                            .metadata()
-                            .get("primary_key")
-                            .expect("primary key is required")
-                } else if let (Expr::Literal(_), Expr::Column(c)) = (lhs.as_ref(), rhs.as_ref()) {
-                    &c.name
-                        == self
-                            .schema()
-                            .metadata()
-                            .get("primary_key")
-                            .expect("primary key is required")
-                } else {
-                    false
-                }
-            } else {
-                false
-            }
-        } else {
-            false
-        }
-    }
-
     fn primary_key_filter(&self, expr: &Expr) -> Option<i32> {
         if let Expr::BinaryExpr(binary_expr) = expr {
             if let (lhs, Operator::Eq, rhs) =
@@ -214,6 +588,177 @@ impl MemTable {
         }
     }
 
+    /// Partition the table by the value of column `column`, moving each value's rows into a
+    /// partition of their own
+    pub fn with_partition_by(mut self, column: &str) -> Result<Self> {
+        let batches = self.batches.get();
+        let all_batches = batches
+            .iter()
+            .flat_map(|partition| {
+                partition
+                    .try_read()
+                    .expect("new table isn't shared")
+                    .clone()
+            })
+            .collect();
+        let (partitions, routed) = Partitions::partitioned(&self.schema, column, all_batches)?;
+        let mut primary_key_index = BTreeMap::new();
+        for (partition_idx, partition) in routed.iter().enumerate() {
+            for (batch_idx, batch) in partition.iter().enumerate() {
+                index_primary_key(
+                    &mut primary_key_index,
+                    batch,
+                    self.primary_key(),
+                    partition_idx,
+                    batch_idx,
+                )?;
+            }
+        }
+        self.batches = partitions;
+        self.primary_key_index = Arc::new(RwLock::new(primary_key_index));
+        Ok(self)
+    }
+
+    /// Assign row-level security policies
+    pub fn with_policies(self, policies: Vec<Policy>) -> Self {
+        *self.policies.lock() = policies;
+        self
+    }
+
+    /// Add a row-level security policy
+    pub fn create_policy(&self, policy: Policy) -> Result<()> {
+        let mut policies = self.policies.lock();
+        if policies.iter().any(|p| p.name == policy.name) {
+            return exec_err!("Policy {} already exists", policy.name);
+        }
+        policies.push(policy);
+        Ok(())
+    }
+
+    /// Drop the row-level security policy named `name`. Returns whether it existed.
+    pub fn drop_policy(&self, name: &str) -> bool {
+        let mut policies = self.policies.lock();
+        let count = policies.len();
+        policies.retain(|policy| policy.name != name);
+        policies.len() < count
+    }
+
+    pub fn policies(&self) -> Vec<Policy> {
+        self.policies.lock().clone()
+    }
+
+    /// The predicate rows must satisfy for the session of `state` to see and change them, or
+    /// `None` if the table has no policies
+    fn visible_rows(&self, state: &SessionState) -> Result<Option<Expr>> {
+        visible_rows(
+            &self.policies(),
+            &self.schema,
+            state.config_options(),
+            state.execution_props(),
+        )
+    }
+
+    /// [`Self::visible_rows`] as a physical expression
+    fn policy(&self, state: &SessionState) -> Result<Option<Arc<dyn PhysicalExpr>>> {
+        let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
+        self.visible_rows(state)?
+            .map(|expr| create_physical_expr(&expr, &df_schema, state.execution_props()))
+            .transpose()
+    }
+
+    /// Name of the column the table is partitioned by, if any
+    pub fn partition_by(&self) -> Option<String> {
+        self.batches.key_name()
+    }
+
+    /// Rows and memory of each partition of a partitioned table, by value of the partition key
+    pub async fn partition_stats(&self) -> Vec<PartitionStats> {
+        self.batches.stats().await
+    }
+
+    /// Submit a write to the session's writer that removes every row with one of `values` of the
+    /// partition key, and with `drop` also drops the values' partitions. Returns the number of
+    /// rows removed.
+    pub async fn truncate_partitions(
+        &self,
+        state: &SessionState,
+        values: Vec<ScalarValue>,
+        drop: bool,
+    ) -> Result<u64> {
+        let Some(column) = self.batches.key_column() else {
+            return plan_err!("Table isn't partitioned");
+        };
+        let data_type = self.schema.field(column).data_type();
+        let values = values
+            .iter()
+            .map(|value| value.cast_to(data_type))
+            .collect::<Result<Vec<_>>>()?;
+        let table = self.handle();
+        let id = self.id;
+        let retired = self.retired.clone();
+        let writer = writer_for(state.config());
+        let log = writer.is_logged();
+        let _lock = self.lock.lock_for_write().await;
+        writer
+            .submit(move |_| async move {
+                retired.check(id)?;
+                // Partitions whose values were dropped can't be restored
+                let saved = if drop { None } else { Some(table.save().await) };
+                let row_count = table.truncate_partitions(&values, drop).await?;
+                let log = if log {
+                    vec![LogRecord::truncate_partitions(
+                        id,
+                        &table.schema,
+                        column,
+                        &values,
+                        drop,
+                    )?]
+                } else {
+                    vec![]
+                };
+                let applied = Applied::new(row_count, log);
+                Ok(match saved {
+                    Some(saved) => {
+                        applied.with_rollback(|| async move { table.restore(saved).await })
+                    }
+                    None => applied,
+                })
+            })
+            .await
+    }
+
+    /// Redo a truncation of partitions read from the write-ahead log
+    pub(crate) async fn redo_truncate_partitions(
+        &self,
+        values: &[ScalarValue],
+        drop: bool,
+    ) -> Result<()> {
+        self.handle().truncate_partitions(values, drop).await?;
+        Ok(())
+    }
+
+    /// Assign the id of a table recovered from the write-ahead log
+    pub fn with_id(mut self, id: TableId) -> Self {
+        reserve_table_id(id);
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> TableId {
+        self.id
+    }
+
+    /// Make writes that haven't been applied yet fail, since the table has been replaced or
+    /// dropped
+    pub fn retire(&self) {
+        self.retired.retire();
+    }
+
+    /// The lock that keeps writes away from the table while it's altered
+    pub fn table_lock(&self) -> &TableLock {
+        &self.lock
+    }
+
     /// Assign constraints
     pub fn with_constraints(mut self, constraints: Constraints) -> Self {
         self.constraints = constraints;
@@ -226,6 +771,10 @@ impl MemTable {
         self
     }
 
+    pub fn column_defaults(&self) -> &HashMap<String, Expr> {
+        &self.column_defaults
+    }
+
     /// Specify an optional pre-known sort order(s). Must be `SortExpr`s.
     ///
     /// If the data is not sorted by this order, DataFusion may produce
@@ -241,6 +790,408 @@ impl MemTable {
         self
     }
 
+    fn primary_key(&self) -> &str {
+        self.schema
+            .metadata()
+            .get("primary_key")
+            .expect("primary key is required")
+    }
+
+    /// Add the empty secondary index `index` and index the data already in the table
+    pub async fn create_index(&self, index: SecondaryIndex) -> Result<()> {
+        let mut indexes = self.indexes.write().await;
+        let name = index.name().to_string();
+        if indexes.contains_key(&name) {
+            return exec_err!("Index {name} already exists");
+        }
+        let list = self.batches.get();
+        let mut partitions = Vec::with_capacity(list.len());
+        for partition in list.iter() {
+            partitions.push(partition.read().await);
+        }
+        let all_batches: Vec<RecordBatch> =
+            partitions.iter().flat_map(|p| p.iter().cloned()).collect();
+        index.check_unique(&all_batches)?;
+        for (partition_idx, partition) in partitions.iter().enumerate() {
+            for (batch_idx, batch) in partition.iter().enumerate() {
+                index.insert_batch(batch, partition_idx, batch_idx)?;
+            }
+        }
+        indexes.insert(name, index);
+        Ok(())
+    }
+
+    /// Drop the secondary index named `name`. Returns whether the index existed.
+    pub async fn drop_index(&self, name: &str) -> bool {
+        self.indexes.write().await.remove(name).is_some()
+    }
+
+    /// Names of this table's secondary indexes
+    pub async fn index_names(&self) -> Vec<String> {
+        self.indexes.read().await.keys().cloned().collect()
+    }
+
+    /// The name, columns and uniqueness of each of this table's secondary indexes
+    pub async fn index_definitions(&self) -> Vec<(String, Vec<String>, bool)> {
+        self.indexes
+            .read()
+            .await
+            .values()
+            .map(|index| {
+                let columns = index.columns().to_vec();
+                (index.name().to_string(), columns, index.is_unique())
+            })
+            .collect()
+    }
+
+    /// The batches of each partition of the table
+    pub(crate) async fn partitions(&self) -> Vec<Vec<RecordBatch>> {
+        let mut partitions = vec![];
+        for partition in self.batches.get().iter() {
+            partitions.push(partition.read().await.clone());
+        }
+        partitions
+    }
+
+    /// Every batch of the table
+    pub(crate) async fn all_batches(&self) -> Vec<RecordBatch> {
+        let mut batches = vec![];
+        for partition in self.batches.get().iter() {
+            batches.extend(partition.read().await.iter().cloned());
+        }
+        batches
+    }
+
+    /// Approximate bytes of memory used by each of this table's secondary indexes
+    pub async fn index_memory_usage(&self) -> Vec<(String, usize)> {
+        self.indexes
+            .read()
+            .await
+            .iter()
+            .map(|(name, index)| (name.clone(), index.memory_usage()))
+            .collect()
+    }
+
+    /// Delete every row matching `predicate`, or every row if there is no predicate. Returns the
+    /// number of rows deleted.
+    pub async fn delete(&self, state: &SessionState, predicate: Option<&Expr>) -> Result<u64> {
+        self.rewrite_matching(state, predicate, |batch, mask| {
+            Ok(filter_record_batch(batch, &not(mask)?)?)
+        })
+        .await
+    }
+
+    /// Set every row matching `predicate`, or every row if there is no predicate, to the values
+    /// of `assignments`, which must have one expression per column of the table. Returns the
+    /// number of rows updated.
+    pub async fn update(
+        &self,
+        state: &SessionState,
+        predicate: Option<&Expr>,
+        assignments: &[Expr],
+    ) -> Result<u64> {
+        if assignments.len() != self.schema.fields().len() {
+            return plan_err!("Update must assign a value to every column of the table");
+        }
+        if let Some(column) = self.batches.key_column() {
+            let key = self.schema.field(column).name();
+            if !matches!(assignments[column].clone().unalias(), Expr::Column(c) if &c.name == key) {
+                return not_impl_err!("Updating partition key {key} isn't supported");
+            }
+        }
+        let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
+        let assignments = assignments
+            .iter()
+            .map(|expr| {
+                let expr = unnormalize_col(expr.clone().unalias());
+                create_physical_expr(&expr, &df_schema, state.execution_props())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let policy = self.policy(state)?;
+        let count = self
+            .rewrite_matching(state, predicate, move |batch, mask| {
+                if mask.true_count() == 0 {
+                    return Ok(batch.clone());
+                }
+                let columns = assignments
+                    .iter()
+                    .zip(batch.columns())
+                    .map(|(expr, old)| {
+                        let new = expr.evaluate(batch)?.into_array(batch.num_rows())?;
+                        Ok(zip(mask, &new, old)?)
+                    })
+                    .collect::<Result<Vec<ArrayRef>>>()?;
+                let updated = RecordBatch::try_new(batch.schema(), columns)?;
+                check_new_rows(policy.as_ref(), &filter_record_batch(&updated, mask)?)?;
+                Ok(updated)
+            })
+            .await?;
+        // Updated rows may no longer follow the sort order
+        *self.sort_order.lock() = vec![];
+        Ok(count)
+    }
+
+    /// Submit a write to the session's writer that replaces every batch with the result of
+    /// `rewrite`, which receives a mask of the rows matching `predicate` that the table's policies
+    /// let the session see, then rebuilds the indexes. Nothing is changed if the new rows would
+    /// break the primary key or a unique index.
+    async fn rewrite_matching<F>(
+        &self,
+        state: &SessionState,
+        predicate: Option<&Expr>,
+        rewrite: F,
+    ) -> Result<u64>
+    where
+        F: Fn(&RecordBatch, &BooleanArray) -> Result<RecordBatch> + Send + 'static,
+    {
+        let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
+        let predicate = match (predicate.cloned(), self.visible_rows(state)?) {
+            (Some(predicate), Some(visible_rows)) => Some(predicate.and(visible_rows)),
+            (predicate, visible_rows) => predicate.or(visible_rows),
+        };
+        let predicate = predicate
+            .as_ref()
+            .map(|expr| {
+                create_physical_expr(
+                    &unnormalize_col(expr.clone()),
+                    &df_schema,
+                    state.execution_props(),
+                )
+            })
+            .transpose()?;
+        let table = self.handle();
+        let id = self.id;
+        let retired = self.retired.clone();
+        let writer = writer_for(state.config());
+        let log = writer.is_logged();
+        let _lock = self.lock.lock_for_write().await;
+        writer
+            .submit(move |_| async move {
+                retired.check(id)?;
+                let saved = table.save().await;
+                let (row_count, deleted, inserted) =
+                    table.rewrite_matching(predicate, rewrite, log).await?;
+                let log = if log && row_count > 0 {
+                    vec![LogRecord::change(id, &table.schema, &deleted, &inserted)?]
+                } else {
+                    vec![]
+                };
+                Ok(Applied::new(row_count, log)
+                    .with_rollback(|| async move { table.restore(saved).await }))
+            })
+            .await
+    }
+
+    /// Redo a change read from the write-ahead log: delete the row with the primary key of each
+    /// row of `deleted` and insert `inserted`
+    pub(crate) async fn redo(
+        &self,
+        deleted: Vec<RecordBatch>,
+        inserted: Vec<RecordBatch>,
+    ) -> Result<()> {
+        *self.sort_order.lock() = vec![];
+        self.handle().redo(deleted, inserted).await
+    }
+
+    fn handle(&self) -> TableHandle {
+        TableHandle {
+            schema: self.schema.clone(),
+            batches: self.batches.clone(),
+            primary_key_index: self.primary_key_index.clone(),
+            indexes: self.indexes.clone(),
+            policy: None,
+        }
+    }
+
+    /// Build an index scan for the rows that may satisfy `filters`, using the primary key if it
+    /// is filtered on and otherwise the secondary index matching the most filters. Returns
+    /// `None` if no index applies.
+    async fn index_scan(
+        &self,
+        handle: &TableHandle,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+    ) -> Result<Option<IndexScanExec>> {
+        // TODO: Fill out the full set of operators we can optimize with our index
+        let primary_key_filter = filters
+            .iter()
+            .find_map(|expr| Some((self.primary_key_filter(expr)?, expr)));
+        if let Some((key, filter)) = primary_key_filter {
+            let estimated_rows =
+                usize::from(self.primary_key_index.read().await.contains_key(&key));
+            return IndexScanExec::try_new(
+                ScannedTable::Memory(handle.clone()),
+                IndexLookup::PrimaryKey(key),
+                vec![filter.clone()],
+                estimated_rows,
+                projection.cloned(),
+            )
+            .map(Some);
+        }
+
+        let indexes = self.indexes.read().await;
+        let Some((index, range)) = best_index(indexes.values(), filters)? else {
+            return self.text_index_scan(handle, &indexes, projection, filters);
+        };
+        let estimated_rows = index.count(&range);
+        debug!(
+            "index {} matched {estimated_rows} rows for filters {filters:?}",
+            index.name(),
+        );
+        let bounds = index
+            .bounding_filters(filters)
+            .into_iter()
+            .cloned()
+            .collect();
+        IndexScanExec::try_new(
+            ScannedTable::Memory(handle.clone()),
+            IndexLookup::Secondary {
+                name: index.name().to_string(),
+                range,
+            },
+            bounds,
+            estimated_rows,
+            projection.cloned(),
+        )
+        .map(Some)
+    }
+
+    /// The positions in the schema of `columns`, if they are all in the table
+    fn column_indices(&self, columns: &[Column]) -> Option<Vec<usize>> {
+        columns
+            .iter()
+            .map(|column| self.schema.index_of(&column.name).ok())
+            .collect()
+    }
+
+    /// Build an index scan for the rows matching a `match()` filter on columns that are all in
+    /// one fulltext index, if there is one
+    fn text_index_scan(
+        &self,
+        handle: &TableHandle,
+        indexes: &SecondaryIndexes,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+    ) -> Result<Option<IndexScanExec>> {
+        for filter in filters {
+            let Some((columns, search)) = match_filter(filter) else {
+                continue;
+            };
+            let columns: Vec<Column> = columns.into_iter().cloned().collect();
+            let (Some(query), Some(column_indices)) =
+                (search.query(), self.column_indices(&columns))
+            else {
+                continue;
+            };
+            let Some((index, text_index, fields)) = covering_text_index(indexes, &column_indices)
+            else {
+                continue;
+            };
+            let query = query.analyze(text_index.analyzer(), search.fields())?;
+            let estimated_rows = text_index.estimate_rows(&query, &fields);
+            debug!(
+                "index {} estimates {estimated_rows} rows for {filter}",
+                index.name(),
+            );
+            return IndexScanExec::try_new(
+                ScannedTable::Memory(handle.clone()),
+                IndexLookup::Text {
+                    name: index.name().to_string(),
+                    fields,
+                    query,
+                },
+                vec![filter.clone()],
+                estimated_rows,
+                projection.cloned(),
+            )
+            .map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Give the `match()` calls in `filters` the analyzer of their columns and the BM25
+    /// statistics `score()` ranks their rows with, from a fulltext index on all the columns if
+    /// there is one and otherwise from the rows of `partitions`
+    async fn text_search_stats(
+        &self,
+        filters: &[Expr],
+        partitions: &[Vec<RecordBatch>],
+    ) -> Result<()> {
+        for (columns, search) in text_searches(filters) {
+            let (Some(query), Some(column_indices)) =
+                (search.query(), self.column_indices(&columns))
+            else {
+                continue;
+            };
+            let indexes = self.indexes.read().await;
+            let (analyzer, stats) = match covering_text_index(&indexes, &column_indices) {
+                Some((_, text_index, fields)) => {
+                    let analyzer = text_index.analyzer().clone();
+                    let query = query.analyze(&analyzer, search.fields())?;
+                    let mut terms = query.terms();
+                    // The terms fuzzy ones match need statistics as well
+                    for (term, distance) in query.fuzzy_terms() {
+                        for (matched, _) in text_index.fuzzy_terms(&term, distance) {
+                            if !terms.contains(&matched) {
+                                terms.push(matched);
+                            }
+                        }
+                    }
+                    let stats = fields
+                        .iter()
+                        .map(|field| text_index.stats(*field, &terms))
+                        .collect();
+                    (analyzer, stats)
+                }
+                None => {
+                    let analyzer = Analyzer::standard();
+                    let query = query.analyze(&analyzer, search.fields())?;
+                    let fuzzy = query.fuzzy_terms();
+                    let mut stats = vec![Bm25Stats::new(&query.terms()); column_indices.len()];
+                    for batch in partitions.iter().flatten() {
+                        for (column_index, stats) in column_indices.iter().zip(stats.iter_mut()) {
+                            let column = batch.column(*column_index);
+                            column_stats(column, &analyzer, &fuzzy, stats)?;
+                        }
+                    }
+                    (analyzer, stats)
+                }
+            };
+            search.set_scanned(analyzer, stats);
+        }
+        Ok(())
+    }
+
+    /// The indexes that can find rows by the value of a single column, keyed by the position of
+    /// the column in `projection`. The primary key is preferred over secondary indexes.
+    async fn join_indexes(&self, projection: Option<&Vec<usize>>) -> BTreeMap<usize, JoinIndex> {
+        let output_position = |column: usize| match projection {
+            Some(projection) => projection.iter().position(|c| *c == column),
+            None => Some(column),
+        };
+        let mut join_indexes = BTreeMap::new();
+        if let Some(position) = self
+            .schema
+            .index_of(self.primary_key())
+            .ok()
+            .and_then(output_position)
+        {
+            join_indexes.insert(position, JoinIndex::PrimaryKey);
+        }
+        for index in self.indexes.read().await.values() {
+            if index.method() != IndexMethod::BTree {
+                continue;
+            }
+            let leading_column = self.schema.index_of(&index.columns()[0]).ok();
+            if let Some(position) = leading_column.and_then(output_position) {
+                join_indexes
+                    .entry(position)
+                    .or_insert_with(|| JoinIndex::Secondary(index.name().to_string()));
+            }
+        }
+        join_indexes
+    }
+
     /// Create a mem table by reading from another data source
     pub async fn load(
         t: Arc<dyn TableProvider>,
@@ -323,6 +1274,16 @@ impl TableProvider for MemTable {
         TableType::Base
     }
 
+    /// Filters are always re-applied to the rows returned by [`Self::scan`], but passing them
+    /// down lets [`IndexScanRule`](crate::index_scan::IndexScanRule) use the primary key or a
+    /// secondary index to find the rows.
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> Result<Vec<TableProviderFilterPushDown>> {
+        Ok(vec![TableProviderFilterPushDown::Inexact; filters.len()])
+    }
+
     async fn scan(
         &self,
         state: &SessionState,
@@ -330,31 +1291,59 @@ impl TableProvider for MemTable {
         filters: &[Expr],
         _limit: Option<usize>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        // TODO: Fill out the full set of operators we can optimize with our index
-        let primary_key_filter = filters
-            .iter()
-            .find_map(|expr| self.primary_key_filter(expr));
-        if let Some(i) = primary_key_filter {
-            let primary_key_index = self.primary_key_index.read().await;
-            if let Some((partition_idx, batch_idx, value_idx)) = (*primary_key_index).get(&i) {
-                let batches = self.batches[*partition_idx as usize].read().await;
-                let batch = &batches[*batch_idx as usize];
-                let partitions = vec![vec![batch.slice(*value_idx as usize, 1)]];
-                let exec = MemoryExec::try_new(&partitions, self.schema(), projection.cloned())?;
-                return Ok(Arc::new(exec));
-            }
+        let visible_rows = self.visible_rows(state)?;
+        let mut handle = self.handle();
+        let mut pruning_filters = filters.to_vec();
+        if let Some(visible_rows) = &visible_rows {
+            let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
+            handle.policy = Some(create_physical_expr(
+                visible_rows,
+                &df_schema,
+                state.execution_props(),
+            )?);
+            pruning_filters.push(visible_rows.clone());
         }
-        // TODO: Use tree that supports duplicate keys
+
+        let list = self.batches.get();
         let mut partitions = vec![];
-        for arc_inner_vec in self.batches.iter() {
-            let inner_vec = arc_inner_vec.read().await;
-            partitions.push(inner_vec.clone())
+        match self.batches.matching(&pruning_filters) {
+            Some(matching) => {
+                for partition in matching {
+                    partitions.push(list[partition].read().await.clone());
+                }
+            }
+            None => {
+                for arc_inner_vec in list.iter() {
+                    let inner_vec = arc_inner_vec.read().await;
+                    partitions.push(inner_vec.clone())
+                }
+            }
+        }
+        // Rows the policies hide are left out of the scan altogether
+        for batches in partitions.iter_mut() {
+            for batch in batches.iter_mut() {
+                if let Some(mask) = handle.visible(batch)? {
+                    *batch = filter_record_batch(batch, &mask)?;
+                }
+            }
+        }
+        self.text_search_stats(filters, &partitions).await?;
+        let partitioned = self.batches.key_name().is_some();
+        if partitioned {
+            // A table partitioned by tenant can have far more partitions than are worth scanning
+            // in parallel
+            let target_partitions = state.config().target_partitions().max(1);
+            let mut merged = vec![vec![]; partitions.len().clamp(1, target_partitions)];
+            for (i, batches) in partitions.into_iter().enumerate() {
+                merged[i % target_partitions].extend(batches);
+            }
+            partitions = merged;
         }
         let mut exec = MemoryExec::try_new(&partitions, self.schema(), projection.cloned())?;
 
-        // add sort information if present
-        let sort_order = self.sort_order.lock();
-        if !sort_order.is_empty() {
+        // add sort information if present. Merging partitions loses their order.
+        let sort_order = self.sort_order.lock().clone();
+        if !sort_order.is_empty() && !partitioned {
             let df_schema = DFSchema::try_from(self.schema.as_ref().clone())?;
 
             let file_sort_order = sort_order
@@ -371,6 +1360,11 @@ impl TableProvider for MemTable {
             exec = exec.with_sort_information(file_sort_order);
         }
 
+        let index_scan = self.index_scan(&handle, projection, filters).await?;
+        let join_indexes = self.join_indexes(projection).await;
+        let exec = MemTableScanExec::new(exec, handle, projection.cloned())
+            .with_index_scan(index_scan)
+            .with_join_indexes(join_indexes);
         Ok(Arc::new(exec))
     }
 
@@ -388,12 +1382,10 @@ impl TableProvider for MemTable {
     /// * A plan that returns the number of rows written.
     async fn insert_into(
         &self,
-        _state: &SessionState,
+        state: &SessionState,
         input: Arc<dyn ExecutionPlan>,
         overwrite: bool,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        // TODO: Update primary key index here
-        //
         // If we are inserting into the table, any sort order may be messed up so reset it here
         *self.sort_order.lock() = vec![];
 
@@ -408,7 +1400,18 @@ impl TableProvider for MemTable {
         if overwrite {
             return not_impl_err!("Overwrite not implemented for MemoryTable yet");
         }
-        let sink = Arc::new(MemSink::new(self.batches.clone()));
+        let sink = Arc::new(MemSink::new(
+            writer_for(state.config()),
+            self.id,
+            self.schema.clone(),
+            self.batches.clone(),
+            self.primary_key().to_string(),
+            self.primary_key_index.clone(),
+            self.indexes.clone(),
+            self.retired.clone(),
+            self.lock.clone(),
+            self.policy(state)?,
+        ));
         Ok(Arc::new(FileSinkExec::new(
             input,
             sink,
@@ -422,10 +1425,104 @@ impl TableProvider for MemTable {
     }
 }
 
+/// Rows of `batch` for which `policy` is true
+fn policy_mask(policy: &Arc<dyn PhysicalExpr>, batch: &RecordBatch) -> Result<BooleanArray> {
+    let mask = policy.evaluate(batch)?.into_array(batch.num_rows())?;
+    let mask = as_boolean_array(&mask)?;
+    Ok(if mask.null_count() > 0 {
+        prep_null_mask_filter(mask)
+    } else {
+        mask.clone()
+    })
+}
+
+/// Fail unless every row of `batch`, which is being written to the table, satisfies `policy`
+fn check_new_rows(policy: Option<&Arc<dyn PhysicalExpr>>, batch: &RecordBatch) -> Result<()> {
+    match policy {
+        Some(policy) if policy_mask(policy, batch)?.true_count() < batch.num_rows() => {
+            exec_err!("New row violates row-level security policy")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The primary keys of the rows of `batch`, failing if any of them is null
+fn primary_keys<'a>(batch: &'a RecordBatch, primary_key: &str) -> Result<&'a [i32]> {
+    let column = batch
+        .column_by_name(primary_key)
+        .expect("table must have primary key column");
+    if column.null_count() > 0 {
+        return exec_err!("Primary key {primary_key} can't be null");
+    }
+    Ok(column
+        .as_any()
+        .downcast_ref::<Int32Array>()
+        .expect("failed to downcast")
+        .values())
+}
+
+/// Add the primary key of every row in `batch` to `index`, failing on null keys and on keys
+/// that are already present
+fn index_primary_key(
+    index: &mut PrimaryKeyIndex,
+    batch: &RecordBatch,
+    primary_key: &str,
+    partition_idx: usize,
+    batch_idx: usize,
+) -> Result<()> {
+    for (value_idx, value) in primary_keys(batch, primary_key)?.iter().enumerate() {
+        match index.entry(*value) {
+            Entry::Occupied(_) => {
+                return exec_err!("Duplicate key value violates primary key {primary_key}")
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((partition_idx as i32, batch_idx as i32, value_idx as i32));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that replacing the rows of `removed`, which are in the table, with those of `added`
+/// would not give two rows of a table with primary key `index` the same key or a row a null one
+fn check_primary_keys(
+    index: &PrimaryKeyIndex,
+    removed: &[RecordBatch],
+    added: &[RecordBatch],
+    primary_key: &str,
+) -> Result<()> {
+    let mut removed_keys = HashSet::new();
+    for batch in removed {
+        removed_keys.extend(primary_keys(batch, primary_key)?.iter().copied());
+    }
+    let mut new_keys = HashSet::new();
+    for batch in added {
+        for key in primary_keys(batch, primary_key)? {
+            let taken = index.contains_key(key) && !removed_keys.contains(key);
+            if taken || !new_keys.insert(*key) {
+                return exec_err!("Duplicate key value violates primary key {primary_key}");
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Implements for writing to a [`MemTable`]
+#[derive(Clone)]
 struct MemSink {
+    writer: Writer,
+    /// Table id and schema of the log records
+    id: TableId,
+    schema: SchemaRef,
     /// Target locations for writing data
-    batches: Vec<PartitionData>,
+    batches: Partitions,
+    primary_key: String,
+    primary_key_index: Arc<RwLock<PrimaryKeyIndex>>,
+    indexes: Arc<RwLock<SecondaryIndexes>>,
+    retired: Retired,
+    lock: TableLock,
+    /// Predicate every inserted row must satisfy, from the table's policies
+    policy: Option<Arc<dyn PhysicalExpr>>,
 }
 
 impl Debug for MemSink {
@@ -448,8 +1545,86 @@ impl DisplayAs for MemSink {
 }
 
 impl MemSink {
-    fn new(batches: Vec<PartitionData>) -> Self {
-        Self { batches }
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        writer: Writer,
+        id: TableId,
+        schema: SchemaRef,
+        batches: Partitions,
+        primary_key: String,
+        primary_key_index: Arc<RwLock<PrimaryKeyIndex>>,
+        indexes: Arc<RwLock<SecondaryIndexes>>,
+        retired: Retired,
+        lock: TableLock,
+        policy: Option<Arc<dyn PhysicalExpr>>,
+    ) -> Self {
+        Self {
+            writer,
+            id,
+            schema,
+            batches,
+            primary_key,
+            primary_key_index,
+            indexes,
+            retired,
+            lock,
+            policy,
+        }
+    }
+
+    /// Handles to the table's data and indexes
+    fn handle(&self) -> TableHandle {
+        TableHandle {
+            schema: self.schema.clone(),
+            batches: self.batches.clone(),
+            primary_key_index: self.primary_key_index.clone(),
+            indexes: self.indexes.clone(),
+            policy: None,
+        }
+    }
+
+    /// Append the new batches to the partitions they're routed to and index their rows
+    async fn append(&self, new_batches: Vec<RecordBatch>) -> Result<()> {
+        // Lock the indexes before the data, in the same order as readers, and validate unique
+        // indexes before anything is written. Secondary indexes can be updated while they're being
+        // read, so they are only locked to keep them from being rebuilt or dropped.
+        let indexes = self.indexes.read().await;
+        let mut primary_key_index = self.primary_key_index.write().await;
+        check_primary_keys(&primary_key_index, &[], &new_batches, &self.primary_key)?;
+        for index in indexes.values() {
+            index.check_unique(&new_batches)?;
+        }
+
+        // write the outputs into the batches
+        let new_batches = self.batches.route(new_batches)?;
+        for (partition_idx, (target, mut batches)) in
+            self.batches.get().iter().zip(new_batches).enumerate()
+        {
+            let mut target = target.write().await;
+            let first_batch_idx = target.len();
+            for (i, batch) in batches.iter().enumerate() {
+                index_primary_key(
+                    &mut primary_key_index,
+                    batch,
+                    &self.primary_key,
+                    partition_idx,
+                    first_batch_idx + i,
+                )?;
+            }
+            // Append all the new batches in one go to minimize locking overhead
+            let appended = batches.clone();
+            target.append(&mut batches);
+            drop(target);
+
+            // Index the rows only once they're in the table so readers never find missing rows
+            for (i, batch) in appended.iter().enumerate() {
+                for index in indexes.values() {
+                    index.insert_batch(batch, partition_idx, first_batch_idx + i)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -468,26 +1643,34 @@ impl DataSink for MemSink {
         mut data: SendableRecordBatchStream,
         _context: &Arc<TaskContext>,
     ) -> Result<u64> {
-        let num_partitions = self.batches.len();
-
-        // buffer up the data round robin style into num_partitions
-
-        let mut new_batches = vec![vec![]; num_partitions];
-        let mut i = 0;
+        // buffer up the data, which is routed to partitions on the writer
+        let mut new_batches = vec![];
         let mut row_count = 0;
         while let Some(batch) = data.next().await.transpose()? {
+            check_new_rows(self.policy.as_ref(), &batch)?;
             row_count += batch.num_rows();
-            new_batches[i].push(batch);
-            i = (i + 1) % num_partitions;
+            new_batches.push(batch);
         }
 
-        // write the outputs into the batches
-        for (target, mut batches) in self.batches.iter().zip(new_batches.into_iter()) {
-            // Append all the new batches in one go to minimize locking overhead
-            target.write().await.append(&mut batches);
-        }
-
-        Ok(row_count as u64)
+        let sink = self.clone();
+        let log = self.writer.is_logged();
+        let _lock = self.lock.lock_for_write().await;
+        self.writer
+            .submit(move |_| async move {
+                sink.retired.check(sink.id)?;
+                let table = sink.handle();
+                let saved = table.save().await;
+                let inserted: Vec<RecordBatch> = if log { new_batches.clone() } else { vec![] };
+                sink.append(new_batches).await?;
+                let log = if inserted.is_empty() {
+                    vec![]
+                } else {
+                    vec![LogRecord::change(sink.id, &sink.schema, &[], &inserted)?]
+                };
+                Ok(Applied::new(row_count as u64, log)
+                    .with_rollback(|| async move { table.restore(saved).await }))
+            })
+            .await
     }
 }
 
@@ -501,7 +1684,7 @@ mod tests {
     use datafusion::physical_plan::collect;
     use datafusion::prelude::SessionContext;
     use datafusion_common::Column;
-    use datafusion_expr::LogicalPlanBuilder;
+    use datafusion_expr::{BinaryExpr, LogicalPlanBuilder};
     use futures::StreamExt;
     use std::collections::HashMap;
 
@@ -593,8 +1776,14 @@ mod tests {
         let exec = provider
             .scan(&session_ctx.state(), Some(&vec![2, 1]), &[filter], None)
             .await?;
-
-        let mut it = exec.execute(0, task_ctx)?;
+        let index_scan = exec
+            .as_any()
+            .downcast_ref::<MemTableScanExec>()
+            .and_then(MemTableScanExec::index_scan)
+            .expect("scan should offer a primary key lookup");
+        assert_eq!(1, index_scan.estimated_rows());
+
+        let mut it = index_scan.execute(0, task_ctx)?;
         let batch2 = it.next().await.unwrap()?;
         assert_eq!(2, batch2.schema().fields().len());
         assert_eq!("c", batch2.schema().field(0).name());
@@ -853,7 +2042,7 @@ mod tests {
 
         // Read the data from the initial table and store it in a vector of partitions
         let mut partitions = vec![];
-        for partition in initial_table.batches.iter() {
+        for partition in initial_table.batches.get().iter() {
             let part = partition.read().await.clone();
             partitions.push(part);
         }
@@ -911,7 +2100,7 @@ mod tests {
         let resulting_data_in_table = experiment(
             schema.clone(),
             vec![vec![build_test_batch(schema.clone(), 1)]],
-            vec![vec![build_test_batch(schema.clone(), 2)]],
+            vec![vec![build_test_batch(schema.clone(), 4)]],
         )
         .await?;
         // Ensure that the table now contains two batches of data in the same partition
@@ -992,7 +2181,7 @@ mod tests {
         ));
 
         // Create a new batch of data to insert into the table
-        let batch = RecordBatch::try_new(
+        let _batch = RecordBatch::try_new(
             schema.clone(),
             vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
         )?;
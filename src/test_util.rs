@@ -0,0 +1,19 @@
+//! Helpers shared by the tests of several modules
+
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty::pretty_format_batches;
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+
+use crate::sql::{execute_logical_plan, sql_to_plan};
+
+/// Plan and execute `sql` in `ctx`, collecting its results
+pub async fn run(ctx: &SessionContext, sql: &str) -> Result<Vec<RecordBatch>> {
+    let plan = sql_to_plan(&ctx.state(), sql).await?;
+    execute_logical_plan(ctx, plan).await?.collect().await
+}
+
+/// The results of `sql` as a table
+pub async fn query(ctx: &SessionContext, sql: &str) -> Result<String> {
+    Ok(pretty_format_batches(&run(ctx, sql).await?)?.to_string())
+}
@@ -0,0 +1,585 @@
+//! Inverted indexes for full-text search over the text columns of a
+//! [`MemTable`](crate::table_provider::MemTable).
+//!
+//! A [`TextIndex`] maps every term its [`Analyzer`] finds in its columns to a posting list of the
+//! rows containing the term, with the positions of the term in the row's value that phrase
+//! queries need (see [`crate::text_query`]). It also keeps the number of indexed values and
+//! their total length per column, which together with the document frequency of a term are the
+//! statistics [BM25](https://en.wikipedia.org/wiki/Okapi_BM25) needs to rank rows.
+//! Like the ordered indexes, text indexes add the rows a write inserts and remove the rows it
+//! deletes, an update being both.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion_common::{exec_err, plan_err, DataFusionError};
+use parking_lot::RwLock;
+
+use crate::analysis::Analyzer;
+use crate::fuzzy::fuzzy_terms;
+use crate::table_provider::TupletOffset;
+use crate::text_query::{phrase_matches, Occur, TextQuery};
+
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 length normalization
+const B: f64 = 0.75;
+
+/// Whether a text column can be indexed for full-text search
+pub fn is_text_type(data_type: &DataType) -> bool {
+    matches!(data_type, DataType::Utf8 | DataType::LargeUtf8)
+}
+
+/// The values of a text column as strings
+pub fn text_values(array: &ArrayRef) -> Result<Vec<Option<&str>>> {
+    Ok(match array.data_type() {
+        DataType::Utf8 => array.as_string::<i32>().iter().collect(),
+        DataType::LargeUtf8 => array.as_string::<i64>().iter().collect(),
+        data_type => return exec_err!("Can't search {data_type} values as text"),
+    })
+}
+
+/// A row containing a term, with the positions of the term in the row's value
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Posting {
+    offset: TupletOffset,
+    /// Position of the column in the index
+    field: usize,
+    positions: Vec<u32>,
+}
+
+/// The statistics BM25 uses to score the rows matching a query
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bm25Stats {
+    /// Number of non-null values
+    pub docs: usize,
+    /// Total number of terms in the values
+    pub total_len: usize,
+    /// Number of values containing each query term
+    pub doc_freqs: HashMap<String, usize>,
+}
+
+impl Bm25Stats {
+    /// Empty statistics for a query with `terms`
+    pub fn new(terms: &[String]) -> Self {
+        Self {
+            docs: 0,
+            total_len: 0,
+            doc_freqs: terms.iter().map(|term| (term.clone(), 0)).collect(),
+        }
+    }
+
+    /// Count a value whose terms are `terms`
+    pub fn add_document(&mut self, terms: &[String]) {
+        self.docs += 1;
+        self.total_len += terms.len();
+        for (term, doc_freq) in self.doc_freqs.iter_mut() {
+            if terms.contains(term) {
+                *doc_freq += 1;
+            }
+        }
+    }
+
+    /// BM25 score of a value whose terms are `terms` for the query terms
+    pub fn score(&self, terms: &[String]) -> f64 {
+        self.doc_freqs
+            .keys()
+            .map(|term| {
+                let tf = terms.iter().filter(|t| *t == term).count();
+                self.term_score(term, tf, terms.len())
+            })
+            .sum()
+    }
+
+    /// BM25 score of a query term that occurs `tf` times in a value of `len` terms
+    pub fn term_score(&self, term: &str, tf: usize, len: usize) -> f64 {
+        let Some(doc_freq) = self.doc_freqs.get(term) else {
+            return 0.0;
+        };
+        if self.docs == 0 || tf == 0 {
+            return 0.0;
+        }
+        let avg_len = self.total_len as f64 / self.docs as f64;
+        let (tf, df) = (tf as f64, *doc_freq as f64);
+        let idf = (1.0 + (self.docs as f64 - df + 0.5) / (df + 0.5)).ln();
+        let norm = if avg_len > 0.0 {
+            1.0 - B + B * len as f64 / avg_len
+        } else {
+            1.0
+        };
+        idf * tf * (K1 + 1.0) / (tf + K1 * norm)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Postings {
+    /// Posting lists by term, each in insertion order
+    terms: BTreeMap<String, Vec<Posting>>,
+    /// Number of indexed values and their total number of terms, per column
+    fields: Vec<(usize, usize)>,
+    num_postings: usize,
+}
+
+impl Postings {
+    /// The postings of `term` in the column at `field` of the index
+    fn postings<'a>(&'a self, term: &str, field: usize) -> impl Iterator<Item = &'a Posting> {
+        self.terms
+            .get(term)
+            .into_iter()
+            .flatten()
+            .filter(move |posting| posting.field == field)
+    }
+
+    /// Number of rows with `term` in the column at `field` of the index
+    fn doc_freq(&self, term: &str, field: usize) -> usize {
+        self.postings(term, field).count()
+    }
+
+    /// Estimate of the number of rows matching `query`, from the document frequencies of its
+    /// terms rather than by matching the rows
+    fn estimate(&self, query: &TextQuery, fields: &[usize]) -> usize {
+        match query {
+            TextQuery::Term {
+                fields: searched,
+                term,
+                ..
+            } => searched
+                .iter()
+                .map(|field| self.doc_freq(term, fields[*field]))
+                .sum(),
+            TextQuery::Fuzzy {
+                fields: searched,
+                term,
+                distance,
+                ..
+            } => {
+                let terms = fuzzy_terms(&self.terms, term, *distance);
+                searched
+                    .iter()
+                    .flat_map(|field| {
+                        terms
+                            .iter()
+                            .map(move |(term, _)| self.doc_freq(term, fields[*field]))
+                    })
+                    .sum()
+            }
+            // A row can only match a phrase if it has its rarest term
+            TextQuery::Phrase {
+                fields: searched,
+                terms,
+                ..
+            } => searched
+                .iter()
+                .map(|field| {
+                    terms
+                        .iter()
+                        .map(|(_, term)| self.doc_freq(term, fields[*field]))
+                        .min()
+                        .unwrap_or(0)
+                })
+                .sum(),
+            TextQuery::Bool { clauses, .. } => {
+                let estimates = |occur: Occur| {
+                    clauses
+                        .iter()
+                        .filter(move |(o, _)| *o == occur)
+                        .map(|(_, clause)| self.estimate(clause, fields))
+                };
+                estimates(Occur::Must)
+                    .min()
+                    .unwrap_or_else(|| estimates(Occur::Should).sum())
+            }
+        }
+    }
+
+    /// The rows matching `query`, evaluated clause by clause on the posting lists
+    fn matching(&self, query: &TextQuery, fields: &[usize]) -> BTreeSet<TupletOffset> {
+        match query {
+            TextQuery::Term {
+                fields: searched,
+                term,
+                ..
+            } => searched
+                .iter()
+                .flat_map(|field| self.postings(term, fields[*field]))
+                .map(|posting| posting.offset)
+                .collect(),
+            TextQuery::Fuzzy {
+                fields: searched,
+                term,
+                distance,
+                ..
+            } => {
+                let terms = fuzzy_terms(&self.terms, term, *distance);
+                searched
+                    .iter()
+                    .flat_map(|field| {
+                        let field = fields[*field];
+                        terms
+                            .iter()
+                            .flat_map(move |(term, _)| self.postings(term, field))
+                    })
+                    .map(|posting| posting.offset)
+                    .collect()
+            }
+            TextQuery::Phrase {
+                fields: searched,
+                terms,
+                slop,
+                ..
+            } => {
+                let offsets: Vec<u32> = terms.iter().map(|(offset, _)| *offset).collect();
+                let mut rows = BTreeSet::new();
+                for field in searched.iter().map(|field| fields[*field]) {
+                    let lists: Vec<HashMap<TupletOffset, &[u32]>> = terms
+                        .iter()
+                        .map(|(_, term)| {
+                            self.postings(term, field)
+                                .map(|posting| (posting.offset, posting.positions.as_slice()))
+                                .collect()
+                        })
+                        .collect();
+                    let Some((first, rest)) = lists.split_first() else {
+                        continue;
+                    };
+                    for (offset, positions) in first {
+                        let positions: Option<Vec<&[u32]>> = std::iter::once(Some(*positions))
+                            .chain(rest.iter().map(|list| list.get(offset).copied()))
+                            .collect();
+                        if positions
+                            .is_some_and(|positions| phrase_matches(&offsets, &positions, *slop))
+                        {
+                            rows.insert(*offset);
+                        }
+                    }
+                }
+                rows
+            }
+            TextQuery::Bool { clauses, .. } => {
+                let mut required: Option<BTreeSet<TupletOffset>> = None;
+                let (mut optional, mut excluded) = (BTreeSet::new(), BTreeSet::new());
+                for (occur, clause) in clauses {
+                    let rows = self.matching(clause, fields);
+                    match occur {
+                        Occur::Must => {
+                            required = Some(match required {
+                                Some(required) => required.intersection(&rows).copied().collect(),
+                                None => rows,
+                            })
+                        }
+                        Occur::Should => optional.extend(rows),
+                        Occur::MustNot => excluded.extend(rows),
+                    }
+                }
+                let mut rows = required.unwrap_or(optional);
+                rows.retain(|row| !excluded.contains(row));
+                rows
+            }
+        }
+    }
+}
+
+/// Inverted index over one or more text columns
+pub struct TextIndex {
+    column_indices: Vec<usize>,
+    analyzer: Arc<Analyzer>,
+    /// Rows can be added while the index is read, like the ordered indexes
+    postings: RwLock<Postings>,
+}
+
+impl Debug for TextIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let postings = self.postings.read();
+        f.debug_struct("TextIndex")
+            .field("column_indices", &self.column_indices)
+            .field("analyzer", &self.analyzer.to_string())
+            .field("num_terms", &postings.terms.len())
+            .field("num_postings", &postings.num_postings)
+            .finish()
+    }
+}
+
+impl TextIndex {
+    /// Create an empty index over the columns of `schema` at `column_indices`, which must be
+    /// text columns, whose values `analyzer` splits into terms
+    pub fn try_new(
+        name: &str,
+        column_indices: Vec<usize>,
+        analyzer: Arc<Analyzer>,
+        schema: &SchemaRef,
+    ) -> Result<Self> {
+        for column_index in column_indices.iter() {
+            let field = schema.field(*column_index);
+            if !is_text_type(field.data_type()) {
+                return plan_err!(
+                    "Fulltext index {name} can't index column {} of type {}",
+                    field.name(),
+                    field.data_type()
+                );
+            }
+        }
+        let postings = Postings {
+            fields: vec![(0, 0); column_indices.len()],
+            ..Default::default()
+        };
+        Ok(Self {
+            column_indices,
+            analyzer,
+            postings: RwLock::new(postings),
+        })
+    }
+
+    /// Add the values of every row of `batch`, located at `batch_idx` in partition
+    /// `partition_idx`, to the index
+    pub fn insert_batch(
+        &self,
+        batch: &RecordBatch,
+        partition_idx: usize,
+        batch_idx: usize,
+    ) -> Result<()> {
+        let mut postings = self.postings.write();
+        for (field, column_index) in self.column_indices.iter().enumerate() {
+            let column = batch.column(*column_index);
+            for (value_idx, value) in text_values(column)?.into_iter().enumerate() {
+                let Some(value) = value else {
+                    continue;
+                };
+                let offset = (partition_idx as i32, batch_idx as i32, value_idx as i32);
+                let tokens = self.analyzer.tokens(value);
+                postings.fields[field].0 += 1;
+                postings.fields[field].1 += tokens.len();
+                let mut positions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+                for token in tokens {
+                    positions
+                        .entry(token.term)
+                        .or_default()
+                        .push(token.position);
+                }
+                postings.num_postings += positions.len();
+                for (term, positions) in positions {
+                    postings.terms.entry(term).or_default().push(Posting {
+                        offset,
+                        field,
+                        positions,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the values of every row of `batch`, located at `batch_idx` in partition
+    /// `partition_idx`, from the index
+    pub fn remove_batch(
+        &self,
+        batch: &RecordBatch,
+        partition_idx: usize,
+        batch_idx: usize,
+    ) -> Result<()> {
+        let mut postings = self.postings.write();
+        // The postings to remove by term, so each posting list is only filtered once
+        let mut removed: HashMap<String, HashSet<(TupletOffset, usize)>> = HashMap::new();
+        for (field, column_index) in self.column_indices.iter().enumerate() {
+            let column = batch.column(*column_index);
+            for (value_idx, value) in text_values(column)?.into_iter().enumerate() {
+                let Some(value) = value else {
+                    continue;
+                };
+                let offset = (partition_idx as i32, batch_idx as i32, value_idx as i32);
+                let tokens = self.analyzer.tokens(value);
+                postings.fields[field].0 -= 1;
+                postings.fields[field].1 -= tokens.len();
+                for token in tokens {
+                    removed
+                        .entry(token.term)
+                        .or_default()
+                        .insert((offset, field));
+                }
+            }
+        }
+        for (term, offsets) in removed {
+            let Some(list) = postings.terms.get_mut(&term) else {
+                continue;
+            };
+            let count = list.len();
+            list.retain(|posting| !offsets.contains(&(posting.offset, posting.field)));
+            let remaining = list.len();
+            if remaining == 0 {
+                postings.terms.remove(&term);
+            }
+            postings.num_postings -= count - remaining;
+        }
+        Ok(())
+    }
+
+    /// The analyzer that splits values and queries into terms
+    pub fn analyzer(&self) -> &Arc<Analyzer> {
+        &self.analyzer
+    }
+
+    /// Position in the index of the column at `column_index`, if it is indexed
+    pub fn field(&self, column_index: usize) -> Option<usize> {
+        self.column_indices.iter().position(|c| *c == column_index)
+    }
+
+    /// Tuple offsets of the rows matching `query`, in offset order. The query refers to the
+    /// columns it searches by position, and `fields` has their positions in the index.
+    pub fn search(&self, query: &TextQuery, fields: &[usize]) -> Vec<TupletOffset> {
+        self.postings
+            .read()
+            .matching(query, fields)
+            .into_iter()
+            .collect()
+    }
+
+    /// Estimate of the number of rows [`search`](Self::search) finds for `query`, from the
+    /// document frequencies of its terms
+    pub fn estimate_rows(&self, query: &TextQuery, fields: &[usize]) -> usize {
+        self.postings.read().estimate(query, fields)
+    }
+
+    /// The terms of the index at most `distance` edits away from `term`, with their distances
+    pub fn fuzzy_terms(&self, term: &str, distance: u32) -> Vec<(String, u32)> {
+        fuzzy_terms(&self.postings.read().terms, term, distance)
+            .into_iter()
+            .map(|(term, distance)| (term.to_string(), distance))
+            .collect()
+    }
+
+    /// BM25 statistics of `field` for a query with `terms`
+    pub fn stats(&self, field: usize, terms: &[String]) -> Bm25Stats {
+        let postings = self.postings.read();
+        let (docs, total_len) = postings.fields.get(field).copied().unwrap_or_default();
+        let doc_freqs = terms
+            .iter()
+            .map(|term| {
+                let doc_freq = postings.terms.get(term).map_or(0, |list| {
+                    list.iter().filter(|posting| posting.field == field).count()
+                });
+                (term.clone(), doc_freq)
+            })
+            .collect();
+        Bm25Stats {
+            docs,
+            total_len,
+            doc_freqs,
+        }
+    }
+
+    /// Number of distinct terms in the index
+    pub fn num_terms(&self) -> usize {
+        self.postings.read().terms.len()
+    }
+
+    /// Number of (term, column, row) entries in the index
+    pub fn num_postings(&self) -> usize {
+        self.postings.read().num_postings
+    }
+
+    /// Approximate bytes of memory used by the index
+    pub fn memory_usage(&self) -> usize {
+        let postings = self.postings.read();
+        postings
+            .terms
+            .iter()
+            .map(|(term, list)| {
+                term.len()
+                    + list
+                        .iter()
+                        .map(|posting| {
+                            std::mem::size_of::<Posting>()
+                                + posting.positions.len() * std::mem::size_of::<u32>()
+                        })
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_query::parse;
+    use arrow::array::{Int32Array, StringArray};
+    use arrow::datatypes::{Field, Schema};
+    use std::sync::Arc;
+
+    #[test]
+    fn search_and_score() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("title", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3, 4])),
+                Arc::new(StringArray::from(vec![
+                    Some("Rust in action"),
+                    Some("Programming Rust, rust everywhere"),
+                    None,
+                    Some("Python crash course"),
+                ])),
+            ],
+        )?;
+        let analyzer = Analyzer::standard();
+        assert!(TextIndex::try_new("ids", vec![0], analyzer.clone(), &schema).is_err());
+        let index = TextIndex::try_new("titles", vec![1], analyzer.clone(), &schema)?;
+        index.insert_batch(&batch, 0, 0)?;
+        assert_eq!(8, index.num_terms());
+        assert_eq!(9, index.num_postings());
+
+        let search = |query: &str| -> Result<Vec<TupletOffset>> {
+            let query = parse(query)?.analyze(&analyzer, &["title".to_string()])?;
+            Ok(index.search(&query, &[0]))
+        };
+        assert_eq!(
+            vec![(0, 0, 0), (0, 0, 1), (0, 0, 3)],
+            search("rust python")?
+        );
+        assert!(search("java")?.is_empty());
+        assert_eq!(vec![(0, 0, 1)], search("\"programming rust\"")?);
+        assert!(search("\"rust programming\"")?.is_empty());
+        assert_eq!(vec![(0, 0, 1)], search("\"rust programming\"~2")?);
+        assert_eq!(vec![(0, 0, 0)], search("+rust -everywhere")?);
+        assert_eq!(vec![(0, 0, 3)], search("title:(crash AND course)")?);
+        assert_eq!(vec![(0, 0, 0), (0, 0, 1)], search("rusty~")?);
+        assert_eq!(vec![(0, 0, 3)], search("pyton~ -rust")?);
+
+        let estimate = |query: &str| -> Result<usize> {
+            let query = parse(query)?.analyze(&analyzer, &["title".to_string()])?;
+            Ok(index.estimate_rows(&query, &[0]))
+        };
+        assert_eq!(3, estimate("rust python")?);
+        assert_eq!(0, estimate("java")?);
+        assert_eq!(1, estimate("\"programming rust\"")?);
+        assert_eq!(1, estimate("title:(crash AND course)")?);
+        assert_eq!(2, estimate("rusty~")?);
+        assert_eq!(
+            vec![("python".to_string(), 1)],
+            index.fuzzy_terms("pyton", 1)
+        );
+
+        let terms = analyzer.query_terms("rust python");
+
+        let stats = index.stats(0, &terms);
+        assert_eq!(3, stats.docs);
+        assert_eq!(10, stats.total_len);
+        assert_eq!(Some(&2), stats.doc_freqs.get("rust"));
+        assert_eq!(Some(&1), stats.doc_freqs.get("python"));
+
+        // Rarer terms and more occurrences score higher
+        let rust_in_action = stats.score(&analyzer.terms("Rust in action"));
+        let programming_rust = stats.score(&analyzer.terms("Programming Rust, rust everywhere"));
+        let python = stats.score(&analyzer.terms("Python crash course"));
+        assert!(programming_rust > rust_in_action);
+        assert!(python > rust_in_action);
+        assert_eq!(0.0, stats.score(&analyzer.terms("nothing here")));
+        Ok(())
+    }
+}
@@ -0,0 +1,943 @@
+//! The query syntax of `match()`.
+//!
+//! A query is a list of clauses, and rows match it if they match any of them:
+//!
+//! - `shoes` matches the rows containing the term, and `"running shoes"` the rows containing the
+//!   terms of the phrase next to each other and in order. `"red dress"~3` lets the terms of a
+//!   phrase be up to 3 positions away from where the phrase has them.
+//! - `addidas~` matches the terms a few edits away from `addidas` as well, more the longer the
+//!   term is, and `addidas~1` the terms at most one edit away (see [`crate::fuzzy`]).
+//! - `+shoes` is required and `-kids` or `NOT kids` excluded.
+//! - `a AND b` requires both sides and `a OR b` either. `AND` binds tighter than `OR`, and clauses
+//!   next to each other are or-ed like with `OR`.
+//! - `brand:nike` searches only the column `brand` of the columns of the `match()`, and the
+//!   other clauses search all of them. `brand:(nike OR adidas)` applies to a group.
+//! - `^2` after a term, phrase or group multiplies its score by 2, so `title:shoes^2 shoes`
+//!   ranks the rows with shoes in the title higher.
+//! - Parentheses group clauses, and `\` escapes the character after it.
+//!
+//! [`parse`] checks the syntax of a query and gives a [`QueryNode`], and
+//! [`QueryNode::analyze`] splits its words into terms with the analyzer of the columns searched.
+//! The resulting [`TextQuery`] is evaluated on the values of a row, or on the posting lists of
+//! a fulltext index by [`TextIndex::search`](crate::text_index::TextIndex::search).
+
+use std::collections::HashMap;
+use std::fmt;
+
+use datafusion_common::{plan_err, DataFusionError, Result};
+
+use crate::analysis::Analyzer;
+use crate::fuzzy::{fuzzy_weight, levenshtein, Fuzziness, MAX_EDITS};
+use crate::text_index::Bm25Stats;
+
+/// How a clause of a group restricts the rows matching the group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occur {
+    /// Rows must match the clause
+    Must,
+    /// Rows match the group if they match any of these clauses, unless it has required ones
+    Should,
+    /// Rows must not match the clause
+    MustNot,
+}
+
+/// A parsed query, whose words aren't split into terms yet
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryNode {
+    Word {
+        field: Option<String>,
+        word: String,
+        fuzziness: Option<Fuzziness>,
+        boost: f64,
+    },
+    Phrase {
+        field: Option<String>,
+        text: String,
+        slop: u32,
+        boost: f64,
+    },
+    Group {
+        clauses: Vec<(Occur, QueryNode)>,
+        boost: f64,
+    },
+}
+
+impl QueryNode {
+    /// Split the words of the query into terms with `analyzer`. `fields` are the names of the
+    /// columns searched, which the query refers to by position.
+    pub fn analyze(&self, analyzer: &Analyzer, fields: &[String]) -> Result<TextQuery> {
+        Ok(match self {
+            QueryNode::Word {
+                field,
+                word,
+                fuzziness,
+                boost,
+            } => {
+                let fields = resolve_field(field.as_deref(), fields)?;
+                let term_query = |term: String, boost: f64| {
+                    let fields = fields.clone();
+                    match fuzziness.map(|fuzziness| fuzziness.distance(&term)) {
+                        Some(distance) if distance > 0 => TextQuery::Fuzzy {
+                            fields,
+                            term,
+                            distance,
+                            boost,
+                        },
+                        _ => TextQuery::Term {
+                            fields,
+                            term,
+                            boost,
+                        },
+                    }
+                };
+                let mut terms = analyzer.query_terms(word);
+                if terms.len() == 1 {
+                    term_query(terms.remove(0), *boost)
+                } else {
+                    // Analyzers can split a word in several terms, such as n-grams
+                    let clauses = terms
+                        .into_iter()
+                        .map(|term| (Occur::Should, term_query(term, 1.0)))
+                        .collect();
+                    TextQuery::Bool {
+                        clauses,
+                        boost: *boost,
+                    }
+                }
+            }
+            QueryNode::Phrase {
+                field,
+                text,
+                slop,
+                boost,
+            } => {
+                let fields = resolve_field(field.as_deref(), fields)?;
+                let tokens = analyzer.tokens(text);
+                let first = tokens.iter().map(|token| token.position).min();
+                let mut terms: Vec<(u32, String)> = tokens
+                    .into_iter()
+                    .map(|token| (token.position - first.unwrap_or(0), token.term))
+                    .collect();
+                match terms.len() {
+                    0 => TextQuery::Bool {
+                        clauses: vec![],
+                        boost: *boost,
+                    },
+                    1 => TextQuery::Term {
+                        fields,
+                        term: terms.remove(0).1,
+                        boost: *boost,
+                    },
+                    _ => TextQuery::Phrase {
+                        fields,
+                        terms,
+                        slop: *slop,
+                        boost: *boost,
+                    },
+                }
+            }
+            QueryNode::Group { clauses, boost } => {
+                let mut analyzed = Vec::with_capacity(clauses.len());
+                for (occur, clause) in clauses {
+                    let clause = clause.analyze(analyzer, fields)?;
+                    // Clauses of stop words only are left out rather than matching nothing
+                    if !clause.is_empty() {
+                        analyzed.push((*occur, clause));
+                    }
+                }
+                TextQuery::Bool {
+                    clauses: analyzed,
+                    boost: *boost,
+                }
+            }
+        })
+    }
+
+    /// Fail if a group only excludes rows, which can't be searched for
+    fn check_exclusions(&self, query: &str) -> Result<()> {
+        let QueryNode::Group { clauses, .. } = self else {
+            return Ok(());
+        };
+        if !clauses.is_empty() && clauses.iter().all(|(occur, _)| *occur == Occur::MustNot) {
+            return plan_err!(
+                "Text query {query:?} only excludes terms, it needs a term to search for"
+            );
+        }
+        for (_, clause) in clauses {
+            clause.check_exclusions(query)?;
+        }
+        Ok(())
+    }
+}
+
+/// The positions among `fields` of the columns a clause on `field` searches, all of them if it
+/// has no field
+fn resolve_field(field: Option<&str>, fields: &[String]) -> Result<Vec<usize>> {
+    let Some(field) = field else {
+        return Ok((0..fields.len()).collect());
+    };
+    match fields.iter().position(|f| f.eq_ignore_ascii_case(field)) {
+        Some(position) => Ok(vec![position]),
+        None => plan_err!(
+            "Column {field} of the text query isn't one of the columns searched: {}",
+            fields.join(", ")
+        ),
+    }
+}
+
+/// A query whose words are split into terms, ready to search rows or an index with
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextQuery {
+    /// Rows containing `term` in any of `fields`, the positions of columns among those searched
+    Term {
+        fields: Vec<usize>,
+        term: String,
+        boost: f64,
+    },
+    /// Rows containing a term at most `distance` edits away from `term` in any of `fields`
+    Fuzzy {
+        fields: Vec<usize>,
+        term: String,
+        distance: u32,
+        boost: f64,
+    },
+    /// Rows containing `terms` at their offsets from each other in any of `fields`, give or take
+    /// `slop` positions
+    Phrase {
+        fields: Vec<usize>,
+        terms: Vec<(u32, String)>,
+        slop: u32,
+        boost: f64,
+    },
+    /// Rows matching the clauses as their [`Occur`] says. Without clauses it matches no rows.
+    Bool {
+        clauses: Vec<(Occur, TextQuery)>,
+        boost: f64,
+    },
+}
+
+impl TextQuery {
+    /// Whether the query matches no rows because it has no terms
+    pub fn is_empty(&self) -> bool {
+        matches!(self, TextQuery::Bool { clauses, .. } if clauses.is_empty())
+    }
+
+    /// The distinct terms of the query, in the order they first appear
+    pub fn terms(&self) -> Vec<String> {
+        let mut terms = vec![];
+        self.collect_terms(&mut terms);
+        terms
+    }
+
+    fn collect_terms(&self, terms: &mut Vec<String>) {
+        let mut add = |term: &String| {
+            if !terms.contains(term) {
+                terms.push(term.clone());
+            }
+        };
+        match self {
+            TextQuery::Term { term, .. } | TextQuery::Fuzzy { term, .. } => add(term),
+            TextQuery::Phrase { terms: phrase, .. } => phrase.iter().for_each(|(_, t)| add(t)),
+            TextQuery::Bool { clauses, .. } => {
+                for (_, clause) in clauses {
+                    clause.collect_terms(terms);
+                }
+            }
+        }
+    }
+
+    /// The fuzzy terms of the query and the most edits away from them the terms they match can be
+    pub fn fuzzy_terms(&self) -> Vec<(String, u32)> {
+        match self {
+            TextQuery::Fuzzy { term, distance, .. } => vec![(term.clone(), *distance)],
+            TextQuery::Term { .. } | TextQuery::Phrase { .. } => vec![],
+            TextQuery::Bool { clauses, .. } => clauses
+                .iter()
+                .flat_map(|(_, clause)| clause.fuzzy_terms())
+                .collect(),
+        }
+    }
+
+    /// Whether a row whose searched columns have the values `values` matches the query
+    pub fn matches(&self, values: &[Document]) -> bool {
+        match self {
+            TextQuery::Term { fields, term, .. } => fields
+                .iter()
+                .any(|field| values[*field].positions.contains_key(term)),
+            TextQuery::Fuzzy {
+                fields,
+                term,
+                distance,
+                ..
+            } => fields.iter().any(|field| {
+                values[*field]
+                    .fuzzy_matches(term, *distance)
+                    .next()
+                    .is_some()
+            }),
+            TextQuery::Phrase {
+                fields,
+                terms,
+                slop,
+                ..
+            } => fields
+                .iter()
+                .any(|field| values[*field].contains_phrase(terms, *slop)),
+            TextQuery::Bool { clauses, .. } => {
+                let (mut required, mut optional) = (false, false);
+                for (occur, clause) in clauses {
+                    let matched = clause.matches(values);
+                    match occur {
+                        Occur::Must if !matched => return false,
+                        Occur::MustNot if matched => return false,
+                        Occur::Must => required = true,
+                        Occur::Should => optional |= matched,
+                        Occur::MustNot => {}
+                    }
+                }
+                required || optional
+            }
+        }
+    }
+
+    /// BM25 score of a row whose searched columns have the values `values`, given the
+    /// statistics of each column. A fuzzy term scores like the best of the terms it matches,
+    /// weighted down by their distance, a phrase scores like its terms where it matches, and a
+    /// group is the sum of the clauses the row matches.
+    pub fn score(&self, values: &[Document], stats: &[Bm25Stats]) -> f64 {
+        let term_score = |field: usize, term: &String| {
+            let value = &values[field];
+            let tf = value
+                .positions
+                .get(term)
+                .map_or(0, |positions| positions.len());
+            stats[field].term_score(term, tf, value.len)
+        };
+        match self {
+            TextQuery::Term {
+                fields,
+                term,
+                boost,
+            } => {
+                let score: f64 = fields.iter().map(|field| term_score(*field, term)).sum();
+                score * boost
+            }
+            TextQuery::Fuzzy {
+                fields,
+                term,
+                distance,
+                boost,
+            } => {
+                let score: f64 = fields
+                    .iter()
+                    .map(|field| {
+                        values[*field]
+                            .fuzzy_matches(term, *distance)
+                            .map(|(matched, distance)| {
+                                term_score(*field, matched) * fuzzy_weight(distance)
+                            })
+                            .fold(0.0, f64::max)
+                    })
+                    .sum();
+                score * boost
+            }
+            TextQuery::Phrase {
+                fields,
+                terms,
+                slop,
+                boost,
+            } => {
+                let score: f64 = fields
+                    .iter()
+                    .filter(|field| values[**field].contains_phrase(terms, *slop))
+                    .flat_map(|field| terms.iter().map(|(_, term)| term_score(*field, term)))
+                    .sum();
+                score * boost
+            }
+            TextQuery::Bool { clauses, boost } => {
+                let score: f64 = clauses
+                    .iter()
+                    .filter(|(occur, clause)| *occur != Occur::MustNot && clause.matches(values))
+                    .map(|(_, clause)| clause.score(values, stats))
+                    .sum();
+                score * boost
+            }
+        }
+    }
+}
+
+/// The terms of a value with their positions, to evaluate a [`TextQuery`] on
+#[derive(Debug, Default)]
+pub struct Document {
+    positions: HashMap<String, Vec<u32>>,
+    /// Number of terms in the value
+    len: usize,
+}
+
+impl Document {
+    /// The terms `analyzer` splits `value` into, none if it is null
+    pub fn new(analyzer: &Analyzer, value: Option<&str>) -> Self {
+        let mut document = Document::default();
+        for token in value
+            .map(|value| analyzer.tokens(value))
+            .unwrap_or_default()
+        {
+            document.len += 1;
+            document
+                .positions
+                .entry(token.term)
+                .or_default()
+                .push(token.position);
+        }
+        document
+    }
+
+    /// The terms of the value at most `distance` edits away from `term`, with their distances
+    fn fuzzy_matches<'a>(
+        &'a self,
+        term: &'a str,
+        distance: u32,
+    ) -> impl Iterator<Item = (&'a String, u32)> + 'a {
+        self.positions.keys().filter_map(move |value_term| {
+            Some((value_term, levenshtein(value_term, term, distance)?))
+        })
+    }
+
+    fn contains_phrase(&self, terms: &[(u32, String)], slop: u32) -> bool {
+        let positions: Option<Vec<&[u32]>> = terms
+            .iter()
+            .map(|(_, term)| self.positions.get(term).map(Vec::as_slice))
+            .collect();
+        let offsets: Vec<u32> = terms.iter().map(|(offset, _)| *offset).collect();
+        positions.is_some_and(|positions| phrase_matches(&offsets, &positions, slop))
+    }
+}
+
+/// Whether terms that are at `offsets` from each other in a phrase occur at `positions` in a
+/// value, each term at most `slop` positions away from where the others put it. Finds the
+/// smallest range containing a position of every term, once shifted back by its offset.
+pub fn phrase_matches(offsets: &[u32], positions: &[&[u32]], slop: u32) -> bool {
+    let shifted: Vec<Vec<i64>> = offsets
+        .iter()
+        .zip(positions)
+        .map(|(offset, positions)| {
+            let mut shifted: Vec<i64> = positions
+                .iter()
+                .map(|position| *position as i64 - *offset as i64)
+                .collect();
+            shifted.sort_unstable();
+            shifted
+        })
+        .collect();
+    let mut next = vec![0; shifted.len()];
+    loop {
+        let (mut min, mut min_term, mut max) = (i64::MAX, 0, i64::MIN);
+        for (term, positions) in shifted.iter().enumerate() {
+            let Some(&position) = positions.get(next[term]) else {
+                return false;
+            };
+            if position < min {
+                (min, min_term) = (position, term);
+            }
+            max = max.max(position);
+        }
+        if max - min <= slop as i64 {
+            return true;
+        }
+        next[min_term] += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Word(String),
+    /// A column name followed by `:`
+    Field(String),
+    Phrase(String),
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    And,
+    Or,
+    Not,
+    /// `~`, with the number after it if there is one, for the slop of a phrase or the distance of
+    /// a fuzzy term
+    Tilde(Option<u32>),
+    /// `^` and the number after it
+    Boost(f64),
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lexeme::Word(word) => write!(f, "{word}"),
+            Lexeme::Field(field) => write!(f, "{field}:"),
+            Lexeme::Phrase(text) => write!(f, "\"{text}\""),
+            Lexeme::LParen => write!(f, "("),
+            Lexeme::RParen => write!(f, ")"),
+            Lexeme::Plus => write!(f, "+"),
+            Lexeme::Minus => write!(f, "-"),
+            Lexeme::And => write!(f, "AND"),
+            Lexeme::Or => write!(f, "OR"),
+            Lexeme::Not => write!(f, "NOT"),
+            Lexeme::Tilde(_) => write!(f, "~"),
+            Lexeme::Boost(_) => write!(f, "^"),
+        }
+    }
+}
+
+/// A syntax error at character `position` of a query, counting from 1
+fn syntax_error<T>(position: usize, message: impl fmt::Display) -> Result<T> {
+    plan_err!("Syntax error in text query at character {position}: {message}")
+}
+
+/// Split `query` into lexemes and the positions they start at
+fn lex(query: &str) -> Result<Vec<(usize, Lexeme)>> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut lexemes = vec![];
+    let mut i = 0;
+    while let Some(&c) = chars.get(i) {
+        let position = i + 1;
+        i += 1;
+        let lexeme = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Lexeme::LParen,
+            ')' => Lexeme::RParen,
+            '+' => Lexeme::Plus,
+            '-' => Lexeme::Minus,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => return syntax_error(position, "the phrase has no closing quote"),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            text.push(chars[i + 1]);
+                            i += 1;
+                        }
+                        Some(c) => text.push(*c),
+                    }
+                    i += 1;
+                }
+                i += 1;
+                Lexeme::Phrase(text)
+            }
+            '^' => {
+                let start = i;
+                while chars
+                    .get(i)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+                {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                match number.parse::<f64>() {
+                    Ok(boost) if boost > 0.0 && boost.is_finite() => Lexeme::Boost(boost),
+                    _ => return syntax_error(position, "^ must be followed by a positive number"),
+                }
+            }
+            '~' => {
+                let start = i;
+                while chars.get(i).is_some_and(char::is_ascii_digit) {
+                    i += 1;
+                }
+                if start == i {
+                    Lexeme::Tilde(None)
+                } else {
+                    let number: String = chars[start..i].iter().collect();
+                    match number.parse() {
+                        Ok(slop) => Lexeme::Tilde(Some(slop)),
+                        Err(_) => return syntax_error(position, format!("{number} is too large")),
+                    }
+                }
+            }
+            ':' => return syntax_error(position, ": must follow the name of a column"),
+            c => {
+                let (mut word, mut escaped) = (String::new(), false);
+                let mut c = Some(c);
+                while let Some(next) = c {
+                    if next == '\\' {
+                        escaped = true;
+                        word.extend(chars.get(i));
+                        i += 1;
+                    } else {
+                        word.push(next);
+                    }
+                    c = chars
+                        .get(i)
+                        .filter(|c| !c.is_whitespace() && !"()\":^~".contains(**c))
+                        .copied();
+                    if c.is_some() {
+                        i += 1;
+                    }
+                }
+                if chars.get(i) == Some(&':') {
+                    i += 1;
+                    Lexeme::Field(word)
+                } else if escaped {
+                    Lexeme::Word(word)
+                } else {
+                    match word.as_str() {
+                        "AND" => Lexeme::And,
+                        "OR" => Lexeme::Or,
+                        "NOT" => Lexeme::Not,
+                        _ => Lexeme::Word(word),
+                    }
+                }
+            }
+        };
+        lexemes.push((position, lexeme));
+    }
+    Ok(lexemes)
+}
+
+struct QueryParser {
+    lexemes: Vec<(usize, Lexeme)>,
+    next: usize,
+    /// Position just past the end of the query
+    end: usize,
+}
+
+impl QueryParser {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.next).map(|(_, lexeme)| lexeme)
+    }
+
+    fn position(&self) -> usize {
+        self.lexemes
+            .get(self.next)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn advance(&mut self) -> Option<Lexeme> {
+        let lexeme = self.peek().cloned();
+        self.next += 1;
+        lexeme
+    }
+
+    /// Fail on the next lexeme, which should have started a clause
+    fn expected_clause<T>(&self) -> Result<T> {
+        let found = match self.peek() {
+            Some(lexeme) => lexeme.to_string(),
+            None => "the end of the query".to_string(),
+        };
+        let after = match self.next.checked_sub(1).and_then(|i| self.lexemes.get(i)) {
+            Some((_, lexeme)) => format!(" after {lexeme}"),
+            None => String::new(),
+        };
+        syntax_error(
+            self.position(),
+            format!("expected a term, phrase or group{after} but found {found}"),
+        )
+    }
+
+    /// The clauses up to the end of the query or of the group
+    fn clauses(&mut self, field: Option<&str>) -> Result<Vec<(Occur, QueryNode)>> {
+        let mut clauses = vec![];
+        while !matches!(self.peek(), None | Some(Lexeme::RParen)) {
+            clauses.push(self.conjunction(field)?);
+            if self.peek() == Some(&Lexeme::Or) {
+                self.advance();
+                if matches!(self.peek(), None | Some(Lexeme::RParen)) {
+                    return self.expected_clause();
+                }
+            }
+        }
+        Ok(clauses)
+    }
+
+    /// A clause, or clauses joined by `AND` as a group that requires them all
+    fn conjunction(&mut self, field: Option<&str>) -> Result<(Occur, QueryNode)> {
+        let first = self.unary(field)?;
+        if self.peek() != Some(&Lexeme::And) {
+            return Ok(first);
+        }
+        let required = |(occur, clause)| match occur {
+            Occur::MustNot => (Occur::MustNot, clause),
+            _ => (Occur::Must, clause),
+        };
+        let mut clauses = vec![required(first)];
+        while self.peek() == Some(&Lexeme::And) {
+            self.advance();
+            clauses.push(required(self.unary(field)?));
+        }
+        let boost = 1.0;
+        Ok((Occur::Should, QueryNode::Group { clauses, boost }))
+    }
+
+    fn unary(&mut self, field: Option<&str>) -> Result<(Occur, QueryNode)> {
+        let occur = match self.peek() {
+            Some(Lexeme::Plus) => Occur::Must,
+            Some(Lexeme::Minus | Lexeme::Not) => Occur::MustNot,
+            _ => return Ok((Occur::Should, self.primary(field)?)),
+        };
+        self.advance();
+        Ok((occur, self.primary(field)?))
+    }
+
+    /// A term, phrase or group, with its field and boost
+    fn primary(&mut self, field: Option<&str>) -> Result<QueryNode> {
+        let position = self.position();
+        let mut node = match self.peek().cloned() {
+            Some(Lexeme::Field(name)) => {
+                self.advance();
+                if matches!(self.peek(), Some(Lexeme::Field(_))) {
+                    return self.expected_clause();
+                }
+                return self.primary(Some(&name));
+            }
+            Some(Lexeme::Word(word)) => {
+                self.advance();
+                let fuzziness = match self.peek() {
+                    Some(Lexeme::Tilde(None)) => Some(Fuzziness::Auto),
+                    Some(Lexeme::Tilde(Some(edits))) if *edits <= MAX_EDITS => {
+                        Some(Fuzziness::Edits(*edits))
+                    }
+                    Some(Lexeme::Tilde(Some(_))) => {
+                        return syntax_error(
+                            self.position(),
+                            format!("a fuzzy term can be at most {MAX_EDITS} edits away"),
+                        )
+                    }
+                    _ => None,
+                };
+                if fuzziness.is_some() {
+                    self.advance();
+                }
+                QueryNode::Word {
+                    field: field.map(str::to_string),
+                    word,
+                    fuzziness,
+                    boost: 1.0,
+                }
+            }
+            Some(Lexeme::Phrase(text)) => {
+                self.advance();
+                let slop = match self.peek() {
+                    Some(Lexeme::Tilde(Some(slop))) => *slop,
+                    Some(Lexeme::Tilde(None)) => {
+                        return syntax_error(
+                            self.position(),
+                            "~ must be followed by the number of positions the terms can move",
+                        )
+                    }
+                    _ => 0,
+                };
+                if let Some(Lexeme::Tilde(_)) = self.peek() {
+                    self.advance();
+                }
+                QueryNode::Phrase {
+                    field: field.map(str::to_string),
+                    text,
+                    slop,
+                    boost: 1.0,
+                }
+            }
+            Some(Lexeme::LParen) => {
+                self.advance();
+                let clauses = self.clauses(field)?;
+                if self.advance().is_none() {
+                    return syntax_error(position, "the group has no closing parenthesis");
+                }
+                QueryNode::Group {
+                    clauses,
+                    boost: 1.0,
+                }
+            }
+            _ => return self.expected_clause(),
+        };
+        if let Some(Lexeme::Boost(boost)) = self.peek() {
+            match &mut node {
+                QueryNode::Word { boost: b, .. }
+                | QueryNode::Phrase { boost: b, .. }
+                | QueryNode::Group { boost: b, .. } => *b = *boost,
+            }
+            self.advance();
+        }
+        Ok(node)
+    }
+}
+
+/// Parse a query of `match()`, failing with the position of the first syntax error
+pub fn parse(query: &str) -> Result<QueryNode> {
+    let mut parser = QueryParser {
+        lexemes: lex(query)?,
+        next: 0,
+        end: query.chars().count() + 1,
+    };
+    let clauses = parser.clauses(None)?;
+    if parser.peek().is_some() {
+        return syntax_error(parser.position(), ") has no opening parenthesis");
+    }
+    let node = QueryNode::Group {
+        clauses,
+        boost: 1.0,
+    };
+    node.check_exclusions(query)?;
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<String> {
+        vec!["title".to_string(), "brand".to_string()]
+    }
+
+    fn analyze(query: &str) -> Result<TextQuery> {
+        parse(query)?.analyze(&Analyzer::standard(), &fields())
+    }
+
+    fn matches(query: &str, title: &str, brand: &str) -> bool {
+        let analyzer = Analyzer::standard();
+        let values = [
+            Document::new(&analyzer, Some(title)),
+            Document::new(&analyzer, Some(brand)),
+        ];
+        analyze(query).unwrap().matches(&values)
+    }
+
+    #[test]
+    fn parse_queries() -> Result<()> {
+        let term = |field: &[usize], term: &str, boost: f64| TextQuery::Term {
+            fields: field.to_vec(),
+            term: term.to_string(),
+            boost,
+        };
+        assert_eq!(
+            TextQuery::Bool {
+                clauses: vec![
+                    (
+                        Occur::Should,
+                        TextQuery::Phrase {
+                            fields: vec![0, 1],
+                            terms: vec![(0, "running".to_string()), (1, "shoes".to_string())],
+                            slop: 0,
+                            boost: 1.0,
+                        }
+                    ),
+                    (Occur::MustNot, term(&[0, 1], "kids", 1.0)),
+                    (Occur::Should, term(&[1], "nike", 2.5)),
+                ],
+                boost: 1.0,
+            },
+            analyze("\"Running shoes\" -kids Brand:nike^2.5")?
+        );
+        assert_eq!(
+            TextQuery::Bool {
+                clauses: vec![
+                    (
+                        Occur::Should,
+                        TextQuery::Bool {
+                            clauses: vec![
+                                (Occur::Must, term(&[0, 1], "a", 1.0)),
+                                (Occur::MustNot, term(&[0, 1], "b", 1.0)),
+                            ],
+                            boost: 1.0,
+                        }
+                    ),
+                    (
+                        Occur::Must,
+                        TextQuery::Bool {
+                            clauses: vec![
+                                (Occur::Should, term(&[0], "c", 1.0)),
+                                (Occur::Should, term(&[0], "d", 1.0)),
+                            ],
+                            boost: 3.0,
+                        }
+                    ),
+                ],
+                boost: 1.0,
+            },
+            analyze("a AND NOT b OR +title:(c d)^3")?
+        );
+        // Escaped operators are words, and words can split into several terms
+        assert_eq!(
+            vec!["and", "e", "mail", "x:y"],
+            analyze("\\AND e-mail x\\:y")?.terms()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn match_queries() {
+        assert!(matches("\"running shoes\"", "Trail running shoes", "Nike"));
+        assert!(!matches("\"running shoes\"", "Shoes for running", "Nike"));
+        assert!(!matches("\"red dress\"", "Red summer dress", "Zara"));
+        assert!(matches("\"red dress\"~1", "Red summer dress", "Zara"));
+        assert!(matches("\"dress red\"~2", "Red dress", "Zara"));
+        assert!(matches("brand:addidas~", "Shoes", "Adidas"));
+        assert!(!matches("brand:addidas~0", "Shoes", "Adidas"));
+        assert!(!matches("brand:nkie~", "Shoes", "Nike"));
+        assert!(matches("brand:nkie~2", "Shoes", "Nike"));
+        assert!(matches("shoes -kids brand:nike", "Running shoes", "Adidas"));
+        assert!(!matches("shoes -kids", "Kids shoes", "Adidas"));
+        assert!(!matches("+shoes brand:nike", "Running socks", "Nike"));
+        assert!(matches("title:nike OR adidas", "Shoes", "Adidas"));
+        assert!(!matches("title:nike", "Shoes", "Nike"));
+        assert!(matches("shoes AND NOT (kids OR toddler)", "Shoes", "Nike"));
+        assert!(!matches("", "Shoes", "Nike"));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |query: &str| analyze(query).unwrap_err().strip_backtrace();
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 7: \
+             the phrase has no closing quote",
+            error("shoes \"running")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 1: \
+             the group has no closing parenthesis",
+            error("(a OR b")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 2: \
+             ) has no opening parenthesis",
+            error("a) b")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 10: \
+             expected a term, phrase or group after AND but found the end of the query",
+            error("shoes AND")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 1: \
+             expected a term, phrase or group but found OR",
+            error("OR shoes")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 6: \
+             a fuzzy term can be at most 2 edits away",
+            error("shoes~3")
+        );
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 12: \
+             ^ must be followed by a positive number",
+            error("\"red dress\"^0")
+        );
+        assert_eq!(
+            "Error during planning: Text query \"-kids NOT toddler\" only excludes terms, \
+             it needs a term to search for",
+            error("-kids NOT toddler")
+        );
+        assert_eq!(
+            "Error during planning: Column size of the text query isn't one of the columns \
+             searched: title, brand",
+            error("shoes size:42")
+        );
+    }
+
+    #[test]
+    fn phrases() {
+        assert!(phrase_matches(&[0, 1], &[&[3, 7], &[8]], 0));
+        assert!(!phrase_matches(&[0, 1], &[&[3, 7], &[5]], 0));
+        assert!(phrase_matches(&[0, 1], &[&[3, 7], &[5]], 1));
+        // Terms can swap places with enough slop
+        assert!(!phrase_matches(&[0, 1], &[&[4], &[3]], 1));
+        assert!(phrase_matches(&[0, 1], &[&[4], &[3]], 2));
+    }
+}
@@ -0,0 +1,1023 @@
+//! Full-text search in SQL: `match(column, ..., 'query')` and `score()`.
+//!
+//! `match(title, body, 'query')` is true for the rows whose values of `title` and `body` match
+//! the query, whose syntax of terms, phrases, operators and column names is described in
+//! [`crate::text_query`], and `score()` ranks them with BM25. Values and queries are split into
+//! terms by the analyzer of the fulltext index on the columns, or the standard one if they have
+//! none (see [`crate::analysis`]). [`MemTable::scan`] finds the rows of a `match()` filter in a
+//! fulltext index on all its columns if there is one, see [`crate::text_index`], and otherwise
+//! evaluates it on every row. [`check_text_queries`] reports syntax errors in literal queries
+//! before planning.
+//!
+//! BM25 needs statistics about whole columns, so scores can't be computed row by row alone.
+//! [`TextSearchRule`] binds each `score()` to the `match()` with the same arguments through a
+//! shared [`TextSearch`], and the scan the `match()` is pushed down to fills in the statistics,
+//! from the index or from the rows it scans. `score()` without arguments refers to the only
+//! `match()` of its statement and is expanded by [`bind_scores`] before planning.
+//!
+//! `MATCH` is a keyword in the generic and MySQL dialects, where `MATCH (...) AGAINST (...)` is
+//! MySQL's full-text search syntax, so [`quote_match_calls`] turns other uses into quoted
+//! function names first.
+//!
+//! [`MemTable::scan`]: crate::table_provider::MemTable
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, BooleanArray, Float64Array};
+use arrow::datatypes::DataType;
+use datafusion::logical_expr::{ColumnarValue, Expr, ScalarUDFImpl, Signature, Volatility};
+use datafusion::optimizer::analyzer::AnalyzerRule;
+use datafusion::scalar::ScalarValue;
+use datafusion_common::config::ConfigOptions;
+use datafusion_common::tree_node::{Transformed, TreeNode, VisitRecursion};
+use datafusion_common::{exec_err, plan_err, Column, DataFusionError, Result};
+use datafusion_expr::expr::{Exists, InSubquery, ScalarFunction, ScalarFunctionDefinition};
+use datafusion_expr::{LogicalPlan, ScalarUDF, Subquery};
+use datafusion_sql::parser::Statement as DFStatement;
+use datafusion_sql::sqlparser::ast::{
+    self, visit_expressions, visit_expressions_mut, FunctionArg, FunctionArgExpr, Ident,
+};
+use datafusion_sql::sqlparser::dialect::dialect_from_str;
+use datafusion_sql::sqlparser::keywords::Keyword;
+use datafusion_sql::sqlparser::tokenizer::{Token, Tokenizer};
+use parking_lot::Mutex;
+
+use crate::analysis::Analyzer;
+use crate::fuzzy::levenshtein;
+use crate::text_index::{is_text_type, text_values, Bm25Stats};
+use crate::text_query::{parse, Document, QueryNode, TextQuery};
+
+/// Name of the full-text search predicate
+pub const MATCH: &str = "match";
+/// Name of the full-text search score
+pub const SCORE: &str = "score";
+
+/// A `match()` call, and the analyzer and BM25 statistics of its columns, shared with the
+/// `score()` calls that rank its rows
+#[derive(Debug)]
+pub struct TextSearch {
+    /// Names of the columns searched
+    fields: Vec<String>,
+    /// The query, if it is a literal
+    query: Option<QueryNode>,
+    scanned: Mutex<Option<Scanned>>,
+}
+
+/// The analyzer of the columns searched and the statistics of each
+type Scanned = (Arc<Analyzer>, Arc<Vec<Bm25Stats>>);
+
+impl TextSearch {
+    pub fn new(fields: Vec<String>, query: Option<QueryNode>) -> Self {
+        Self {
+            fields,
+            query,
+            scanned: Mutex::new(None),
+        }
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn query(&self) -> Option<&QueryNode> {
+        self.query.as_ref()
+    }
+
+    /// Set the analyzer of the columns searched and the statistics of each, when they are
+    /// scanned
+    pub fn set_scanned(&self, analyzer: Arc<Analyzer>, stats: Vec<Bm25Stats>) {
+        *self.scanned.lock() = Some((analyzer, Arc::new(stats)));
+    }
+
+    /// The analyzer of the columns searched, or the standard one until they are scanned
+    pub fn analyzer(&self) -> Arc<Analyzer> {
+        match self.scanned.lock().as_ref() {
+            Some((analyzer, _)) => analyzer.clone(),
+            None => Analyzer::standard(),
+        }
+    }
+
+    pub fn stats(&self) -> Option<Arc<Vec<Bm25Stats>>> {
+        self.scanned.lock().as_ref().map(|(_, stats)| stats.clone())
+    }
+}
+
+/// The signature of `match()` and `score()`, which take any number of columns before the query.
+/// DataFusion compares functions by name and signature, so bound calls are stable rather than
+/// immutable to tell them apart, or rewriting a plan could lose their bindings.
+fn text_signature(bound: bool) -> Signature {
+    let volatility = if bound {
+        Volatility::Stable
+    } else {
+        Volatility::Immutable
+    };
+    Signature::variadic_any(volatility)
+}
+
+/// Check that the arguments of `match()` or `score()` are text columns and a text query
+fn check_arg_types(name: &str, arg_types: &[DataType]) -> Result<()> {
+    match arg_types.split_last() {
+        Some((query, columns)) if !columns.is_empty() && is_text_type(query) => {
+            match columns.iter().find(|data_type| !is_text_type(data_type)) {
+                Some(data_type) => plan_err!("{name}() can't search {data_type} values as text"),
+                None => Ok(()),
+            }
+        }
+        _ => plan_err!("{name}() takes the text columns to search and a query"),
+    }
+}
+
+/// `match(column, ..., 'query')`, whether the columns match `query`, see [`crate::text_query`]
+#[derive(Debug)]
+pub struct MatchFunction {
+    signature: Signature,
+    search: Option<Arc<TextSearch>>,
+}
+
+impl MatchFunction {
+    pub fn new() -> Self {
+        Self {
+            signature: text_signature(false),
+            search: None,
+        }
+    }
+
+    fn bound(search: Arc<TextSearch>) -> Self {
+        Self {
+            signature: text_signature(true),
+            search: Some(search),
+        }
+    }
+}
+
+impl Default for MatchFunction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScalarUDFImpl for MatchFunction {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        MATCH
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, arg_types: &[DataType]) -> Result<DataType> {
+        check_arg_types(MATCH, arg_types)?;
+        Ok(DataType::Boolean)
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        let analyzer = match &self.search {
+            Some(search) => search.analyzer(),
+            None => Analyzer::standard(),
+        };
+        let (columns, queries) = text_and_queries(args, self.search.as_deref(), &analyzer)?;
+        let values = columns
+            .iter()
+            .map(text_values)
+            .collect::<Result<Vec<_>>>()?;
+        let matches: BooleanArray = queries
+            .into_iter()
+            .enumerate()
+            .map(|(row, query)| {
+                let documents = row_documents(&values, row, &analyzer)?;
+                Some(query?.matches(&documents))
+            })
+            .collect();
+        Ok(ColumnarValue::Array(Arc::new(matches)))
+    }
+}
+
+/// `score()`, the BM25 score of a row for the `match()` of its statement. Bound to the
+/// `match()` it ranks by [`TextSearchRule`].
+#[derive(Debug)]
+pub struct ScoreFunction {
+    signature: Signature,
+    search: Option<Arc<TextSearch>>,
+}
+
+impl ScoreFunction {
+    pub fn new() -> Self {
+        Self {
+            signature: text_signature(false),
+            search: None,
+        }
+    }
+
+    fn bound(search: Arc<TextSearch>) -> Self {
+        Self {
+            signature: text_signature(true),
+            search: Some(search),
+        }
+    }
+}
+
+impl Default for ScoreFunction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScalarUDFImpl for ScoreFunction {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        SCORE
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, arg_types: &[DataType]) -> Result<DataType> {
+        check_arg_types(SCORE, arg_types)?;
+        Ok(DataType::Float64)
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        let Some((search, stats)) = self
+            .search
+            .as_ref()
+            .and_then(|search| Some((search, search.stats()?)))
+        else {
+            return exec_err!(
+                "{SCORE}() needs a {MATCH}() with the same arguments that filters a table"
+            );
+        };
+        let analyzer = search.analyzer();
+        let (columns, queries) = text_and_queries(args, Some(search), &analyzer)?;
+        let values = columns
+            .iter()
+            .map(text_values)
+            .collect::<Result<Vec<_>>>()?;
+        let scores: Float64Array = queries
+            .into_iter()
+            .enumerate()
+            .map(|(row, query)| {
+                let documents = row_documents(&values, row, &analyzer)?;
+                Some(query?.score(&documents, &stats))
+            })
+            .collect();
+        Ok(ColumnarValue::Array(Arc::new(scores)))
+    }
+}
+
+/// The columns of a `match()` or `score()` call, and the query of each row with its words split
+/// into terms by `analyzer`
+#[allow(clippy::type_complexity)]
+fn text_and_queries(
+    args: &[ColumnarValue],
+    search: Option<&TextSearch>,
+    analyzer: &Analyzer,
+) -> Result<(Vec<ArrayRef>, Vec<Option<Arc<TextQuery>>>)> {
+    let Some((query, columns)) = args.split_last().filter(|(_, columns)| !columns.is_empty())
+    else {
+        return exec_err!("{MATCH}() takes the text columns to search and a query");
+    };
+    let arrays: Vec<ArrayRef> = ColumnarValue::values_to_arrays(args)?;
+    let (query_array, column_arrays) = arrays.split_last().expect("checked above");
+    let num_rows = query_array.len();
+    // The columns of unbound calls have no names for the query to refer to
+    let unnamed = vec![String::new(); columns.len()];
+    let fields = search.map_or(unnamed.as_slice(), |search| search.fields());
+    let analyze = |query: &str| -> Result<Arc<TextQuery>> {
+        Ok(Arc::new(parse(query)?.analyze(analyzer, fields)?))
+    };
+    let queries = match (search.and_then(TextSearch::query), query) {
+        // Most queries are literals, so they are only analyzed once
+        (Some(parsed), _) => vec![Some(Arc::new(parsed.analyze(analyzer, fields)?)); num_rows],
+        (None, ColumnarValue::Scalar(ScalarValue::Utf8(query))) => {
+            vec![query.as_deref().map(analyze).transpose()?; num_rows]
+        }
+        _ => text_values(query_array)?
+            .into_iter()
+            .map(|query| query.map(analyze).transpose())
+            .collect::<Result<_>>()?,
+    };
+    Ok((column_arrays.to_vec(), queries))
+}
+
+/// The values of the columns searched in `row`, or `None` if they are all null
+fn row_documents(
+    values: &[Vec<Option<&str>>],
+    row: usize,
+    analyzer: &Analyzer,
+) -> Option<Vec<Document>> {
+    if values.iter().all(|column| column[row].is_none()) {
+        return None;
+    }
+    Some(
+        values
+            .iter()
+            .map(|column| Document::new(analyzer, column[row]))
+            .collect(),
+    )
+}
+
+/// The columns and search of a bound `match(column, ..., 'query')` filter
+pub fn match_filter(expr: &Expr) -> Option<(Vec<&Column>, Arc<TextSearch>)> {
+    match expr {
+        Expr::ScalarFunction(ScalarFunction {
+            func_def: ScalarFunctionDefinition::UDF(udf),
+            args,
+        }) if udf.name() == MATCH => {
+            let search = udf
+                .inner()
+                .as_any()
+                .downcast_ref::<MatchFunction>()?
+                .search
+                .clone()
+                .filter(|search| search.query().is_some())?;
+            let columns = args[..args.len().saturating_sub(1)]
+                .iter()
+                .map(|arg| match arg {
+                    Expr::Column(column) => Some(column),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some((columns, search))
+        }
+        _ => None,
+    }
+}
+
+/// The columns and searches of the bound `match()` calls with literal queries in `filters`
+pub fn text_searches(filters: &[Expr]) -> Vec<(Vec<Column>, Arc<TextSearch>)> {
+    let mut searches = vec![];
+    for filter in filters {
+        let _ = filter.apply(&mut |expr| {
+            if let Some((columns, search)) = match_filter(expr) {
+                searches.push((columns.into_iter().cloned().collect(), search));
+            }
+            Ok(VisitRecursion::Continue)
+        });
+    }
+    searches
+}
+
+/// Add the values of the text column `column`, as `analyzer` splits them, to `stats`. The terms
+/// that `fuzzy` terms match, at most their distance away, are counted too.
+pub fn column_stats(
+    column: &ArrayRef,
+    analyzer: &Analyzer,
+    fuzzy: &[(String, u32)],
+    stats: &mut Bm25Stats,
+) -> Result<()> {
+    for value in text_values(column)?.into_iter().flatten() {
+        let terms = analyzer.terms(value);
+        for term in terms.iter() {
+            let matched = || {
+                fuzzy
+                    .iter()
+                    .any(|(fuzzy, distance)| levenshtein(term, fuzzy, *distance).is_some())
+            };
+            if !stats.doc_freqs.contains_key(term) && matched() {
+                stats.doc_freqs.insert(term.clone(), 0);
+            }
+        }
+        stats.add_document(&terms);
+    }
+    Ok(())
+}
+
+/// Quote the function name of every `match(...)` in `sql` that isn't MySQL's
+/// `MATCH (...) AGAINST (...)`, so that the parser sees a function call
+pub fn quote_match_calls(sql: &str, dialect: &str) -> Result<String> {
+    let dialect = dialect_from_str(dialect)
+        .ok_or_else(|| DataFusionError::Plan(format!("Unsupported SQL dialect: {dialect}")))?;
+    let tokens = Tokenizer::new(dialect.as_ref(), sql)
+        .tokenize_with_location()
+        .map_err(|e| DataFusionError::SQL(e.into(), None))?;
+    let words: Vec<_> = tokens
+        .iter()
+        .filter(|token| !matches!(token.token, Token::Whitespace(_)))
+        .collect();
+    let mut calls = vec![];
+    for (i, token) in words.iter().enumerate() {
+        let Token::Word(word) = &token.token else {
+            continue;
+        };
+        if word.keyword != Keyword::MATCH
+            || word.quote_style.is_some()
+            || !matches!(words.get(i + 1).map(|t| &t.token), Some(Token::LParen))
+        {
+            continue;
+        }
+        let mut depth = 0;
+        let mut end = i + 1;
+        for (j, token) in words.iter().enumerate().skip(i + 1) {
+            match token.token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                end = j;
+                break;
+            }
+        }
+        let against = matches!(
+            words.get(end + 1).map(|t| &t.token),
+            Some(Token::Word(word)) if word.keyword == Keyword::AGAINST
+        );
+        if !against {
+            calls.push((token.location.line, token.location.column));
+        }
+    }
+    if calls.is_empty() {
+        return Ok(sql.to_string());
+    }
+    let quote = if dialect.is_delimited_identifier_start('"') {
+        '"'
+    } else {
+        '`'
+    };
+    let mut quoted = String::with_capacity(sql.len() + 2 * calls.len());
+    let (mut line, mut column) = (1, 1);
+    let mut chars = sql.chars();
+    while let Some(c) = chars.next() {
+        if calls.contains(&(line, column)) {
+            // The keyword is ASCII, so it is five characters long
+            let word: String = std::iter::once(c).chain(chars.by_ref().take(4)).collect();
+            quoted.push(quote);
+            quoted.push_str(&word.to_ascii_lowercase());
+            quoted.push(quote);
+            column += 5;
+            continue;
+        }
+        quoted.push(c);
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    Ok(quoted)
+}
+
+fn is_function(function: &ast::Function, name: &str) -> bool {
+    function
+        .name
+        .0
+        .last()
+        .is_some_and(|ident| ident.value.eq_ignore_ascii_case(name))
+}
+
+/// Give every `score()` without arguments the arguments of the only `match()` of `statement`
+pub fn bind_scores(statement: &mut DFStatement) -> Result<()> {
+    let statement = match statement {
+        DFStatement::Statement(statement) => statement.as_mut(),
+        DFStatement::Explain(explain) => return bind_scores(explain.statement.as_mut()),
+        _ => return Ok(()),
+    };
+    let mut matches: Vec<Vec<FunctionArg>> = vec![];
+    let _ = visit_expressions(statement, |expr| {
+        if let ast::Expr::Function(function) = expr {
+            if is_function(function, MATCH) && !matches.contains(&function.args) {
+                matches.push(function.args.clone());
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    let result = visit_expressions_mut(statement, |expr| {
+        if let ast::Expr::Function(function) = expr {
+            if is_function(function, SCORE) && function.args.is_empty() {
+                let [args] = matches.as_slice() else {
+                    return ControlFlow::Break(plan_err!(
+                        "{SCORE}() needs exactly one {MATCH}() in the statement, or the arguments \
+                         of the {MATCH}() it ranks"
+                    ));
+                };
+                function.name = ast::ObjectName(vec![Ident::new(SCORE)]);
+                function.args = args.clone();
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    match result {
+        ControlFlow::Break(result) => result,
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+/// Check the literal queries of the `match()` and `score()` calls in `statement`, and that the
+/// columns they name are searched, so that mistakes in them fail before planning
+pub fn check_text_queries(statement: &DFStatement) -> Result<()> {
+    let statement = match statement {
+        DFStatement::Statement(statement) => statement.as_ref(),
+        DFStatement::Explain(explain) => return check_text_queries(explain.statement.as_ref()),
+        _ => return Ok(()),
+    };
+    let result = visit_expressions(statement, |expr| {
+        let ast::Expr::Function(function) = expr else {
+            return ControlFlow::Continue(());
+        };
+        if !is_function(function, MATCH) && !is_function(function, SCORE) {
+            return ControlFlow::Continue(());
+        }
+        let Some((FunctionArg::Unnamed(FunctionArgExpr::Expr(query)), columns)) =
+            function.args.split_last()
+        else {
+            return ControlFlow::Continue(());
+        };
+        let ast::Expr::Value(ast::Value::SingleQuotedString(query)) = query else {
+            return ControlFlow::Continue(());
+        };
+        let query = match parse(query) {
+            Ok(query) => query,
+            Err(e) => return ControlFlow::Break(e),
+        };
+        // The names of the columns can only be checked if they are all plain columns
+        let fields: Option<Vec<String>> = columns
+            .iter()
+            .map(|column| match column {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(ast::Expr::Identifier(ident))) => {
+                    Some(ident.value.clone())
+                }
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(ast::Expr::CompoundIdentifier(
+                    idents,
+                ))) => idents.last().map(|ident| ident.value.clone()),
+                _ => None,
+            })
+            .collect();
+        if let Some(fields) = fields {
+            if let Err(e) = query.analyze(&Analyzer::standard(), &fields) {
+                return ControlFlow::Break(e);
+            }
+        }
+        ControlFlow::Continue(())
+    });
+    match result {
+        ControlFlow::Break(e) => Err(e),
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+type Searches = RefCell<HashMap<Vec<Expr>, Arc<TextSearch>>>;
+
+/// Bind `match()` and `score()` calls with the same arguments to the same [`TextSearch`]
+fn bind_expr(expr: Expr, searches: &Searches) -> Result<Expr> {
+    expr.transform_up(&|expr| {
+        Ok(match expr {
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                args,
+            }) if udf.name() == MATCH || udf.name() == SCORE => {
+                let Some((query, columns)) = args.split_last() else {
+                    return Ok(Transformed::No(Expr::ScalarFunction(ScalarFunction {
+                        func_def: ScalarFunctionDefinition::UDF(udf),
+                        args,
+                    })));
+                };
+                let query = match query {
+                    Expr::Literal(ScalarValue::Utf8(Some(query))) => Some(parse(query)?),
+                    _ => None,
+                };
+                let fields = columns
+                    .iter()
+                    .map(|column| match column {
+                        Expr::Column(column) => Ok(column.name.clone()),
+                        expr => expr.display_name(),
+                    })
+                    .collect::<Result<_>>()?;
+                let search = searches
+                    .borrow_mut()
+                    .entry(args.clone())
+                    .or_insert_with(|| Arc::new(TextSearch::new(fields, query)))
+                    .clone();
+                let udf = if udf.name() == MATCH {
+                    ScalarUDF::new_from_impl(MatchFunction::bound(search))
+                } else {
+                    ScalarUDF::new_from_impl(ScoreFunction::bound(search))
+                };
+                Transformed::Yes(Expr::ScalarFunction(ScalarFunction {
+                    func_def: ScalarFunctionDefinition::UDF(Arc::new(udf)),
+                    args,
+                }))
+            }
+            Expr::ScalarSubquery(subquery) => {
+                Transformed::Yes(Expr::ScalarSubquery(bind_subquery(subquery, searches)?))
+            }
+            Expr::Exists(Exists { subquery, negated }) => Transformed::Yes(Expr::Exists(Exists {
+                subquery: bind_subquery(subquery, searches)?,
+                negated,
+            })),
+            Expr::InSubquery(InSubquery {
+                expr,
+                subquery,
+                negated,
+            }) => Transformed::Yes(Expr::InSubquery(InSubquery {
+                expr,
+                subquery: bind_subquery(subquery, searches)?,
+                negated,
+            })),
+            expr => Transformed::No(expr),
+        })
+    })
+}
+
+fn bind_subquery(subquery: Subquery, searches: &Searches) -> Result<Subquery> {
+    Ok(Subquery {
+        subquery: Arc::new(bind_plan(subquery.subquery.as_ref(), searches)?),
+        outer_ref_columns: subquery.outer_ref_columns,
+    })
+}
+
+fn bind_plan(plan: &LogicalPlan, searches: &Searches) -> Result<LogicalPlan> {
+    plan.clone().transform_up(&|plan| {
+        let exprs = plan.expressions();
+        if !exprs.iter().any(has_text_search) {
+            return Ok(Transformed::No(plan));
+        }
+        let new_exprs = exprs
+            .into_iter()
+            .map(|expr| bind_expr(expr, searches))
+            .collect::<Result<Vec<_>>>()?;
+        let inputs = plan.inputs().into_iter().cloned().collect();
+        Ok(Transformed::Yes(plan.with_new_exprs(new_exprs, inputs)?))
+    })
+}
+
+fn has_text_search(expr: &Expr) -> bool {
+    let mut found = false;
+    let _ = expr.apply(&mut |expr| {
+        found = match expr {
+            Expr::ScalarFunction(ScalarFunction {
+                func_def: ScalarFunctionDefinition::UDF(udf),
+                ..
+            }) => udf.name() == MATCH || udf.name() == SCORE,
+            Expr::ScalarSubquery(_) | Expr::Exists(_) | Expr::InSubquery(_) => true,
+            _ => false,
+        };
+        Ok(if found {
+            VisitRecursion::Stop
+        } else {
+            VisitRecursion::Continue
+        })
+    });
+    found
+}
+
+/// Analyzer rule that binds each `score()` to the `match()` with the same arguments. It must run
+/// before type coercion, which may cast their arguments differently.
+#[derive(Debug, Default)]
+pub struct TextSearchRule {}
+
+impl TextSearchRule {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl AnalyzerRule for TextSearchRule {
+    fn analyze(&self, plan: LogicalPlan, _config: &ConfigOptions) -> Result<LogicalPlan> {
+        bind_plan(&plan, &RefCell::new(HashMap::new()))
+    }
+
+    fn name(&self) -> &str {
+        "text_search"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::Int32Array;
+    use datafusion::execution::context::SessionContext;
+    use datafusion::physical_plan::displayable;
+
+    use crate::session::new_context;
+    use crate::sql::sql_to_plan;
+    use crate::test_util::{query, run};
+
+    use super::*;
+
+    async fn docs() -> Result<SessionContext> {
+        let ctx = new_context();
+        query(
+            &ctx,
+            "CREATE TABLE docs (id INT PRIMARY KEY, title VARCHAR, body VARCHAR)",
+        )
+        .await?;
+        query(
+            &ctx,
+            "INSERT INTO docs VALUES \
+             (1, 'Rust in Action', 'Systems programming with Rust'), \
+             (2, 'Programming Rust', 'Fast, safe systems development. Rust, rust, rust!'), \
+             (3, 'Python Crash Course', 'A hands-on introduction to programming'), \
+             (4, 'The Go Programming Language', NULL)",
+        )
+        .await?;
+        Ok(ctx)
+    }
+
+    const RANKED: &str = "SELECT id, score() > 0 AS scored FROM docs \
+                          WHERE match(body, 'rust programming') ORDER BY score() DESC";
+
+    #[tokio::test]
+    async fn match_and_score() -> Result<()> {
+        let ctx = docs().await?;
+        let ranked = "+----+--------+\n\
+                      | id | scored |\n\
+                      +----+--------+\n\
+                      | 1  | true   |\n\
+                      | 2  | true   |\n\
+                      | 3  | true   |\n\
+                      +----+--------+";
+        // Without an index, every row is searched and the scan collects the statistics
+        assert_eq!(ranked, query(&ctx, RANKED).await?);
+
+        query(
+            &ctx,
+            "CREATE INDEX docs_text ON docs USING fulltext (title, body)",
+        )
+        .await?;
+        assert_eq!(ranked, query(&ctx, RANKED).await?);
+        let plan = ctx
+            .state()
+            .create_physical_plan(&sql_to_plan(&ctx.state(), RANKED).await?)
+            .await?;
+        let plan_str = format!("{}", displayable(plan.as_ref()).indent(false));
+        // The documents of either term, counting those with both twice
+        assert!(
+            plan_str.contains("IndexScanExec: index=docs_text")
+                && plan_str.contains("estimated_rows=4"),
+            "{plan_str}"
+        );
+
+        // The index follows inserts, updates and deletes
+        query(
+            &ctx,
+            "INSERT INTO docs VALUES (5, 'Zero to Production', 'Backend Rust')",
+        )
+        .await?;
+        query(&ctx, "UPDATE docs SET body = 'Statistics' WHERE id = 3").await?;
+        query(&ctx, "DELETE FROM docs WHERE id = 1").await?;
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 2  |\n| 5  |\n+----+",
+            query(
+                &ctx,
+                "SELECT id FROM docs WHERE MATCH(body, 'RUST') ORDER BY id"
+            )
+            .await?
+        );
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 3  |\n| 4  |\n+----+",
+            query(
+                &ctx,
+                "SELECT id FROM docs WHERE match(title, 'language course') \
+                 ORDER BY score(title, 'language course') DESC",
+            )
+            .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn text_search_errors() -> Result<()> {
+        let ctx = docs().await?;
+        let error = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { query(&ctx, sql).await.unwrap_err().strip_backtrace() }
+        };
+        assert_eq!(
+            "Error during planning: Fulltext index i can't be unique",
+            error("CREATE UNIQUE INDEX i ON docs USING fulltext (title)").await
+        );
+        assert_eq!(
+            "Error during planning: Fulltext index i can't index column id of type Int32",
+            error("CREATE INDEX i ON docs USING fulltext (id)").await
+        );
+        assert_eq!(
+            "Error during planning: Unsupported index method hash",
+            error("CREATE INDEX i ON docs USING hash (title)").await
+        );
+        assert_eq!(
+            "Error during planning: score() needs exactly one match() in the statement, \
+             or the arguments of the match() it ranks",
+            error("SELECT score() FROM docs").await
+        );
+        assert_eq!(
+            "Execution error: score() needs a match() with the same arguments that filters a table",
+            error("SELECT score(title, 'rust') FROM docs").await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_syntax() -> Result<()> {
+        let ctx = new_context();
+        query(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, brand VARCHAR)",
+        )
+        .await?;
+        query(
+            &ctx,
+            "INSERT INTO products VALUES \
+             (1, 'Running shoes', 'Nike'), \
+             (2, 'Kids running shoes', 'Nike'), \
+             (3, 'Shoes for running', 'Adidas'), \
+             (4, 'Red summer dress', 'Zara'), \
+             (5, 'Nike air socks', 'Puma'), \
+             (6, 'Trail running shoes', 'Salomon'), \
+             (7, 'Air max', 'Nike')",
+        )
+        .await?;
+        let ids = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move {
+                let batches = run(&ctx, sql).await?;
+                let ids: Vec<i32> = batches
+                    .iter()
+                    .flat_map(|batch| {
+                        let ids = batch.column(0).as_any().downcast_ref::<Int32Array>();
+                        ids.unwrap().values().to_vec()
+                    })
+                    .collect();
+                Ok::<_, DataFusionError>(ids)
+            }
+        };
+        const SEARCH: &str = "SELECT id FROM products \
+                              WHERE match(name, brand, '\"running shoes\" -kids brand:nike') \
+                              ORDER BY id";
+        const NAME_BOOST: &str = "SELECT id FROM products \
+                                  WHERE match(name, brand, 'nike name:nike^3') \
+                                  ORDER BY score() DESC, id";
+        const BRAND_BOOST: &str = "SELECT id FROM products \
+                                   WHERE match(name, brand, 'nike brand:nike^3') \
+                                   ORDER BY score() DESC, id";
+        for indexed in [false, true] {
+            if indexed {
+                query(
+                    &ctx,
+                    "CREATE INDEX products_text ON products USING fulltext (name, brand)",
+                )
+                .await?;
+            }
+            assert_eq!(vec![1, 6, 7], ids(SEARCH).await?);
+            assert_eq!(
+                vec![4],
+                ids("SELECT id FROM products WHERE match(name, '\"red dress\"~1')").await?
+            );
+            assert!(
+                ids("SELECT id FROM products WHERE match(name, '\"red dress\"')")
+                    .await?
+                    .is_empty()
+            );
+            assert_eq!(
+                vec![2, 3],
+                ids("SELECT id FROM products \
+                     WHERE match(name, brand, '+running AND (kids OR adidas)') ORDER BY id")
+                .await?
+            );
+            assert_eq!(vec![5, 1, 2, 7], ids(NAME_BOOST).await?);
+            assert_eq!(vec![1, 2, 7, 5], ids(BRAND_BOOST).await?);
+        }
+        let plan = ctx
+            .state()
+            .create_physical_plan(&sql_to_plan(&ctx.state(), SEARCH).await?)
+            .await?;
+        let plan_str = format!("{}", displayable(plan.as_ref()).indent(false));
+        // Estimated from the terms' document frequencies, so it's more than the 3 matches
+        assert!(
+            plan_str.contains("IndexScanExec: index=products_text")
+                && plan_str.contains("estimated_rows=7"),
+            "{plan_str}"
+        );
+
+        let error = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { query(&ctx, sql).await.unwrap_err().strip_backtrace() }
+        };
+        assert_eq!(
+            "Error during planning: Syntax error in text query at character 9: \
+             the phrase has no closing quote",
+            error("SELECT id FROM products WHERE match(name, 'running \"shoes')").await
+        );
+        assert_eq!(
+            "Error during planning: Column brand of the text query isn't one of the columns \
+             searched: name",
+            error("SELECT id FROM products WHERE match(name, 'brand:nike')").await
+        );
+        // Arguments aren't cast to text
+        let type_error = error("SELECT id FROM products WHERE match(id, name, 'nike')").await;
+        assert!(
+            type_error
+                .ends_with("Error during planning: match() can't search Int32 values as text"),
+            "{type_error}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fuzzy_search() -> Result<()> {
+        let ctx = new_context();
+        query(
+            &ctx,
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, brand VARCHAR)",
+        )
+        .await?;
+        query(
+            &ctx,
+            "INSERT INTO products VALUES \
+             (1, 'Adidas running shoes', 'Adidas'), \
+             (2, 'Addidas knockoff shoes', 'Addidas'), \
+             (3, 'Nike running shoes', 'Nike')",
+        )
+        .await?;
+        let ranked = "+----+\n| id |\n+----+\n| 1  |\n| 2  |\n+----+";
+        for indexed in [false, true] {
+            if indexed {
+                query(
+                    &ctx,
+                    "CREATE INDEX products_text ON products USING fulltext (name)",
+                )
+                .await?;
+            }
+            // Exact matches rank above fuzzy ones
+            assert_eq!(
+                ranked,
+                query(
+                    &ctx,
+                    "SELECT id FROM products WHERE match(name, 'adidas~') ORDER BY score() DESC"
+                )
+                .await?
+            );
+            assert_eq!(
+                "+----+\n| id |\n+----+\n| 2  |\n| 1  |\n+----+",
+                query(
+                    &ctx,
+                    "SELECT id FROM products WHERE match(name, 'addidas~1') ORDER BY score() DESC"
+                )
+                .await?
+            );
+            assert_eq!(
+                "+----+\n| id |\n+----+\n| 3  |\n+----+",
+                query(
+                    &ctx,
+                    "SELECT id FROM products WHERE match(name, 'nikke~ nkie~')"
+                )
+                .await?
+            );
+        }
+        assert_eq!(
+            ranked,
+            query(
+                &ctx,
+                "SELECT id FROM products WHERE similarity(brand, 'addidas') > 0.5 ORDER BY id"
+            )
+            .await?
+        );
+        assert_eq!(
+            "+------+------+\n\
+             | same | null |\n\
+             +------+------+\n\
+             | 1.0  |      |\n\
+             +------+------+",
+            query(
+                &ctx,
+                "SELECT similarity('Adidas', 'adidas!') AS same, similarity('a', NULL) AS null"
+            )
+            .await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quote_match() -> Result<()> {
+        assert_eq!(
+            "SELECT * FROM t WHERE \"match\"(title, 'a (b)') AND x IN (1)",
+            quote_match_calls(
+                "SELECT * FROM t WHERE MATCH(title, 'a (b)') AND x IN (1)",
+                "generic"
+            )?
+        );
+        assert_eq!(
+            "SELECT 'é'\n  WHERE `match` (b, 'é') OR MATCH (a) AGAINST ('x')",
+            quote_match_calls(
+                "SELECT 'é'\n  WHERE match (b, 'é') OR MATCH (a) AGAINST ('x')",
+                "mysql"
+            )?
+        );
+        assert_eq!(
+            "SELECT 'match(a)' AS \"match\"",
+            quote_match_calls("SELECT 'match(a)' AS \"match\"", "generic")?
+        );
+        Ok(())
+    }
+}
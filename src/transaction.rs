@@ -0,0 +1,193 @@
+//! Write transactions and read snapshots for the timestamp ordering described in
+//! `docs/mvcc-design.md`.
+//!
+//! A single write transaction runs at a time. It tags the versions it writes with its id, one
+//! more than the last committed id, and publishes them by storing its id as the last committed
+//! id when it commits. Readers take a [`Snapshot`] of the last committed id and only see versions
+//! tagged with ids up to it.
+//!
+//! Readers register their snapshot as an [`ActiveSnapshot`] for as long as they use it, so that
+//! garbage collection can find the oldest snapshot still in use and free the older versions no
+//! snapshot can see.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use datafusion::prelude::SessionConfig;
+use parking_lot::{const_mutex, Mutex, MutexGuard};
+
+pub type TransactionId = u64;
+
+/// Id of the last committed write transaction. Rows loaded before any transaction have id 0.
+static LAST_COMMITTED: AtomicU64 = AtomicU64::new(0);
+
+/// Held by the running write transaction
+static WRITER: Mutex<()> = const_mutex(());
+
+/// Snapshots in use by readers
+static ACTIVE: Mutex<ActiveSnapshots> = const_mutex(ActiveSnapshots {
+    oldest: 0,
+    counts: VecDeque::new(),
+});
+
+/// Ring buffer of the number of active snapshots of each transaction id from the oldest active
+/// one on. Snapshots are only taken of the last committed id, so new ones go at the back, and
+/// the front is popped as the oldest ones are dropped.
+#[derive(Debug)]
+struct ActiveSnapshots {
+    /// Id of the snapshots counted at the front
+    oldest: TransactionId,
+    counts: VecDeque<usize>,
+}
+
+/// The only running write transaction. Dropping it without committing leaves its versions
+/// invisible, so its writes must be rolled back before the next transaction reuses its id.
+#[derive(Debug)]
+pub struct WriteTransaction {
+    id: TransactionId,
+    _writer: MutexGuard<'static, ()>,
+}
+
+impl WriteTransaction {
+    /// Begin a write transaction, waiting for the running one to finish
+    pub fn begin() -> Self {
+        let writer = WRITER.lock();
+        Self {
+            id: LAST_COMMITTED.load(Ordering::Acquire) + 1,
+            _writer: writer,
+        }
+    }
+
+    pub fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    /// Make the transaction's versions visible to snapshots taken from now on
+    pub fn commit(self) {
+        LAST_COMMITTED.store(self.id, Ordering::Release);
+    }
+}
+
+/// The versions a reader sees: those written by transactions committed when it was taken.
+///
+/// Snapshots that are read from for a while should be registered as an [`ActiveSnapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot(TransactionId);
+
+impl Snapshot {
+    /// A snapshot of every committed transaction
+    pub fn latest() -> Self {
+        Snapshot(LAST_COMMITTED.load(Ordering::Acquire))
+    }
+
+    /// A snapshot of the transactions up to `id`
+    pub fn at(id: TransactionId) -> Self {
+        Snapshot(id)
+    }
+
+    /// A snapshot that also sees uncommitted versions, for the running write transaction
+    pub fn uncommitted() -> Self {
+        Snapshot(TransactionId::MAX)
+    }
+
+    /// Id of the last transaction the snapshot sees
+    pub fn id(&self) -> TransactionId {
+        self.0
+    }
+
+    /// Whether versions written by `id` are visible
+    pub fn sees(&self, id: TransactionId) -> bool {
+        id <= self.0
+    }
+}
+
+/// A snapshot registered as in use until it is dropped, so garbage collection keeps the versions
+/// it sees.
+///
+/// Sessions can pin a snapshot by adding one to their `SessionConfig` as an extension. Scans in
+/// sessions without one see the latest committed versions.
+#[derive(Debug)]
+pub struct ActiveSnapshot(Snapshot);
+
+impl ActiveSnapshot {
+    /// Register a snapshot of every committed transaction
+    pub fn begin() -> Self {
+        let mut active = ACTIVE.lock();
+        // Loaded under the lock so that snapshots are registered in order
+        let id = LAST_COMMITTED.load(Ordering::Acquire);
+        if active.counts.is_empty() {
+            active.oldest = id;
+        }
+        let index = (id - active.oldest) as usize;
+        if active.counts.len() <= index {
+            active.counts.resize(index + 1, 0);
+        }
+        active.counts[index] += 1;
+        ActiveSnapshot(Snapshot(id))
+    }
+
+    /// The snapshot pinned in `config`, or a newly registered one of the latest transaction if
+    /// there is none
+    pub fn from_config(config: &SessionConfig) -> Arc<Self> {
+        config
+            .get_extension::<ActiveSnapshot>()
+            .unwrap_or_else(|| Arc::new(ActiveSnapshot::begin()))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.0
+    }
+}
+
+impl Drop for ActiveSnapshot {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock();
+        let index = (self.0.id() - active.oldest) as usize;
+        active.counts[index] -= 1;
+        while active.counts.front() == Some(&0) {
+            active.counts.pop_front();
+            active.oldest += 1;
+        }
+    }
+}
+
+/// The oldest snapshot in use, or the latest one if no snapshot is in use. Versions it doesn't
+/// need are not needed by any snapshot taken later either.
+pub fn oldest_active() -> Snapshot {
+    let active = ACTIVE.lock();
+    if active.counts.is_empty() {
+        Snapshot::latest()
+    } else {
+        Snapshot(active.oldest)
+    }
+}
+
+/// Id of the last committed write transaction
+pub fn last_committed() -> TransactionId {
+    LAST_COMMITTED.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_active_snapshot() {
+        // Other tests commit and take snapshots concurrently, so only check what they can't change
+        let first = ActiveSnapshot::begin();
+        assert!(oldest_active().sees(first.snapshot().id()));
+        let transaction = WriteTransaction::begin();
+        let id = transaction.id();
+        transaction.commit();
+        let second = ActiveSnapshot::begin();
+        assert!(second.snapshot().sees(id));
+        assert!(!first.snapshot().sees(id));
+        // The first snapshot keeps the oldest active one from moving past it
+        assert!(!oldest_active().sees(id));
+        drop(first);
+        let oldest = oldest_active();
+        drop(second);
+        assert!(oldest.id() <= oldest_active().id());
+    }
+}
@@ -0,0 +1,1776 @@
+//! Write-ahead log that makes writes survive a restart.
+//!
+//! The writer (see [`crate::writer`]) appends the [`LogRecord`]s of each transaction it commits
+//! as one frame: a little-endian `u32` length, a CRC32 of the payload and the payload, a
+//! protobuf-encoded list of records. Rows are logged as Arrow IPC streams. Frames go into segment
+//! files named by their sequence number, and a new segment is started once one grows past
+//! [`WalOptions::segment_size`].
+//!
+//! [`Wal::open`] replays every frame into a session context before appending to the log: the system
+//! catalog (see [`crate::system_catalog`]) is rebuilt from the logged tables, which are recreated
+//! from it and registered, their rows are inserted, updated and deleted, keeping their indexes up
+//! to date, and dropped tables and schemas are dropped again. Roles and grants (see
+//! [`crate::auth`]) are logged with the hash of any password. A frame torn by a crash at the end of
+//! the last segment is truncated, since its transaction never completed. If the directory holds a
+//! checkpoint (see [`crate::checkpoint`]), it is loaded first and only the segments after it are
+//! replayed.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arrow::datatypes::{Field, Schema, SchemaRef};
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::{IpcWriteOptions, StreamWriter};
+use arrow::ipc::CompressionType;
+use arrow::record_batch::RecordBatch;
+use datafusion::datasource::TableProvider;
+use datafusion::error::Result;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::Expr;
+use datafusion::scalar::ScalarValue;
+use datafusion_common::{
+    exec_err, plan_err, Constraint, Constraints, DataFusionError, TableReference,
+};
+use log::{info, warn};
+use prost::Message;
+
+use crate::alter_table::{self, TableChange};
+use crate::analysis::Analyzer;
+use crate::auth::{Privilege, Role, Securable};
+use crate::catalog::TableId;
+use crate::checkpoint::{self, Manifest};
+use crate::file_io::{AppendFile, IoBackend};
+use crate::index::IndexMethod;
+use crate::sql::{as_block_table, as_mem_table, TableStorage};
+use crate::system_catalog::{system_catalog, IndexEntry, SystemCatalog, TableEntry};
+
+/// Size past which a segment is closed and a new one started
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Bytes before each frame's payload: its length and checksum
+const FRAME_HEADER_LEN: usize = 8;
+
+/// When appended frames are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync every frame before the writes in it complete
+    Commit,
+    /// Sync at most once per interval, so one sync covers every commit in it. Writes complete
+    /// once their frame is handed to the OS, so a machine crash can lose the last interval.
+    Interval(Duration),
+    /// Leave syncing to the OS
+    Off,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = DataFusionError;
+
+    /// Parse `commit`, `off`, or an interval like `10ms`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "commit" => Ok(SyncPolicy::Commit),
+            "off" => Ok(SyncPolicy::Off),
+            s => match s.strip_suffix("ms").map(str::parse) {
+                Some(Ok(ms)) => Ok(SyncPolicy::Interval(Duration::from_millis(ms))),
+                _ => {
+                    plan_err!("Unknown sync policy {s}, expected commit, off or an interval in ms")
+                }
+            },
+        }
+    }
+}
+
+/// Where the write-ahead log is kept and how it is written
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    pub dir: PathBuf,
+    pub sync: SyncPolicy,
+    pub segment_size: u64,
+    /// How often the server takes a checkpoint, if it takes them on its own
+    pub checkpoint_interval: Option<Duration>,
+    /// How segments and checkpoints are written
+    pub io: IoBackend,
+}
+
+impl WalOptions {
+    /// Keep the log in `dir`, syncing every commit
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            sync: SyncPolicy::Commit,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            checkpoint_interval: None,
+            io: IoBackend::Std,
+        }
+    }
+
+    pub fn with_sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    pub fn with_checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Write with `io`, or `std::fs` if the platform doesn't support it
+    pub fn with_io(mut self, io: IoBackend) -> Self {
+        self.io = io.or_fallback();
+        self
+    }
+}
+
+/// What replaying the log at startup did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub transactions: usize,
+    pub records: usize,
+    /// Bytes of a torn frame cut off the end of the log
+    pub truncated_bytes: u64,
+    /// First segment after the checkpoint that was loaded, if there was one
+    pub checkpoint: Option<u64>,
+}
+
+/// The log, open for appending the frames of new transactions
+#[derive(Debug)]
+pub struct Wal {
+    options: WalOptions,
+    segment: Box<dyn AppendFile>,
+    segment_index: u64,
+    segment_len: u64,
+    /// When the oldest frame that hasn't been synced was appended
+    unsynced_since: Option<Instant>,
+}
+
+impl Wal {
+    /// Replay the log in `options.dir` into `ctx`, creating the directory if it doesn't exist,
+    /// then start a new segment for the frames appended from now on
+    pub async fn open(options: WalOptions, ctx: &SessionContext) -> Result<(Self, ReplayStats)> {
+        fs::create_dir_all(&options.dir)?;
+        let mut replay = Replay {
+            ctx,
+            system_catalog: system_catalog(ctx)?,
+            tables: HashMap::new(),
+            stats: ReplayStats::default(),
+        };
+        let mut first_segment = 0;
+        if let Some((path, manifest)) = checkpoint::latest(&options.dir)? {
+            first_segment = manifest.wal_segment;
+            replay.checkpoint(&path, manifest).await?;
+        }
+        // Segments the checkpoint covers are left behind if a crash interrupts truncation
+        let mut segments = segments(&options.dir)?;
+        segments.retain(|(index, _)| *index >= first_segment);
+        for (position, (_, path)) in segments.iter().enumerate() {
+            let bytes = fs::read(path)?;
+            let mut offset = 0;
+            while offset < bytes.len() {
+                match read_frame(&bytes[offset..]) {
+                    Some((records, len)) => {
+                        replay.transaction(records).await?;
+                        offset += len;
+                    }
+                    None if position + 1 == segments.len() => {
+                        warn!(
+                            "Truncating a torn frame at byte {offset} of {}",
+                            path.display()
+                        );
+                        OpenOptions::new()
+                            .write(true)
+                            .open(path)?
+                            .set_len(offset as u64)?;
+                        replay.stats.truncated_bytes = (bytes.len() - offset) as u64;
+                        break;
+                    }
+                    None => {
+                        return exec_err!(
+                            "Write-ahead log segment {} is corrupt at byte {offset}",
+                            path.display()
+                        )
+                    }
+                }
+            }
+        }
+        let stats = replay.stats;
+        info!(
+            "Replayed {} transactions from the write-ahead log in {}",
+            stats.transactions,
+            options.dir.display()
+        );
+        let segment_index = segments
+            .last()
+            .map_or(first_segment, |(index, _)| index + 1);
+        let wal = Self {
+            segment: create_segment(&options, segment_index)?,
+            options,
+            segment_index,
+            segment_len: 0,
+            unsynced_since: None,
+        };
+        Ok((wal, stats))
+    }
+
+    /// Append the records of a committed transaction as one frame, syncing it as the sync policy
+    /// says
+    pub fn append(&mut self, records: Vec<LogRecord>) -> Result<()> {
+        let payload = Frame { records }.encode_to_vec();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        self.segment.append(&frame)?;
+        self.segment_len += frame.len() as u64;
+        self.unsynced_since.get_or_insert_with(Instant::now);
+        match self.options.sync {
+            SyncPolicy::Commit => self.sync()?,
+            SyncPolicy::Interval(_) => {
+                if self
+                    .sync_deadline()
+                    .is_some_and(|deadline| deadline <= Instant::now())
+                {
+                    self.sync()?;
+                } else {
+                    self.segment.flush()?;
+                }
+            }
+            SyncPolicy::Off => self.segment.flush()?,
+        }
+        if self.segment_len >= self.options.segment_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Start a new segment unless the current one is empty, so that the frames appended from now
+    /// on can be replayed on top of a checkpoint. Returns the index of that segment.
+    pub fn start_checkpoint(&mut self) -> Result<u64> {
+        if self.segment_len > 0 {
+            self.rotate()?;
+        }
+        Ok(self.segment_index)
+    }
+
+    pub fn options(&self) -> &WalOptions {
+        &self.options
+    }
+
+    /// Sync the frames appended since the last sync to disk
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced_since.take().is_some() {
+            self.segment.sync()?;
+        }
+        Ok(())
+    }
+
+    /// When frames appended but not yet synced must be synced by, if the sync policy syncs at an
+    /// interval
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        match (self.options.sync, self.unsynced_since) {
+            (SyncPolicy::Interval(interval), Some(since)) => Some(since + interval),
+            _ => None,
+        }
+    }
+
+    /// Close the current segment and start the next one
+    fn rotate(&mut self) -> Result<()> {
+        if self.options.sync != SyncPolicy::Off {
+            self.sync()?;
+        }
+        self.segment.finish()?;
+        self.segment_index += 1;
+        self.segment = create_segment(&self.options, self.segment_index)?;
+        self.segment_len = 0;
+        Ok(())
+    }
+}
+
+/// The segments in `dir` and their sequence numbers, in order
+pub fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "wal") {
+            let index = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok());
+            match index {
+                Some(index) => segments.push((index, path)),
+                None => warn!("Ignoring unexpected file {} in the log", path.display()),
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn create_segment(options: &WalOptions, index: u64) -> Result<Box<dyn AppendFile>> {
+    let segment = options
+        .io
+        .create(&options.dir.join(format!("{index:016}.wal")))?;
+    if options.sync != SyncPolicy::Off {
+        // Make sure the new file itself survives a crash
+        File::open(&options.dir)?.sync_all()?;
+    }
+    Ok(segment)
+}
+
+/// The records of the frame at the start of `bytes` and the frame's length, or `None` if it is
+/// incomplete or doesn't match its checksum
+fn read_frame(bytes: &[u8]) -> Option<(Vec<LogRecord>, usize)> {
+    let header = bytes.get(..FRAME_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().expect("header has 8 bytes")) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().expect("header has 8 bytes"));
+    if len == 0 {
+        // Every frame has a record, so this is the padding after an O_DIRECT write
+        return None;
+    }
+    let payload = bytes.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    let frame = Frame::decode(payload).ok()?;
+    Some((frame.records, FRAME_HEADER_LEN + len))
+}
+
+/// Applies the frames of the log to a session context
+struct Replay<'a> {
+    ctx: &'a SessionContext,
+    system_catalog: Arc<SystemCatalog>,
+    tables: HashMap<TableId, Arc<dyn TableProvider>>,
+    stats: ReplayStats,
+}
+
+impl Replay<'_> {
+    async fn transaction(&mut self, records: Vec<LogRecord>) -> Result<()> {
+        self.stats.transactions += 1;
+        for record in records {
+            self.stats.records += 1;
+            self.record(record).await?;
+        }
+        Ok(())
+    }
+
+    /// Recreate the tables of a checkpoint and their indexes, and insert their rows
+    async fn checkpoint(&mut self, path: &Path, manifest: Manifest) -> Result<()> {
+        for schema in manifest.schemas {
+            self.record(schema).await?;
+        }
+        for table in manifest.tables {
+            let mut records = table.records.into_iter();
+            // The table is created first, and its indexes built once its rows are in
+            if let Some(create) = records.next() {
+                self.record(create).await?;
+            }
+            let rows = checkpoint::read_rows(path, &table.file)?;
+            self.redo(table.id, vec![], rows).await?;
+            for record in records {
+                self.record(record).await?;
+            }
+        }
+        for role in manifest.roles {
+            self.record(role).await?;
+        }
+        info!("Loaded the checkpoint in {}", path.display());
+        self.stats.checkpoint = Some(manifest.wal_segment);
+        Ok(())
+    }
+
+    async fn record(&mut self, record: LogRecord) -> Result<()> {
+        match record.record {
+            Some(Record::CreateTable(create)) => self.create_table(create)?,
+            Some(Record::CreateIndex(create)) => {
+                if let Some(table) = self.quokka_table(create.table) {
+                    // Indexes logged before there were index methods are ordered ones
+                    let method = match create.method.as_str() {
+                        "" => IndexMethod::BTree,
+                        method => method.parse()?,
+                    };
+                    let index = IndexEntry {
+                        name: create.name,
+                        columns: create.columns,
+                        unique: create.unique,
+                        method,
+                        analyzer: create.analyzer,
+                    };
+                    self.system_catalog.create_index(&table, index).await?;
+                }
+            }
+            Some(Record::DropIndex(drop)) => {
+                if let Some(table) = self.quokka_table(drop.table) {
+                    self.system_catalog.drop_index(&table, &drop.name).await?;
+                }
+            }
+            Some(Record::Change(change)) => {
+                let (_, deleted) = decode_batches(&change.deleted)?;
+                let (_, inserted) = decode_batches(&change.inserted)?;
+                self.redo(change.table, deleted, inserted).await?;
+            }
+            Some(Record::AlterTable(alter)) => self.alter_table(alter).await?,
+            Some(Record::DropTable(drop)) => {
+                if self
+                    .system_catalog
+                    .drop_table(self.ctx, drop.table)?
+                    .is_none()
+                {
+                    warn!("Skipping the logged drop of unknown table {}", drop.table);
+                }
+                self.tables.remove(&drop.table);
+            }
+            Some(Record::CreateSchema(schema)) => {
+                self.system_catalog
+                    .create_schema(self.ctx, &schema.catalog, &schema.schema_name)?
+            }
+            Some(Record::DropSchema(schema)) => {
+                self.system_catalog
+                    .drop_schema(self.ctx, &schema.catalog, &schema.schema_name)?
+            }
+            Some(Record::TruncatePartitions(truncate)) => {
+                let (_, batches) = decode_batches(&truncate.values)?;
+                let mut values = vec![];
+                for batch in batches {
+                    for row in 0..batch.num_rows() {
+                        values.push(ScalarValue::try_from_array(batch.column(0), row)?);
+                    }
+                }
+                if let Some(table) = self.mem_table(truncate.table) {
+                    let table = as_mem_table(&table).expect("mem_table only returns mem tables");
+                    table
+                        .redo_truncate_partitions(&values, truncate.drop)
+                        .await?;
+                }
+            }
+            Some(Record::CreatePolicy(create)) => {
+                if let Some(table) = self.mem_table(create.table) {
+                    self.system_catalog.create_policy(
+                        &self.ctx.state(),
+                        &table,
+                        &create.name,
+                        &create.using,
+                    )?;
+                }
+            }
+            Some(Record::DropPolicy(drop)) => {
+                if let Some(table) = self.mem_table(drop.table) {
+                    self.system_catalog.drop_policy(&table, &drop.name)?;
+                }
+            }
+            Some(Record::CreateRole(create)) => {
+                self.system_catalog.roles_mut().create(create.into())?;
+            }
+            Some(Record::AlterRole(alter)) => {
+                self.system_catalog.roles_mut().alter(alter.into())?;
+            }
+            Some(Record::DropRole(name)) => {
+                self.system_catalog.roles_mut().remove(&name);
+            }
+            Some(Record::CreateAnalyzer(create)) => {
+                let analyzer = create.definition.parse()?;
+                self.system_catalog
+                    .analyzers_mut()
+                    .create(&create.name, analyzer)?;
+            }
+            Some(Record::DropAnalyzer(name)) => {
+                self.system_catalog.analyzers_mut().remove(&name);
+            }
+            Some(Record::Grant(grant)) => {
+                let securable = match grant.table {
+                    Some(table) => Securable::Table(table),
+                    None => Securable::Schema {
+                        catalog: grant.catalog,
+                        schema: grant.schema_name,
+                    },
+                };
+                let privileges = grant
+                    .privileges
+                    .iter()
+                    .map(|p| p.parse())
+                    .collect::<Result<Vec<Privilege>>>()?;
+                let mut roles = self.system_catalog.roles_mut();
+                if grant.revoke {
+                    roles.revoke(&grant.grantee, securable, &privileges);
+                } else {
+                    roles.grant(&grant.grantee, securable, &privileges)?;
+                }
+            }
+            Some(Record::GrantRole(grant)) => {
+                let mut roles = self.system_catalog.roles_mut();
+                if grant.revoke {
+                    roles.revoke_role(&grant.role, &grant.member);
+                } else {
+                    roles.grant_role(&grant.role, &grant.member)?;
+                }
+            }
+            None => return exec_err!("Write-ahead log record has an unknown type"),
+        }
+        Ok(())
+    }
+
+    async fn redo(
+        &mut self,
+        id: TableId,
+        deleted: Vec<RecordBatch>,
+        inserted: Vec<RecordBatch>,
+    ) -> Result<()> {
+        let Some(table) = self.tables.get(&id) else {
+            warn!("Skipping a logged change to unknown table {id}");
+            return Ok(());
+        };
+        if let Some(table) = as_mem_table(table) {
+            table.redo(deleted, inserted).await?;
+        } else if let Some(table) = as_block_table(table) {
+            table.redo(&deleted, &inserted)?;
+        }
+        Ok(())
+    }
+
+    fn create_table(&mut self, create: CreateTable) -> Result<()> {
+        let storage = match create.storage.as_str() {
+            "memory" => TableStorage::Memory,
+            "block" => TableStorage::Block,
+            storage => return exec_err!("Logged table has unknown storage {storage}"),
+        };
+        let (schema, _) = decode_batches(&create.schema)?;
+        let constraints = Constraints::new_unverified(
+            create
+                .constraints
+                .into_iter()
+                .map(|constraint| {
+                    let columns = constraint.columns.iter().map(|c| *c as usize).collect();
+                    if constraint.primary_key {
+                        Constraint::PrimaryKey(columns)
+                    } else {
+                        Constraint::Unique(columns)
+                    }
+                })
+                .collect(),
+        );
+        let mut column_defaults = HashMap::new();
+        let (_, defaults) = decode_batches(&create.column_defaults)?;
+        for batch in defaults.iter().take(1) {
+            for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+                let value = ScalarValue::try_from_array(column, 0)?;
+                column_defaults.insert(field.name().clone(), Expr::Literal(value));
+            }
+        }
+        let entry = TableEntry {
+            id: create.id,
+            name: TableReference::full(create.catalog, create.schema_name, create.table)
+                .resolve("", ""),
+            storage,
+            schema,
+            constraints,
+            column_defaults,
+            indexes: vec![],
+            created_at: create.created_at,
+            partition_by: create.partition_by,
+            policies: vec![],
+        };
+        // One empty partition for the logged rows to be inserted into
+        let table =
+            self.system_catalog
+                .create_table(self.ctx, entry, vec![vec![]], create.or_replace)?;
+        self.tables.insert(create.id, table);
+        Ok(())
+    }
+
+    async fn alter_table(&mut self, alter: AlterTable) -> Result<()> {
+        if !self.tables.contains_key(&alter.table) {
+            warn!("Skipping a logged change to unknown table {}", alter.table);
+            return Ok(());
+        }
+        let change = match alter.alteration {
+            Some(Alteration::AddColumn(add)) => {
+                let (schema, _) = decode_batches(&add.column)?;
+                let [field] = schema.fields().to_vec().try_into().map_err(|_| {
+                    DataFusionError::Execution("Logged column has no schema".to_string())
+                })?;
+                let (_, fill) = decode_batches(&add.fill)?;
+                let Some(fill) = fill.first() else {
+                    return exec_err!("Logged column {} has no fill value", field.name());
+                };
+                let (_, default) = decode_batches(&add.default)?;
+                let default = default
+                    .first()
+                    .map(|default| ScalarValue::try_from_array(default.column(0), 0))
+                    .transpose()?
+                    .map(Expr::Literal);
+                TableChange::AddColumn {
+                    fill: ScalarValue::try_from_array(fill.column(0), 0)?,
+                    field,
+                    default,
+                }
+            }
+            Some(Alteration::DropColumn(name)) => TableChange::DropColumn(name),
+            Some(Alteration::RenameColumn(rename)) => TableChange::RenameColumn {
+                old: rename.old,
+                new: rename.new,
+            },
+            Some(Alteration::RenameTable(rename)) => TableChange::RenameTable(
+                TableReference::full(rename.catalog, rename.schema_name, rename.table)
+                    .resolve("", ""),
+            ),
+            None => return exec_err!("Write-ahead log record has an unknown type"),
+        };
+        let table =
+            alter_table::apply_changes(self.ctx, &self.system_catalog, alter.table, &[change])
+                .await?;
+        self.tables.insert(alter.table, table);
+        Ok(())
+    }
+
+    fn quokka_table(&self, id: TableId) -> Option<Arc<dyn TableProvider>> {
+        let table = self.tables.get(&id);
+        if table.is_none() {
+            warn!("Skipping a logged change to unknown table {id}");
+        }
+        table.cloned()
+    }
+
+    fn mem_table(&self, id: TableId) -> Option<Arc<dyn TableProvider>> {
+        let table = self
+            .tables
+            .get(&id)
+            .filter(|table| as_mem_table(table).is_some());
+        if table.is_none() {
+            warn!("Skipping a logged change to unknown memory table {id}");
+        }
+        table.cloned()
+    }
+}
+
+/// The records of one committed transaction
+#[derive(Clone, PartialEq, Message)]
+struct Frame {
+    #[prost(message, repeated, tag = "1")]
+    records: Vec<LogRecord>,
+}
+
+/// A change to the catalog or a table, which replaying redoes
+#[derive(Clone, PartialEq, Message)]
+pub struct LogRecord {
+    #[prost(
+        oneof = "Record",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18"
+    )]
+    record: Option<Record>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Record {
+    #[prost(message, tag = "1")]
+    CreateTable(CreateTable),
+    #[prost(message, tag = "2")]
+    CreateIndex(CreateIndex),
+    #[prost(message, tag = "3")]
+    DropIndex(DropIndex),
+    #[prost(message, tag = "4")]
+    Change(Change),
+    #[prost(message, tag = "5")]
+    AlterTable(AlterTable),
+    #[prost(message, tag = "6")]
+    DropTable(DropTable),
+    #[prost(message, tag = "7")]
+    CreateSchema(LoggedSchema),
+    #[prost(message, tag = "8")]
+    DropSchema(LoggedSchema),
+    #[prost(message, tag = "9")]
+    TruncatePartitions(TruncatePartitions),
+    #[prost(message, tag = "10")]
+    CreatePolicy(CreatePolicy),
+    #[prost(message, tag = "11")]
+    DropPolicy(DropPolicy),
+    #[prost(message, tag = "12")]
+    CreateRole(CreateRole),
+    #[prost(string, tag = "13")]
+    DropRole(String),
+    #[prost(message, tag = "14")]
+    Grant(Grant),
+    #[prost(message, tag = "15")]
+    GrantRole(GrantRole),
+    #[prost(message, tag = "16")]
+    CreateAnalyzer(CreateAnalyzer),
+    #[prost(string, tag = "17")]
+    DropAnalyzer(String),
+    /// The attributes of a role after `ALTER ROLE`
+    #[prost(message, tag = "18")]
+    AlterRole(CreateRole),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct CreateTable {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(string, tag = "2")]
+    catalog: String,
+    #[prost(string, tag = "3")]
+    schema_name: String,
+    #[prost(string, tag = "4")]
+    table: String,
+    #[prost(string, tag = "5")]
+    storage: String,
+    /// Arrow IPC stream of the table's schema
+    #[prost(bytes = "vec", tag = "6")]
+    schema: Vec<u8>,
+    #[prost(message, repeated, tag = "7")]
+    constraints: Vec<LoggedConstraint>,
+    /// Arrow IPC stream with one row holding the literal column defaults
+    #[prost(bytes = "vec", tag = "8")]
+    column_defaults: Vec<u8>,
+    #[prost(bool, tag = "9")]
+    or_replace: bool,
+    /// Microseconds since the Unix epoch
+    #[prost(int64, tag = "10")]
+    created_at: i64,
+    #[prost(string, optional, tag = "11")]
+    partition_by: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LoggedConstraint {
+    #[prost(bool, tag = "1")]
+    primary_key: bool,
+    #[prost(uint64, repeated, tag = "2")]
+    columns: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct CreateIndex {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, repeated, tag = "3")]
+    columns: Vec<String>,
+    #[prost(bool, tag = "4")]
+    unique: bool,
+    #[prost(string, tag = "5")]
+    method: String,
+    #[prost(string, optional, tag = "6")]
+    analyzer: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DropIndex {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+}
+
+/// Rows deleted from and inserted into a table. An update deletes the old versions of the rows
+/// it changes and inserts the new ones. Rows with the same values can't be told apart, so
+/// replaying deletes any row equal to each deleted one.
+#[derive(Clone, PartialEq, Message)]
+struct Change {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    /// Arrow IPC stream of the deleted rows
+    #[prost(bytes = "vec", tag = "2")]
+    deleted: Vec<u8>,
+    /// Arrow IPC stream of the inserted rows
+    #[prost(bytes = "vec", tag = "3")]
+    inserted: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AlterTable {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    #[prost(oneof = "Alteration", tags = "2, 3, 4, 5")]
+    alteration: Option<Alteration>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Alteration {
+    #[prost(message, tag = "2")]
+    AddColumn(AddColumn),
+    #[prost(string, tag = "3")]
+    DropColumn(String),
+    #[prost(message, tag = "4")]
+    RenameColumn(RenameColumn),
+    #[prost(message, tag = "5")]
+    RenameTable(RenameTable),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AddColumn {
+    /// Arrow IPC stream of a schema holding just the new column
+    #[prost(bytes = "vec", tag = "1")]
+    column: Vec<u8>,
+    /// Arrow IPC stream with one row: the value the existing rows were given
+    #[prost(bytes = "vec", tag = "2")]
+    fill: Vec<u8>,
+    /// Arrow IPC stream with one row holding the column's default, if it's a literal
+    #[prost(bytes = "vec", tag = "3")]
+    default: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RenameColumn {
+    #[prost(string, tag = "1")]
+    old: String,
+    #[prost(string, tag = "2")]
+    new: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RenameTable {
+    #[prost(string, tag = "1")]
+    catalog: String,
+    #[prost(string, tag = "2")]
+    schema_name: String,
+    #[prost(string, tag = "3")]
+    table: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DropTable {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+}
+
+/// Rows removed from a partitioned table by the value of its partition key
+#[derive(Clone, PartialEq, Message)]
+struct TruncatePartitions {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    /// Arrow IPC stream with one row for each value of the partition key
+    #[prost(bytes = "vec", tag = "2")]
+    values: Vec<u8>,
+    /// Whether the values' partitions were dropped too
+    #[prost(bool, tag = "3")]
+    drop: bool,
+}
+
+/// A row-level security policy, logged as SQL and planned again on replay
+#[derive(Clone, PartialEq, Message)]
+struct CreatePolicy {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    using: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DropPolicy {
+    #[prost(uint64, tag = "1")]
+    table: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+}
+
+/// A role, with the hash of its password rather than the password
+#[derive(Clone, PartialEq, Message)]
+struct CreateRole {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(bool, tag = "2")]
+    login: bool,
+    #[prost(bool, tag = "3")]
+    superuser: bool,
+    #[prost(string, optional, tag = "4")]
+    password_hash: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    tenant_id: Option<String>,
+}
+
+impl From<&Role> for CreateRole {
+    fn from(role: &Role) -> Self {
+        Self {
+            name: role.name.clone(),
+            login: role.login,
+            superuser: role.superuser,
+            password_hash: role.password_hash().map(str::to_string),
+            tenant_id: role.tenant_id.clone(),
+        }
+    }
+}
+
+impl From<CreateRole> for Role {
+    fn from(create: CreateRole) -> Self {
+        Role::new(&create.name, create.login, create.superuser)
+            .with_password_hash(create.password_hash)
+            .with_tenant_id(create.tenant_id)
+    }
+}
+
+/// Privileges granted or revoked on a table, or on a schema if `table` isn't set
+#[derive(Clone, PartialEq, Message)]
+struct Grant {
+    #[prost(string, tag = "1")]
+    grantee: String,
+    #[prost(string, repeated, tag = "2")]
+    privileges: Vec<String>,
+    #[prost(uint64, optional, tag = "3")]
+    table: Option<u64>,
+    #[prost(string, tag = "4")]
+    catalog: String,
+    #[prost(string, tag = "5")]
+    schema_name: String,
+    #[prost(bool, tag = "6")]
+    revoke: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GrantRole {
+    #[prost(string, tag = "1")]
+    role: String,
+    #[prost(string, tag = "2")]
+    member: String,
+    #[prost(bool, tag = "3")]
+    revoke: bool,
+}
+
+/// A text analyzer, with its definition as `CREATE TEXT ANALYZER` takes it
+#[derive(Clone, PartialEq, Message)]
+struct CreateAnalyzer {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    definition: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LoggedSchema {
+    #[prost(string, tag = "1")]
+    catalog: String,
+    #[prost(string, tag = "2")]
+    schema_name: String,
+}
+
+impl LogRecord {
+    /// Record the creation of the table `entry` describes, without its indexes. Only literal
+    /// column defaults are logged; tables replayed from the log lose any others.
+    pub fn create_table(entry: &TableEntry, or_replace: bool) -> Result<Self> {
+        let mut fields = vec![];
+        let mut columns = vec![];
+        for (column, default) in &entry.column_defaults {
+            match default {
+                Expr::Literal(value) => {
+                    fields.push(Field::new(column, value.data_type(), true));
+                    columns.push(value.to_array()?);
+                }
+                default => warn!(
+                    "The default {default} of column {column} won't be written to the \
+                     write-ahead log"
+                ),
+            }
+        }
+        let defaults_schema = Arc::new(Schema::new(fields));
+        let defaults = if columns.is_empty() {
+            vec![]
+        } else {
+            vec![RecordBatch::try_new(defaults_schema.clone(), columns)?]
+        };
+        let constraints = entry
+            .constraints
+            .iter()
+            .map(|constraint| {
+                let (primary_key, columns) = match constraint {
+                    Constraint::PrimaryKey(columns) => (true, columns),
+                    Constraint::Unique(columns) => (false, columns),
+                };
+                LoggedConstraint {
+                    primary_key,
+                    columns: columns.iter().map(|c| *c as u64).collect(),
+                }
+            })
+            .collect();
+        Ok(Self::from(Record::CreateTable(CreateTable {
+            id: entry.id,
+            catalog: entry.name.catalog.to_string(),
+            schema_name: entry.name.schema.to_string(),
+            table: entry.name.table.to_string(),
+            storage: entry.storage.to_string(),
+            schema: encode_batches(&entry.schema, &[])?,
+            constraints,
+            column_defaults: encode_batches(&defaults_schema, &defaults)?,
+            or_replace,
+            created_at: entry.created_at,
+            partition_by: entry.partition_by.clone(),
+        })))
+    }
+
+    pub fn create_index(table: TableId, index: &IndexEntry) -> Self {
+        Self::from(Record::CreateIndex(CreateIndex {
+            table,
+            name: index.name.clone(),
+            columns: index.columns.clone(),
+            unique: index.unique,
+            method: index.method.to_string(),
+            analyzer: index.analyzer.clone(),
+        }))
+    }
+
+    pub fn drop_index(table: TableId, name: &str) -> Self {
+        Self::from(Record::DropIndex(DropIndex {
+            table,
+            name: name.to_string(),
+        }))
+    }
+
+    pub fn create_policy(table: TableId, name: &str, using: &str) -> Self {
+        Self::from(Record::CreatePolicy(CreatePolicy {
+            table,
+            name: name.to_string(),
+            using: using.to_string(),
+        }))
+    }
+
+    pub fn drop_policy(table: TableId, name: &str) -> Self {
+        Self::from(Record::DropPolicy(DropPolicy {
+            table,
+            name: name.to_string(),
+        }))
+    }
+
+    pub fn create_role(role: &Role) -> Self {
+        Self::from(Record::CreateRole(role.into()))
+    }
+
+    pub fn alter_role(role: &Role) -> Self {
+        Self::from(Record::AlterRole(role.into()))
+    }
+
+    pub fn drop_role(name: &str) -> Self {
+        Self::from(Record::DropRole(name.to_string()))
+    }
+
+    pub fn create_analyzer(name: &str, analyzer: &Analyzer) -> Self {
+        Self::from(Record::CreateAnalyzer(CreateAnalyzer {
+            name: name.to_string(),
+            definition: analyzer.to_string(),
+        }))
+    }
+
+    pub fn drop_analyzer(name: &str) -> Self {
+        Self::from(Record::DropAnalyzer(name.to_string()))
+    }
+
+    pub fn grant(
+        grantee: &str,
+        securable: &Securable,
+        privileges: &[Privilege],
+        revoke: bool,
+    ) -> Self {
+        let (table, catalog, schema_name) = match securable {
+            Securable::Table(table) => (Some(*table), String::new(), String::new()),
+            Securable::Schema { catalog, schema } => (None, catalog.clone(), schema.clone()),
+        };
+        Self::from(Record::Grant(Grant {
+            grantee: grantee.to_string(),
+            privileges: privileges.iter().map(|p| p.to_string()).collect(),
+            table,
+            catalog,
+            schema_name,
+            revoke,
+        }))
+    }
+
+    pub fn grant_role(role: &str, member: &str, revoke: bool) -> Self {
+        Self::from(Record::GrantRole(GrantRole {
+            role: role.to_string(),
+            member: member.to_string(),
+            revoke,
+        }))
+    }
+
+    pub fn drop_table(table: TableId) -> Self {
+        Self::from(Record::DropTable(DropTable { table }))
+    }
+
+    pub fn create_schema(catalog: &str, schema: &str) -> Self {
+        Self::from(Record::CreateSchema(LoggedSchema {
+            catalog: catalog.to_string(),
+            schema_name: schema.to_string(),
+        }))
+    }
+
+    pub fn drop_schema(catalog: &str, schema: &str) -> Self {
+        Self::from(Record::DropSchema(LoggedSchema {
+            catalog: catalog.to_string(),
+            schema_name: schema.to_string(),
+        }))
+    }
+
+    /// Record that the rows with `values` of column `column`, the partition key of table
+    /// `table`, were removed, and with `drop` that their partitions were dropped
+    pub fn truncate_partitions(
+        table: TableId,
+        schema: &SchemaRef,
+        column: usize,
+        values: &[ScalarValue],
+        drop: bool,
+    ) -> Result<Self> {
+        let field = schema.field(column).clone().with_nullable(true);
+        let values_schema = Arc::new(Schema::new(vec![field]));
+        let batches = if values.is_empty() {
+            vec![]
+        } else {
+            let values = ScalarValue::iter_to_array(values.iter().cloned())?;
+            vec![RecordBatch::try_new(values_schema.clone(), vec![values])?]
+        };
+        Ok(Self::from(Record::TruncatePartitions(TruncatePartitions {
+            table,
+            values: encode_batches(&values_schema, &batches)?,
+            drop,
+        })))
+    }
+
+    /// Record that `deleted` rows were deleted from table `table` and `inserted` rows inserted
+    pub fn change(
+        table: TableId,
+        schema: &SchemaRef,
+        deleted: &[RecordBatch],
+        inserted: &[RecordBatch],
+    ) -> Result<Self> {
+        Ok(Self::from(Record::Change(Change {
+            table,
+            deleted: encode_batches(schema, deleted)?,
+            inserted: encode_batches(schema, inserted)?,
+        })))
+    }
+}
+
+impl LogRecord {
+    /// Record a change `ALTER TABLE` made to table `table`. Like column defaults at creation,
+    /// only a literal default of an added column is logged.
+    pub fn alter_table(table: TableId, change: &TableChange) -> Result<Self> {
+        let alteration = match change {
+            TableChange::AddColumn {
+                field,
+                fill,
+                default,
+            } => {
+                // The fill value of an empty table can be null even if the column isn't nullable
+                let values = Arc::new(Schema::new(vec![field
+                    .as_ref()
+                    .clone()
+                    .with_nullable(true)]));
+                let fill = RecordBatch::try_new(values.clone(), vec![fill.to_array()?])?;
+                let default = match default {
+                    Some(Expr::Literal(value)) => vec![RecordBatch::try_new(
+                        values.clone(),
+                        vec![value.cast_to(field.data_type())?.to_array()?],
+                    )?],
+                    Some(default) => {
+                        warn!(
+                            "The default {default} of column {} won't be written to the \
+                             write-ahead log",
+                            field.name()
+                        );
+                        vec![]
+                    }
+                    None => vec![],
+                };
+                Alteration::AddColumn(AddColumn {
+                    column: encode_batches(&Arc::new(Schema::new(vec![field.clone()])), &[])?,
+                    fill: encode_batches(&values, &[fill])?,
+                    default: encode_batches(&values, &default)?,
+                })
+            }
+            TableChange::DropColumn(name) => Alteration::DropColumn(name.clone()),
+            TableChange::RenameColumn { old, new } => Alteration::RenameColumn(RenameColumn {
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            TableChange::RenameTable(name) => Alteration::RenameTable(RenameTable {
+                catalog: name.catalog.to_string(),
+                schema_name: name.schema.to_string(),
+                table: name.table.to_string(),
+            }),
+        };
+        Ok(Self::from(Record::AlterTable(AlterTable {
+            table,
+            alteration: Some(alteration),
+        })))
+    }
+}
+
+impl From<Record> for LogRecord {
+    fn from(record: Record) -> Self {
+        Self {
+            record: Some(record),
+        }
+    }
+}
+
+/// Write `batches` as an LZ4 compressed Arrow IPC stream
+fn encode_batches(schema: &SchemaRef, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let options =
+        IpcWriteOptions::default().try_with_compression(Some(CompressionType::LZ4_FRAME))?;
+    let mut writer = StreamWriter::try_new_with_options(vec![], schema, options)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+fn decode_batches(bytes: &[u8]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+    let schema = reader.schema();
+    let batches = reader.collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok((schema, batches))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::prelude::SessionConfig;
+
+    use super::*;
+    use crate::session::{new_context, new_context_with_config};
+    use crate::test_util::{query, run};
+    use crate::writer::{Writer, MAX_WRITE_GROUP, WRITE_QUEUE_CAPACITY};
+
+    /// A session whose writes are logged to `options.dir`, after replaying the log into it
+    async fn logged_context(options: WalOptions) -> Result<(SessionContext, ReplayStats)> {
+        let replayed = new_context();
+        let (wal, stats) = Wal::open(options, &replayed).await?;
+        let writer = Writer::start(WRITE_QUEUE_CAPACITY, MAX_WRITE_GROUP, Some(wal));
+        let ctx = new_context_with_config(SessionConfig::new().with_extension(Arc::new(writer)));
+        let catalog = replayed
+            .catalog("datafusion")
+            .expect("default catalog exists");
+        ctx.register_catalog("datafusion", catalog);
+        Ok((ctx, stats))
+    }
+
+    #[tokio::test]
+    async fn replay_restores_tables() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, stats) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(ReplayStats::default(), stats);
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, price DOUBLE DEFAULT 1.5)",
+            "INSERT INTO products VALUES (1, 'apple', 1.0), (2, 'pear', 2.0), (3, 'plum', 3.0)",
+            "CREATE INDEX products_name ON products (name)",
+            "UPDATE products SET price = 5.0 WHERE id = 2",
+            "DELETE FROM products WHERE id = 3",
+            "CREATE TABLE t (id INT, price DOUBLE) WITH (storage = 'block')",
+            "INSERT INTO t VALUES (1, 1.5), (2, NULL), (3, 3.5), (3, 3.5)",
+            "UPDATE t SET price = 2.5 WHERE price IS NULL",
+            "DELETE FROM t WHERE id = 1",
+            "INSERT INTO t VALUES (4, 4.5)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let products = query(&ctx, "SELECT * FROM products ORDER BY id").await?;
+        let t = query(&ctx, "SELECT * FROM t ORDER BY id").await?;
+        let catalog = query(&ctx, "SELECT * FROM qs_catalog.qs_tables").await?;
+        drop(ctx);
+
+        let ctx = new_context();
+        let (_wal, stats) = Wal::open(WalOptions::new(dir.path()), &ctx).await?;
+        assert_eq!(10, stats.transactions);
+        assert_eq!(0, stats.truncated_bytes);
+        assert_eq!(
+            products,
+            query(&ctx, "SELECT * FROM products ORDER BY id").await?
+        );
+        assert_eq!(t, query(&ctx, "SELECT * FROM t ORDER BY id").await?);
+        // The system catalog is rebuilt, creation times included
+        assert_eq!(
+            catalog,
+            query(&ctx, "SELECT * FROM qs_catalog.qs_tables").await?
+        );
+
+        let table = ctx.table_provider("products").await?;
+        assert_eq!(
+            vec!["products_name".to_string()],
+            as_mem_table(&table)
+                .expect("memory table")
+                .index_names()
+                .await
+        );
+        // Literal defaults are logged with the table
+        run(&ctx, "INSERT INTO products (id, name) VALUES (4, 'fig')").await?;
+        assert_eq!(
+            "+-------+\n| price |\n+-------+\n| 1.5   |\n+-------+",
+            query(&ctx, "SELECT price FROM products WHERE id = 4").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_many_changes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = WalOptions::new(dir.path()).with_sync(SyncPolicy::Off);
+        let (ctx, _) = logged_context(options.clone()).await?;
+        for sql in [
+            "CREATE TABLE items (id INT PRIMARY KEY, kind INT, name VARCHAR)",
+            "CREATE INDEX items_kind ON items (kind)",
+            "CREATE INDEX items_name ON items USING fulltext (name)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let colors = ["red", "green", "blue"];
+        for id in 0..400 {
+            let sql = format!(
+                "INSERT INTO items VALUES ({id}, {}, '{} item')",
+                id % 10,
+                colors[id % 3]
+            );
+            run(&ctx, &sql).await?;
+        }
+        for id in (0..400).step_by(5) {
+            let sql = format!("UPDATE items SET kind = kind + 10, name = 'moved' WHERE id = {id}");
+            run(&ctx, &sql).await?;
+        }
+        for id in (0..400).step_by(7) {
+            run(&ctx, &format!("DELETE FROM items WHERE id = {id}")).await?;
+        }
+        let queries = [
+            "SELECT count(*), sum(id), sum(kind) FROM items",
+            "SELECT id FROM items WHERE kind = 13 ORDER BY id",
+            "SELECT id FROM items WHERE id = 36 OR id = 35",
+            "SELECT count(*) FROM items WHERE match(name, 'moved')",
+            "SELECT count(*) FROM items WHERE match(name, 'red')",
+        ];
+        let mut expected = vec![];
+        for sql in queries {
+            expected.push(query(&ctx, sql).await?);
+        }
+        drop(ctx);
+
+        let ctx = new_context();
+        let (_wal, stats) = Wal::open(options, &ctx).await?;
+        assert_eq!(3 + 400 + 80 + 58, stats.transactions);
+        for (sql, expected) in queries.iter().zip(expected.iter()) {
+            assert_eq!(*expected, query(&ctx, sql).await?, "{sql}");
+        }
+        // The indexes were kept up to date with each replayed change
+        run(&ctx, "SET quokka.enable_index_scan = false").await?;
+        for (sql, expected) in queries.iter().zip(expected.iter()) {
+            assert_eq!(*expected, query(&ctx, sql).await?, "{sql}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_alter_table() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR, price DOUBLE)",
+            "INSERT INTO products VALUES (1, 'apple', 1.0), (2, 'pear', 2.0)",
+            "CREATE INDEX products_name ON products (name)",
+            "CREATE TEXT ANALYZER plurals TOKENIZER standard FILTERS lowercase, stemmer(english)",
+            "CREATE TEXT ANALYZER unused TOKENIZER whitespace",
+            "DROP TEXT ANALYZER unused",
+            "CREATE INDEX products_text ON products USING fulltext (name) \
+             WITH (analyzer = 'plurals')",
+            // Computed defaults aren't logged, but the values they filled in are
+            "ALTER TABLE products ADD COLUMN stock INT DEFAULT 1 + 1",
+            "ALTER TABLE products DROP COLUMN price, RENAME COLUMN name TO title",
+            "ALTER TABLE products RENAME TO items",
+            "INSERT INTO items VALUES (3, 'plum', 7)",
+            "CREATE TABLE t (id INT) WITH (storage = 'block')",
+            "INSERT INTO t VALUES (1)",
+            "ALTER TABLE t ADD COLUMN price DOUBLE DEFAULT 1.5",
+            "UPDATE t SET price = 2.5",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let items = query(&ctx, "SELECT * FROM items ORDER BY id").await?;
+        let t = query(&ctx, "SELECT * FROM t").await?;
+        let columns = query(
+            &ctx,
+            "SELECT table_id, column_name, data_type FROM qs_catalog.qs_columns",
+        )
+        .await?;
+        drop(ctx);
+
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(items, query(&ctx, "SELECT * FROM items ORDER BY id").await?);
+        assert_eq!(t, query(&ctx, "SELECT * FROM t").await?);
+        assert_eq!(
+            columns,
+            query(
+                &ctx,
+                "SELECT table_id, column_name, data_type FROM qs_catalog.qs_columns"
+            )
+            .await?
+        );
+        let table = ctx.table_provider("items").await?;
+        assert_eq!(
+            vec![
+                (
+                    "products_name".to_string(),
+                    vec!["title".to_string()],
+                    false
+                ),
+                (
+                    "products_text".to_string(),
+                    vec!["title".to_string()],
+                    false
+                )
+            ],
+            as_mem_table(&table)
+                .expect("memory table")
+                .index_definitions()
+                .await
+        );
+
+        // The altered schemas are checkpointed too
+        checkpoint::checkpoint(&ctx).await?;
+        run(&ctx, "INSERT INTO t (id) VALUES (2)").await?;
+        drop(ctx);
+        let (ctx, stats) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(1, stats.transactions);
+        assert_eq!(items, query(&ctx, "SELECT * FROM items ORDER BY id").await?);
+        let entry = system_catalog(&ctx)?
+            .entries()
+            .into_iter()
+            .find(|entry| entry.name.table == "items")
+            .expect("items entry");
+        assert_eq!(IndexMethod::FullText, entry.indexes[1].method);
+        assert_eq!(Some("plurals"), entry.indexes[1].analyzer.as_deref());
+        assert_eq!(
+            "+---------------+--------------------------------------------------------+\n\
+             | analyzer_name | definition                                             |\n\
+             +---------------+--------------------------------------------------------+\n\
+             | plurals       | TOKENIZER standard FILTERS lowercase, stemmer(english) |\n\
+             +---------------+--------------------------------------------------------+",
+            query(
+                &ctx,
+                "SELECT analyzer_name, definition FROM qs_catalog.qs_analyzers WHERE NOT builtin"
+            )
+            .await?
+        );
+        assert_eq!(
+            "+----+\n| id |\n+----+\n| 3  |\n+----+",
+            query(&ctx, "SELECT id FROM items WHERE match(title, 'plums')").await?
+        );
+        assert_eq!(
+            "+----+-------+\n\
+             | id | price |\n\
+             +----+-------+\n\
+             | 1  | 2.5   |\n\
+             | 2  | 1.5   |\n\
+             +----+-------+",
+            query(&ctx, "SELECT * FROM t ORDER BY id").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_drops() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        for sql in [
+            "CREATE SCHEMA shop",
+            "CREATE SCHEMA archive",
+            "CREATE TABLE shop.products (id INT PRIMARY KEY, name VARCHAR)",
+            "INSERT INTO shop.products VALUES (1, 'apple')",
+            "CREATE TABLE archive.orders (id INT) WITH (storage = 'block')",
+            "CREATE TABLE customers (id INT PRIMARY KEY)",
+            "DROP TABLE customers",
+            "CREATE TABLE customers (id INT PRIMARY KEY, name VARCHAR)",
+            "INSERT INTO customers VALUES (1, 'ann')",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        // Schemas are checkpointed too, empty or not
+        checkpoint::checkpoint(&ctx).await?;
+        run(&ctx, "CREATE SCHEMA empty").await?;
+        run(&ctx, "DROP SCHEMA archive CASCADE").await?;
+        let tables = "SELECT table_id, schema_name, table_name FROM qs_catalog.qs_tables";
+        let expected = query(&ctx, tables).await?;
+        drop(ctx);
+
+        let (ctx, stats) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(2, stats.transactions);
+        assert_eq!(expected, query(&ctx, tables).await?);
+        assert_eq!(
+            "+----+------+\n\
+             | id | name |\n\
+             +----+------+\n\
+             | 1  | ann  |\n\
+             +----+------+",
+            query(&ctx, "SELECT * FROM customers").await?
+        );
+        let catalog = ctx.catalog("datafusion").expect("default catalog exists");
+        assert!(catalog.schema("archive").is_none());
+        assert!(catalog.schema("empty").is_some());
+        assert_eq!(
+            vec![
+                ("datafusion".to_string(), "empty".to_string()),
+                ("datafusion".to_string(), "shop".to_string())
+            ],
+            system_catalog(&ctx)?.schemas()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_partitions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        for sql in [
+            "CREATE TABLE orders (id INT PRIMARY KEY, tenant_id INT) PARTITION BY (tenant_id)",
+            "INSERT INTO orders VALUES (1, 1), (2, 2), (3, 3), (4, 2)",
+            "TRUNCATE orders PARTITION (tenant_id = 1)",
+            "ALTER TABLE orders DROP PARTITION (3)",
+            "INSERT INTO orders VALUES (5, 4)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let partitions = "SELECT partition_value, row_count FROM qs_catalog.qs_partitions";
+        let expected = query(&ctx, partitions).await?;
+        drop(ctx);
+
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(expected, query(&ctx, partitions).await?);
+        assert_eq!(
+            "+----+-----------+\n\
+             | id | tenant_id |\n\
+             +----+-----------+\n\
+             | 2  | 2         |\n\
+             | 4  | 2         |\n\
+             | 5  | 4         |\n\
+             +----+-----------+",
+            query(&ctx, "SELECT * FROM orders ORDER BY id").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_policies() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        for sql in [
+            "CREATE TABLE docs (id INT PRIMARY KEY, tenant_id VARCHAR)",
+            "INSERT INTO docs VALUES (1, 'acme'), (2, 'globex'), (3, 'acme')",
+            "CREATE POLICY tenant ON docs USING (tenant_id = current_setting('quokka.tenant_id'))",
+            "CHECKPOINT",
+            "CREATE POLICY first ON docs USING (id = 1)",
+            "CREATE POLICY second ON docs USING (id = 2)",
+            "DROP POLICY first ON docs",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let policies = "SELECT policy_name, using FROM qs_catalog.qs_policies";
+        let expected = query(&ctx, policies).await?;
+        drop(ctx);
+
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(expected, query(&ctx, policies).await?);
+        run(&ctx, "SET quokka.tenant_id = 'acme'").await?;
+        assert_eq!(
+            "+----+\n\
+             | id |\n\
+             +----+\n\
+             | 1  |\n\
+             | 2  |\n\
+             | 3  |\n\
+             +----+",
+            query(&ctx, "SELECT id FROM docs ORDER BY id").await?
+        );
+        run(&ctx, "SET quokka.tenant_id = 'initech'").await?;
+        assert_eq!(
+            "+----+\n\
+             | id |\n\
+             +----+\n\
+             | 2  |\n\
+             +----+",
+            query(&ctx, "SELECT id FROM docs ORDER BY id").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_roles() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        for sql in [
+            "CREATE TABLE docs (id INT PRIMARY KEY)",
+            "CREATE ROLE readers",
+            "GRANT SELECT ON docs TO readers",
+            "CREATE USER alice PASSWORD 'secret'",
+            "CHECKPOINT",
+            "GRANT readers TO alice",
+            "CREATE USER bob PASSWORD 'hunter2'",
+            "GRANT INSERT, DELETE ON SCHEMA public TO bob",
+            "REVOKE DELETE ON SCHEMA public FROM bob",
+            "CREATE ROLE writers",
+            "DROP ROLE writers",
+            "ALTER USER bob TENANT 'acme' PASSWORD 'swordfish'",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let roles = "SELECT role_name, can_login, tenant_id, member_of FROM qs_catalog.qs_roles";
+        let grants = "SELECT grantee, privilege, table_id, schema_name FROM qs_catalog.qs_grants";
+        let expected = (query(&ctx, roles).await?, query(&ctx, grants).await?);
+        drop(ctx);
+
+        let (ctx, _) = logged_context(WalOptions::new(dir.path())).await?;
+        assert_eq!(
+            expected,
+            (query(&ctx, roles).await?, query(&ctx, grants).await?)
+        );
+        let system_catalog = system_catalog(&ctx)?;
+        let roles = system_catalog.roles();
+        roles.authenticate("alice", "secret")?;
+        roles.authenticate("bob", "swordfish")?;
+        assert!(roles.authenticate("bob", "hunter2").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn torn_frames_are_truncated() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Every frame fills a segment
+        let options = WalOptions::new(dir.path()).with_segment_size(1);
+        let (mut wal, _) = Wal::open(options.clone(), &new_context()).await?;
+        for _ in 0..3 {
+            wal.append(vec![LogRecord::drop_index(42, "missing")])?;
+        }
+        drop(wal);
+        let segments = segments(dir.path())?;
+        assert_eq!(4, segments.len());
+
+        // A crash while appending to the last segment leaves part of a frame
+        let (_, last) = segments.last().expect("segments were created");
+        fs::write(last, [9, 0, 0, 0, 1, 2])?;
+        let (wal, stats) = Wal::open(options.clone(), &new_context()).await?;
+        drop(wal);
+        assert_eq!(3, stats.transactions);
+        assert_eq!(6, stats.truncated_bytes);
+        assert_eq!(0, fs::metadata(last)?.len());
+
+        // Anywhere else, a bad frame means the log is corrupt
+        let (_, first) = &segments[0];
+        let mut bytes = fs::read(first)?;
+        let last_byte = bytes.len() - 1;
+        bytes[last_byte] ^= 1;
+        fs::write(first, bytes)?;
+        assert!(Wal::open(options, &new_context()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unlogged_writes_are_rolled_back() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Every frame fills a segment, so appending one starts the next segment
+        let options = WalOptions::new(dir.path()).with_segment_size(1);
+        let (ctx, _) = logged_context(options).await?;
+        for sql in [
+            "CREATE TABLE products (id INT PRIMARY KEY, name VARCHAR)",
+            "CREATE INDEX products_name ON products (name)",
+            "INSERT INTO products VALUES (1, 'apple'), (2, 'pear')",
+            "CREATE TABLE t (id INT, price DOUBLE) WITH (storage = 'block')",
+            "CREATE INDEX t_price ON t (price)",
+            "INSERT INTO t VALUES (1, 1.5), (2, 2.5)",
+        ] {
+            run(&ctx, sql).await?;
+        }
+        let products = query(&ctx, "SELECT * FROM products ORDER BY id").await?;
+        let t = query(&ctx, "SELECT * FROM t ORDER BY id").await?;
+
+        // Without its directory, the log fails to start a segment after each frame
+        fs::remove_dir_all(dir.path())?;
+        for sql in [
+            "INSERT INTO products VALUES (3, 'plum')",
+            "UPDATE products SET name = 'fig' WHERE id = 1",
+            "DELETE FROM products WHERE id = 2",
+            "INSERT INTO t VALUES (3, 3.5)",
+            "UPDATE t SET price = 9.5 WHERE id = 1",
+            "DELETE FROM t WHERE id = 2",
+        ] {
+            assert!(run(&ctx, sql).await.is_err(), "{sql}");
+        }
+        assert_eq!(
+            products,
+            query(&ctx, "SELECT * FROM products ORDER BY id").await?
+        );
+        assert_eq!(t, query(&ctx, "SELECT * FROM t ORDER BY id").await?);
+        for (sql, expected) in [
+            (
+                "SELECT * FROM products WHERE name = 'fig'",
+                "+----+------+\n| id | name |\n+----+------+\n+----+------+",
+            ),
+            (
+                "SELECT * FROM t WHERE price = 9.5",
+                "+----+-------+\n| id | price |\n+----+-------+\n+----+-------+",
+            ),
+        ] {
+            assert_eq!(expected, query(&ctx, sql).await?, "{sql}");
+        }
+
+        // Creating a table can't be rolled back, so the writer stops
+        assert!(run(&ctx, "CREATE TABLE u (id INT PRIMARY KEY)")
+            .await
+            .is_err());
+        let e = run(&ctx, "INSERT INTO products VALUES (3, 'plum')")
+            .await
+            .expect_err("writer has stopped");
+        // Depending on whether it was queued before the writer stopped taking requests
+        let e = e.to_string();
+        assert!(
+            e.contains("The writer has stopped") || e.contains("The writer stopped after failing"),
+            "{e}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_backend_replays_its_log() -> Result<()> {
+        // O_DIRECT depends on the filesystem, so it's only covered by the file_io tests
+        for backend in [IoBackend::Std, IoBackend::IoUring { direct: false }] {
+            let dir = tempfile::tempdir()?;
+            let options = WalOptions::new(dir.path()).with_io(backend);
+            let (mut wal, _) = Wal::open(options.clone(), &new_context()).await?;
+            for _ in 0..3 {
+                wal.append(vec![LogRecord::drop_index(42, "missing")])?;
+            }
+            drop(wal);
+            let (_, stats) = Wal::open(options, &new_context()).await?;
+            assert_eq!(3, stats.transactions, "{backend}");
+            assert_eq!(0, stats.truncated_bytes, "{backend}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_sync_policies() -> Result<()> {
+        assert_eq!(SyncPolicy::Commit, "commit".parse()?);
+        assert_eq!(SyncPolicy::Off, "off".parse()?);
+        assert_eq!(
+            SyncPolicy::Interval(Duration::from_millis(10)),
+            "10ms".parse()?
+        );
+        assert!("10s".parse::<SyncPolicy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn frames_are_checksummed() {
+        let records = vec![LogRecord::drop_index(1, "index")];
+        let payload = Frame {
+            records: records.clone(),
+        }
+        .encode_to_vec();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        assert_eq!(Some((records, frame.len())), read_frame(&frame));
+        assert_eq!(None, read_frame(&frame[..frame.len() - 1]));
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        assert_eq!(None, read_frame(&frame));
+    }
+}
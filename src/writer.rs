@@ -0,0 +1,479 @@
+//! The single writer that applies every `INSERT`, `UPDATE` and `DELETE`.
+//!
+//! Sessions submit their writes to a bounded queue, waiting for room when it is full. A dedicated
+//! thread takes the queued writes in groups, applies them one after another as a single
+//! [`WriteTransaction`], appends the log records of the writes that succeeded to the
+//! write-ahead log (see [`crate::wal`]) if there is one, commits the transaction and then
+//! completes each write with its row count. A write that fails must leave the tables as it found
+//! them; the rest of its group still commits.
+//!
+//! If the group's log records can't be appended, the writes that succeeded are rolled back in
+//! reverse order and the whole group fails without committing. Writes that can't be rolled back,
+//! like changes to the catalog, leave the tables ahead of the log, so the writer then stops and
+//! fails every write after them.
+//!
+//! A checkpoint (see [`crate::checkpoint`]) is queued like a write. The writer commits the writes
+//! queued before it, starts a new log segment and lets the checkpoint capture the tables before
+//! applying any more writes, so the checkpoint holds exactly the writes logged before that
+//! segment.
+
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+use datafusion::error::Result;
+use datafusion::prelude::SessionConfig;
+use datafusion_common::DataFusionError;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::error;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::transaction::{TransactionId, WriteTransaction};
+use crate::wal::{LogRecord, Wal, WalOptions};
+
+/// Number of writes that can wait in the queue before submitting blocks
+pub const WRITE_QUEUE_CAPACITY: usize = 1024;
+
+/// Most writes applied as one transaction
+pub const MAX_WRITE_GROUP: usize = 64;
+
+/// Undoes a write whose transaction can't commit
+pub type Rollback = Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>;
+
+/// What a write did
+#[derive(Default)]
+pub struct Applied {
+    /// Rows the write inserted, updated or deleted
+    pub row_count: u64,
+    /// Records that redo the write when the write-ahead log is replayed
+    pub log: Vec<LogRecord>,
+    /// Undoes the write if it can't be logged, or `None` if it can't be undone
+    pub rollback: Option<Rollback>,
+}
+
+impl Applied {
+    pub fn new(row_count: u64, log: Vec<LogRecord>) -> Self {
+        Self {
+            row_count,
+            log,
+            rollback: None,
+        }
+    }
+
+    /// Undo the write with `rollback` if it can't be logged. It runs in the write's
+    /// transaction, after the writes applied after it were rolled back.
+    pub fn with_rollback<F, Fut>(mut self, rollback: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.rollback = Some(Box::new(move || rollback().boxed()));
+        self
+    }
+}
+
+impl Debug for Applied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Applied")
+            .field("row_count", &self.row_count)
+            .field("log", &self.log)
+            .field("rollback", &self.rollback.is_some())
+            .finish()
+    }
+}
+
+type Write = Box<dyn FnOnce(TransactionId) -> BoxFuture<'static, Result<Applied>> + Send>;
+
+struct WriteRequest {
+    write: Write,
+    done: oneshot::Sender<Result<u64>>,
+}
+
+/// Captures the tables for a checkpoint, given the first log segment after it
+type Checkpoint = Box<dyn FnOnce(Result<u64>) -> BoxFuture<'static, ()> + Send>;
+
+enum Request {
+    Write(WriteRequest),
+    Checkpoint(Checkpoint),
+}
+
+/// Handle to a writer thread. The thread stops once every handle is dropped and the queue is
+/// empty.
+#[derive(Debug, Clone)]
+pub struct Writer {
+    requests: mpsc::Sender<Request>,
+    metrics: Arc<WriterMetrics>,
+    /// Options of the write-ahead log, if writes are logged
+    wal_options: Option<WalOptions>,
+}
+
+/// Totals of the writes applied by a [`Writer`]
+#[derive(Debug, Default)]
+pub struct WriterMetrics {
+    commits: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl WriterMetrics {
+    /// Transactions committed, each holding a group of writes
+    pub fn commits(&self) -> usize {
+        self.commits.load(Ordering::Relaxed)
+    }
+
+    /// Writes applied, including failed ones
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl Writer {
+    /// Start a writer thread with room for `queue_capacity` waiting writes, applying at most
+    /// `max_group` of them per transaction and logging them to `wal`
+    pub fn start(queue_capacity: usize, max_group: usize, wal: Option<Wal>) -> Self {
+        let (requests, receiver) = mpsc::channel(queue_capacity);
+        let metrics = Arc::new(WriterMetrics::default());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("writer runtime can be built");
+        let writer_metrics = metrics.clone();
+        let wal_options = wal.as_ref().map(|wal| wal.options().clone());
+        thread::Builder::new()
+            .name("quokka-writer".to_string())
+            .spawn(move || runtime.block_on(run(receiver, max_group, wal, &writer_metrics)))
+            .expect("writer thread can be spawned");
+        Self {
+            requests,
+            metrics,
+            wal_options,
+        }
+    }
+
+    /// Queue `write`, waiting for room if the queue is full, and return its row count once the
+    /// transaction it was applied in has committed. `write` receives the id of that transaction.
+    pub async fn submit<F, Fut>(&self, write: F) -> Result<u64>
+    where
+        F: FnOnce(TransactionId) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Applied>> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let request = WriteRequest {
+            write: Box::new(move |id| write(id).boxed()),
+            done,
+        };
+        self.send(Request::Write(request)).await?;
+        result
+            .await
+            .map_err(|_| DataFusionError::Execution("The writer dropped a write".to_string()))?
+    }
+
+    /// Queue a checkpoint, which runs `capture` once the writes queued before it have committed
+    /// and before any queued after it are applied. Returns the first log segment holding writes
+    /// that `capture` didn't see, along with what it captured.
+    pub async fn checkpoint<F, Fut, T>(&self, capture: F) -> Result<(u64, T)>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let checkpoint: Checkpoint = Box::new(move |segment| {
+            async move {
+                let captured = match segment {
+                    Ok(segment) => capture().await.map(|captured| (segment, captured)),
+                    Err(e) => Err(e),
+                };
+                // The caller may have stopped waiting
+                let _ = done.send(captured);
+            }
+            .boxed()
+        });
+        self.send(Request::Checkpoint(checkpoint)).await?;
+        result.await.map_err(|_| {
+            DataFusionError::Execution("The writer dropped a checkpoint".to_string())
+        })?
+    }
+
+    async fn send(&self, request: Request) -> Result<()> {
+        self.requests
+            .send(request)
+            .await
+            .map_err(|_| DataFusionError::Execution("The writer has stopped".to_string()))
+    }
+
+    /// Whether writes are logged to a write-ahead log, so they should return log records
+    pub fn is_logged(&self) -> bool {
+        self.wal_options.is_some()
+    }
+
+    /// Options of the write-ahead log, if writes are logged
+    pub fn wal_options(&self) -> Option<&WalOptions> {
+        self.wal_options.as_ref()
+    }
+
+    pub fn metrics(&self) -> &WriterMetrics {
+        &self.metrics
+    }
+}
+
+/// The writer of sessions that don't add one to their `SessionConfig`, started on first use. It
+/// doesn't log writes.
+pub fn writer() -> &'static Writer {
+    static WRITER: OnceLock<Writer> = OnceLock::new();
+    WRITER.get_or_init(|| Writer::start(WRITE_QUEUE_CAPACITY, MAX_WRITE_GROUP, None))
+}
+
+/// The writer added to `config` as an extension, or the default [`writer`]
+pub fn writer_for(config: &SessionConfig) -> Writer {
+    config
+        .get_extension::<Writer>()
+        .map_or_else(|| writer().clone(), |writer| writer.as_ref().clone())
+}
+
+async fn run(
+    mut receiver: mpsc::Receiver<Request>,
+    max_group: usize,
+    mut wal: Option<Wal>,
+    metrics: &WriterMetrics,
+) {
+    let mut group = Vec::with_capacity(max_group);
+    loop {
+        let sync_deadline = wal.as_ref().and_then(Wal::sync_deadline);
+        let received = match sync_deadline {
+            Some(deadline) => {
+                let received = receiver.recv_many(&mut group, max_group);
+                match tokio::time::timeout_at(deadline.into(), received).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        // Nothing was written for a while, so sync what was
+                        if let Err(e) = wal.as_mut().expect("deadline is from the log").sync() {
+                            error!("Failed to sync the write-ahead log: {e}");
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => receiver.recv_many(&mut group, max_group).await,
+        };
+        if received == 0 {
+            break;
+        }
+        let mut transaction = None;
+        let mut results = Vec::with_capacity(group.len());
+        let mut requests = group.drain(..);
+        while let Some(request) = requests.next() {
+            match request {
+                Request::Write(request) => {
+                    let transaction = transaction.get_or_insert_with(WriteTransaction::begin);
+                    let result = AssertUnwindSafe((request.write)(transaction.id()))
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|_| {
+                            error!("A write panicked");
+                            Err(DataFusionError::Execution("The write panicked".to_string()))
+                        });
+                    results.push((request.done, result));
+                }
+                Request::Checkpoint(checkpoint) => {
+                    if let Some(transaction) = transaction.take() {
+                        if !commit(transaction, &mut results, wal.as_mut(), metrics).await {
+                            let checkpoint = std::iter::once(Request::Checkpoint(checkpoint));
+                            stop(&mut receiver, checkpoint.chain(requests)).await;
+                            return;
+                        }
+                    }
+                    let segment = match wal.as_mut() {
+                        Some(wal) => wal.start_checkpoint(),
+                        None => Err(DataFusionError::Execution(
+                            "Checkpoints need a write-ahead log".to_string(),
+                        )),
+                    };
+                    checkpoint(segment).await;
+                }
+            }
+        }
+        drop(requests);
+        if let Some(transaction) = transaction {
+            if !commit(transaction, &mut results, wal.as_mut(), metrics).await {
+                stop(&mut receiver, []).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Log the writes of `transaction` that succeeded, commit it and complete its writes. If they
+/// can't be logged, they are rolled back and fail instead. Returns whether the writer can go on,
+/// which it can't once writes it couldn't roll back weren't logged.
+async fn commit(
+    transaction: WriteTransaction,
+    results: &mut Vec<(oneshot::Sender<Result<u64>>, Result<Applied>)>,
+    wal: Option<&mut Wal>,
+    metrics: &WriterMetrics,
+) -> bool {
+    if let Some(wal) = wal {
+        // A write that changed anything logs it, so one without a rollback can't be undone
+        let irreversible = results
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok())
+            .any(|applied| applied.rollback.is_none() && !applied.log.is_empty());
+        let log: Vec<LogRecord> = results
+            .iter_mut()
+            .filter_map(|(_, result)| result.as_mut().ok())
+            .flat_map(|applied| applied.log.drain(..))
+            .collect();
+        if !log.is_empty() {
+            if let Err(e) = wal.append(log) {
+                error!("Failed to append to the write-ahead log: {e}");
+                let rolled_back = roll_back(results).await;
+                // The transaction's versions were rolled back, so its id can be reused
+                drop(transaction);
+                metrics.writes.fetch_add(results.len(), Ordering::Relaxed);
+                for (done, result) in results.drain(..) {
+                    let result = result.and_then(|_| {
+                        Err(DataFusionError::Execution(format!(
+                            "The write couldn't be logged: {e}"
+                        )))
+                    });
+                    // The caller may have stopped waiting
+                    let _ = done.send(result);
+                }
+                return rolled_back && !irreversible;
+            }
+        }
+    }
+    transaction.commit();
+    metrics.commits.fetch_add(1, Ordering::Relaxed);
+    metrics.writes.fetch_add(results.len(), Ordering::Relaxed);
+    for (done, result) in results.drain(..) {
+        // The caller may have stopped waiting
+        let _ = done.send(result.map(|applied| applied.row_count));
+    }
+    true
+}
+
+/// Undo the writes of `results` that succeeded, the last one first. Returns whether every
+/// rollback succeeded.
+async fn roll_back(results: &mut [(oneshot::Sender<Result<u64>>, Result<Applied>)]) -> bool {
+    let mut rolled_back = true;
+    for (_, result) in results.iter_mut().rev() {
+        let Some(rollback) = result
+            .as_mut()
+            .ok()
+            .and_then(|applied| applied.rollback.take())
+        else {
+            continue;
+        };
+        if let Err(e) = rollback().await {
+            error!("Failed to roll back a write: {e}");
+            rolled_back = false;
+        }
+    }
+    rolled_back
+}
+
+/// Fail `requests` and every queued one, and stop taking requests, once the tables are ahead of
+/// the write-ahead log
+async fn stop(receiver: &mut mpsc::Receiver<Request>, requests: impl IntoIterator<Item = Request>) {
+    error!("Stopping the writer, since writes it couldn't roll back weren't logged");
+    receiver.close();
+    let stopped = || {
+        DataFusionError::Execution(
+            "The writer stopped after failing to append to the write-ahead log".to_string(),
+        )
+    };
+    let queued = std::iter::from_fn(|| receiver.try_recv().ok());
+    for request in requests.into_iter().chain(queued) {
+        match request {
+            Request::Write(request) => {
+                // The caller may have stopped waiting
+                let _ = request.done.send(Err(stopped()));
+            }
+            Request::Checkpoint(checkpoint) => checkpoint(Err(stopped())).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion_common::exec_err;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queued_writes_commit_together() -> Result<()> {
+        let writer = Writer::start(2, 8, None);
+        let (started, wait_started) = oneshot::channel();
+        let (release, wait_release) = oneshot::channel::<()>();
+        let first = tokio::spawn({
+            let writer = writer.clone();
+            async move {
+                writer
+                    .submit(move |id| async move {
+                        started.send(id).expect("test is waiting");
+                        wait_release.await.expect("test releases the write");
+                        Ok(Applied::new(1, vec![]))
+                    })
+                    .await
+            }
+        });
+        let first_id = wait_started.await.expect("first write started");
+
+        // The queue holds two writes while the first is applied, and the third waits for room
+        let submit = |rows: u64| {
+            let writer = writer.clone();
+            async move {
+                writer
+                    .submit(move |id| async move {
+                        if rows == 0 {
+                            return exec_err!("nothing to write");
+                        }
+                        Ok(Applied::new(id * 100 + rows, vec![]))
+                    })
+                    .await
+            }
+            .boxed()
+        };
+        let mut second = submit(2);
+        let mut failing = submit(0);
+        let mut third = submit(3);
+        assert!(futures::poll!(&mut second).is_pending());
+        assert!(futures::poll!(&mut failing).is_pending());
+        assert!(futures::poll!(&mut third).is_pending());
+        assert_eq!(0, writer.requests.capacity());
+
+        release.send(()).expect("write is waiting");
+        assert_eq!(1, first.await.expect("write doesn't panic")?);
+        let second = second.await?;
+        assert!(failing.await.is_err());
+        let third = third.await?;
+        // The second write was queued first, so it commits in the transaction after the first
+        assert!(second / 100 > first_id);
+        assert_eq!(2, second % 100);
+        assert_eq!(3, third % 100);
+        // The third write was queued once the second's group was taken, so it commits after it
+        assert!(third / 100 > second / 100);
+        assert_eq!(4, writer.metrics().writes());
+        // The second and failing writes were queued together, so they share a transaction
+        assert_eq!(3, writer.metrics().commits());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn panicking_writes_fail() {
+        let writer = Writer::start(1, 1, None);
+        let result = writer.submit(|_| async { panic!("write panicked") }).await;
+        assert!(result.is_err());
+        assert_eq!(
+            7,
+            writer
+                .submit(|_| async { Ok(Applied::new(7, vec![])) })
+                .await
+                .unwrap()
+        );
+    }
+}